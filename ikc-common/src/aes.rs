@@ -1,8 +1,9 @@
 pub mod cbc {
     use crate::error::CommonError;
     use crate::Result;
+    use aes::cipher::block_padding::{Iso7816, Pkcs7};
     use aes::cipher::generic_array::GenericArray;
-    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+    use aes::cipher::{BlockDecryptMut, BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit};
 
     type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
     type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
@@ -30,14 +31,106 @@ pub mod cbc {
         let iv = GenericArray::from_slice(iv);
         let pt = Aes128CbcDec::new(key, iv)
             .decrypt_padded_b2b_mut::<Pkcs7>(encrypted, &mut buf)
+            .map_err(|_| CommonError::InvalidPadding)?;
+        Ok(pt.to_vec())
+    }
+
+    /**
+    encrypt with the ISO 7816-4 padding (80 00 .. 00) used by secure messaging
+    */
+    pub fn encrypt_iso7816(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        if key.len() != 16 || iv.len() != 16 {
+            return Err(CommonError::InvalidKeyIvLength.into());
+        }
+        let mut buf = vec![0u8; data.len() + 16 - data.len() % 16];
+        let ct = Aes128CbcEnc::new(key.into(), iv.into())
+            .encrypt_padded_b2b_mut::<Iso7816>(data, &mut buf)
             .unwrap();
+        Ok(ct.to_vec())
+    }
+
+    pub fn decrypt_iso7816(encrypted: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        if key.len() != 16 || iv.len() != 16 {
+            return Err(CommonError::InvalidKeyIvLength.into());
+        }
+        let mut buf = vec![0u8; encrypted.len()];
+        let pt = Aes128CbcDec::new(key.into(), iv.into())
+            .decrypt_padded_b2b_mut::<Iso7816>(encrypted, &mut buf)
+            .map_err(|_| CommonError::InvalidPadding)?;
         Ok(pt.to_vec())
     }
+
+    /**
+    encrypt a single block without chaining, used to derive the secure messaging IVs
+    */
+    pub fn encrypt_block(block: &[u8; 16], key: &[u8]) -> Result<[u8; 16]> {
+        let cipher =
+            aes::Aes128::new_from_slice(key).map_err(|_| CommonError::InvalidKeyIvLength)?;
+        let mut block = GenericArray::clone_from_slice(block);
+        cipher.encrypt_block(&mut block);
+        Ok(block.into())
+    }
+}
+
+pub mod cmac {
+    use crate::error::CommonError;
+    use crate::Result;
+    use cmac::{Cmac, Mac};
+
+    /**
+    AES-128 CMAC (NIST SP 800-38B) of the data
+    */
+    pub fn cmac(data: &[u8], key: &[u8]) -> Result<[u8; 16]> {
+        let mut mac = <Cmac<aes::Aes128> as Mac>::new_from_slice(key)
+            .map_err(|_| CommonError::InvalidKeyIvLength)?;
+        mac.update(data);
+        Ok(mac.finalize().into_bytes().into())
+    }
+}
+
+pub mod gcm {
+    use crate::error::CommonError;
+    use crate::Result;
+    use aes_gcm::aead::{Aead, Payload};
+    use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+
+    /**
+    AES-256-GCM, the 16 byte tag follows the ciphertext. The associated data is authenticated
+    but not encrypted.
+    */
+    pub fn encrypt(data: &[u8], key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if nonce.len() != 12 {
+            return Err(CommonError::InvalidKeyIvLength.into());
+        }
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| CommonError::InvalidKeyIvLength)?;
+        let payload = Payload { msg: data, aad };
+        cipher
+            .encrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| CommonError::InvalidTag.into())
+    }
+
+    pub fn decrypt(encrypted: &[u8], key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if nonce.len() != 12 {
+            return Err(CommonError::InvalidKeyIvLength.into());
+        }
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| CommonError::InvalidKeyIvLength)?;
+        let payload = Payload {
+            msg: encrypted,
+            aad,
+        };
+        cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| CommonError::InvalidTag.into())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::aes::cbc::{decrypt_pkcs7, encrypt_pkcs7};
+    use crate::aes::cbc::{
+        decrypt_iso7816, decrypt_pkcs7, encrypt_block, encrypt_iso7816, encrypt_pkcs7,
+    };
+    use crate::aes::cmac::cmac;
+    use crate::aes::gcm;
     #[test]
     fn test_encrypt_pkcs7() {
         let data = "TokenCoreX".as_bytes();
@@ -57,4 +150,85 @@ mod test {
         let ret = encrypt_pkcs7(&data, &key, &iv);
         assert_eq!(ret.err().unwrap().to_string(), "invalid_key_iv_length");
     }
+
+    #[test]
+    fn test_encrypt_iso7816() {
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        let iv = [0u8; 16];
+        let ret = encrypt_iso7816(&[0x01, 0x02], &key, &iv).unwrap();
+        assert_eq!(ret.len(), 16);
+        assert_eq!(decrypt_iso7816(&ret, &key, &iv).unwrap(), vec![0x01, 0x02]);
+        let ret = encrypt_iso7816(&[0u8; 16], &key, &iv).unwrap();
+        assert_eq!(ret.len(), 32);
+
+        let ret = decrypt_pkcs7(&encrypt_pkcs7(&[0x01], &key, &iv).unwrap(), &key, &iv).unwrap();
+        assert_eq!(ret, vec![0x01]);
+        let wrong_padding = encrypt_pkcs7(&[0x01], &key, &iv).unwrap();
+        assert_eq!(
+            decrypt_iso7816(&wrong_padding, &key, &iv)
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid_padding"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_block() {
+        //FIPS-197 appendix C.1 example vector
+        let key = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let mut block = [0u8; 16];
+        block.copy_from_slice(&hex::decode("00112233445566778899aabbccddeeff").unwrap());
+        assert_eq!(
+            hex::encode(encrypt_block(&block, &key).unwrap()),
+            "69c4e0d86a7b0430d8cdb78070b4c55a"
+        );
+    }
+
+    #[test]
+    fn test_cmac() {
+        //NIST SP 800-38B AES-128 examples 1 and 2
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        assert_eq!(
+            hex::encode(cmac(&[], &key).unwrap()),
+            "bb1d6929e95937287fa37d129b756746"
+        );
+        let data = hex::decode("6bc1bee22e409f96e93d7e117393172a").unwrap();
+        assert_eq!(
+            hex::encode(cmac(&data, &key).unwrap()),
+            "070a16b46b4d4144f79bdd9dd04a287c"
+        );
+    }
+
+    #[test]
+    fn test_gcm() {
+        //test case 14 of the GCM specification (McGrew and Viega): 256 bit zero key
+        let key = [0u8; 32];
+        let nonce = [0u8; 12];
+        let ret = gcm::encrypt(&[0u8; 16], &key, &nonce, &[]).unwrap();
+        assert_eq!(
+            hex::encode(&ret),
+            "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"
+        );
+        assert_eq!(gcm::decrypt(&ret, &key, &nonce, &[]).unwrap(), [0u8; 16]);
+
+        let ret = gcm::encrypt(&[0x01], &key, &nonce, &[0xAA]).unwrap();
+        assert_eq!(
+            gcm::decrypt(&ret, &key, &nonce, &[0xAB])
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid_tag"
+        );
+        let mut tampered = ret.clone();
+        tampered[0] ^= 0x01;
+        assert!(gcm::decrypt(&tampered, &key, &nonce, &[0xAA]).is_err());
+        assert_eq!(
+            gcm::encrypt(&[0x01], &key[..16], &nonce, &[])
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid_key_iv_length"
+        );
+    }
 }
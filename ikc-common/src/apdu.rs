@@ -1,13 +1,16 @@
-use crate::constants::{BTC_AID, COSMOS_AID, EOS_AID, ETH_AID, LC_MAX};
+use crate::constants::{BTC_AID, COSMOS_AID, EOS_AID, ETH_AID, EXTENDED_LC_MAX, LC_MAX};
 use crate::error::ApduError;
-use crate::hex::ToHex;
+use crate::status_word::StatusWord;
 use crate::Result;
+use async_trait::async_trait;
 use hex;
+use ikc_transport::device::Device;
+use std::time::Duration;
 
 pub trait CoinCommonApdu: Default {
-    fn select_applet() -> String;
-    fn get_xpub(path: &str, verify_flag: bool) -> String;
-    fn register_address(address: &[u8]) -> String;
+    fn select_applet() -> Command;
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command>;
+    fn register_address(address: &[u8]) -> Result<Command>;
 }
 
 pub struct BtcApdu();
@@ -19,134 +22,62 @@ impl Default for BtcApdu {
 }
 
 impl CoinCommonApdu for BtcApdu {
-    fn select_applet() -> String {
+    fn select_applet() -> Command {
         Apdu::select_applet(BTC_AID)
     }
 
-    fn get_xpub(path: &str, verify_flag: bool) -> String {
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command> {
         Apdu::get_pubkey(0x43, path, verify_flag)
     }
 
-    fn register_address(address: &[u8]) -> String {
+    fn register_address(address: &[u8]) -> Result<Command> {
         Apdu::register_address(0x36, address)
     }
 }
 
 impl BtcApdu {
-    pub fn btc_prepare(ins: u8, p1: u8, data: &Vec<u8>) -> Vec<String> {
-        let mut apdu_vec = Vec::new();
-        let apdu_number = (data.len() - 1) / LC_MAX as usize + 1;
-        for index in 0..apdu_number {
-            if index == apdu_number - 1 {
-                let length = if data.len() % LC_MAX as usize == 0 {
-                    LC_MAX
-                } else {
-                    (data.len() % LC_MAX as usize) as u32
-                };
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, p1, 0x80, length as u8).to_array();
-                temp_apdu_vec.extend_from_slice(&data[index * LC_MAX as usize..]);
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            } else {
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, p1, 0x00, LC_MAX as u8).to_array();
-                temp_apdu_vec.extend_from_slice(
-                    &data[index * LC_MAX as usize..((index + 1) * LC_MAX as usize) as usize],
-                );
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            }
-        }
-        return apdu_vec;
+    pub fn btc_prepare(ins: u8, p1: u8, data: &[u8]) -> Vec<Command> {
+        Apdu::chunks(ins, data, false, |_, last| {
+            (p1, if last { 0x80 } else { 0x00 })
+        })
     }
 
-    pub fn btc_perpare_input(p1: u8, data: &Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x41, p1, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_perpare_input(p1: u8, data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, 0x41, p1, 0x00, data)
     }
 
     /**
      *p2 00:sign psbt transaction  80: sign message
+     *a preview longer than LC_MAX is sent as an extended length command
      **/
-    pub fn btc_psbt_preview(data: &Vec<u8>, p2: u8) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x4C, 0x00, p2, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_psbt_preview(data: &[u8], p2: u8) -> Result<Command> {
+        Apdu::command(0x80, 0x4C, 0x00, p2, data)
     }
 
-    pub fn btc_sign(index: u8, hash_type: u8, path: &str) -> String {
-        let path_bytes = path.as_bytes();
-        let mut apdu =
-            ApduHeader::new(0x80, 0x42, index, hash_type, path_bytes.len() as u8).to_array();
-        apdu.extend(path_bytes.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_sign(index: u8, hash_type: u8, path: &str) -> Command {
+        Command::new(0x80, 0x42, index, hash_type, path.as_bytes(), Some(0x00))
     }
 
-    pub fn btc_segwit_sign(last_one: bool, hash_type: u8, data: Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-
-        let mut apdu = match last_one {
-            true => ApduHeader::new(0x80, 0x32, 0x80, hash_type, data.len() as u8).to_array(),
-            _ => ApduHeader::new(0x80, 0x32, 0x00, hash_type, data.len() as u8).to_array(),
-        };
-
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_segwit_sign(last_one: bool, hash_type: u8, data: Vec<u8>) -> Result<Command> {
+        let p1 = if last_one { 0x80 } else { 0x00 };
+        Apdu::short_command(0x80, 0x32, p1, hash_type, &data)
     }
 
-    pub fn btc_taproot_sign(last_one: bool, data: Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-
-        let mut apdu = match last_one {
-            true => ApduHeader::new(0x80, 0x40, 0x80, 0x00, data.len() as u8).to_array(),
-            _ => ApduHeader::new(0x80, 0x40, 0x00, 0x00, data.len() as u8).to_array(),
-        };
-
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_taproot_sign(last_one: bool, data: Vec<u8>) -> Result<Command> {
+        let p1 = if last_one { 0x80 } else { 0x00 };
+        Apdu::short_command(0x80, 0x40, p1, 0x00, &data)
     }
 
-    pub fn btc_taproot_script_sign(last_one: bool, data: Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-
-        let mut apdu = match last_one {
-            true => ApduHeader::new(0x80, 0x40, 0x80, 0x80, data.len() as u8).to_array(),
-            _ => ApduHeader::new(0x80, 0x40, 0x00, 0x80, data.len() as u8).to_array(),
-        };
-
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_taproot_script_sign(last_one: bool, data: Vec<u8>) -> Result<Command> {
+        let p1 = if last_one { 0x80 } else { 0x00 };
+        Apdu::short_command(0x80, 0x40, p1, 0x80, &data)
     }
 
-    pub fn omni_prepare_data(p1: u8, data: Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x44, p1, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn omni_prepare_data(p1: u8, data: Vec<u8>) -> Result<Command> {
+        Apdu::short_command(0x80, 0x44, p1, 0x00, &data)
     }
 
-    pub fn register_name_address(name: &[u8], address: &[u8]) -> String {
+    pub fn register_name_address(name: &[u8], address: &[u8]) -> Result<Command> {
         let mut data: Vec<u8> = vec![];
         data.push(address.len() as u8);
         data.extend(address);
@@ -155,56 +86,17 @@ impl BtcApdu {
         Apdu::register_address(0x37, &data)
     }
 
-    pub fn btc_single_utxo_sign_prepare(ins: u8, data: &Vec<u8>) -> Vec<String> {
-        let mut apdu_vec = Vec::new();
-        let apdu_number = (data.len() - 1) / LC_MAX as usize + 1;
-        for index in 0..apdu_number {
-            if index == 0 && index == apdu_number - 1 {
-                let length = if data.len() % LC_MAX as usize == 0 {
-                    LC_MAX
-                } else {
-                    (data.len() % LC_MAX as usize) as u32
-                };
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, 0x00, 0x80, length as u8).to_array();
-                temp_apdu_vec.extend_from_slice(&data[index * LC_MAX as usize..]);
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            } else if index == 0 && index != apdu_number - 1 {
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, 0x00, 0x00, LC_MAX as u8).to_array();
-                temp_apdu_vec.extend_from_slice(
-                    &data[index * LC_MAX as usize..((index + 1) * LC_MAX as usize) as usize],
-                );
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            } else if index != 0 && index != apdu_number - 1 {
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, 0x80, 0x00, LC_MAX as u8).to_array();
-                temp_apdu_vec.extend_from_slice(
-                    &data[index * LC_MAX as usize..((index + 1) * LC_MAX as usize) as usize],
-                );
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            } else if index != 0 && index == apdu_number - 1 {
-                let length = if data.len() % LC_MAX as usize == 0 {
-                    LC_MAX
-                } else {
-                    (data.len() % LC_MAX as usize) as u32
-                };
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, 0x80, 0x80, length as u8).to_array();
-                temp_apdu_vec.extend_from_slice(&data[index * LC_MAX as usize..]);
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
-            }
-        }
-        return apdu_vec;
+    pub fn btc_single_utxo_sign_prepare(ins: u8, data: &[u8]) -> Vec<Command> {
+        Apdu::chunks(ins, data, false, |first, last| {
+            (
+                if first { 0x00 } else { 0x80 },
+                if last { 0x80 } else { 0x00 },
+            )
+        })
     }
 
-    pub fn btc_single_utxo_sign(index: u8, hash_type: u8, path: &str) -> String {
-        let path_bytes = path.as_bytes();
-        let mut apdu =
-            ApduHeader::new(0x80, 0x45, index, hash_type, path_bytes.len() as u8).to_array();
-        apdu.extend(path_bytes.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn btc_single_utxo_sign(index: u8, hash_type: u8, path: &str) -> Command {
+        Command::new(0x80, 0x45, index, hash_type, path.as_bytes(), Some(0x00))
     }
 }
 
@@ -217,33 +109,33 @@ impl Default for EthApdu {
 }
 
 impl CoinCommonApdu for EthApdu {
-    fn select_applet() -> String {
+    fn select_applet() -> Command {
         Apdu::select_applet(ETH_AID)
     }
 
-    fn get_xpub(path: &str, verify_flag: bool) -> String {
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command> {
         Apdu::get_pubkey(0x53, path, verify_flag)
     }
 
-    fn register_address(address: &[u8]) -> String {
+    fn register_address(address: &[u8]) -> Result<Command> {
         Apdu::register_address(0x56, address)
     }
 }
 
 impl EthApdu {
-    pub fn prepare_sign(data: Vec<u8>) -> Vec<String> {
+    pub fn prepare_sign(data: Vec<u8>) -> Vec<Command> {
         Apdu::prepare_sign(0x51, data)
     }
 
-    pub fn sign_digest(path: &str) -> String {
+    pub fn sign_digest(path: &str) -> Result<Command> {
         Apdu::sign_digest(0x52, 0x00, 0x00, path)
     }
 
-    pub fn prepare_personal_sign(data: Vec<u8>) -> Vec<String> {
+    pub fn prepare_personal_sign(data: Vec<u8>) -> Vec<Command> {
         Apdu::prepare_sign(0x54, data)
     }
 
-    pub fn personal_sign(path: &str) -> String {
+    pub fn personal_sign(path: &str) -> Result<Command> {
         Apdu::sign_digest(0x55, 0x00, 0x00, path)
     }
 }
@@ -257,46 +149,40 @@ impl Default for EosApdu {
 }
 
 impl CoinCommonApdu for EosApdu {
-    fn select_applet() -> String {
+    fn select_applet() -> Command {
         Apdu::select_applet(EOS_AID)
     }
 
-    fn get_xpub(path: &str, verify_flag: bool) -> String {
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command> {
         Apdu::get_pubkey(0x63, path, verify_flag)
     }
 
-    fn register_address(address: &[u8]) -> String {
+    fn register_address(address: &[u8]) -> Result<Command> {
         Apdu::register_address(0x66, address)
     }
 }
 
 impl EosApdu {
-    pub fn prepare_sign(data: Vec<u8>) -> Vec<String> {
+    pub fn prepare_sign(data: Vec<u8>) -> Vec<Command> {
         Apdu::prepare_sign(0x61, data)
     }
 
-    pub fn sign_digest(path: &str) -> String {
+    pub fn sign_digest(path: &str) -> Result<Command> {
         Apdu::sign_digest(0x52, 0x00, 0x00, path)
     }
 
-    pub fn sign_tx(nonce: usize) -> String {
-        let mut apdu = ApduHeader::new(0x80, 0x62, 0x00, 0x00, 0x02).to_array();
-        apdu.push(((nonce & 0xFF00) >> 8) as u8);
-        apdu.push((nonce & 0x00FF) as u8);
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn sign_tx(nonce: usize) -> Command {
+        let nonce = (nonce as u16).to_be_bytes();
+        Command::new(0x80, 0x62, 0x00, 0x00, &nonce, Some(0x00))
     }
 
-    pub fn prepare_message_sign(data: Vec<u8>) -> Vec<String> {
+    pub fn prepare_message_sign(data: Vec<u8>) -> Vec<Command> {
         Apdu::prepare_sign(0x64, data)
     }
 
-    pub fn sign_message(nonce: usize) -> String {
-        let mut apdu = ApduHeader::new(0x80, 0x65, 0x00, 0x00, 0x02).to_array();
-        apdu.push(((nonce & 0xFF00) >> 8) as u8);
-        apdu.push((nonce & 0x00FF) as u8);
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn sign_message(nonce: usize) -> Command {
+        let nonce = (nonce as u16).to_be_bytes();
+        Command::new(0x80, 0x65, 0x00, 0x00, &nonce, Some(0x00))
     }
 }
 
@@ -309,25 +195,25 @@ impl Default for CosmosApdu {
 }
 
 impl CoinCommonApdu for CosmosApdu {
-    fn select_applet() -> String {
+    fn select_applet() -> Command {
         Apdu::select_applet(COSMOS_AID)
     }
 
-    fn get_xpub(path: &str, verify_flag: bool) -> String {
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command> {
         Apdu::get_pubkey(0x73, path, verify_flag)
     }
 
-    fn register_address(address: &[u8]) -> String {
+    fn register_address(address: &[u8]) -> Result<Command> {
         Apdu::register_address(0x76, address)
     }
 }
 
 impl CosmosApdu {
-    pub fn prepare_sign(data: Vec<u8>) -> Vec<String> {
+    pub fn prepare_sign(data: Vec<u8>) -> Vec<Command> {
         Apdu::prepare_sign(0x71, data)
     }
 
-    pub fn sign_digest(path: &str) -> String {
+    pub fn sign_digest(path: &str) -> Result<Command> {
         Apdu::sign_digest(0x72, 0x00, 0x00, path)
     }
 }
@@ -341,21 +227,15 @@ impl Default for Secp256k1Apdu {
 }
 
 impl Secp256k1Apdu {
-    pub fn sign(data: &[u8]) -> Vec<String> {
+    pub fn sign(data: &[u8]) -> Vec<Command> {
         Apdu::prepare_sign(0x81, data.to_vec())
     }
 
-    pub fn get_xpub(data: &[u8]) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x82, 0x00, 0x00, data.len() as u8).to_array();
-        apdu.extend(data);
-        apdu.push(0x00); //Le
-        hex::encode(apdu)
+    pub fn get_xpub(data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, 0x82, 0x00, 0x00, data)
     }
 
-    pub fn register_address(name: &[u8], address: &[u8]) -> String {
+    pub fn register_address(name: &[u8], address: &[u8]) -> Result<Command> {
         let mut data: Vec<u8> = vec![];
         data.push(address.len() as u8);
         data.extend(address);
@@ -374,21 +254,15 @@ impl Default for Ed25519Apdu {
 }
 
 impl Ed25519Apdu {
-    pub fn sign(data: &[u8]) -> Vec<String> {
+    pub fn sign(data: &[u8]) -> Vec<Command> {
         Apdu::prepare_sign(0x81, data.to_vec())
     }
 
-    pub fn get_xpub(data: &[u8]) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x82, 0x00, 0x00, data.len() as u8).to_array();
-        apdu.extend(data);
-        apdu.push(0x00); //Le
-        hex::encode(apdu)
+    pub fn get_xpub(data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, 0x82, 0x00, 0x00, data)
     }
 
-    pub fn register_address(name: &[u8], address: &[u8]) -> String {
+    pub fn register_address(name: &[u8], address: &[u8]) -> Result<Command> {
         let mut data: Vec<u8> = vec![];
         data.push(address.len() as u8);
         data.extend(address);
@@ -407,194 +281,331 @@ impl Default for BtcForkApdu {
 }
 
 impl CoinCommonApdu for BtcForkApdu {
-    fn select_applet() -> String {
+    fn select_applet() -> Command {
         Apdu::select_applet(BTC_AID)
     }
 
-    fn get_xpub(path: &str, verify_flag: bool) -> String {
+    fn get_xpub(path: &str, verify_flag: bool) -> Result<Command> {
         Apdu::get_pubkey(0x43, path, verify_flag)
     }
 
-    fn register_address(address: &[u8]) -> String {
+    fn register_address(address: &[u8]) -> Result<Command> {
         Apdu::register_address(0x36, address)
     }
 }
 
 impl BtcForkApdu {
-    pub fn btc_fork_prepare(ins: u8, p1: u8, data: &Vec<u8>) -> Vec<String> {
-        let mut apdu_vec = Vec::new();
-        let apdu_number = (data.len() - 1) / LC_MAX as usize + 1;
-        for index in 0..apdu_number {
-            if index == apdu_number - 1 {
-                let length = if data.len() % LC_MAX as usize == 0 {
-                    LC_MAX
-                } else {
-                    (data.len() % LC_MAX as usize) as u32
-                };
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, p1, 0x80, length as u8).to_array();
-                temp_apdu_vec.extend_from_slice(&data[index * LC_MAX as usize..]);
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
+    pub fn btc_fork_prepare(ins: u8, p1: u8, data: &[u8]) -> Vec<Command> {
+        Apdu::chunks(ins, data, false, |_, last| {
+            (p1, if last { 0x80 } else { 0x00 })
+        })
+    }
+
+    pub fn btc_fork_perpare_input(ins: u8, p1: u8, data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, ins, p1, 0x00, data)
+    }
+
+    pub fn btc_fork_sign(ins: u8, index: u8, hash_type: u8, path: &str) -> Command {
+        Command::new(0x80, ins, index, hash_type, path.as_bytes(), Some(0x00))
+    }
+
+    pub fn btc_fork_segwit_sign(
+        ins: u8,
+        last_one: bool,
+        hash_type: u8,
+        data: Vec<u8>,
+    ) -> Result<Command> {
+        let p1 = if last_one { 0x80 } else { 0x00 };
+        Apdu::short_command(0x80, ins, p1, hash_type, &data)
+    }
+}
+
+/**
+Command APDU. le is the Le field when present, zero asks for as many bytes as available
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+    pub le: Option<u16>,
+}
+
+impl Command {
+    pub fn new(cla: u8, ins: u8, p1: u8, p2: u8, data: &[u8], le: Option<u16>) -> Command {
+        Command {
+            cla,
+            ins,
+            p1,
+            p2,
+            data: data.to_vec(),
+            le,
+        }
+    }
+
+    /**
+    serialize the command, with the extended length encoding (Lc and Le on 2 bytes after
+    a zero byte) when the data is longer than LC_MAX
+    */
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if self.data.len() > EXTENDED_LC_MAX {
+            return Err(ApduError::ImkeyDataTooLong.into());
+        }
+        let extended = self.data.len() > LC_MAX as usize || self.le.is_some_and(|le| le > 0x100);
+        let mut apdu = vec![self.cla, self.ins, self.p1, self.p2];
+        if extended {
+            apdu.push(0x00);
+        }
+        if !self.data.is_empty() {
+            if extended {
+                apdu.extend((self.data.len() as u16).to_be_bytes());
+            } else {
+                apdu.push(self.data.len() as u8);
+            }
+            apdu.extend(&self.data);
+        }
+        if let Some(le) = self.le {
+            if extended {
+                apdu.extend(le.to_be_bytes());
             } else {
-                let mut temp_apdu_vec =
-                    ApduHeader::new(0x80, ins, p1, 0x00, LC_MAX as u8).to_array();
-                temp_apdu_vec.extend_from_slice(
-                    &data[index * LC_MAX as usize..((index + 1) * LC_MAX as usize) as usize],
-                );
-                apdu_vec.push(hex::encode_upper(temp_apdu_vec));
+                apdu.push(le as u8);
             }
         }
-        return apdu_vec;
+        Ok(apdu)
+    }
+
+    /**
+    parse a short or extended length command
+    */
+    pub fn decode(apdu: &[u8]) -> Result<Command> {
+        if apdu.len() < 4 {
+            return Err(ApduError::ImkeyApduFormatError.into());
+        }
+        let (header, body) = apdu.split_at(4);
+        let (data, le) = match body {
+            [] => (&body[..0], None),
+            [le] => (&body[..0], Some(*le as u16)),
+            [0x00, le_1, le_2] => (&body[..0], Some(u16::from_be_bytes([*le_1, *le_2]))),
+            [0x00, lc_1, lc_2, rest @ ..] => {
+                let lc = u16::from_be_bytes([*lc_1, *lc_2]) as usize;
+                match rest.len() {
+                    length if length == lc && lc > 0 => (rest, None),
+                    length if length == lc + 2 && lc > 0 => (
+                        &rest[..lc],
+                        Some(u16::from_be_bytes([rest[lc], rest[lc + 1]])),
+                    ),
+                    _ => return Err(ApduError::ImkeyApduFormatError.into()),
+                }
+            }
+            [lc, rest @ ..] if *lc > 0 => {
+                let lc = *lc as usize;
+                match rest.len() {
+                    length if length == lc => (rest, None),
+                    length if length == lc + 1 => (&rest[..lc], Some(rest[lc] as u16)),
+                    _ => return Err(ApduError::ImkeyApduFormatError.into()),
+                }
+            }
+            _ => return Err(ApduError::ImkeyApduFormatError.into()),
+        };
+        Ok(Command::new(
+            header[0], header[1], header[2], header[3], data, le,
+        ))
     }
+}
+
+/**
+Response APDU: the data followed by the status word
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub data: Vec<u8>,
+    pub sw: u16,
+}
 
-    pub fn btc_fork_perpare_input(ins: u8, p1: u8, data: &Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
+impl Response {
+    pub fn decode(response: &[u8]) -> Result<Response> {
+        if response.len() < 2 {
+            return Err(ApduError::ImkeyInvalidResponse.into());
         }
-        let mut apdu = ApduHeader::new(0x80, ins, p1, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+        let (data, sw) = response.split_at(response.len() - 2);
+        Ok(Response {
+            data: data.to_vec(),
+            sw: u16::from_be_bytes([sw[0], sw[1]]),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut response = self.data.clone();
+        response.extend(self.sw.to_be_bytes());
+        response
     }
 
-    pub fn btc_fork_sign(ins: u8, index: u8, hash_type: u8, path: &str) -> String {
-        let path_bytes = path.as_bytes();
-        let mut apdu =
-            ApduHeader::new(0x80, ins, index, hash_type, path_bytes.len() as u8).to_array();
-        apdu.extend(path_bytes.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn status(&self) -> StatusWord {
+        StatusWord::from(self.sw)
     }
 
-    pub fn btc_fork_segwit_sign(ins: u8, last_one: bool, hash_type: u8, data: Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
+    /**
+    turn an error status word into the matching StatusWord error
+    */
+    pub fn check(&self) -> Result<()> {
+        match self.status() {
+            StatusWord::Success => Ok(()),
+            status => Err(status.into()),
         }
+    }
 
-        let mut apdu = match last_one {
-            true => ApduHeader::new(0x80, ins, 0x80, hash_type, data.len() as u8).to_array(),
-            _ => ApduHeader::new(0x80, ins, 0x00, hash_type, data.len() as u8).to_array(),
-        };
+    /**
+    the data of a successful response
+    */
+    pub fn into_data(self) -> Result<Vec<u8>> {
+        self.check()?;
+        Ok(self.data)
+    }
 
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    /**
+    length bytes of the data from offset, failing when the response is too short
+    */
+    pub fn field(&self, offset: usize, length: usize) -> Result<&[u8]> {
+        self.data
+            .get(offset..offset + length)
+            .ok_or_else(|| ApduError::ImkeyInvalidResponse.into())
     }
 }
 
-pub struct Apdu {}
+/**
+send typed commands to a device
+*/
+#[async_trait(?Send)]
+pub trait Exchange {
+    async fn exchange(&self, command: &Command) -> Result<Response>;
 
-struct ApduHeader {
-    cla: u8,
-    ins: u8,
-    p1: u8,
-    p2: u8,
-    lc: u8,
+    /**
+    exchange a command waiting for a user confirmation, fail with a timeout error
+    if the device did not answer in time
+    */
+    async fn exchange_with_timeout(&self, command: &Command, timeout: Duration)
+        -> Result<Response>;
 }
 
-impl ApduHeader {
-    fn new(cla: u8, ins: u8, p1: u8, p2: u8, lc: u8) -> ApduHeader {
-        ApduHeader {
-            cla: cla,
-            ins: ins,
-            p1: p1,
-            p2: p2,
-            lc: lc,
-        }
+#[async_trait(?Send)]
+impl Exchange for Device {
+    async fn exchange(&self, command: &Command) -> Result<Response> {
+        let response = self
+            .send_apdu(&self.wrap_command(&command.encode()?)?)
+            .await?;
+        let response = get_remaining_data(self, Response::decode(&response)?).await?;
+        Response::decode(&self.unwrap_response(&response.encode())?)
+    }
+
+    async fn exchange_with_timeout(
+        &self,
+        command: &Command,
+        timeout: Duration,
+    ) -> Result<Response> {
+        let response = self
+            .send_apdu_with_timeout(&self.wrap_command(&command.encode()?)?, timeout)
+            .await?;
+        let response = get_remaining_data(self, Response::decode(&response)?).await?;
+        Response::decode(&self.unwrap_response(&response.encode())?)
     }
+}
 
-    fn to_array(&self) -> Vec<u8> {
-        vec![self.cla, self.ins, self.p1, self.p2, self.lc]
+/**
+while the device answers 61xx, read the xx following bytes with GET RESPONSE and return the
+whole data with the final status word
+*/
+async fn get_remaining_data(device: &Device, mut response: Response) -> Result<Response> {
+    let mut data = vec![];
+    //bounds the chain to the largest extended length response
+    for _ in 0..=EXTENDED_LC_MAX / 0x100 {
+        data.append(&mut response.data);
+        let length = match response.status() {
+            StatusWord::MoreData(length) => length,
+            _ => {
+                response.data = data;
+                return Ok(response);
+            }
+        };
+        let get_response = Command::new(0x00, 0xC0, 0x00, 0x00, &[], Some(length as u16));
+        response = Response::decode(&device.send_apdu(&get_response.encode()?).await?)?;
     }
+    Err(ApduError::ImkeyInvalidResponse.into())
 }
 
+pub struct Apdu {}
+
 impl Apdu {
-    pub fn select_applet(aid: &str) -> String {
+    pub fn select_applet(aid: &str) -> Command {
         let aid_array = hex::decode(aid).unwrap();
-        let mut apdu = Vec::new();
-        let apdu_header = ApduHeader::new(0x00, 0xA4, 0x04, 0x00, aid_array.len() as u8);
-        apdu.extend(apdu_header.to_array().iter());
-        apdu.extend(aid_array.iter());
-        apdu.push(0x00); //Le
-        hex::encode(apdu)
-    }
-
-    pub fn prepare_sign(ins: u8, data: Vec<u8>) -> Vec<String> {
-        let mut apdu_list = Vec::new();
-        let size = data.len() as u32 / LC_MAX as u32
-            + if data.len() as u32 % LC_MAX as u32 != 0 {
-                1
-            } else {
-                0
-            };
-
-        for i in 0..size {
-            let mut apdu = Vec::new();
-            let p1 = if i == 0 { 0x00 } else { 0x80 };
-            let (p2, lc) = if i == size - 1 {
-                (
-                    0x80,
-                    (data.len() as u32 - LC_MAX as u32 * (size - 1 as u32)) as u8,
-                )
-            } else {
-                (0x00, 0xF5)
-            };
-            let apdu_header = ApduHeader::new(0x80, ins, p1, p2, lc);
-            apdu.extend(apdu_header.to_array().iter());
-            let payload =
-                &data[(i as u32 * LC_MAX) as usize..(i as u32 * LC_MAX + lc as u32) as usize];
-            apdu.extend(payload.iter()); //payload
-            apdu.push(0x00); //le
-            apdu_list.push(hex::encode(apdu))
-        }
-        apdu_list
+        Command::new(0x00, 0xA4, 0x04, 0x00, &aid_array, Some(0x00))
     }
 
-    pub fn get_pubkey(ins: u8, path: &str, verify_flag: bool) -> String {
-        let path_bytes = path.as_bytes();
-        if path_bytes.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let p1 = if verify_flag { 0x01 } else { 0x00 };
-        let mut apdu = ApduHeader::new(0x80, ins, p1, 0x00, path_bytes.len() as u8).to_array();
-        apdu.extend(path_bytes.iter());
-        apdu.push(0x00); //Le
-        hex::encode(apdu)
+    pub fn prepare_sign(ins: u8, data: Vec<u8>) -> Vec<Command> {
+        Apdu::chunks(ins, &data, true, |first, last| {
+            (
+                if first { 0x00 } else { 0x80 },
+                if last { 0x80 } else { 0x00 },
+            )
+        })
+    }
+
+    /**
+    split data in commands of at most LC_MAX bytes, p1_p2(first, last) gives the parameters
+    of each command. Nothing is sent for empty data
+    */
+    pub fn chunks<F>(ins: u8, data: &[u8], with_le: bool, p1_p2: F) -> Vec<Command>
+    where
+        F: Fn(bool, bool) -> (u8, u8),
+    {
+        let chunk_number = data.len().div_ceil(LC_MAX as usize);
+        let le = if with_le { Some(0x00) } else { None };
+        data.chunks(LC_MAX as usize)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let (p1, p2) = p1_p2(index == 0, index == chunk_number - 1);
+                Command::new(0x80, ins, p1, p2, chunk, le)
+            })
+            .collect()
     }
 
-    pub fn register_address(ins: u8, data: &[u8]) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
+    /**
+    command followed by Le, failing when the data does not fit in a short command
+    */
+    pub fn short_command(cla: u8, ins: u8, p1: u8, p2: u8, data: &[u8]) -> Result<Command> {
+        if data.len() > LC_MAX as usize {
+            return Err(ApduError::ImkeyDataTooLong.into());
         }
-        let mut apdu = ApduHeader::new(0x80, ins, 0x00, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+        Ok(Command::new(cla, ins, p1, p2, data, Some(0x00)))
     }
 
-    pub fn sign_digest(ins: u8, index: u8, hashtype: u8, path: &str) -> String {
-        let path_bytes = path.as_bytes();
-        if path_bytes.len() as u32 > LC_MAX {
-            panic!("data to long");
+    /**
+    command followed by Le, with the extended length encoding (3 bytes Lc, 2 bytes Le) when
+    the data does not fit in a short command
+    */
+    pub fn command(cla: u8, ins: u8, p1: u8, p2: u8, data: &[u8]) -> Result<Command> {
+        if data.len() > EXTENDED_LC_MAX {
+            return Err(ApduError::ImkeyDataTooLong.into());
         }
+        Ok(Command::new(cla, ins, p1, p2, data, Some(0x00)))
+    }
+
+    pub fn get_pubkey(ins: u8, path: &str, verify_flag: bool) -> Result<Command> {
+        let p1 = if verify_flag { 0x01 } else { 0x00 };
+        Apdu::short_command(0x80, ins, p1, 0x00, path.as_bytes())
+    }
 
-        let mut apdu = Vec::new();
+    pub fn register_address(ins: u8, data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, ins, 0x00, 0x00, data)
+    }
 
-        let apdu_header = ApduHeader::new(0x80, ins, index, hashtype, path_bytes.len() as u8);
-        apdu.extend(apdu_header.to_array().iter());
-        apdu.extend(path_bytes.iter()); //payload
-        apdu.push(0x00); //le
-        hex::encode(apdu)
+    pub fn sign_digest(ins: u8, index: u8, hashtype: u8, path: &str) -> Result<Command> {
+        Apdu::short_command(0x80, ins, index, hashtype, path.as_bytes())
     }
 
-    pub fn set_ble_name(ble_name: &str) -> String {
-        let ble_name_array = ble_name.as_bytes();
-        let mut apdu = Vec::new();
-        let apdu_header = ApduHeader::new(0xFF, 0xDA, 0x46, 0x54, ble_name_array.len() as u8);
-        apdu.extend(apdu_header.to_array().iter());
-        apdu.extend(ble_name_array.iter());
-        apdu.push(0x00); //Le
-        hex::encode(apdu)
+    pub fn set_ble_name(ble_name: &str) -> Command {
+        Command::new(0xFF, 0xDA, 0x46, 0x54, ble_name.as_bytes(), Some(0x00))
     }
 }
 
@@ -604,97 +615,73 @@ impl ImkApdu {
     /**
     binding check apdu build
     */
-    pub fn bind_check(data: &Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x71, 0x00, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn bind_check(data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, 0x71, 0x00, 0x00, data)
     }
 
     /**
     binding check apdu build
     */
-    pub fn generate_auth_code() -> String {
-        let apdu = ApduHeader::new(0x80, 0x72, 0x00, 0x00, 0x00).to_array();
-        apdu.to_hex().to_uppercase()
+    pub fn generate_auth_code() -> Command {
+        Command::new(0x80, 0x72, 0x00, 0x00, &[], Some(0x00))
     }
 
     /**
     bind code verify
     */
-    pub fn identity_verify(data: &Vec<u8>) -> String {
-        if data.len() as u32 > LC_MAX {
-            panic!("data to long");
-        }
-        let mut apdu = ApduHeader::new(0x80, 0x73, 0x80, 0x00, data.len() as u8).to_array();
-        apdu.extend(data.iter());
-        apdu.push(0x00);
-        apdu.to_hex().to_uppercase()
+    pub fn identity_verify(data: &[u8]) -> Result<Command> {
+        Apdu::short_command(0x80, 0x73, 0x80, 0x00, data)
     }
-}
 
-pub struct ApduCheck {}
-
-impl ApduCheck {
-    pub fn check_response(response_data: &str) -> Result<()> {
-        let response_data: &str = &response_data[response_data.len() - 4..];
-        match response_data {
-            "9000" => Ok(()),
-            "6940" => Err(ApduError::ImkeyUserNotConfirmed.into()),
-            "6985" => Err(ApduError::ImkeyConditionsNotSatisfied.into()),
-            "6A82" => Err(ApduError::ImkeyAppletNotExist.into()),
-            "6A86" => Err(ApduError::ImkeyCommandFormatError.into()),
-            "6E00" => Err(ApduError::ImkeyCommandFormatError.into()),
-            "6A80" => Err(ApduError::ImkeyCommandDataError.into()),
-            "6700" => Err(ApduError::ImkeyApduWrongLength.into()),
-            "6942" => Err(ApduError::ImkeySignatureVerifyFail.into()),
-            "6D00" => Err(ApduError::ImkeyAppletFunctionNotSupported.into()),
-            "6941" => Err(ApduError::ImkeyExceededMaxUtxoNumber.into()),
-            "F000" => Err(ApduError::ImkeyWalletNotCreated.into()),
-            "F080" => Err(ApduError::ImkeyInMenuPage.into()),
-            "F081" => Err(ApduError::ImkeyPinNotVerified.into()),
-            "6F01" => Err(ApduError::ImkeyBluetoothChannelError.into()),
-            "6943" => Err(ApduError::ImkeyMnemonicCheckFail.into()),
-            _ => Err(anyhow!("imkey_command_execute_fail_{}", response_data)), //Err(ApduError::ImkeyCommandExecuteFail.into())
-        }
+    /**
+    open a secure channel, the device answers its challenge and cryptogram
+    */
+    pub fn open_secure_channel(host_challenge: &[u8]) -> Command {
+        Command::new(0x80, 0x74, 0x00, 0x00, host_challenge, Some(0x00))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::apdu::{
-        Apdu, ApduCheck, ApduHeader, BtcApdu, CoinCommonApdu, CosmosApdu, EosApdu, EthApdu, ImkApdu,
+        Apdu, BtcApdu, CoinCommonApdu, Command, CosmosApdu, EosApdu, EthApdu, Exchange, ImkApdu,
+        Response,
     };
+    use crate::status_word::StatusWord;
+    use futures::executor::block_on;
     use hex::FromHex;
+    use ikc_transport::device::Device;
+    use ikc_transport::mock::MockTransport;
+
+    fn encoded(command: Command) -> String {
+        hex::encode_upper(command.encode().unwrap())
+    }
 
     #[test]
     fn select_applet_test() {
         assert_eq!(
-            BtcApdu::select_applet(),
-            String::from("00a4040005695f62746300")
+            encoded(BtcApdu::select_applet()),
+            String::from("00A4040005695F62746300")
         );
         assert_eq!(
-            EthApdu::select_applet(),
-            String::from("00a4040005695f65746800")
+            encoded(EthApdu::select_applet()),
+            String::from("00A4040005695F65746800")
         );
         assert_eq!(
-            EosApdu::select_applet(),
-            String::from("00a4040005695f656f7300")
+            encoded(EosApdu::select_applet()),
+            String::from("00A4040005695F656F7300")
         );
         assert_eq!(
-            CosmosApdu::select_applet(),
-            String::from("00a4040008695f636f736d6f7300")
+            encoded(CosmosApdu::select_applet()),
+            String::from("00A4040008695F636F736D6F7300")
         );
         assert_eq!(
-            Apdu::select_applet("695F696D6B"),
-            String::from("00a4040005695f696d6b00")
+            encoded(Apdu::select_applet("695F696D6B")),
+            String::from("00A4040005695F696D6B00")
         );
         assert_eq!(
-            Apdu::select_applet("695F66696C65636F696E"),
-            String::from("00a404000a695f66696c65636f696e00")
+            encoded(Apdu::select_applet("695F66696C65636F696E")),
+            String::from("00A404000A695F66696C65636F696E00")
         );
     }
 
@@ -703,40 +690,49 @@ mod tests {
         let path = String::from("m/44/0/0");
         let verify_flag = false;
         assert_eq!(
-            BtcApdu::get_xpub(&path, verify_flag),
-            String::from("80430000086d2f34342f302f3000")
+            encoded(BtcApdu::get_xpub(&path, verify_flag).unwrap()),
+            String::from("80430000086D2F34342F302F3000")
         );
     }
 
     #[test]
-    #[should_panic]
     fn register_address_test() {
         assert_eq!(
-            BtcApdu::register_address("12z6UzsA3tjpaeuvA2Zr9jwx19Azz74D6g".as_bytes()),
+            encoded(
+                BtcApdu::register_address("12z6UzsA3tjpaeuvA2Zr9jwx19Azz74D6g".as_bytes()).unwrap()
+            ),
             String::from(
                 "803600002231327A36557A734133746A706165757641325A72396A77783139417A7A373444366700"
             )
         );
         assert_eq!(
-            BtcApdu::register_address("37E2J9ViM4QFiewo7aw5L3drF2QKB99F9e".as_bytes()),
+            encoded(
+                BtcApdu::register_address("37E2J9ViM4QFiewo7aw5L3drF2QKB99F9e".as_bytes()).unwrap()
+            ),
             String::from(
                 "8036000022333745324A3956694D3451466965776F376177354C3364724632514B42393946396500"
             )
         );
         assert_eq!(
-            EthApdu::register_address("0x6031564e7b2F5cc33737807b2E58DaFF870B590b".as_bytes()),
+            encoded(EthApdu::register_address("0x6031564e7b2F5cc33737807b2E58DaFF870B590b".as_bytes()).unwrap()),
             String::from("805600002A30783630333135363465376232463563633333373337383037623245353844614646383730423539306200")
         );
         assert_eq!(
-            EosApdu::register_address("EOS88XhiiP7Cu5TmAUJqHbyuhyYgd6sei68AU266PyetDDAtjmYWF".as_bytes()),
+            encoded(EosApdu::register_address("EOS88XhiiP7Cu5TmAUJqHbyuhyYgd6sei68AU266PyetDDAtjmYWF".as_bytes()).unwrap()),
             String::from("8066000035454F533838586869695037437535546D41554A71486279756879596764367365693638415532363650796574444441746A6D59574600")
         );
         assert_eq!(
-            CosmosApdu::register_address("cosmos1ajz9y0x3wekez7tz2td2j6l2dftn28v26dd992".as_bytes()),
+            encoded(CosmosApdu::register_address("cosmos1ajz9y0x3wekez7tz2td2j6l2dftn28v26dd992".as_bytes()).unwrap()),
             String::from("807600002D636F736D6F7331616A7A397930783377656B657A37747A327464326A366C326466746E3238763236646439393200")
         );
-        let long_address = hex::decode("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
-        Apdu::register_address(0x36, long_address.as_slice());
+        let long_address = hex::decode("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
+        assert_eq!(
+            Apdu::register_address(0x36, long_address.as_slice())
+                .err()
+                .unwrap()
+                .to_string(),
+            "imkey_data_too_long"
+        );
     }
 
     #[test]
@@ -749,70 +745,67 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn btc_perpare_input_test() {
-        let data = Vec::from_hex("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
+        let data = Vec::from_hex("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
         assert_eq!(
-            BtcApdu::btc_perpare_input(0x80, &data),
+            encoded(BtcApdu::btc_perpare_input(0x80, &data).unwrap()),
             String::from("80418000427A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF00")
         );
-        let long_data = Vec::from_hex("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
-        BtcApdu::btc_perpare_input(0x80, &long_data);
+        let long_data = Vec::from_hex("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
+        assert!(BtcApdu::btc_perpare_input(0x80, &long_data).is_err());
     }
 
     #[test]
     fn btc_sign_test() {
         assert_eq!(
-            BtcApdu::btc_sign(02, 01, "m/44'/0'/0'/0/0"),
+            encoded(BtcApdu::btc_sign(02, 01, "m/44'/0'/0'/0/0")),
             String::from("804202010F6D2F3434272F30272F30272F302F3000")
         );
     }
 
     #[test]
-    #[should_panic]
     fn btc_segwit_sign_test() {
-        let data = Vec::from_hex("0200000080a10bc28928f4c17a287318125115c3f098ed20a8237d1e8e4125bc25d1be99752adad0a7b9ceca853768aebb6965eca126a62965f698a0c1bc43d83db632ad7f717276057e6012afa99385c18cc692397a666560520577679bf38c08b5cec2000000001976a914654fbb08267f3d50d715a8f1abb55979b160dd5b88ac50c3000000000000ffffffffd622ad82d85a944f2c242762292e13462240fddd7d19791829e911d7885dec770000000001000000").unwrap();
+        let data = Vec::from_hex("0200000080A10BC28928F4C17A287318125115C3F098ED20A8237D1E8E4125BC25D1BE99752ADAD0A7B9CECA853768AEBB6965ECA126A62965F698A0C1BC43D83DB632AD7F717276057E6012AFA99385C18CC692397A666560520577679BF38C08B5CEC2000000001976A914654FBB08267F3D50D715A8F1ABB55979B160DD5B88AC50C3000000000000FFFFFFFFD622AD82D85A944F2C242762292E13462240FDDD7D19791829E911D7885DEC770000000001000000").unwrap();
         assert_eq!(
-            BtcApdu::btc_segwit_sign(true, 01, data),
+            encoded(BtcApdu::btc_segwit_sign(true, 01, data).unwrap()),
             String::from("80328001B60200000080A10BC28928F4C17A287318125115C3F098ED20A8237D1E8E4125BC25D1BE99752ADAD0A7B9CECA853768AEBB6965ECA126A62965F698A0C1BC43D83DB632AD7F717276057E6012AFA99385C18CC692397A666560520577679BF38C08B5CEC2000000001976A914654FBB08267F3D50D715A8F1ABB55979B160DD5B88AC50C3000000000000FFFFFFFFD622AD82D85A944F2C242762292E13462240FDDD7D19791829E911D7885DEC77000000000100000000")
         );
-        let long_data = Vec::from_hex("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
-        BtcApdu::btc_segwit_sign(true, 01, long_data);
+        let long_data = Vec::from_hex("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
+        assert!(BtcApdu::btc_segwit_sign(true, 01, long_data).is_err());
     }
 
     #[test]
-    #[should_panic]
     fn omni_prepare_data_test() {
-        let data = Vec::from_hex("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
+        let data = Vec::from_hex("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
         assert_eq!(
-            BtcApdu::omni_prepare_data(0x00, data),
+            encoded(BtcApdu::omni_prepare_data(0x00, data).unwrap()),
             String::from("80440000427A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF00")
         );
-        let long_data = Vec::from_hex("7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff7a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000001976a914a189f2f7836812aa7a0e36e28a20a10e64010bf688acffffffff").unwrap();
-        BtcApdu::omni_prepare_data(0x00, long_data);
+        let long_data = Vec::from_hex("7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF7A222FB053B6E5339A9B6F9649F88A9481606CF3C64C4557802B3A819DDF3A98000000001976A914A189F2F7836812AA7A0E36E28A20A10E64010BF688ACFFFFFFFF").unwrap();
+        assert!(BtcApdu::omni_prepare_data(0x00, long_data).is_err());
     }
 
     #[test]
     fn eth_personal_sign_test() {
         assert_eq!(
-            EthApdu::personal_sign("m/44'/60'/0'/0/0"),
-            String::from("80550000106d2f3434272f3630272f30272f302f3000")
+            encoded(EthApdu::personal_sign("m/44'/60'/0'/0/0").unwrap()),
+            String::from("80550000106D2F3434272F3630272F30272F302F3000")
         );
     }
 
     #[test]
     fn eth_get_xpub_test() {
         assert_eq!(
-            EthApdu::get_xpub("m/44'/60'/0'/0/0", true),
-            "80530100106d2f3434272f3630272f30272f302f3000"
+            encoded(EthApdu::get_xpub("m/44'/60'/0'/0/0", true).unwrap()),
+            "80530100106D2F3434272F3630272F30272F302F3000"
         );
     }
 
     #[test]
     fn eth_sign_digest_test() {
         assert_eq!(
-            EthApdu::sign_digest("m/44'/60'/0'/0/0"),
-            String::from("80520000106d2f3434272f3630272f30272f302f3000")
+            encoded(EthApdu::sign_digest("m/44'/60'/0'/0/0").unwrap()),
+            String::from("80520000106D2F3434272F3630272F30272F302F3000")
         );
     }
 
@@ -821,7 +814,10 @@ mod tests {
         let data = Vec::from_hex("11223344556677889900").unwrap();
         let apdu_vec = EthApdu::prepare_personal_sign(data);
         for apdu in apdu_vec {
-            assert_eq!(apdu, String::from("805400800a1122334455667788990000"));
+            assert_eq!(
+                encoded(apdu),
+                String::from("805400800A1122334455667788990000")
+            );
         }
     }
 
@@ -834,8 +830,8 @@ mod tests {
         let apdu_vec = EthApdu::prepare_sign(data);
         for apdu in apdu_vec {
             assert_eq!(
-                apdu,
-                String::from("8051008025e4088504a817c8088302e2489435353535353535353535353535353535353535358202008000")
+                encoded(apdu),
+                String::from("8051008025E4088504A817C8088302E2489435353535353535353535353535353535353535358202008000")
             );
         }
     }
@@ -843,8 +839,8 @@ mod tests {
     #[test]
     fn eos_get_xpub_test() {
         assert_eq!(
-            EthApdu::get_xpub("m/44'/194'/0'/0/0", true),
-            "80530100116d2f3434272f313934272f30272f302f3000"
+            encoded(EthApdu::get_xpub("m/44'/194'/0'/0/0", true).unwrap()),
+            "80530100116D2F3434272F313934272F30272F302F3000"
         );
     }
 
@@ -854,8 +850,8 @@ mod tests {
         let apdu_vec = EosApdu::prepare_sign(data);
         for apdu in apdu_vec {
             assert_eq!(
-                apdu,
-                String::from("806100805100044cabb9db0704786d746f0806786d66726f6d09063132333435360120b998c88d8478e87e6dee727adecec067a3201da03ec8f8e8861c946559be635505116d2f3434272f313934272f30272f302f3000")
+                encoded(apdu),
+                String::from("806100805100044CABB9DB0704786D746F0806786D66726F6D09063132333435360120B998C88D8478E87E6DEE727ADECEC067A3201DA03EC8F8E8861C946559BE635505116D2F3434272F313934272F30272F302F3000")
             );
         }
     }
@@ -863,14 +859,17 @@ mod tests {
     #[test]
     fn eos_sign_digest_test() {
         assert_eq!(
-            EosApdu::sign_digest("m/44'/194'/0'/0/0"),
-            String::from("80520000116d2f3434272f313934272f30272f302f3000")
+            encoded(EosApdu::sign_digest("m/44'/194'/0'/0/0").unwrap()),
+            String::from("80520000116D2F3434272F313934272F30272F302F3000")
         );
     }
 
     #[test]
     fn eos_sign_tx_test() {
-        assert_eq!(EosApdu::sign_tx(0101), "8062000002006500".to_string());
+        assert_eq!(
+            encoded(EosApdu::sign_tx(0101)),
+            "8062000002006500".to_string()
+        );
     }
 
     #[test]
@@ -878,20 +877,26 @@ mod tests {
         let data = Vec::from_hex("11223344556677889900").unwrap();
         let apdu_vec = EosApdu::prepare_message_sign(data);
         for apdu in apdu_vec {
-            assert_eq!(apdu, String::from("806400800a1122334455667788990000"));
+            assert_eq!(
+                encoded(apdu),
+                String::from("806400800A1122334455667788990000")
+            );
         }
     }
 
     #[test]
     fn eos_sign_message_test() {
-        assert_eq!(EosApdu::sign_message(1), String::from("8065000002000100"));
+        assert_eq!(
+            encoded(EosApdu::sign_message(1)),
+            String::from("8065000002000100")
+        );
     }
 
     #[test]
     fn cosmos_get_xpub_test() {
         assert_eq!(
-            CosmosApdu::get_xpub("m/44'/118'/0'/0/0", true),
-            "80730100116d2f3434272f313138272f30272f302f3000"
+            encoded(CosmosApdu::get_xpub("m/44'/118'/0'/0/0", true).unwrap()),
+            "80730100116D2F3434272F313138272F30272F302F3000"
         );
     }
 
@@ -901,8 +906,8 @@ mod tests {
         let apdu_vec = CosmosApdu::prepare_sign(data);
         for apdu in apdu_vec {
             assert_eq!(
-                apdu,
-                String::from("80710080b30046304402204c6301e02c4b37d7828d6f20ca6406eb0afadbebb1c563baaa371982eab8be5e02204a12558fea32093e7175fa022919f1067194e542a78f8c2fe1138a4a0750d866012090260fea755e8ca08f6df4506f64fdbb5b806a5706c51ff056c76f05111794ac070a302e3030312041544f4d082d636f736d6f73317965636b787a377461707a33346b6a776e6a78766d787a7572657271756874726d786d757874090c302e30303037352061746f6d00")
+                encoded(apdu),
+                String::from("80710080B30046304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D00")
             );
         }
     }
@@ -910,87 +915,235 @@ mod tests {
     #[test]
     fn cosmos_sign_digest_test() {
         assert_eq!(
-            CosmosApdu::sign_digest("m/44'/118'/0'/0/0"),
-            String::from("80720000116d2f3434272f313138272f30272f302f3000")
+            encoded(CosmosApdu::sign_digest("m/44'/118'/0'/0/0").unwrap()),
+            String::from("80720000116D2F3434272F313138272F30272F302F3000")
         );
     }
 
     #[test]
-    #[should_panic]
     fn bind_check_test() {
         let data = Vec::from_hex("304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D").unwrap();
         assert_eq!(
-            ImkApdu::bind_check(&data),
+            encoded(ImkApdu::bind_check(&data).unwrap()),
             String::from("80710000B1304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D00")
         );
-        let long_data = Vec::from_hex("304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6DD082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D74090C302E30303037352061746F6D74090C302E30303037352061746F6D").unwrap();
-        ImkApdu::bind_check(&long_data);
+        let long_data = [data.clone(), data.clone()].concat();
+        assert!(ImkApdu::bind_check(&long_data).is_err());
     }
 
     #[test]
     fn generate_auth_code_test() {
-        assert_eq!(ImkApdu::generate_auth_code(), String::from("8072000000"));
+        assert_eq!(
+            encoded(ImkApdu::generate_auth_code()),
+            String::from("8072000000")
+        );
     }
 
     #[test]
-    #[should_panic]
     fn identity_verify_test() {
         let data = Vec::from_hex("304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D").unwrap();
         assert_eq!(
-            ImkApdu::identity_verify(&data),
+            encoded(ImkApdu::identity_verify(&data).unwrap()),
             String::from("80738000B1304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D00")
         );
         let long_data = Vec::from_hex("304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D304402204C6301E02C4B37D7828D6F20CA6406EB0AFADBEBB1C563BAAA371982EAB8BE5E02204A12558FEA32093E7175FA022919F1067194E542A78F8C2FE1138A4A0750D866012090260FEA755E8CA08F6DF4506F64FDBB5B806A5706C51FF056C76F05111794AC070A302E3030312041544F4D082D636F736D6F73317965636B787A377461707A33346B6A776E6A78766D787A7572657271756874726D786D757874090C302E30303037352061746F6D").unwrap();
-        ImkApdu::identity_verify(&long_data);
+        assert!(ImkApdu::identity_verify(&long_data).is_err());
     }
 
     #[test]
-    fn apdu_header_test() {
-        assert_eq!(
-            ApduHeader::new(0x00, 0xA4, 0x04, 0x00, 0x00).to_array(),
-            vec![0x00, 0xA4, 0x04, 0x00, 0x00]
-        );
+    fn command_encode_test() {
+        let command = Command::new(0x00, 0xA4, 0x04, 0x00, &[], Some(0x00));
+        assert_eq!(encoded(command), "00A4040000");
+        let command = Command::new(0x80, 0xCB, 0x80, 0x00, &[0xDF, 0xFF], None);
+        assert_eq!(encoded(command), "80CB800002DFFF");
+        let command = Command::new(0x80, 0xCB, 0x80, 0x00, &[], None);
+        assert_eq!(encoded(command), "80CB8000");
+        let command = Command::new(0x80, 0xCA, 0x00, 0x44, &[], Some(0x1000));
+        assert_eq!(encoded(command), "80CA0044001000");
+    }
+
+    #[test]
+    fn command_decode_test() {
+        for apdu in [
+            "80CB8000",
+            "00A4040000",
+            "80CB800005DFFF028101",
+            "00A4040005695F62746300",
+            "80CA0044001000",
+        ] {
+            let command = Command::decode(&hex::decode(apdu).unwrap()).unwrap();
+            assert_eq!(encoded(command), apdu);
+        }
+
+        let extended = Apdu::command(0x80, 0x4C, 0x00, 0x80, &vec![0x33; 300]).unwrap();
+        let command = Command::decode(&extended.encode().unwrap()).unwrap();
+        assert_eq!(command, extended);
+
+        let command = Command::decode(&hex::decode("00A4040005695F627463").unwrap()).unwrap();
+        assert_eq!(command.data, hex::decode("695F627463").unwrap());
+        assert_eq!(command.le, None);
+
+        for apdu in [
+            "00A404",
+            "00A4040005695F",
+            "00A4040005695F6274630000",
+            "00A40400000005AA",
+        ] {
+            assert_eq!(
+                Command::decode(&hex::decode(apdu).unwrap())
+                    .err()
+                    .unwrap()
+                    .to_string(),
+                "imkey_apdu_format_error"
+            );
+        }
     }
 
     #[test]
-    #[should_panic]
     fn apdu_get_xpub() {
         let long_path = "m/44'/60'/0'/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0";
-        Apdu::get_pubkey(0x43, long_path, true);
+        assert!(Apdu::get_pubkey(0x43, long_path, true).is_err());
     }
 
     #[test]
-    #[should_panic]
     fn apdu_sign_digest_test() {
         let long_path = "m/44'/60'/0'/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0/0";
-        Apdu::sign_digest(0x52, 0x00, 0x00, long_path);
+        assert!(Apdu::sign_digest(0x52, 0x00, 0x00, long_path).is_err());
+    }
+
+    #[test]
+    fn chunks_test() {
+        let data = vec![0x11; 500];
+        let apdus = Apdu::chunks(0x41, &data, false, |first, last| {
+            (
+                if first { 0x00 } else { 0x80 },
+                if last { 0x80 } else { 0x00 },
+            )
+        });
+        assert_eq!(apdus.len(), 3);
+        assert_eq!(
+            apdus[0].encode().unwrap()[..5],
+            [0x80, 0x41, 0x00, 0x00, 0xF5]
+        );
+        assert_eq!(
+            apdus[1].encode().unwrap()[..5],
+            [0x80, 0x41, 0x80, 0x00, 0xF5]
+        );
+        assert_eq!(
+            apdus[2].encode().unwrap()[..5],
+            [0x80, 0x41, 0x80, 0x80, 0x0A]
+        );
+        assert_eq!(apdus[2].data.len(), 10);
+        assert_eq!(apdus[2].le, None);
+        assert!(Apdu::chunks(0x41, &[], true, |_, _| (0x00, 0x80)).is_empty());
+
+        let apdus = BtcApdu::btc_prepare(0x41, 0x00, &vec![0x22; 245]);
+        assert_eq!(apdus.len(), 1);
+        assert_eq!(&encoded(apdus[0].clone())[..10], "80410080F5");
+    }
+
+    #[test]
+    fn extended_command_test() {
+        let short = Apdu::command(0x80, 0x4C, 0x00, 0x00, &[0x01, 0x02]).unwrap();
+        assert_eq!(encoded(short), "804C000002010200");
+
+        let extended = Apdu::command(0x80, 0x4C, 0x00, 0x80, &vec![0x33; 300])
+            .unwrap()
+            .encode()
+            .unwrap();
+        assert_eq!(extended[..7], [0x80, 0x4C, 0x00, 0x80, 0x00, 0x01, 0x2C]);
+        assert_eq!(extended.len(), 7 + 300 + 2);
+        assert_eq!(extended[307..], [0x00, 0x00]);
+
+        let largest = Apdu::command(0x80, 0x4C, 0x00, 0x00, &vec![0x33; 65526])
+            .unwrap()
+            .encode()
+            .unwrap();
+        assert_eq!(largest.len(), u16::MAX as usize);
+        assert_eq!(
+            Apdu::command(0x80, 0x4C, 0x00, 0x00, &vec![0x33; 65527])
+                .err()
+                .unwrap()
+                .to_string(),
+            "imkey_data_too_long"
+        );
+        assert!(Apdu::short_command(0x80, 0x4C, 0x00, 0x00, &vec![0x33; 246]).is_err());
     }
 
     #[test]
     fn apdu_set_ble_name_test() {
         assert_eq!(
-            Apdu::set_ble_name("helloimkey"),
-            "ffda46540a68656c6c6f696d6b657900"
+            encoded(Apdu::set_ble_name("helloimkey")),
+            "FFDA46540A68656C6C6F696D6B657900"
         );
     }
 
+    #[test]
+    fn response_test() {
+        let response = Response::decode(&[0x01, 0x02, 0x90, 0x00]).unwrap();
+        assert_eq!(response.data, vec![0x01, 0x02]);
+        assert_eq!(response.sw, 0x9000);
+        assert_eq!(response.encode(), vec![0x01, 0x02, 0x90, 0x00]);
+        assert_eq!(response.field(1, 1).unwrap(), [0x02]);
+        assert!(response.field(1, 2).is_err());
+        assert_eq!(response.into_data().unwrap(), vec![0x01, 0x02]);
+
+        let response = Response::decode(&[0x90, 0x00]).unwrap();
+        assert!(response.data.is_empty());
+        assert_eq!(
+            Response::decode(&[0x90]).err().unwrap().to_string(),
+            "imkey_invalid_response"
+        );
+    }
+
+    #[test]
+    fn get_response_test() {
+        let device = Device::new(MockTransport::new(|apdu: &[u8]| {
+            match hex::encode_upper(apdu).as_str() {
+                "80CB800000" => Ok(hex::decode("01026102")?),
+                "00C0000002" => Ok(hex::decode("03046101")?),
+                "00C0000001" => Ok(hex::decode("059000")?),
+                "80CC800000" => Ok(hex::decode("6100")?),
+                _ => Ok(hex::decode("6D00")?),
+            }
+        }));
+        let command = Command::new(0x80, 0xCB, 0x80, 0x00, &[], Some(0x00));
+        let response = block_on(device.exchange(&command)).unwrap();
+        assert_eq!(response.data, vec![0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(response.status(), StatusWord::Success);
+
+        let command = Command::new(0x80, 0xCC, 0x80, 0x00, &[], Some(0x00));
+        let response = block_on(device.exchange(&command)).unwrap();
+        assert_eq!(response.status(), StatusWord::InsNotSupported);
+    }
+
     #[test]
     fn check_response_test() {
-        assert!(ApduCheck::check_response("009000").is_ok());
-        assert!(ApduCheck::check_response("006940").is_err());
-        assert!(ApduCheck::check_response("006985").is_err());
-        assert!(ApduCheck::check_response("006280").is_err());
-        assert!(ApduCheck::check_response("006A86").is_err());
-        assert!(ApduCheck::check_response("006E00").is_err());
-        assert!(ApduCheck::check_response("006A80").is_err());
-        assert!(ApduCheck::check_response("006700").is_err());
-        assert!(ApduCheck::check_response("006942").is_err());
-        assert!(ApduCheck::check_response("006D00").is_err());
-        assert!(ApduCheck::check_response("006941").is_err());
-        assert!(ApduCheck::check_response("00F000").is_err());
-        assert!(ApduCheck::check_response("00F080").is_err());
-        assert!(ApduCheck::check_response("00F081").is_err());
-        assert!(ApduCheck::check_response("006F01").is_err());
-        assert!(ApduCheck::check_response("000000").is_err());
+        let check = |response: &str| Response::decode(&hex::decode(response).unwrap())?.check();
+        assert!(check("009000").is_ok());
+        assert!(check("006940").is_err());
+        assert!(check("006985").is_err());
+        assert!(check("006280").is_err());
+        assert!(check("006A86").is_err());
+        assert!(check("006E00").is_err());
+        assert!(check("006A80").is_err());
+        assert!(check("006700").is_err());
+        assert!(check("006942").is_err());
+        assert!(check("006D00").is_err());
+        assert!(check("006941").is_err());
+        assert!(check("00F000").is_err());
+        assert!(check("00F080").is_err());
+        assert!(check("00F081").is_err());
+        assert!(check("006F01").is_err());
+        assert!(check("000000").is_err());
+        assert_eq!(
+            check("6A81").err().unwrap().to_string(),
+            "imkey_command_execute_fail_6A81"
+        );
+
+        let error = check("F080").err().unwrap();
+        let status = error.downcast_ref::<StatusWord>().unwrap();
+        assert_eq!(*status, StatusWord::InMenuPage);
+        assert!(status.requires_user_action());
     }
 }
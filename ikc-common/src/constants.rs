@@ -15,6 +15,9 @@ pub const TSM_ACTION_COS_CHECK_UPDATE: &str = "/cosCheckUpdate";
 
 //apud related constant
 pub const LC_MAX: u32 = 245;
+//data bytes of an extended length command, so that the whole command (4 bytes header,
+//3 bytes Lc, 2 bytes Le) fits in the 2 bytes length of a HID transfer
+pub const EXTENDED_LC_MAX: usize = 65535 - 9;
 
 pub const ETH_AID: &str = "695F657468";
 pub const EOS_AID: &str = "695F656F73";
@@ -53,6 +56,8 @@ pub const BTC_FORK_DUST: u64 = 546;
 // imkey device status
 pub const IMKEY_DEV_STATUS_INACTIVATED: &str = "inactivated";
 pub const IMKEY_DEV_STATUS_LATEST: &str = "latest";
+pub const IMKEY_DEV_STATUS_STOP_USING: &str = "stop_using";
+pub const IMKEY_DEV_STATUS_ILLEGAL: &str = "illegal";
 
 //device bind status
 pub const BIND_STATUS_UNBOUND: &str = "00";
@@ -87,10 +92,14 @@ pub const TSM_RETURNCODE_DEVICE_UNSUPPORT_APPLET: &str = "BAPP0015";
 
 //tsm end flag
 pub const TSM_END_FLAG: &str = "end";
+//steps of a TSM script or COS upgrade after which the server is not waited for anymore
+pub const TSM_MAX_SCRIPT_STEPS: usize = 64;
+pub const TSM_MAX_COS_UPGRADE_STEPS: usize = 1024;
 
 pub const APDU_RSP_SUCCESS: &str = "9000";
 pub const APDU_RSP_USER_NOT_CONFIRMED: &str = "6940";
 pub const APDU_CONDITIONS_NOT_SATISFIED: &str = "6985";
+pub const APDU_RSP_SECURITY_STATUS_NOT_SATISFIED: &str = "6982";
 pub const APDU_RSP_APPLET_NOT_EXIST: &str = "6A82";
 pub const APDU_RSP_INCORRECT_P1P2: &str = "6A86";
 pub const APDU_RSP_CLA_NOT_SUPPORTED: &str = "6E00";
@@ -8,40 +8,26 @@ pub enum CommonError {
     InvalidKeyIvLength,
     #[error("invalid_base58")]
     InvalidBase58,
+    #[error("invalid_padding")]
+    InvalidPadding,
+    #[error("invalid_tlv")]
+    InvalidTlv,
+    #[error("invalid_tag")]
+    InvalidTag,
 }
 
 #[derive(Error, Debug, PartialOrd, PartialEq)]
 pub enum ApduError {
-    #[error("imkey_user_not_confirmed")]
-    ImkeyUserNotConfirmed,
-    #[error("imkey_conditions_not_satisfied")]
-    ImkeyConditionsNotSatisfied,
-    #[error("imkey_command_format_error")]
-    ImkeyCommandFormatError,
-    #[error("imkey_command_data_error")]
-    ImkeyCommandDataError,
-    #[error("imkey_applet_not_exist")]
-    ImkeyAppletNotExist,
-    #[error("imkey_apdu_wrong_length")]
-    ImkeyApduWrongLength,
-    #[error("imkey_signature_verify_fail")]
-    ImkeySignatureVerifyFail,
-    #[error("imkey_bluetooth_channel_error")]
-    ImkeyBluetoothChannelError,
-    #[error("imkey_applet_function_not_supported")]
-    ImkeyAppletFunctionNotSupported,
-    #[error("imkey_exceeded_max_utxo_number")]
-    ImkeyExceededMaxUtxoNumber,
-    #[error("imkey_command_execute_fail")]
-    ImkeyCommandExecuteFail,
-    #[error("imkey_wallet_not_created")]
-    ImkeyWalletNotCreated,
-    #[error("imkey_in_menu_page")]
-    ImkeyInMenuPage,
-    #[error("imkey_pin_not_verified")]
-    ImkeyPinNotVerified,
-    #[error("imkey_mnemonic_check_fail")]
-    ImkeyMnemonicCheckFail,
+    #[error("imkey_data_too_long")]
+    ImkeyDataTooLong,
+    #[error("imkey_apdu_format_error")]
+    ImkeyApduFormatError,
+    #[error("imkey_invalid_response")]
+    ImkeyInvalidResponse,
+    #[error("imkey_secure_channel_auth_fail")]
+    ImkeySecureChannelAuthFail,
+    #[error("imkey_secure_channel_mac_fail")]
+    ImkeySecureChannelMacFail,
 }
 
 #[derive(Error, Debug, PartialOrd, PartialEq)]
@@ -1,16 +1,18 @@
-pub mod constants;
 pub mod aes;
 pub mod apdu;
-pub mod utility;
+pub mod coin_info;
+pub mod constants;
+pub mod curve;
 pub mod error;
 pub mod hex;
 pub mod path;
-pub mod coin_info;
-pub mod curve;
+pub mod secure_channel;
+pub mod status_word;
+pub mod tlv;
+pub mod utility;
 
 use parking_lot::RwLock;
 
-
 #[macro_use]
 extern crate lazy_static;
 
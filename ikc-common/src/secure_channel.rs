@@ -0,0 +1,417 @@
+use crate::aes::cbc::{decrypt_iso7816, encrypt_block, encrypt_iso7816};
+use crate::aes::cmac::cmac;
+use crate::apdu::{Command, Response};
+use crate::constants::{
+    BCH_AID, BTC_AID, COSMOS_AID, EOS_AID, ETH_AID, FILECOIN_AID, IMK_AID, KUSAMA_AID, LTC_AID,
+    NERVOS_AID, POLKADOT_AID, TEZOS_AID, TRON_AID,
+};
+use crate::error::ApduError;
+use crate::status_word::StatusWord;
+use crate::Result;
+use ikc_transport::device::SecureMessaging;
+
+pub const CHALLENGE_LENGTH: usize = 8;
+pub const MAC_LENGTH: usize = 8;
+//class byte bit of a command protected by secure messaging (80 -> 84)
+pub const CLA_SECURE_MESSAGING: u8 = 0x04;
+//the IMK applet the channel is opened with and the coin applets, selecting any other
+//applet (ISD, bootloader) ends the channel
+pub const SECURE_CHANNEL_AIDS: &[&str] = &[
+    IMK_AID,
+    BTC_AID,
+    ETH_AID,
+    EOS_AID,
+    COSMOS_AID,
+    FILECOIN_AID,
+    POLKADOT_AID,
+    KUSAMA_AID,
+    TRON_AID,
+    NERVOS_AID,
+    TEZOS_AID,
+    BCH_AID,
+    LTC_AID,
+];
+
+//SCP03 derivation constants
+const DERIVE_CARD_CRYPTOGRAM: u8 = 0x00;
+const DERIVE_S_ENC: u8 = 0x04;
+const DERIVE_S_MAC: u8 = 0x06;
+const DERIVE_S_RMAC: u8 = 0x07;
+
+/**
+Keys of one secure channel session, derived as in SCP03 from the binding session key with the
+host and card challenges as context.
+*/
+pub struct SessionKeys {
+    enc: [u8; 16],
+    mac: [u8; 16],
+    rmac: [u8; 16],
+    context: Vec<u8>,
+}
+
+impl SessionKeys {
+    pub fn derive(
+        session_key: &[u8],
+        host_challenge: &[u8],
+        card_challenge: &[u8],
+    ) -> Result<SessionKeys> {
+        let mut context = host_challenge.to_vec();
+        context.extend(card_challenge);
+        Ok(SessionKeys {
+            enc: derive(session_key, DERIVE_S_ENC, 128, &context)?,
+            mac: derive(session_key, DERIVE_S_MAC, 128, &context)?,
+            rmac: derive(session_key, DERIVE_S_RMAC, 128, &context)?,
+            context,
+        })
+    }
+
+    /**
+    proof that the card knows the session key, returned with the card challenge
+    */
+    pub fn card_cryptogram(&self) -> Result<Vec<u8>> {
+        let cryptogram = derive(&self.mac, DERIVE_CARD_CRYPTOGRAM, 64, &self.context)?;
+        Ok(cryptogram[..MAC_LENGTH].to_vec())
+    }
+
+    /**
+    MAC chaining value of a protected command holding the encrypted data, the Lc covered by
+    the MAC counts the MAC bytes appended after it
+    */
+    pub fn command_mac(&self, chaining: &[u8; 16], command: &Command) -> Result<[u8; 16]> {
+        let mut data = chaining.to_vec();
+        data.extend([command.cla, command.ins, command.p1, command.p2].iter());
+        data.extend(
+            ((command.data.len() + MAC_LENGTH) as u16)
+                .to_be_bytes()
+                .iter(),
+        );
+        data.extend(&command.data);
+        cmac(&data, &self.mac)
+    }
+
+    pub fn response_mac(&self, chaining: &[u8; 16], data: &[u8], sw: u16) -> Result<[u8; 16]> {
+        let mut mac_data = chaining.to_vec();
+        mac_data.extend(data);
+        mac_data.extend(sw.to_be_bytes().iter());
+        cmac(&mac_data, &self.rmac)
+    }
+
+    pub fn encrypt_command(&self, counter: u128, data: &[u8]) -> Result<Vec<u8>> {
+        match data.is_empty() {
+            true => Ok(vec![]),
+            false => encrypt_iso7816(data, &self.enc, &self.icv(counter, false)?),
+        }
+    }
+
+    pub fn decrypt_command(&self, counter: u128, data: &[u8]) -> Result<Vec<u8>> {
+        match data.is_empty() {
+            true => Ok(vec![]),
+            false => decrypt_iso7816(data, &self.enc, &self.icv(counter, false)?),
+        }
+    }
+
+    pub fn encrypt_response(&self, counter: u128, data: &[u8]) -> Result<Vec<u8>> {
+        match data.is_empty() {
+            true => Ok(vec![]),
+            false => encrypt_iso7816(data, &self.enc, &self.icv(counter, true)?),
+        }
+    }
+
+    pub fn decrypt_response(&self, counter: u128, data: &[u8]) -> Result<Vec<u8>> {
+        match data.is_empty() {
+            true => Ok(vec![]),
+            false => decrypt_iso7816(data, &self.enc, &self.icv(counter, true)?),
+        }
+    }
+
+    /**
+    IV of the command or response data: the encrypted command counter, with the first byte
+    set to 80 for responses
+    */
+    fn icv(&self, counter: u128, response: bool) -> Result<[u8; 16]> {
+        let mut block = counter.to_be_bytes();
+        if response {
+            block[0] = 0x80;
+        }
+        encrypt_block(&block, &self.enc)
+    }
+}
+
+/**
+SCP03 key derivation function (NIST SP 800-108 counter mode with AES-CMAC)
+*/
+fn derive(key: &[u8], constant: u8, length: u16, context: &[u8]) -> Result<[u8; 16]> {
+    let mut data = vec![0u8; 11];
+    data.push(constant);
+    data.push(0x00);
+    data.extend(length.to_be_bytes().iter());
+    data.push(0x01);
+    data.extend(context);
+    cmac(&data, key)
+}
+
+/**
+Host side of the secure channel. Proprietary commands (class 80) of the IMK and coin applets,
+such as the address and signing commands, are sent encrypted with a chained C-MAC, their
+responses, errors included, must carry a valid R-MAC. Interindustry commands such as SELECT and
+GET RESPONSE stay in clear. Selecting an applet out of SECURE_CHANNEL_AIDS ends the channel.
+*/
+pub struct SecureChannel {
+    keys: SessionKeys,
+    counter: u128,
+    chaining: [u8; 16],
+    protected: bool,
+    open: bool,
+}
+
+impl SecureChannel {
+    /**
+    check the card challenge and cryptogram answering the host challenge
+    */
+    pub fn open(
+        session_key: &[u8],
+        host_challenge: &[u8],
+        response: &[u8],
+    ) -> Result<SecureChannel> {
+        if response.len() != CHALLENGE_LENGTH + MAC_LENGTH {
+            return Err(ApduError::ImkeySecureChannelAuthFail.into());
+        }
+        let (card_challenge, cryptogram) = response.split_at(CHALLENGE_LENGTH);
+        let keys = SessionKeys::derive(session_key, host_challenge, card_challenge)?;
+        if keys.card_cryptogram()? != cryptogram {
+            return Err(ApduError::ImkeySecureChannelAuthFail.into());
+        }
+        Ok(SecureChannel {
+            keys,
+            counter: 0,
+            chaining: [0u8; 16],
+            protected: false,
+            open: true,
+        })
+    }
+
+    pub fn wrap_command(&mut self, command: &Command) -> Result<Command> {
+        self.counter += 1;
+        let mut wrapped = Command::new(
+            command.cla | CLA_SECURE_MESSAGING,
+            command.ins,
+            command.p1,
+            command.p2,
+            &self.keys.encrypt_command(self.counter, &command.data)?,
+            command.le,
+        );
+        self.chaining = self.keys.command_mac(&self.chaining, &wrapped)?;
+        wrapped.data.extend(&self.chaining[..MAC_LENGTH]);
+        Ok(wrapped)
+    }
+
+    pub fn unwrap_response(&mut self, response: Response) -> Result<Response> {
+        //the card dropped the channel after a MAC failure, this answer is the only one in clear
+        if response.status() == StatusWord::SecurityStatusNotSatisfied {
+            return Err(response.status().into());
+        }
+        if response.data.len() < MAC_LENGTH {
+            return Err(ApduError::ImkeySecureChannelMacFail.into());
+        }
+        let (data, mac) = response.data.split_at(response.data.len() - MAC_LENGTH);
+        let expected = self.keys.response_mac(&self.chaining, data, response.sw)?;
+        if expected[..MAC_LENGTH] != *mac {
+            return Err(ApduError::ImkeySecureChannelMacFail.into());
+        }
+        Ok(Response {
+            data: self.keys.decrypt_response(self.counter, data)?,
+            sw: response.sw,
+        })
+    }
+}
+
+impl SecureMessaging for SecureChannel {
+    fn wrap(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let command = Command::decode(apdu)?;
+        if command.cla == 0x00
+            && command.ins == 0xA4
+            && !SECURE_CHANNEL_AIDS.contains(&hex::encode_upper(&command.data).as_str())
+        {
+            self.open = false;
+        }
+        self.protected = self.open && command.cla == 0x80;
+        match self.protected {
+            true => self.wrap_command(&command)?.encode(),
+            false => Ok(apdu.to_vec()),
+        }
+    }
+
+    fn unwrap(&mut self, response: &[u8]) -> Result<Vec<u8>> {
+        match self.protected {
+            true => Ok(self.unwrap_response(Response::decode(response)?)?.encode()),
+            false => Ok(response.to_vec()),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::apdu::{Command, Response};
+    use crate::secure_channel::{SecureChannel, SessionKeys, MAC_LENGTH};
+    use ikc_transport::device::SecureMessaging;
+
+    const SESSION_KEY: &str = "404142434445464748494A4B4C4D4E4F";
+    const HOST_CHALLENGE: &str = "0102030405060708";
+    const CARD_CHALLENGE: &str = "1112131415161718";
+
+    fn keys() -> SessionKeys {
+        SessionKeys::derive(
+            &hex::decode(SESSION_KEY).unwrap(),
+            &hex::decode(HOST_CHALLENGE).unwrap(),
+            &hex::decode(CARD_CHALLENGE).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn open() -> SecureChannel {
+        let mut response = hex::decode(CARD_CHALLENGE).unwrap();
+        response.extend(keys().card_cryptogram().unwrap());
+        SecureChannel::open(
+            &hex::decode(SESSION_KEY).unwrap(),
+            &hex::decode(HOST_CHALLENGE).unwrap(),
+            &response,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn open_test() {
+        open();
+        let mut response = hex::decode(CARD_CHALLENGE).unwrap();
+        response.extend([0u8; 8].iter());
+        let result = SecureChannel::open(
+            &hex::decode(SESSION_KEY).unwrap(),
+            &hex::decode(HOST_CHALLENGE).unwrap(),
+            &response,
+        );
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_secure_channel_auth_fail"
+        );
+    }
+
+    #[test]
+    fn wrap_command_test() {
+        let mut channel = open();
+        let keys = keys();
+        let command = Command::new(0x80, 0x71, 0x00, 0x00, &[0x01, 0x02, 0x03], Some(0x00));
+        let wrapped = channel.wrap_command(&command).unwrap();
+        assert_eq!(wrapped.cla, 0x84);
+        assert_eq!(wrapped.data.len(), 16 + MAC_LENGTH);
+
+        let encrypted = &wrapped.data[..16];
+        assert_eq!(
+            keys.decrypt_command(1, encrypted).unwrap(),
+            vec![0x01, 0x02, 0x03]
+        );
+        let unmacked = Command::new(0x84, 0x71, 0x00, 0x00, encrypted, Some(0x00));
+        let mac = keys.command_mac(&[0u8; 16], &unmacked).unwrap();
+        assert_eq!(wrapped.data[16..], mac[..MAC_LENGTH]);
+
+        //the same command gives another ciphertext and MAC once the counter moved
+        let second = channel.wrap_command(&command).unwrap();
+        assert_ne!(second.data, wrapped.data);
+    }
+
+    #[test]
+    fn unwrap_response_test() {
+        let mut channel = open();
+        let keys = keys();
+        let command = Command::new(0x80, 0xCB, 0x80, 0x00, &[], Some(0x00));
+        let wrapped = channel.wrap_command(&command).unwrap();
+        let chaining = keys
+            .command_mac(
+                &[0u8; 16],
+                &Command::new(0x84, 0xCB, 0x80, 0x00, &[], Some(0x00)),
+            )
+            .unwrap();
+        assert_eq!(wrapped.data.len(), MAC_LENGTH);
+
+        let mut data = keys.encrypt_response(1, &[0xAA, 0xBB]).unwrap();
+        let mac = keys.response_mac(&chaining, &data, 0x9000).unwrap();
+        data.extend(&mac[..MAC_LENGTH]);
+        let response = channel
+            .unwrap_response(Response {
+                data: data.clone(),
+                sw: 0x9000,
+            })
+            .unwrap();
+        assert_eq!(response.data, vec![0xAA, 0xBB]);
+
+        data[0] ^= 0x01;
+        let result = channel.unwrap_response(Response { data, sw: 0x9000 });
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_secure_channel_mac_fail"
+        );
+
+        //errors are MACed too, the card cannot make them up
+        let mac = keys.response_mac(&chaining, &[], 0x6A80).unwrap();
+        let response = Response {
+            data: mac[..MAC_LENGTH].to_vec(),
+            sw: 0x6A80,
+        };
+        let response = channel.unwrap_response(response).unwrap();
+        assert_eq!((response.data, response.sw), (vec![], 0x6A80));
+        let response = Response {
+            data: vec![],
+            sw: 0x6A80,
+        };
+        assert_eq!(
+            channel.unwrap_response(response).err().unwrap().to_string(),
+            "imkey_secure_channel_mac_fail"
+        );
+        let response = Response {
+            data: vec![],
+            sw: 0x6982,
+        };
+        assert_eq!(
+            channel.unwrap_response(response).err().unwrap().to_string(),
+            "imkey_security_status_not_satisfied"
+        );
+    }
+
+    #[test]
+    fn clear_command_test() {
+        let mut channel = open();
+        let select = hex::decode("00A4040005695F696D6B00").unwrap();
+        assert_eq!(channel.wrap(&select).unwrap(), select);
+        assert_eq!(channel.unwrap(&[0x6A, 0x82]).unwrap(), vec![0x6A, 0x82]);
+
+        let wrapped = channel.wrap(&hex::decode("80CB800000").unwrap()).unwrap();
+        assert_eq!(wrapped[0], 0x84);
+        assert!(channel.unwrap(&[0x90, 0x00]).is_err());
+    }
+
+    #[test]
+    fn select_other_applet_test() {
+        let mut channel = open();
+        let select_imk = hex::decode("00A4040005695F696D6B00").unwrap();
+        assert_eq!(channel.wrap(&select_imk).unwrap(), select_imk);
+        assert!(channel.is_open());
+
+        //the coin applets are protected as well
+        let select_btc = hex::decode("00A4040005695F62746300").unwrap();
+        assert_eq!(channel.wrap(&select_btc).unwrap(), select_btc);
+        assert!(channel.is_open());
+        let get_xpub = hex::decode("804300000100").unwrap();
+        assert_eq!(channel.wrap(&get_xpub).unwrap()[0], 0x84);
+
+        //ISD and TSM script commands are not addressed to the IMK or a coin applet
+        let select_isd = hex::decode("00A4040000").unwrap();
+        assert_eq!(channel.wrap(&select_isd).unwrap(), select_isd);
+        assert!(!channel.is_open());
+        let initialize_update = hex::decode("8050000008010203040506070800").unwrap();
+        assert_eq!(channel.wrap(&initialize_update).unwrap(), initialize_update);
+        assert_eq!(channel.unwrap(&[0x90, 0x00]).unwrap(), vec![0x90, 0x00]);
+    }
+}
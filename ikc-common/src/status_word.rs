@@ -0,0 +1,195 @@
+use thiserror::Error;
+
+/**
+Status word ending every response APDU: the ISO 7816 classes handled by the SDK and the imKey
+specific ones. Any status but Success is also the error returned for the command.
+*/
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusWord {
+    #[error("imkey_success")]
+    Success,
+    //61xx: xx more bytes to read with GET RESPONSE, 00 for 256
+    #[error("imkey_command_execute_fail_61{0:02X}")]
+    MoreData(u8),
+    //6Cxx: the command must be sent again with Le = xx
+    #[error("imkey_command_execute_fail_6C{0:02X}")]
+    WrongLe(u8),
+    //63Cx: verification failed, x tries left
+    #[error("imkey_command_execute_fail_63C{0:X}")]
+    RetriesLeft(u8),
+    #[error("imkey_apdu_wrong_length")]
+    WrongLength,
+    #[error("imkey_user_not_confirmed")]
+    UserNotConfirmed,
+    #[error("imkey_security_status_not_satisfied")]
+    SecurityStatusNotSatisfied,
+    #[error("imkey_exceeded_max_utxo_number")]
+    ExceededMaxUtxoNumber,
+    #[error("imkey_signature_verify_fail")]
+    SignatureVerifyFail,
+    #[error("imkey_mnemonic_check_fail")]
+    MnemonicCheckFail,
+    #[error("imkey_conditions_not_satisfied")]
+    ConditionsNotSatisfied,
+    #[error("imkey_command_data_error")]
+    WrongData,
+    #[error("imkey_applet_not_exist")]
+    AppletNotExist,
+    #[error("imkey_command_format_error")]
+    IncorrectP1P2,
+    #[error("imkey_applet_function_not_supported")]
+    InsNotSupported,
+    #[error("imkey_command_format_error")]
+    ClaNotSupported,
+    #[error("imkey_bluetooth_channel_error")]
+    BluetoothChannelError,
+    #[error("imkey_wallet_not_created")]
+    WalletNotCreated,
+    #[error("imkey_in_menu_page")]
+    InMenuPage,
+    #[error("imkey_pin_not_verified")]
+    PinNotVerified,
+    #[error("imkey_command_execute_fail_{0:04X}")]
+    Unknown(u16),
+}
+
+impl StatusWord {
+    pub fn is_success(&self) -> bool {
+        *self == StatusWord::Success
+    }
+
+    /**
+    whether sending the command again can succeed without the user doing anything:
+    reading the remaining data, fixing Le or a lost bluetooth frame
+    */
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            StatusWord::MoreData(_) | StatusWord::WrongLe(_) | StatusWord::BluetoothChannelError
+        )
+    }
+
+    /**
+    whether the user has to act on the device (confirm, leave the menu, enter the PIN,
+    create the wallet) before the operation is tried again
+    */
+    pub fn requires_user_action(&self) -> bool {
+        matches!(
+            self,
+            StatusWord::UserNotConfirmed
+                | StatusWord::InMenuPage
+                | StatusWord::PinNotVerified
+                | StatusWord::WalletNotCreated
+                | StatusWord::RetriesLeft(_)
+        )
+    }
+}
+
+impl From<u16> for StatusWord {
+    fn from(sw: u16) -> Self {
+        let [sw1, sw2] = sw.to_be_bytes();
+        match (sw1, sw2) {
+            (0x90, 0x00) => StatusWord::Success,
+            (0x61, length) => StatusWord::MoreData(length),
+            (0x6C, length) => StatusWord::WrongLe(length),
+            (0x63, retries) if retries & 0xF0 == 0xC0 => StatusWord::RetriesLeft(retries & 0x0F),
+            (0x67, 0x00) => StatusWord::WrongLength,
+            (0x69, 0x40) => StatusWord::UserNotConfirmed,
+            (0x69, 0x41) => StatusWord::ExceededMaxUtxoNumber,
+            (0x69, 0x42) => StatusWord::SignatureVerifyFail,
+            (0x69, 0x43) => StatusWord::MnemonicCheckFail,
+            (0x69, 0x82) => StatusWord::SecurityStatusNotSatisfied,
+            (0x69, 0x85) => StatusWord::ConditionsNotSatisfied,
+            (0x6A, 0x80) => StatusWord::WrongData,
+            (0x6A, 0x82) => StatusWord::AppletNotExist,
+            (0x6A, 0x86) => StatusWord::IncorrectP1P2,
+            (0x6D, 0x00) => StatusWord::InsNotSupported,
+            (0x6E, 0x00) => StatusWord::ClaNotSupported,
+            (0x6F, 0x01) => StatusWord::BluetoothChannelError,
+            (0xF0, 0x00) => StatusWord::WalletNotCreated,
+            (0xF0, 0x80) => StatusWord::InMenuPage,
+            (0xF0, 0x81) => StatusWord::PinNotVerified,
+            _ => StatusWord::Unknown(sw),
+        }
+    }
+}
+
+impl From<StatusWord> for u16 {
+    fn from(status: StatusWord) -> Self {
+        match status {
+            StatusWord::Success => 0x9000,
+            StatusWord::MoreData(length) => 0x6100 | length as u16,
+            StatusWord::WrongLe(length) => 0x6C00 | length as u16,
+            StatusWord::RetriesLeft(retries) => 0x63C0 | (retries & 0x0F) as u16,
+            StatusWord::WrongLength => 0x6700,
+            StatusWord::UserNotConfirmed => 0x6940,
+            StatusWord::ExceededMaxUtxoNumber => 0x6941,
+            StatusWord::SignatureVerifyFail => 0x6942,
+            StatusWord::MnemonicCheckFail => 0x6943,
+            StatusWord::SecurityStatusNotSatisfied => 0x6982,
+            StatusWord::ConditionsNotSatisfied => 0x6985,
+            StatusWord::WrongData => 0x6A80,
+            StatusWord::AppletNotExist => 0x6A82,
+            StatusWord::IncorrectP1P2 => 0x6A86,
+            StatusWord::InsNotSupported => 0x6D00,
+            StatusWord::ClaNotSupported => 0x6E00,
+            StatusWord::BluetoothChannelError => 0x6F01,
+            StatusWord::WalletNotCreated => 0xF000,
+            StatusWord::InMenuPage => 0xF080,
+            StatusWord::PinNotVerified => 0xF081,
+            StatusWord::Unknown(sw) => sw,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::status_word::StatusWord;
+
+    #[test]
+    fn from_u16_test() {
+        assert_eq!(StatusWord::from(0x9000), StatusWord::Success);
+        assert_eq!(StatusWord::from(0x6110), StatusWord::MoreData(0x10));
+        assert_eq!(StatusWord::from(0x6C08), StatusWord::WrongLe(0x08));
+        assert_eq!(StatusWord::from(0x63C2), StatusWord::RetriesLeft(2));
+        assert_eq!(StatusWord::from(0x6300), StatusWord::Unknown(0x6300));
+        assert_eq!(StatusWord::from(0xF080), StatusWord::InMenuPage);
+        assert_eq!(StatusWord::from(0x6A81), StatusWord::Unknown(0x6A81));
+
+        for sw in [
+            0x9000, 0x6100, 0x6CFF, 0x63C0, 0x6700, 0x6940, 0x6941, 0x6942, 0x6943, 0x6982, 0x6985,
+            0x6A80, 0x6A82, 0x6A86, 0x6D00, 0x6E00, 0x6F01, 0xF000, 0xF080, 0xF081, 0x6A81,
+        ] {
+            assert_eq!(u16::from(StatusWord::from(sw)), sw);
+        }
+    }
+
+    #[test]
+    fn hints_test() {
+        assert!(StatusWord::MoreData(0).is_retryable());
+        assert!(StatusWord::WrongLe(2).is_retryable());
+        assert!(!StatusWord::UserNotConfirmed.is_retryable());
+        assert!(StatusWord::UserNotConfirmed.requires_user_action());
+        assert!(StatusWord::InMenuPage.requires_user_action());
+        assert!(StatusWord::PinNotVerified.requires_user_action());
+        assert!(StatusWord::RetriesLeft(3).requires_user_action());
+        assert!(!StatusWord::AppletNotExist.requires_user_action());
+        assert!(!StatusWord::AppletNotExist.is_retryable());
+    }
+
+    #[test]
+    fn message_test() {
+        assert_eq!(
+            StatusWord::UserNotConfirmed.to_string(),
+            "imkey_user_not_confirmed"
+        );
+        assert_eq!(
+            StatusWord::from(0x63C2).to_string(),
+            "imkey_command_execute_fail_63C2"
+        );
+        assert_eq!(
+            StatusWord::from(0x6A81).to_string(),
+            "imkey_command_execute_fail_6A81"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::error::CommonError;
+use crate::Result;
+
+/**
+BER-TLV data object (ISO 7816-4 / GlobalPlatform): multi-byte tags, short and long form
+lengths, constructed tags holding nested objects in their value.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    pub tag: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    pub fn new(tag: &[u8], value: &[u8]) -> Tlv {
+        Tlv {
+            tag: tag.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    /**
+    constructed object whose value is the encoding of the children
+    */
+    pub fn constructed(tag: &[u8], children: &[Tlv]) -> Tlv {
+        Tlv {
+            tag: tag.to_vec(),
+            value: children.iter().flat_map(Tlv::encode).collect(),
+        }
+    }
+
+    pub fn is_constructed(&self) -> bool {
+        self.tag.first().is_some_and(|tag| tag & 0x20 == 0x20)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.tag.clone();
+        data.extend(encode_length(self.value.len()));
+        data.extend(&self.value);
+        data
+    }
+
+    /**
+    first object of the data and the data following it
+    */
+    pub fn decode(data: &[u8]) -> Result<(Tlv, &[u8])> {
+        let tag_length = tag_length(data)?;
+        let (length, length_size) = decode_length(&data[tag_length..])?;
+        let start = tag_length + length_size;
+        let value = data
+            .get(start..start + length)
+            .ok_or(CommonError::InvalidTlv)?;
+        Ok((
+            Tlv::new(&data[..tag_length], value),
+            &data[start + length..],
+        ))
+    }
+
+    /**
+    every object of a concatenation of objects
+    */
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<Tlv>> {
+        let mut objects = vec![];
+        while !data.is_empty() {
+            let (object, rest) = Tlv::decode(data)?;
+            objects.push(object);
+            data = rest;
+        }
+        Ok(objects)
+    }
+
+    pub fn children(&self) -> Result<Vec<Tlv>> {
+        if !self.is_constructed() {
+            return Err(CommonError::InvalidTlv.into());
+        }
+        Tlv::decode_all(&self.value)
+    }
+
+    /**
+    first object with the tag among the children, searching constructed children depth first.
+    Constructed children whose value is not a list of objects are leaves, like the GP public
+    key tag B0.
+    */
+    pub fn find(&self, tag: &[u8]) -> Result<Option<Tlv>> {
+        for child in self.children()? {
+            if child.tag == tag {
+                return Ok(Some(child));
+            }
+            if let Ok(Some(found)) = child.find(tag) {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/**
+a tag is one byte, unless its low 5 bits are all set: then the following bytes with bit 8 set
+and one last byte without it
+*/
+fn tag_length(data: &[u8]) -> Result<usize> {
+    let first = data.first().ok_or(CommonError::InvalidTlv)?;
+    if first & 0x1F != 0x1F {
+        return Ok(1);
+    }
+    let mut length = 1;
+    while data.get(length).ok_or(CommonError::InvalidTlv)? & 0x80 == 0x80 {
+        length += 1;
+    }
+    Ok(length + 1)
+}
+
+/**
+short form below 0x80, else 81 to 84 followed by that many length bytes
+*/
+fn decode_length(data: &[u8]) -> Result<(usize, usize)> {
+    let first = *data.first().ok_or(CommonError::InvalidTlv)?;
+    if first < 0x80 {
+        return Ok((first as usize, 1));
+    }
+    let size = (first & 0x7F) as usize;
+    if size == 0 || size > 4 {
+        return Err(CommonError::InvalidTlv.into());
+    }
+    let bytes = data.get(1..1 + size).ok_or(CommonError::InvalidTlv)?;
+    let length = bytes
+        .iter()
+        .fold(0usize, |length, byte| length << 8 | *byte as usize);
+    Ok((length, 1 + size))
+}
+
+fn encode_length(length: usize) -> Vec<u8> {
+    if length < 0x80 {
+        return vec![length as u8];
+    }
+    let bytes: Vec<u8> = (length as u32)
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|byte| *byte == 0)
+        .collect();
+    let mut data = vec![0x80 | bytes.len() as u8];
+    data.extend(bytes);
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tlv::Tlv;
+    use proptest::prelude::*;
+
+    fn decode(data: &str) -> (Tlv, Vec<u8>) {
+        let data = hex::decode(data).unwrap();
+        let (tlv, rest) = Tlv::decode(&data).unwrap();
+        (tlv, rest.to_vec())
+    }
+
+    #[test]
+    fn decode_test() {
+        let (tlv, rest) = decode("9302AABB01");
+        assert_eq!(tlv, Tlv::new(&[0x93], &[0xAA, 0xBB]));
+        assert_eq!(rest, [0x01]);
+
+        let (tlv, _) = decode("9F810102AABB");
+        assert_eq!(tlv.tag, vec![0x9F, 0x81, 0x01]);
+        assert_eq!(tlv.value, vec![0xAA, 0xBB]);
+
+        let (tlv, _) = decode("A60483021518");
+        assert_eq!(tlv.find(&[0x83]).unwrap().unwrap().value, vec![0x15, 0x18]);
+
+        let (tlv, rest) = decode("5F7F81010203");
+        assert_eq!(rest, [0x03]);
+        assert_eq!(tlv.tag, vec![0x5F, 0x7F]);
+
+        let mut data = hex::decode("7F21820100").unwrap();
+        data.extend([0u8; 0x100].iter());
+        let (tlv, rest) = Tlv::decode(&data).unwrap();
+        assert_eq!(tlv.value.len(), 0x100);
+        assert!(rest.is_empty());
+
+        //long form is accepted even where the short form would do
+        let (tlv, _) = decode("938100");
+        assert!(tlv.value.is_empty());
+
+        for data in ["", "93", "9302AA", "1F", "9F81", "9385", "9380"] {
+            assert!(Tlv::decode(&hex::decode(data).unwrap()).is_err());
+        }
+        assert_eq!(
+            Tlv::decode(&[0x93, 0x02]).err().unwrap().to_string(),
+            "invalid_tlv"
+        );
+    }
+
+    #[test]
+    fn encode_test() {
+        assert_eq!(
+            hex::encode_upper(Tlv::new(&[0xDF, 0xFF], &[0x81, 0x01]).encode()),
+            "DFFF028101"
+        );
+        assert_eq!(
+            Tlv::new(&[0x93], &[0u8; 0x80]).encode()[..3],
+            [0x93, 0x81, 0x80]
+        );
+        assert_eq!(
+            Tlv::new(&[0x93], &[0u8; 0x1234]).encode()[..4],
+            [0x93, 0x82, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn constructed_test() {
+        let public_key = Tlv::constructed(
+            &[0x7F, 0x49],
+            &[Tlv::new(&[0xB0], &[0x04, 0x01]), Tlv::new(&[0xF0], &[0x00])],
+        );
+        let cert = Tlv::constructed(&[0x7F, 0x21], &[Tlv::new(&[0x93], &[0x19]), public_key]);
+        assert_eq!(
+            hex::encode_upper(cert.encode()),
+            "7F210D9301197F4907B0020401F00100"
+        );
+        assert!(cert.is_constructed());
+        assert_eq!(cert.children().unwrap().len(), 2);
+        assert_eq!(
+            cert.find(&[0xB0]).unwrap(),
+            Some(Tlv::new(&[0xB0], &[0x04, 0x01]))
+        );
+        assert_eq!(cert.find(&[0x5F, 0x37]).unwrap(), None);
+        assert!(Tlv::new(&[0x93], &[0x19]).children().is_err());
+    }
+
+    fn tag() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            any::<u8>()
+                .prop_filter("single byte tag", |tag| tag & 0x1F != 0x1F)
+                .prop_map(|tag| vec![tag]),
+            (
+                any::<u8>(),
+                prop::collection::vec(0x80u8.., 0..3),
+                0u8..0x80
+            )
+                .prop_map(|(first, middle, last)| {
+                    let mut tag = vec![first | 0x1F];
+                    tag.extend(middle);
+                    tag.push(last);
+                    tag
+                }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_test(tag in tag(), value in prop::collection::vec(any::<u8>(), 0..0x300)) {
+            let tlv = Tlv { tag, value };
+            let encoded = tlv.encode();
+            let (decoded, rest) = Tlv::decode(&encoded).unwrap();
+            prop_assert_eq!(decoded, tlv);
+            prop_assert!(rest.is_empty());
+        }
+
+        #[test]
+        fn nested_round_trip_test(
+            values in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..0x90), 0..8)
+        ) {
+            let children: Vec<Tlv> = values.iter().map(|value| Tlv::new(&[0x5F, 0x20], value)).collect();
+            let parent = Tlv::constructed(&[0xBF, 0x21], &children);
+            let (decoded, _) = Tlv::decode(&parent.encode()).unwrap();
+            prop_assert_eq!(decoded.children().unwrap(), children);
+        }
+
+        #[test]
+        fn decode_never_panics_test(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok((tlv, rest)) = Tlv::decode(&data) {
+                prop_assert!(tlv.encode().len() + rest.len() <= data.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::device_manager;
+use crate::error::BindError;
+use crate::tsm::{AppDeleteRequest, AppDownloadRequest, AppUpdateRequest, TsmClient};
+use crate::Result;
+use ikc_transport::device::Device;
+
+/**
+Installs, updates and deletes the coin applets of a device, running the GlobalPlatform scripts
+the TSM server issues for the device.
+*/
+pub struct AppManager<'a> {
+    device: &'a Device,
+    tsm: &'a TsmClient,
+}
+
+impl<'a> AppManager<'a> {
+    pub fn new(device: &'a Device, tsm: &'a TsmClient) -> AppManager<'a> {
+        AppManager { device, tsm }
+    }
+
+    pub async fn download(&self, aid: &str) -> Result<()> {
+        let (seid, device_cert) = self.identify(aid).await?;
+        let mut request = AppDownloadRequest::new(&seid, aid, &device_cert);
+        self.tsm.run_script(self.device, &mut request).await
+    }
+
+    /**
+    replace the installed applet with the latest version, its data is lost
+    */
+    pub async fn update(&self, aid: &str) -> Result<()> {
+        let (seid, device_cert) = self.identify(aid).await?;
+        let mut request = AppUpdateRequest::new(&seid, aid, &device_cert);
+        self.tsm.run_script(self.device, &mut request).await
+    }
+
+    pub async fn delete(&self, aid: &str) -> Result<()> {
+        let (seid, device_cert) = self.identify(aid).await?;
+        let mut request = AppDeleteRequest::new(&seid, aid, &device_cert);
+        self.tsm.run_script(self.device, &mut request).await
+    }
+
+    /**
+    SEID and certificate the server authenticates the device with
+    */
+    async fn identify(&self, aid: &str) -> Result<(String, String)> {
+        check_aid(aid)?;
+        let seid = device_manager::get_se_id(self.device).await?;
+        let device_cert = device_manager::get_cert(self.device).await?;
+        Ok((seid, device_cert))
+    }
+}
+
+/**
+AIDs are 5 to 16 bytes, given in upper case hex like the constants
+*/
+fn check_aid(aid: &str) -> Result<()> {
+    let length = aid.len() / 2;
+    let is_hex = aid
+        .chars()
+        .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c));
+    if !aid.len().is_multiple_of(2) || !(5..=16).contains(&length) || !is_hex {
+        return Err(BindError::ImkeySdkIllegalArgument.into());
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::app_manager::AppManager;
+    use crate::device_manager;
+    use crate::tsm::TsmClient;
+    use futures::executor::block_on;
+    use ikc_common::constants;
+    use ikc_simulator::simulator::Simulator;
+    use ikc_simulator::tsm::MockTsm;
+    use ikc_transport::device::Device;
+    use std::rc::Rc;
+
+    fn setup() -> (Rc<MockTsm>, TsmClient, Device) {
+        let server = Rc::new(MockTsm::new());
+        let client = TsmClient::with_endpoint("https://tsm.test/imkey", server.clone());
+        (server, client, Device::new(Simulator::new()))
+    }
+
+    fn installed(device: &Device) -> Vec<(String, String)> {
+        block_on(device_manager::get_applets(device))
+            .unwrap()
+            .into_iter()
+            .map(|applet| (applet.name, applet.version))
+            .collect()
+    }
+
+    #[test]
+    fn download_delete_test() {
+        let (_, client, device) = setup();
+        let manager = AppManager::new(&device, &client);
+
+        block_on(manager.download(constants::ETH_AID)).unwrap();
+        assert!(installed(&device).contains(&("ETH".to_string(), "1.6".to_string())));
+
+        //installing it again fails on the device
+        let result = block_on(manager.download(constants::ETH_AID));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_app_download_fail"
+        );
+
+        block_on(manager.delete(constants::ETH_AID)).unwrap();
+        assert!(!installed(&device).iter().any(|(name, _)| name == "ETH"));
+        let result = block_on(manager.delete(constants::ETH_AID));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_app_delete_fail"
+        );
+    }
+
+    #[test]
+    fn update_test() {
+        let (_, client, device) = setup();
+        let manager = AppManager::new(&device, &client);
+        assert!(installed(&device).contains(&("BTC".to_string(), "1.5".to_string())));
+        block_on(manager.update(constants::BTC_AID)).unwrap();
+        assert!(installed(&device).contains(&("BTC".to_string(), "1.5".to_string())));
+
+        //nothing to update
+        let result = block_on(manager.update(constants::COSMOS_AID));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_app_update_fail"
+        );
+    }
+
+    #[test]
+    fn unsupported_applet_test() {
+        let (server, client, device) = setup();
+        let manager = AppManager::new(&device, &client);
+        let result = block_on(manager.download(constants::TEZOS_AID));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_device_unsupport_applet"
+        );
+
+        server.set_return_code(
+            constants::TSM_ACTION_APP_DOWNLOAD,
+            constants::TSM_RETURNCODE_COS_VERSION_UNSUPPORT_APPLET,
+        );
+        let result = block_on(manager.download(constants::COSMOS_AID));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_cos_version_unsupport_applet"
+        );
+
+        for aid in ["695F", "695f657468", "695F65746", "XYZXYZXYZX"] {
+            let result = block_on(manager.download(aid));
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                "imkey_sdk_illegal_argument"
+            );
+        }
+        //the illegal arguments never reach the server
+        assert_eq!(server.requests().len(), 2);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::device_binding::{find_key_manager, DeviceManage};
+use crate::device_manager;
+use crate::error::BindError;
+use crate::key_manager::{KeyFileSecret, KeyManager};
+use crate::key_store::KeyStore;
+use crate::Result;
+use ikc_transport::device::Device;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/**
+wrong binding codes accepted before a new code has to be displayed
+*/
+pub const MAX_BIND_CODE_ATTEMPTS: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingState {
+    Unbound,
+    /**
+    the device is bound to another host, binding this one replaces it
+    */
+    BoundOther,
+    /**
+    the device displays a binding code for the user to type
+    */
+    AwaitingCode,
+    Bound,
+    /**
+    wrong codes typed for the displayed code
+    */
+    Failed {
+        attempts: u8,
+    },
+}
+
+/**
+Binding of this host to a device, step by step: check, display the code, acquire with the code
+the user typed. Steps are only accepted in the states they apply to, and for the device the
+session was checked with.
+*/
+pub struct BindingSession {
+    seid: String,
+    key_store: Rc<dyn KeyStore>,
+    secret: KeyFileSecret,
+    state: Cell<BindingState>,
+}
+
+impl BindingSession {
+    /**
+    start a session with the bind check, loading the keys of the key file
+    */
+    pub async fn check(
+        device: &Device,
+        key_store: Rc<dyn KeyStore>,
+        secret: KeyFileSecret,
+    ) -> Result<BindingSession> {
+        let state = DeviceManage::bind_check(device, key_store.as_ref(), &secret).await?;
+        Ok(BindingSession {
+            seid: device_manager::get_se_id(device).await?,
+            key_store,
+            secret,
+            state: Cell::new(state),
+        })
+    }
+
+    pub fn state(&self) -> BindingState {
+        self.state.get()
+    }
+
+    pub fn seid(&self) -> &str {
+        &self.seid
+    }
+
+    /**
+    codes the user can still type before a new one has to be displayed
+    */
+    pub fn remaining_attempts(&self) -> u8 {
+        match self.state.get() {
+            BindingState::AwaitingCode => MAX_BIND_CODE_ATTEMPTS,
+            BindingState::Failed { attempts } => MAX_BIND_CODE_ATTEMPTS.saturating_sub(attempts),
+            _ => 0,
+        }
+    }
+
+    /**
+    display a new binding code, the attempts start over
+    */
+    pub async fn display_code(&self, device: &Device) -> Result<BindingState> {
+        self.check_device(device).await?;
+        //no keys to bind with after unbind, until the next check
+        if self.state.get() == BindingState::Bound
+            || find_key_manager(&self.seid)
+                .is_none_or(|key_manager| key_manager.lock().pri_key.is_empty())
+        {
+            return Err(BindError::ImkeyBindStepOutOfOrder.into());
+        }
+        DeviceManage::display_bind_code(device).await?;
+        self.state.set(BindingState::AwaitingCode);
+        Ok(BindingState::AwaitingCode)
+    }
+
+    /**
+    bind with the code the user typed. A wrong code is not an error, the state tells the attempts.
+    */
+    pub async fn acquire(&self, device: &Device, bind_code: &str) -> Result<BindingState> {
+        self.check_device(device).await?;
+        let attempts = match self.state.get() {
+            BindingState::AwaitingCode => 0,
+            BindingState::Failed { attempts } if attempts < MAX_BIND_CODE_ATTEMPTS => attempts,
+            BindingState::Failed { .. } => {
+                return Err(BindError::ImkeyBindCodeAttemptsExceeded.into())
+            }
+            _ => return Err(BindError::ImkeyBindStepOutOfOrder.into()),
+        };
+        let state = match DeviceManage::bind_acquire(device, &bind_code.to_string()).await {
+            Ok(state) => state,
+            Err(e) if e.downcast_ref() == Some(&BindError::ImkeyAuthcodeError) => {
+                BindingState::Failed {
+                    attempts: attempts + 1,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        self.state.set(state);
+        Ok(state)
+    }
+
+    /**
+    forget the keys of this host and its key file. There is nothing to remove on the device:
+    it stays bound to the forgotten key until a host binds it, the next check tells BoundOther.
+    */
+    pub async fn unbind(&self, device: &Device) -> Result<BindingState> {
+        self.check_device(device).await?;
+        DeviceManage::close_secure_channel(device);
+        KeyManager::remove_key_file_data(self.key_store.as_ref(), &self.seid).await?;
+        if let Some(key_manager) = find_key_manager(&self.seid) {
+            key_manager.lock().clear_keys();
+        }
+        self.state.set(BindingState::Unbound);
+        Ok(BindingState::Unbound)
+    }
+
+    /**
+    bind again with new keys, for instance when the key file may have leaked
+    */
+    pub async fn rebind(&self, device: &Device) -> Result<BindingState> {
+        self.unbind(device).await?;
+        let state = DeviceManage::bind_check(device, self.key_store.as_ref(), &self.secret).await?;
+        self.state.set(state);
+        self.display_code(device).await
+    }
+
+    /**
+    the steps of the session only apply to the device it was checked with
+    */
+    async fn check_device(&self, device: &Device) -> Result<()> {
+        let seid = match device.seid() {
+            Some(seid) => seid,
+            None => device_manager::get_se_id(device).await?,
+        };
+        if seid != self.seid {
+            return Err(BindError::ImkeyBindDeviceChanged.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::binding_session::{BindingSession, BindingState, MAX_BIND_CODE_ATTEMPTS};
+    use crate::device_binding::{lock_simulator, test_secret, DeviceManage};
+    use crate::key_manager::KeyManager;
+    use crate::key_store::MemoryKeyStore;
+    use futures::executor::block_on;
+    use ikc_simulator::simulator::{Simulator, SIMULATOR_SEID};
+    use ikc_transport::device::Device;
+    use std::rc::Rc;
+
+    fn check(device: &Device, key_store: &Rc<MemoryKeyStore>) -> BindingSession {
+        block_on(BindingSession::check(
+            device,
+            key_store.clone(),
+            test_secret(),
+        ))
+        .unwrap()
+    }
+
+    fn error<T>(result: crate::Result<T>) -> String {
+        result.err().unwrap().to_string()
+    }
+
+    #[test]
+    fn bind_test() {
+        let _lock = lock_simulator();
+        let simulator = Simulator::new();
+        let device = Device::new(simulator.clone());
+        let key_store = Rc::new(MemoryKeyStore::new());
+        let session = check(&device, &key_store);
+        assert_eq!(session.state(), BindingState::Unbound);
+        assert_eq!(
+            error(block_on(session.acquire(&device, "22222222"))),
+            "imkey_bind_step_out_of_order"
+        );
+
+        block_on(session.display_code(&device)).unwrap();
+        assert_eq!(session.remaining_attempts(), MAX_BIND_CODE_ATTEMPTS);
+        let state = block_on(session.acquire(&device, "22222222")).unwrap();
+        assert_eq!(state, BindingState::Failed { attempts: 1 });
+        assert_eq!(session.remaining_attempts(), MAX_BIND_CODE_ATTEMPTS - 1);
+        //a malformed code is not an attempt
+        assert_eq!(
+            error(block_on(session.acquire(&device, "1"))),
+            "imkey_sdk_illegal_argument"
+        );
+        assert_eq!(session.state(), BindingState::Failed { attempts: 1 });
+
+        let bind_code = simulator.bind_code().unwrap();
+        let state = block_on(session.acquire(&device, &bind_code)).unwrap();
+        assert_eq!(state, BindingState::Bound);
+        assert_eq!(
+            error(block_on(session.display_code(&device))),
+            "imkey_bind_step_out_of_order"
+        );
+        assert_eq!(check(&device, &key_store).state(), BindingState::Bound);
+    }
+
+    #[test]
+    fn attempts_exceeded_test() {
+        let _lock = lock_simulator();
+        let simulator = Simulator::new();
+        let device = Device::new(simulator.clone());
+        let session = check(&device, &Rc::new(MemoryKeyStore::new()));
+        block_on(session.display_code(&device)).unwrap();
+        for attempts in 1..=MAX_BIND_CODE_ATTEMPTS {
+            let state = block_on(session.acquire(&device, "22222222")).unwrap();
+            assert_eq!(state, BindingState::Failed { attempts });
+        }
+        assert_eq!(session.remaining_attempts(), 0);
+        let bind_code = simulator.bind_code().unwrap();
+        assert_eq!(
+            error(block_on(session.acquire(&device, &bind_code))),
+            "imkey_bind_code_attempts_exceeded"
+        );
+
+        //a new code gives new attempts
+        block_on(session.display_code(&device)).unwrap();
+        let bind_code = simulator.bind_code().unwrap();
+        let state = block_on(session.acquire(&device, &bind_code)).unwrap();
+        assert_eq!(state, BindingState::Bound);
+    }
+
+    #[test]
+    fn unbind_rebind_test() {
+        let _lock = lock_simulator();
+        let simulator = Simulator::new();
+        let device = Device::new(simulator.clone());
+        let key_store = Rc::new(MemoryKeyStore::new());
+        let session = check(&device, &key_store);
+        block_on(session.display_code(&device)).unwrap();
+        block_on(session.acquire(&device, &simulator.bind_code().unwrap())).unwrap();
+        let key_file = block_on(KeyManager::get_key_file_data(
+            key_store.as_ref(),
+            SIMULATOR_SEID,
+        ))
+        .unwrap();
+
+        let state = block_on(session.unbind(&device)).unwrap();
+        assert_eq!(state, BindingState::Unbound);
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(
+                key_store.as_ref(),
+                SIMULATOR_SEID
+            ))
+            .unwrap(),
+            ""
+        );
+        assert_eq!(
+            error(block_on(DeviceManage::open_secure_channel(&device))),
+            "imkey_device_not_bound"
+        );
+        //the forgotten keys cannot be bound again
+        assert_eq!(
+            error(block_on(session.display_code(&device))),
+            "imkey_bind_step_out_of_order"
+        );
+
+        let state = block_on(session.rebind(&device)).unwrap();
+        assert_eq!(state, BindingState::AwaitingCode);
+        let state = block_on(session.acquire(&device, &simulator.bind_code().unwrap())).unwrap();
+        assert_eq!(state, BindingState::Bound);
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        assert_ne!(
+            block_on(KeyManager::get_key_file_data(
+                key_store.as_ref(),
+                SIMULATOR_SEID
+            ))
+            .unwrap(),
+            key_file
+        );
+        assert_eq!(check(&device, &key_store).state(), BindingState::Bound);
+    }
+
+    #[test]
+    fn device_changed_test() {
+        let _lock = lock_simulator();
+        let device = Device::new(Simulator::new());
+        let session = check(&device, &Rc::new(MemoryKeyStore::new()));
+        assert_eq!(session.seid(), SIMULATOR_SEID);
+
+        let other = Device::new(Simulator::with_seid("19060000000200860001010000000099"));
+        assert_eq!(
+            error(block_on(session.display_code(&other))),
+            "imkey_bind_device_changed"
+        );
+        assert_eq!(
+            error(block_on(session.unbind(&other))),
+            "imkey_bind_device_changed"
+        );
+        block_on(session.display_code(&device)).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+use crate::device_manager::get_sn;
+use crate::error::ImkeyError;
+use crate::Result;
+use ikc_transport::connector::Connector;
+use ikc_transport::device::Device;
+use ikc_transport::error::TransportError;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    Connected(String),
+    Disconnected(String),
+}
+
+type Listener = Box<dyn Fn(&ConnectionEvent)>;
+
+/**
+Keeps track of the connected imKey devices by serial number and of the one selected by the caller.
+*/
+pub struct ConnectionManager {
+    connector: RefCell<Rc<dyn Connector>>,
+    devices: RefCell<Vec<(String, Rc<Device>)>>,
+    selected: RefCell<Option<String>>,
+    listeners: RefCell<Vec<Listener>>,
+    upgrading: Cell<bool>,
+}
+
+/**
+A COS upgrade of the selected device is running until dropped. The device re-enumerates
+during the upgrade, the other operations fail with a device busy error instead of
+getting a handle of it meanwhile.
+*/
+pub struct UpgradeGuard<'a> {
+    manager: &'a ConnectionManager,
+}
+
+impl Drop for UpgradeGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.upgrading.set(false);
+    }
+}
+
+impl ConnectionManager {
+    pub fn new<C: Connector + 'static>(connector: C) -> ConnectionManager {
+        ConnectionManager {
+            connector: RefCell::new(Rc::new(connector)),
+            devices: RefCell::new(vec![]),
+            selected: RefCell::new(None),
+            listeners: RefCell::new(vec![]),
+            upgrading: Cell::new(false),
+        }
+    }
+
+    /**
+    reserve the devices for a COS upgrade, fail with a device busy error if one is running
+    */
+    pub fn start_upgrade(&self) -> Result<UpgradeGuard<'_>> {
+        self.check_not_upgrading()?;
+        self.upgrading.set(true);
+        Ok(UpgradeGuard { manager: self })
+    }
+
+    pub fn check_not_upgrading(&self) -> Result<()> {
+        match self.upgrading.get() {
+            true => Err(TransportError::DeviceBusy.into()),
+            false => Ok(()),
+        }
+    }
+
+    /**
+    open the devices through another connector from now on, the devices opened through the
+    previous one are dropped and reported disconnected
+    */
+    pub fn set_connector<C: Connector + 'static>(&self, connector: C) {
+        *self.connector.borrow_mut() = Rc::new(connector);
+        let devices = std::mem::take(&mut *self.devices.borrow_mut());
+        for (sn, _) in devices {
+            self.emit(ConnectionEvent::Disconnected(sn));
+        }
+    }
+
+    pub fn add_listener<F: Fn(&ConnectionEvent) + 'static>(&self, listener: F) {
+        self.listeners.borrow_mut().push(Box::new(listener));
+    }
+
+    /**
+    open the available devices again, return the serial numbers of the connected ones.
+    The first device found is selected when none is selected yet.
+    */
+    pub async fn refresh(&self) -> Result<Vec<String>> {
+        self.check_not_upgrading()?;
+        self.open_devices().await
+    }
+
+    async fn open_devices(&self) -> Result<Vec<String>> {
+        //reading the serial numbers must not interleave with a running operation
+        let known: Vec<(String, Rc<Device>)> = self.devices.borrow().clone();
+        let mut sessions = vec![];
+        for (_, device) in known.iter() {
+            sessions.push(device.session().await);
+        }
+
+        let connector = self.connector.borrow().clone();
+        let mut devices = vec![];
+        for device in connector.open_devices().await? {
+            //a device unplugged meanwhile is left for the next refresh
+            let sn = match get_sn(&device).await {
+                Ok(sn) => sn,
+                Err(_) => continue,
+            };
+            //keep the handle of a device still connected, its session may be awaited
+            let device = match known.iter().find(|(known_sn, _)| *known_sn == sn) {
+                Some((_, known_device)) if known_device.is_connected() => known_device.clone(),
+                _ => Rc::new(device),
+            };
+            devices.push((sn, device));
+        }
+        drop(sessions);
+
+        let old_sns = self.serial_numbers();
+        let new_sns: Vec<String> = devices.iter().map(|(sn, _)| sn.clone()).collect();
+        *self.devices.borrow_mut() = devices;
+        for sn in old_sns.iter().filter(|sn| !new_sns.contains(sn)) {
+            self.emit(ConnectionEvent::Disconnected(sn.clone()));
+        }
+        for sn in new_sns.iter().filter(|sn| !old_sns.contains(sn)) {
+            self.emit(ConnectionEvent::Connected(sn.clone()));
+        }
+
+        let mut selected = self.selected.borrow_mut();
+        if selected.is_none() {
+            *selected = new_sns.first().cloned();
+        }
+        Ok(new_sns)
+    }
+
+    /**
+    drop the devices which have been unplugged, called when the platform reports a disconnection
+    */
+    pub fn check_connections(&self) {
+        let mut disconnected = vec![];
+        self.devices.borrow_mut().retain(|(sn, device)| {
+            let connected = device.is_connected();
+            if !connected {
+                disconnected.push(sn.clone());
+            }
+            connected
+        });
+        for sn in disconnected {
+            self.emit(ConnectionEvent::Disconnected(sn));
+        }
+    }
+
+    pub fn serial_numbers(&self) -> Vec<String> {
+        self.devices
+            .borrow()
+            .iter()
+            .map(|(sn, _)| sn.clone())
+            .collect()
+    }
+
+    /**
+    select the device used by the following operations
+    */
+    pub async fn select(&self, sn: &str) -> Result<Rc<Device>> {
+        self.check_not_upgrading()?;
+        if self.find(sn).is_none() {
+            self.open_devices().await?;
+        }
+        let device = self.find(sn).ok_or(TransportError::DeviceNotConnected)?;
+        *self.selected.borrow_mut() = Some(sn.to_string());
+        Ok(device)
+    }
+
+    /**
+    the selected device, opened again when it has been unplugged and plugged back
+    */
+    pub async fn current(&self) -> Result<Rc<Device>> {
+        self.check_not_upgrading()?;
+        self.reconnect_current().await
+    }
+
+    /**
+    the selected device, also while the upgrade reserving the devices waits for it to come back
+    */
+    pub(crate) async fn reconnect_current(&self) -> Result<Rc<Device>> {
+        let sn = self
+            .selected
+            .borrow()
+            .clone()
+            .ok_or(TransportError::DeviceNotConnected)?;
+        if let Some(device) = self.find(&sn).filter(|device| device.is_connected()) {
+            return Ok(device);
+        }
+
+        self.open_devices()
+            .await
+            .map_err(|_| ImkeyError::ImkeyDeviceReconnectFail)?;
+        match self.find(&sn).filter(|device| device.is_connected()) {
+            Some(device) => Ok(device),
+            None => Err(ImkeyError::ImkeyDeviceReconnectFail.into()),
+        }
+    }
+
+    fn find(&self, sn: &str) -> Option<Rc<Device>> {
+        self.devices
+            .borrow()
+            .iter()
+            .find(|(device_sn, _)| device_sn == sn)
+            .map(|(_, device)| device.clone())
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        for listener in self.listeners.borrow().iter() {
+            listener(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::connection_manager::{ConnectionEvent, ConnectionManager};
+    use crate::Result;
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use ikc_transport::connector::Connector;
+    use ikc_transport::device::Device;
+    use ikc_transport::transport::Transport;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    struct TestTransport {
+        sn: &'static str,
+        plugged: Rc<Cell<bool>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for TestTransport {
+        async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+            match apdu {
+                [0x80, 0xCA, 0x00, 0x44, 0x00] => {
+                    let mut response = self.sn.as_bytes().to_vec();
+                    response.extend([0x90, 0x00].iter());
+                    Ok(response)
+                }
+                _ => Ok(vec![0x90, 0x00]),
+            }
+        }
+
+        fn is_connected(&self) -> bool {
+            self.plugged.get()
+        }
+    }
+
+    struct TestConnector {
+        devices: Vec<(&'static str, Rc<Cell<bool>>)>,
+    }
+
+    #[async_trait(?Send)]
+    impl Connector for TestConnector {
+        async fn open_devices(&self) -> Result<Vec<Device>> {
+            Ok(self
+                .devices
+                .iter()
+                .filter(|(_, plugged)| plugged.get())
+                .map(|(sn, plugged)| {
+                    Device::new(TestTransport {
+                        sn,
+                        plugged: plugged.clone(),
+                    })
+                })
+                .collect())
+        }
+    }
+
+    type Events = Rc<RefCell<Vec<ConnectionEvent>>>;
+
+    fn manager() -> (ConnectionManager, Rc<Cell<bool>>, Events) {
+        let plugged = Rc::new(Cell::new(true));
+        let connector = TestConnector {
+            devices: vec![
+                ("imKey01191200001", plugged.clone()),
+                ("imKey01191200002", Rc::new(Cell::new(true))),
+            ],
+        };
+        let manager = ConnectionManager::new(connector);
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorder = events.clone();
+        manager.add_listener(move |event| recorder.borrow_mut().push(event.clone()));
+        (manager, plugged, events)
+    }
+
+    #[test]
+    fn refresh_and_select_test() {
+        let (manager, _, events) = manager();
+        let sns = block_on(manager.refresh()).unwrap();
+        assert_eq!(sns, vec!["imKey01191200001", "imKey01191200002"]);
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                ConnectionEvent::Connected("imKey01191200001".to_string()),
+                ConnectionEvent::Connected("imKey01191200002".to_string()),
+            ]
+        );
+
+        assert!(block_on(manager.select("imKey01191200002")).is_ok());
+        assert_eq!(
+            block_on(manager.select("imKey01191200003"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "imkey_device_not_connected"
+        );
+    }
+
+    #[test]
+    fn disconnect_and_reconnect_test() {
+        let (manager, plugged, events) = manager();
+        block_on(manager.refresh()).unwrap();
+        assert!(block_on(manager.current()).is_ok());
+
+        plugged.set(false);
+        manager.check_connections();
+        assert_eq!(manager.serial_numbers(), vec!["imKey01191200002"]);
+        assert_eq!(
+            events.borrow().last(),
+            Some(&ConnectionEvent::Disconnected(
+                "imKey01191200001".to_string()
+            ))
+        );
+        assert_eq!(
+            block_on(manager.current()).err().unwrap().to_string(),
+            "imkey_device_reconnect_fail"
+        );
+
+        plugged.set(true);
+        assert!(block_on(manager.current()).is_ok());
+        assert_eq!(manager.serial_numbers().len(), 2);
+    }
+
+    #[test]
+    fn refresh_keeps_connected_device_test() {
+        let (manager, _, _) = manager();
+        block_on(manager.refresh()).unwrap();
+        let device = block_on(manager.current()).unwrap();
+        block_on(manager.refresh()).unwrap();
+        assert!(Rc::ptr_eq(&device, &block_on(manager.current()).unwrap()));
+
+        //a refresh waits for the running operation
+        let session = device.try_session().unwrap();
+        assert!(manager.refresh().now_or_never().is_none());
+        drop(session);
+        assert!(manager.refresh().now_or_never().is_some());
+    }
+
+    #[test]
+    fn upgrade_guard_test() {
+        let (manager, _, _) = manager();
+        block_on(manager.refresh()).unwrap();
+        let upgrading = manager.start_upgrade().unwrap();
+        assert_eq!(
+            manager.start_upgrade().err().unwrap().to_string(),
+            "imkey_device_busy"
+        );
+        for result in [
+            block_on(manager.current()).map(|_| ()),
+            block_on(manager.select("imKey01191200002")).map(|_| ()),
+            block_on(manager.refresh()).map(|_| ()),
+        ] {
+            assert_eq!(result.err().unwrap().to_string(), "imkey_device_busy");
+        }
+        //the upgrade itself gets the device back
+        assert!(block_on(manager.reconnect_current()).is_ok());
+
+        drop(upgrading);
+        assert!(block_on(manager.current()).is_ok());
+    }
+
+    #[test]
+    fn set_connector_test() {
+        let (manager, _, events) = manager();
+        block_on(manager.refresh()).unwrap();
+        let device = block_on(manager.current()).unwrap();
+
+        manager.set_connector(TestConnector {
+            devices: vec![("imKey01191200001", Rc::new(Cell::new(true)))],
+        });
+        assert!(manager.serial_numbers().is_empty());
+        assert_eq!(events.borrow().len(), 4);
+
+        //the selected device is opened again through the new connector
+        let reopened = block_on(manager.current()).unwrap();
+        assert!(!Rc::ptr_eq(&device, &reopened));
+        assert_eq!(manager.serial_numbers(), vec!["imKey01191200001"]);
+    }
+}
@@ -0,0 +1,351 @@
+use crate::connection_manager::ConnectionManager;
+use crate::device_manager;
+use crate::error::ImkeyError;
+use crate::tsm::{CosCheckUpdateRequest, CosCheckUpdateResponse, CosUpgradeRequest, TsmClient};
+use crate::{Result, TsmService};
+use async_trait::async_trait;
+use ikc_common::apdu::{Command, Exchange};
+use ikc_common::constants;
+use ikc_transport::device::Device;
+use serde::Serialize;
+use std::rc::Rc;
+
+//905A: the device restarts in the bootloader
+const SWITCH_BL_STATUS_SUCCESS: u16 = 0x905A;
+//progress while the bootloader writes the COS image
+const WRITE_START: u8 = 10;
+const WRITE_END: u8 = 90;
+
+/**
+Gives the handle of the device once it is back after re-enumerating on USB, which it does
+when switching to the bootloader and when restarting the new COS
+*/
+#[async_trait(?Send)]
+pub trait Reconnect {
+    async fn reconnect(&self) -> Result<Rc<Device>>;
+}
+
+#[async_trait(?Send)]
+impl Reconnect for ConnectionManager {
+    async fn reconnect(&self) -> Result<Rc<Device>> {
+        self.reconnect_current().await
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum UpgradeStage {
+    CheckUpdate,
+    SwitchBootloader,
+    WriteCos,
+    Verify,
+    Done,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeProgress {
+    pub stage: UpgradeStage,
+    pub percent: u8,
+}
+
+/**
+COS firmware upgrade run by the TSM server: the device switches to the bootloader, takes the
+COS image, then restarts the new COS. Calling upgrade again on a device left in the bootloader
+by a lost connection resumes the upgrade.
+*/
+pub struct CosUpgrade<'a> {
+    tsm: &'a TsmClient,
+    reconnect: &'a dyn Reconnect,
+    progress: Box<dyn Fn(&UpgradeProgress) + 'a>,
+}
+
+impl<'a> CosUpgrade<'a> {
+    pub fn new<F>(tsm: &'a TsmClient, reconnect: &'a dyn Reconnect, progress: F) -> CosUpgrade<'a>
+    where
+        F: Fn(&UpgradeProgress) + 'a,
+    {
+        CosUpgrade {
+            tsm,
+            reconnect,
+            progress: Box::new(progress),
+        }
+    }
+
+    /**
+    latest COS version the server has for the device
+    */
+    pub async fn check_update(&self, device: &Device) -> Result<CosCheckUpdateResponse> {
+        let seid = device_manager::get_se_id(device).await?;
+        let cos_version = device_manager::get_cos_version(device).await?;
+        CosCheckUpdateRequest::new(&seid, &cos_version)
+            .send_message(self.tsm)
+            .await
+    }
+
+    /**
+    upgrade to the latest COS, nothing to do when the device already runs it.
+    Return the handle of the device, a new one when it re-enumerated.
+    The session of the device is held for the whole upgrade, taken again on the new handle.
+    */
+    pub async fn upgrade(&self, device: Rc<Device>) -> Result<Rc<Device>> {
+        let mut session = device.try_session()?;
+        self.report(UpgradeStage::CheckUpdate, 0);
+        let mut in_bootloader = device_manager::select_isd_or_bootloader(&device).await?;
+        let mut request = if in_bootloader {
+            //the bootloader tells the SEID and SN only, the server knows the rest
+            let seid = device_manager::get_se_id(&device).await?;
+            let sn = device_manager::get_sn(&device).await?;
+            CosUpgradeRequest::new(&seid, &sn, "", "", true)
+        } else {
+            let update = self.check_update(&device).await?;
+            if update.is_latest {
+                self.report(UpgradeStage::Done, 100);
+                return Ok(device);
+            }
+            let seid = device_manager::get_se_id(&device).await?;
+            let sn = device_manager::get_sn(&device).await?;
+            let device_cert = device_manager::get_cert(&device).await?;
+            let cos_version = device_manager::get_cos_version(&device).await?;
+            CosUpgradeRequest::new(&seid, &sn, &device_cert, &cos_version, false)
+        };
+
+        let mut device = device;
+        let mut percent = 0;
+        for _ in 0..constants::TSM_MAX_COS_UPGRADE_STEPS {
+            let response = request.send_message(self.tsm).await?;
+            if response.next_step_key == constants::TSM_END_FLAG {
+                self.report(UpgradeStage::Done, 100);
+                return Ok(device);
+            }
+            let apdu_list = response.apdu_list.unwrap_or_default();
+            //the server does not tell how many write steps follow, each one takes half of the
+            //progress left before the verification
+            let write_start = percent.max(WRITE_START);
+            let write_part = WRITE_END.saturating_sub(write_start) as usize / 2;
+            let mut card_ret_data_list = vec![];
+            let mut status_word = String::new();
+            for (index, apdu) in apdu_list.iter().enumerate() {
+                if !device.is_connected() {
+                    //the handle gone is not waited for when reopening the devices
+                    drop(session);
+                    device = self.reconnect.reconnect().await?;
+                    session = device.try_session()?;
+                    let was_in_bootloader = in_bootloader;
+                    in_bootloader = device_manager::select_isd_or_bootloader(&device).await?;
+                    if was_in_bootloader && !in_bootloader {
+                        percent = 95;
+                        self.report(UpgradeStage::Verify, percent);
+                    }
+                }
+                let command = Command::decode(&hex::decode(apdu)?)?;
+                let res = device.exchange(&command).await?;
+                card_ret_data_list.push(hex::encode_upper(res.encode()));
+                status_word = format!("{:04X}", res.sw);
+                if res.sw == SWITCH_BL_STATUS_SUCCESS {
+                    in_bootloader = true;
+                    percent = WRITE_START;
+                    self.report(UpgradeStage::SwitchBootloader, percent);
+                } else if in_bootloader {
+                    let written = write_part * (index + 1) / apdu_list.len();
+                    percent = write_start + written as u8;
+                    self.report(UpgradeStage::WriteCos, percent);
+                }
+            }
+            let step = &mut request.step;
+            step.step_key = response.next_step_key;
+            step.status_word = Some(status_word);
+            step.card_ret_data_list = Some(card_ret_data_list);
+        }
+        Err(ImkeyError::ImkeyTsmTooManySteps.into())
+    }
+
+    fn report(&self, stage: UpgradeStage, percent: u8) {
+        (self.progress)(&UpgradeProgress { stage, percent });
+    }
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::cos_upgrade::{CosUpgrade, Reconnect, UpgradeProgress, UpgradeStage};
+    use crate::device_manager;
+    use crate::error::ImkeyError;
+    use crate::tsm::TsmClient;
+    use crate::Result;
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use ikc_common::constants;
+    use ikc_simulator::simulator::{Simulator, SIMULATOR_COS_VERSION};
+    use ikc_simulator::tsm::MockTsm;
+    use ikc_transport::device::Device;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    //plugs the simulator back, failing the given number of times first
+    struct TestReconnect {
+        simulator: Simulator,
+        failures: Cell<u32>,
+    }
+
+    #[async_trait(?Send)]
+    impl Reconnect for TestReconnect {
+        async fn reconnect(&self) -> Result<Rc<Device>> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(ImkeyError::ImkeyDeviceReconnectFail.into());
+            }
+            self.simulator.reconnect();
+            Ok(Rc::new(Device::new(self.simulator.clone())))
+        }
+    }
+
+    fn setup(failures: u32) -> (Rc<MockTsm>, TsmClient, TestReconnect) {
+        let server = Rc::new(MockTsm::new());
+        server.set_latest_cos_version("1.8.0");
+        let tsm = TsmClient::with_endpoint("https://tsm.test/imkey", server.clone());
+        let reconnect = TestReconnect {
+            simulator: Simulator::new(),
+            failures: Cell::new(failures),
+        };
+        (server, tsm, reconnect)
+    }
+
+    #[test]
+    fn upgrade_test() {
+        let (server, tsm, reconnect) = setup(0);
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let progress = RefCell::new(vec![]);
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |step: &UpgradeProgress| {
+            progress.borrow_mut().push(step.clone())
+        });
+
+        let update = block_on(upgrade.check_update(&device)).unwrap();
+        assert!(!update.is_latest);
+        assert_eq!(update.latest_cos_version, "1.8.0");
+
+        let device = block_on(upgrade.upgrade(device)).unwrap();
+        assert_eq!(
+            block_on(device_manager::get_cos_version(&device)).unwrap(),
+            "1.8.0"
+        );
+        assert!(block_on(upgrade.check_update(&device)).unwrap().is_latest);
+
+        let progress = progress.borrow();
+        let stages: Vec<UpgradeStage> = progress.iter().map(|step| step.stage).collect();
+        assert_eq!(stages[0], UpgradeStage::CheckUpdate);
+        assert!(stages.contains(&UpgradeStage::SwitchBootloader));
+        assert!(stages.contains(&UpgradeStage::WriteCos));
+        assert!(stages.contains(&UpgradeStage::Verify));
+        assert_eq!(stages.last(), Some(&UpgradeStage::Done));
+        let percents: Vec<u8> = progress.iter().map(|step| step.percent).collect();
+        assert!(percents.windows(2).all(|pair| pair[0] <= pair[1]));
+        //the image is written in several steps
+        let writes: Vec<u8> = progress
+            .iter()
+            .filter(|step| step.stage == UpgradeStage::WriteCos)
+            .map(|step| step.percent)
+            .collect();
+        assert!(writes.len() > 2 && writes[0] > 10 && writes.last() < Some(&90));
+        //switch to the bootloader, two write steps, verify, end
+        let steps = server
+            .requests()
+            .iter()
+            .filter(|(action, _)| action == constants::TSM_ACTION_COS_UPGRADE)
+            .count();
+        assert_eq!(steps, 5);
+        assert_eq!(percents.last(), Some(&100));
+    }
+
+    #[test]
+    fn already_latest_test() {
+        let (server, tsm, reconnect) = setup(0);
+        server.set_latest_cos_version(SIMULATOR_COS_VERSION);
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |_: &UpgradeProgress| {});
+        block_on(upgrade.upgrade(device)).unwrap();
+        assert!(server
+            .requests()
+            .iter()
+            .all(|(action, _)| action != constants::TSM_ACTION_COS_UPGRADE));
+    }
+
+    #[test]
+    fn session_test() {
+        let (server, tsm, reconnect) = setup(0);
+        server.set_latest_cos_version(SIMULATOR_COS_VERSION);
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let held = Cell::new(false);
+        let watched = device.clone();
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |_: &UpgradeProgress| {
+            held.set(watched.try_session().is_err())
+        });
+
+        //no upgrade while another operation uses the device
+        let session = device.try_session().unwrap();
+        let result = block_on(upgrade.upgrade(device.clone()));
+        assert_eq!(result.err().unwrap().to_string(), "imkey_device_busy");
+        drop(session);
+
+        block_on(upgrade.upgrade(device.clone())).unwrap();
+        assert!(held.get());
+        assert!(device.try_session().is_ok());
+    }
+
+    #[test]
+    fn resume_test() {
+        //the connection is lost once the device runs the bootloader
+        let (_, tsm, reconnect) = setup(1);
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |_: &UpgradeProgress| {});
+        let result = block_on(upgrade.upgrade(device));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_device_reconnect_fail"
+        );
+        assert!(reconnect.simulator.is_bootloader());
+
+        //the device comes back in the bootloader, the upgrade starts over from the write
+        let device = block_on(reconnect.reconnect()).unwrap();
+        let device = block_on(upgrade.upgrade(device)).unwrap();
+        assert!(!reconnect.simulator.is_bootloader());
+        assert_eq!(
+            block_on(device_manager::get_cos_version(&device)).unwrap(),
+            "1.8.0"
+        );
+    }
+
+    #[test]
+    fn endless_upgrade_test() {
+        let (server, tsm, reconnect) = setup(0);
+        server.set_endless(constants::TSM_ACTION_COS_UPGRADE);
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |_: &UpgradeProgress| {});
+        let result = block_on(upgrade.upgrade(device));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_too_many_steps"
+        );
+        let steps = server
+            .requests()
+            .iter()
+            .filter(|(action, _)| action == constants::TSM_ACTION_COS_UPGRADE)
+            .count();
+        assert_eq!(steps, constants::TSM_MAX_COS_UPGRADE_STEPS);
+    }
+
+    #[test]
+    fn switch_fail_test() {
+        let (server, tsm, reconnect) = setup(0);
+        server.set_return_code(
+            constants::TSM_ACTION_COS_UPGRADE,
+            constants::TSM_RETURNCODE_SWITCH_BL_STATUS_FAIL,
+        );
+        let device = Rc::new(Device::new(reconnect.simulator.clone()));
+        let upgrade = CosUpgrade::new(&tsm, &reconnect, |_: &UpgradeProgress| {});
+        let result = block_on(upgrade.upgrade(device));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_switch_bl_status_fail"
+        );
+    }
+}
@@ -1,106 +1,179 @@
-use super::key_manager::KeyManager;
-use crate::error::{BindError, ImkeyError};
+use super::key_manager::{KeyFileFormat, KeyFileSecret, KeyManager};
+use crate::binding_session::BindingState;
+use crate::device_manager;
+use crate::error::BindError;
+use crate::key_store::KeyStore;
+#[cfg(feature = "simulator")]
+use crate::key_store::MemoryKeyStore;
+use crate::se_cert::SeCert;
 use crate::Result;
-use crate::{device_manager, TsmService};
+#[cfg(feature = "simulator")]
+use futures::executor::block_on;
 use ikc_common::aes::cbc::encrypt_pkcs7;
-use ikc_common::apdu::{Apdu, ApduCheck, ImkApdu};
+use ikc_common::apdu::{Apdu, Exchange, ImkApdu};
+#[cfg(feature = "simulator")]
+use ikc_common::constants::SECP256K1_ENGINE;
 use ikc_common::constants::{
     BIND_RESULT_ERROR, BIND_RESULT_SUCCESS, BIND_STATUS_BOUND_OTHER, BIND_STATUS_BOUND_THIS,
     BIND_STATUS_UNBOUND, IMK_AID, TIMEOUT_LONG,
 };
+use ikc_common::error::ApduError;
+use ikc_common::secure_channel::{SecureChannel, CHALLENGE_LENGTH};
 use ikc_common::utility::sha256_hash;
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
-use parking_lot::{Mutex, RwLock};
+#[cfg(feature = "simulator")]
+use ikc_simulator::simulator::{
+    Simulator, SIMULATOR_CA_ID, SIMULATOR_CA_PUBLIC_KEY, SIMULATOR_SEID,
+};
+use ikc_transport::device::Device;
+#[cfg(feature = "simulator")]
+use ikc_transport::trace::{ReplayTransport, TraceRecorder};
+use parking_lot::Mutex;
+#[cfg(feature = "simulator")]
+use parking_lot::MutexGuard;
 use rand::rngs::OsRng;
+use rand::RngCore;
 use regex::Regex;
-use rsa::{BigUint, PaddingScheme, PublicKey as RSAPublic, RsaPublicKey};
 use secp256k1::{ecdh, PublicKey, SecretKey};
 use sha1::Sha1;
 use std::collections::HashMap;
-use futures::executor::block_on;
-use web_sys::console;
+#[cfg(feature = "simulator")]
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 
 lazy_static! {
-    pub static ref KEY_MANAGER: Mutex<KeyManager> = Mutex::new(KeyManager::new());
-    static ref BIND_STATUS_MAP: HashMap<&'static str, &'static str> = {
-        let mut bind_status_mapping = HashMap::new();
-        bind_status_mapping.insert(BIND_STATUS_UNBOUND, "unbound");
-        bind_status_mapping.insert(BIND_STATUS_BOUND_THIS, "bound_this");
-        bind_status_mapping.insert(BIND_STATUS_BOUND_OTHER, "bound_other");
-        bind_status_mapping.insert(BIND_RESULT_SUCCESS, "success");
-        bind_status_mapping.insert(BIND_RESULT_ERROR, "authcode_error");
-        bind_status_mapping
-    };
-    pub static ref BIND_DATA: RwLock<String> = RwLock::new("".to_string());
+    //binding keys of every device known to the host, by SEID
+    static ref KEY_MANAGERS: Mutex<HashMap<String, Arc<Mutex<KeyManager>>>> =
+        Mutex::new(HashMap::new());
+}
+
+//binding keys of the device with the SEID, created empty for bind_check to load or generate them
+fn key_manager(seid: &str) -> Arc<Mutex<KeyManager>> {
+    KEY_MANAGERS
+        .lock()
+        .entry(seid.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(KeyManager::new())))
+        .clone()
+}
+
+/**
+binding keys of the device with the SEID, None until bind_check has run for it
+*/
+pub fn find_key_manager(seid: &str) -> Option<Arc<Mutex<KeyManager>>> {
+    KEY_MANAGERS.lock().get(seid).cloned()
+}
+
+/**
+binding keys of the device, known once bind_check has read its SEID and loaded or generated them.
+A device is never given the keys of another one.
+*/
+pub fn device_key_manager(device: &Device) -> Result<Arc<Mutex<KeyManager>>> {
+    let seid = device.seid().ok_or(BindError::ImkeyDeviceNotBound)?;
+    let key_manager = find_key_manager(&seid).ok_or(BindError::ImkeyDeviceNotBound)?;
+    if key_manager.lock().pri_key.is_empty() {
+        return Err(BindError::ImkeyDeviceNotBound.into());
+    }
+    Ok(key_manager)
+}
+
+#[cfg(feature = "simulator")]
+lazy_static! {
+    static ref SIMULATOR_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/**
+run one simulator test at a time, the simulator certificate authority is trusted by them
+*/
+#[cfg(feature = "simulator")]
+pub(crate) fn lock_simulator() -> MutexGuard<'static, ()> {
+    let lock = SIMULATOR_LOCK.lock();
+    crate::se_cert::add_root_key(
+        SIMULATOR_CA_ID.as_bytes(),
+        &hex::decode(SIMULATOR_CA_PUBLIC_KEY).unwrap(),
+    );
+    lock
 }
 
 pub struct DeviceManage {}
 
 impl DeviceManage {
-    pub async fn bind_check(file_path: &String) -> Result<String> {
-        
+    pub async fn bind_check(
+        device: &Device,
+        key_store: &dyn KeyStore,
+        secret: &KeyFileSecret,
+    ) -> Result<BindingState> {
+        secret.check()?;
         //get seid
-        let seid = device_manager::get_se_id().await?;
+        let seid = device_manager::get_se_id(device).await?;
         //get SN number
-        let sn = device_manager::get_sn().await?;
-        //Calculate encryption key
-        let mut key_manager_obj = KEY_MANAGER.lock();
-        key_manager_obj.gen_encrypt_key(&seid, &sn);
-
+        let sn = device_manager::get_sn(device).await?;
         //Get the ciphertext of the local key file
-        let ciphertext = KeyManager::get_key_file_data(file_path, &seid)?;
-        let mut key_flag = false;
-        if !ciphertext.is_empty() {
-            //Decrypt and parse the ciphertext
-            key_flag = !key_manager_obj.decrypt_keys(&ciphertext)?;
-        }
+        let ciphertext = KeyManager::get_key_file_data(key_store, &seid).await?;
 
-        //If the key file does not exist or is empty then regenerate
-        if ciphertext.is_empty() || key_flag {
-            key_manager_obj.gen_local_keys()?;
-            key_flag = true;
-        }
+        //Calculate encryption key, the keys are not locked while exchanging with the device
+        let key_manager = key_manager(&seid);
+        let (bind_check_apdu, key_flag, legacy) = {
+            let mut key_manager_obj = key_manager.lock();
+            key_manager_obj.gen_encrypt_key(&seid, &sn);
+            let mut key_flag = false;
+            let mut legacy = false;
+            if !ciphertext.is_empty() {
+                //Decrypt and parse the ciphertext
+                key_flag = !key_manager_obj.decrypt_keys(&ciphertext, secret)?;
+                legacy = KeyManager::key_file_format(&ciphertext) == KeyFileFormat::Legacy;
+            }
 
-        //gen bindchec apdu
-        let bind_check_apdu = ImkApdu::bind_check(&key_manager_obj.pub_key);
+            //If the key file does not exist or is empty then regenerate
+            if ciphertext.is_empty() || key_flag {
+                key_manager_obj.gen_local_keys()?;
+                key_flag = true;
+            }
+
+            //gen bindchec apdu
+            let bind_check_apdu = ImkApdu::bind_check(&key_manager_obj.pub_key)?;
+            (bind_check_apdu, key_flag, legacy)
+        };
         //send bindcheck command and get return data
-        select_imk_applet().await?;
-        let bind_check_apdu_resp_data = send_apdu(bind_check_apdu).await?;
-        ApduCheck::check_response(bind_check_apdu_resp_data.as_str())?;
-
-        let status = String::from(&bind_check_apdu_resp_data[..2]);
-        let se_pub_key_cert: String =
-            String::from(&bind_check_apdu_resp_data[2..(bind_check_apdu_resp_data.len() - 4)]);
-
-        if status.eq(BIND_STATUS_UNBOUND) || status.eq(BIND_STATUS_BOUND_OTHER) {
-            // //check se cert
-            // DeviceCertCheckRequest::build_request_data(seid.clone(), sn, se_pub_key_cert.clone())
-            //     .send_message()?;
-
-            //get se public key
-            key_manager_obj.se_pub_key = hex::decode(get_se_pubkey(se_pub_key_cert)?)?;
-
-            //calc the session key
-            let pk2 = PublicKey::from_slice(key_manager_obj.se_pub_key.as_slice())?;
-            let sk1 = SecretKey::from_slice(key_manager_obj.pri_key.as_slice())?;
-            let shared_secret = ecdh::shared_secret_point(&pk2, &sk1);
-            let sha1_result = Sha1::from(&shared_secret[..32]).digest().bytes();
-
-            //set the session key
-            key_manager_obj.session_key = sha1_result[..16].to_vec();
-
-            //Save the ciphertext to a local file
-            if key_flag {
-                let ciphertext = key_manager_obj.encrypt_data()?;
-                // KeyManager::save_keys_to_local_file(&ciphertext, file_path, &seid)?;
-                *BIND_DATA.write() = ciphertext;
+        select_imk_applet(device).await?;
+        let bind_check_response = device.exchange(&bind_check_apdu).await?;
+        bind_check_response.check()?;
+
+        //bind status (1) followed by the se public key certificate
+        let status = hex::encode_upper(bind_check_response.field(0, 1)?);
+
+        let key_file = {
+            let mut key_manager_obj = key_manager.lock();
+            if status.eq(BIND_STATUS_UNBOUND) || status.eq(BIND_STATUS_BOUND_OTHER) {
+                //authenticate the se public key before using it for ecdh
+                let se_cert = SeCert::parse(&bind_check_response.data[1..])?;
+                se_cert.verify(&hex::decode(&seid)?)?;
+                key_manager_obj.se_pub_key = se_cert.public_key;
+
+                //calc the session key
+                let pk2 = PublicKey::from_slice(key_manager_obj.se_pub_key.as_slice())?;
+                let sk1 = SecretKey::from_slice(key_manager_obj.pri_key.as_slice())?;
+                let shared_secret = ecdh::shared_secret_point(&pk2, &sk1);
+                let sha1_result = Sha1::from(&shared_secret[..32]).digest().bytes();
+
+                //set the session key
+                key_manager_obj.session_key = sha1_result[..16].to_vec();
             }
+
+            //new keys, or a legacy key file to rewrite in the v2 format
+            match key_flag || legacy {
+                true => Some(key_manager_obj.encrypt_data(secret)?),
+                false => None,
+            }
+        };
+
+        //Save the ciphertext to the key store
+        if let Some(ciphertext) = key_file {
+            KeyManager::save_key_file_data(key_store, &ciphertext, &seid).await?;
         }
-        Ok(BIND_STATUS_MAP.get(status.as_str()).unwrap().to_string())
+        binding_state(&status)
     }
 
-    pub async fn bind_acquire(binding_code: &String) -> Result<String> {
+    pub async fn bind_acquire(device: &Device, binding_code: &String) -> Result<BindingState> {
         let temp_binding_code = binding_code.to_uppercase();
         let binding_code_bytes = temp_binding_code.as_bytes();
         //check auth code
@@ -108,58 +181,102 @@ impl DeviceManage {
         if !bind_code_verify_regex.is_match(temp_binding_code.as_ref()) {
             return Err(BindError::ImkeySdkIllegalArgument.into());
         }
-        //encryption auth code
-        let auth_code_ciphertext = auth_code_encrypt(&temp_binding_code)?;
-        console::log_1(&format!("绑定码加密:{:?}", auth_code_ciphertext.clone()).into());
-        //save auth Code cipher
-        let seid = device_manager::get_se_id().await?;
-        // AuthCodeStorageRequest::build_request_data(seid, auth_code_ciphertext).send_message()?;
-
-        let key_manager_obj = KEY_MANAGER.lock();
+        let seid = device_manager::get_se_id(device).await?;
+
         //select IMK applet
-        select_imk_applet().await?;
-        //calc HASH
-        let mut data: Vec<u8> = vec![];
-        data.extend(binding_code_bytes);
-        data.extend(&key_manager_obj.pub_key);
-        data.extend(&key_manager_obj.se_pub_key);
-        let data_hash = sha256_hash(data.as_slice());
-
-        //encryption hash value by session key
-        let ciphertext = encrypt_pkcs7(
-            &data_hash.as_ref(),
-            &key_manager_obj.session_key,
-            &gen_iv(&temp_binding_code),
-        )?;
-        //gen identityVerify command
-        let mut apdu_data = vec![];
-        apdu_data.extend(&key_manager_obj.pub_key);
-        apdu_data.extend(ciphertext);
-        let identity_verify_apdu = ImkApdu::identity_verify(&apdu_data);
-        std::mem::drop(key_manager_obj);
+        select_imk_applet(device).await?;
+        let identity_verify_apdu = {
+            let key_manager = key_manager(&seid);
+            let key_manager_obj = key_manager.lock();
+            //calc HASH
+            let mut data: Vec<u8> = vec![];
+            data.extend(binding_code_bytes);
+            data.extend(&key_manager_obj.pub_key);
+            data.extend(&key_manager_obj.se_pub_key);
+            let data_hash = sha256_hash(data.as_slice());
+
+            //encryption hash value by session key
+            let ciphertext = encrypt_pkcs7(
+                &data_hash.as_ref(),
+                &key_manager_obj.session_key,
+                &gen_iv(&temp_binding_code),
+            )?;
+            //gen identityVerify command
+            let mut apdu_data = vec![];
+            apdu_data.extend(&key_manager_obj.pub_key);
+            apdu_data.extend(ciphertext);
+            ImkApdu::identity_verify(&apdu_data)?
+        };
         //send command to device
-        // let bind_result = send_apdu_timeout(identity_verify_apdu, TIMEOUT_LONG * 2)?;
-        let bind_result = send_apdu(identity_verify_apdu).await?;
-        ApduCheck::check_response(&bind_result)?;
-        let result_code = &bind_result[..bind_result.len() - 4];
+        let bind_result = device
+            .exchange_with_timeout(
+                &identity_verify_apdu,
+                Duration::from_secs(TIMEOUT_LONG as u64 * 2),
+            )
+            .await?
+            .into_data()?;
+        let result_code = hex::encode_upper(bind_result);
 
-        match result_code {
+        match result_code.as_str() {
             BIND_RESULT_ERROR => Err(BindError::ImkeyAuthcodeError.into()),
-            _ => Ok(BIND_STATUS_MAP.get(result_code).unwrap().to_string()),
+            result_code => binding_state(result_code),
+        }
+    }
+
+    /**
+    open a secure channel with the session key of the binding, the following commands of the
+    IMK and coin applets (addresses, signing) are encrypted and MACed until the channel is
+    closed, a response fails its check or another applet such as the ISD is selected
+    */
+    pub async fn open_secure_channel(device: &Device) -> Result<()> {
+        let session_key = device_key_manager(device)?.lock().session_key.clone();
+        if session_key.is_empty() {
+            return Err(BindError::ImkeyDeviceNotBound.into());
         }
+        device.set_secure_messaging(None);
+        select_imk_applet(device).await?;
+
+        let mut host_challenge = [0u8; CHALLENGE_LENGTH];
+        OsRng.fill_bytes(&mut host_challenge);
+        let response = device
+            .exchange(&ImkApdu::open_secure_channel(&host_challenge))
+            .await?
+            .into_data()?;
+        let secure_channel = SecureChannel::open(&session_key, &host_challenge, &response)?;
+        device.set_secure_messaging(Some(Box::new(secure_channel)));
+        Ok(())
+    }
+
+    pub fn close_secure_channel(device: &Device) {
+        device.set_secure_messaging(None);
     }
 
-    pub async fn display_bind_code() -> Result<()> {
-        select_imk_applet().await?;
-        let gen_auth_code_ret_data = send_apdu(ImkApdu::generate_auth_code()).await?;
-        ApduCheck::check_response(&gen_auth_code_ret_data)
+    pub async fn display_bind_code(device: &Device) -> Result<()> {
+        select_imk_applet(device).await?;
+        device
+            .exchange(&ImkApdu::generate_auth_code())
+            .await?
+            .check()
     }
 }
 
-// #[cfg(target_arch = "wasm32")]
-async fn select_imk_applet() -> Result<()> {
-    let apdu_response = send_apdu(Apdu::select_applet(IMK_AID)).await?;
-    ApduCheck::check_response(apdu_response.as_str())
+/**
+state of the binding for a status of bind check or a result of identity verify
+*/
+fn binding_state(status: &str) -> Result<BindingState> {
+    match status {
+        BIND_STATUS_UNBOUND => Ok(BindingState::Unbound),
+        BIND_STATUS_BOUND_OTHER => Ok(BindingState::BoundOther),
+        BIND_STATUS_BOUND_THIS | BIND_RESULT_SUCCESS => Ok(BindingState::Bound),
+        _ => Err(ApduError::ImkeyInvalidResponse.into()),
+    }
+}
+
+async fn select_imk_applet(device: &Device) -> Result<()> {
+    device
+        .exchange(&Apdu::select_applet(IMK_AID))
+        .await?
+        .check()
 }
 
 /**
@@ -178,38 +295,310 @@ fn gen_iv(auth_code: &String) -> [u8; 16] {
 }
 
 /**
-encrypt auth code
+Simulated device bound to the host. Simulators share one SEID, so one binding key context:
+only one of them lives at a time.
 */
-fn auth_code_encrypt(auth_code: &String) -> Result<String> {
-    let n = hex::decode("C6627A6F0485B33DDC1CA7E062C64E8841133B9246A41F40D0767BAE44EAB2EF453D008FFB07B8D9FDFCD21882487ECC4DA933C97E494242ADA3CE02C5A05189AA49410E771A66E8100E43CB1AF6CC610B59EE4EBB236FF38C62AD7B1D11DFBD4E054D19E3349391A31F5E89CA721292B7380295745D8968CC5C2D223AC6750BB0ACA27773687E9CD76065E47F42F4AE005459BCE5746BD760646A5BD119BA3469A935F48EB898CBAB72CB394C3FEC9E41635EAE954107A17AC7B8C6321D8F1755AD3915A9D2398DB268A3F642CEE9CBE9F82ECD5AD64EBEDDDE66601DC2B891E2FEDDF72DAF627FA8FA16F7C640DB661BE15DCB4274D9576D98DBEB20C25309");
-    let e = hex::decode("010001");
-    let u32_vec_n = BigUint::from_bytes_be(&n.unwrap());
-    let u32_vec_e = BigUint::from_bytes_be(&e.unwrap());
-    let rsa_pub_key = RsaPublicKey::new(u32_vec_n, u32_vec_e)?;
-    let mut rng = OsRng;
-    // let mut rng = OsRng;
-    let enc_data = rsa_pub_key.encrypt(
-        &mut rng,
-        PaddingScheme::PKCS1v15Encrypt,
-        auth_code.as_bytes(),
-    )?;
-    Ok(hex::encode_upper(enc_data))
-}
-
-fn get_se_pubkey(se_pubkey_cert: String) -> Result<String> {
-    let index;
-    if se_pubkey_cert.contains("7F4947B041") {
-        index = se_pubkey_cert
-            .find("7F4947B041")
-            .expect("parsing_se_cert_error");
-    } else if se_pubkey_cert.contains("7F4946B041") {
-        index = se_pubkey_cert
-            .find("7F4946B041")
-            .expect("parsing_se_cert_error");
-    } else {
-        return Err(ImkeyError::ImkeySeCertInvalid.into());
-    }
-
-    Ok(se_pubkey_cert[index + 10..index + 130 + 10].to_string())
+#[cfg(feature = "simulator")]
+pub struct TestDevice {
+    device: Device,
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(feature = "simulator")]
+impl Deref for TestDevice {
+    type Target = Device;
+
+    fn deref(&self) -> &Device {
+        &self.device
+    }
 }
 
+/**
+host secret of the key files written by the tests
+*/
+#[cfg(feature = "simulator")]
+pub(crate) fn test_secret() -> KeyFileSecret {
+    KeyFileSecret::HostSecret(vec![0x5A; 32])
+}
+
+/**
+bind a fresh simulator with the code it displays, used by tests needing a bound device
+*/
+#[cfg(feature = "simulator")]
+pub fn bind_test() -> TestDevice {
+    let lock = lock_simulator();
+    let simulator = Simulator::new();
+    let device = Device::new(simulator.clone());
+    let bind_result = block_on(DeviceManage::bind_check(
+        &device,
+        &MemoryKeyStore::new(),
+        &test_secret(),
+    ))
+    .unwrap();
+    if bind_result != BindingState::Bound {
+        block_on(DeviceManage::display_bind_code(&device)).unwrap();
+        let bind_code = simulator.bind_code().expect("bind code not displayed");
+        let bind_result = block_on(DeviceManage::bind_acquire(&device, &bind_code)).unwrap();
+        assert_eq!(bind_result, BindingState::Bound);
+    }
+    TestDevice {
+        device,
+        _lock: lock,
+    }
+}
+
+//host key restored before recording or replaying a trace, so that the signed preview data
+//sent to the device is the same every time
+#[cfg(feature = "simulator")]
+const TRACE_HOST_PRIVATE_KEY: &str =
+    "5d7c6c4b5b8f3b8e9ef36f6ec3c1f1b0a8d2a0b5c69c9a1ce4a1b3e2f0c3d4a1";
+
+#[cfg(feature = "simulator")]
+fn set_trace_host_key(device: &Device) -> Vec<u8> {
+    let secret_key = SecretKey::from_slice(&hex::decode(TRACE_HOST_PRIVATE_KEY).unwrap()).unwrap();
+    let public_key = PublicKey::from_secret_key(&SECP256K1_ENGINE, &secret_key);
+    device.set_seid(SIMULATOR_SEID);
+    let key_manager = key_manager(SIMULATOR_SEID);
+    let mut key_manager_obj = key_manager.lock();
+    key_manager_obj.pri_key = secret_key.secret_bytes().to_vec();
+    key_manager_obj.pub_key = public_key.serialize_uncompressed().to_vec();
+    key_manager_obj.pub_key.clone()
+}
+
+/**
+simulator already bound to the trace host key, its exchanges are captured by the returned recorder
+*/
+#[cfg(feature = "simulator")]
+pub fn record_test() -> (TestDevice, TraceRecorder) {
+    let lock = lock_simulator();
+    let simulator = Simulator::new();
+    let device = Device::new(simulator.clone());
+    simulator.bind(&set_trace_host_key(&device)).unwrap();
+    let recorder = TraceRecorder::new();
+    device.set_recorder(Some(recorder.clone()));
+    let device = TestDevice {
+        device,
+        _lock: lock,
+    };
+    (device, recorder)
+}
+
+/**
+device answering with a trace recorded by record_test or on a real device bound to the trace host key
+*/
+#[cfg(feature = "simulator")]
+pub fn replay_test(trace: &str) -> TestDevice {
+    let lock = lock_simulator();
+    let device = Device::new(ReplayTransport::from_json(trace).unwrap());
+    set_trace_host_key(&device);
+    TestDevice {
+        device,
+        _lock: lock,
+    }
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::binding_session::BindingState;
+    use crate::device_binding::{
+        bind_test, device_key_manager, find_key_manager, lock_simulator, test_secret, DeviceManage,
+    };
+    use crate::device_manager;
+    use crate::key_manager::{KeyFileFormat, KeyFileSecret, KeyManager};
+    use crate::key_store::{KeyStore, MemoryKeyStore};
+    use crate::ServiceResponse;
+    use futures::executor::block_on;
+    use ikc_common::apdu::{Exchange, ImkApdu};
+    use ikc_simulator::simulator::{Simulator, SIMULATOR_SEID, SIMULATOR_SN};
+    use ikc_transport::device::Device;
+
+    #[test]
+    fn bind_acquire_wrong_code_test() {
+        let device = bind_test();
+        block_on(DeviceManage::display_bind_code(&device)).unwrap();
+        let bind_result = block_on(DeviceManage::bind_acquire(&device, &"22222222".to_string()));
+        assert_eq!(
+            bind_result.err().unwrap().to_string(),
+            "imkey_authcode_error"
+        );
+    }
+
+    //binds a fresh simulator, its keys saved in the key store
+    fn bind_with(key_store: &MemoryKeyStore, secret: &KeyFileSecret) -> (Simulator, Device) {
+        let simulator = Simulator::new();
+        let device = Device::new(simulator.clone());
+        let bind_result = block_on(DeviceManage::bind_check(&device, key_store, secret)).unwrap();
+        assert_eq!(bind_result, BindingState::Unbound);
+        block_on(DeviceManage::display_bind_code(&device)).unwrap();
+        let bind_code = simulator.bind_code().unwrap();
+        let bind_result = block_on(DeviceManage::bind_acquire(&device, &bind_code)).unwrap();
+        assert_eq!(bind_result, BindingState::Bound);
+        (simulator, device)
+    }
+
+    #[test]
+    fn bind_persist_test() {
+        let _lock = lock_simulator();
+        let key_store = MemoryKeyStore::new();
+        let secret = KeyFileSecret::Password("imkey".to_string());
+        let (_, device) = bind_with(&key_store, &secret);
+        let key_file = block_on(KeyManager::get_key_file_data(&key_store, SIMULATOR_SEID)).unwrap();
+        assert_eq!(KeyManager::key_file_format(&key_file), KeyFileFormat::V2);
+
+        //the keys saved by bind_check are found again, after a reload of the page say
+        let bind_result = block_on(DeviceManage::bind_check(&device, &key_store, &secret)).unwrap();
+        assert_eq!(bind_result, BindingState::Bound);
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        assert!(device.is_secure());
+        DeviceManage::close_secure_channel(&device);
+
+        //the key file is not overwritten when the password is wrong
+        let wrong = KeyFileSecret::Password("wrong".to_string());
+        let result = block_on(DeviceManage::bind_check(&device, &key_store, &wrong));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_keyfile_decrypt_fail"
+        );
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(&key_store, SIMULATOR_SEID)).unwrap(),
+            key_file
+        );
+
+        //nor without a secret
+        let empty = KeyFileSecret::HostSecret(vec![]);
+        let result = block_on(DeviceManage::bind_check(&device, &key_store, &empty));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_keyfile_secret_missing"
+        );
+    }
+
+    #[test]
+    fn key_file_migration_test() {
+        let _lock = lock_simulator();
+        let key_store = MemoryKeyStore::new();
+        let secret = test_secret();
+        let (_, device) = bind_with(&key_store, &secret);
+
+        //key file left by ios before 2.0.0
+        let legacy = device_key_manager(&device)
+            .unwrap()
+            .lock()
+            .encrypt_legacy_data()
+            .unwrap();
+        block_on(key_store.remove(&format!("keys{}", &SIMULATOR_SEID[24..]))).unwrap();
+        block_on(key_store.save(&format!("keys{}", SIMULATOR_SEID), &legacy)).unwrap();
+
+        let bind_result = block_on(DeviceManage::bind_check(&device, &key_store, &secret)).unwrap();
+        assert_eq!(bind_result, BindingState::Bound);
+        let key_file = block_on(KeyManager::get_key_file_data(&key_store, SIMULATOR_SEID)).unwrap();
+        assert_eq!(KeyManager::key_file_format(&key_file), KeyFileFormat::V2);
+        assert_eq!(
+            block_on(key_store.load(&format!("keys{}", SIMULATOR_SEID))).unwrap(),
+            None
+        );
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+    }
+
+    #[test]
+    fn secure_channel_test() {
+        let device = bind_test();
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        assert!(device.is_secure());
+        let pub_key = device_key_manager(&device).unwrap().lock().pub_key.clone();
+        let response = block_on(device.exchange(&ImkApdu::bind_check(&pub_key).unwrap()));
+        assert_eq!(response.unwrap().data[0], 0x55);
+        assert!(device.is_secure());
+
+        //selecting the ISD ends the channel, its commands are sent in clear
+        assert_eq!(
+            block_on(device_manager::get_se_id(&device)).unwrap(),
+            SIMULATOR_SEID
+        );
+        assert!(!device.is_secure());
+        assert_eq!(
+            block_on(device_manager::get_sn(&device)).unwrap(),
+            SIMULATOR_SN
+        );
+
+        //TSM scripts end it as well
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        let script = vec!["00A4040000".to_string(), "80CB800005DFFF028101".to_string()];
+        let (responses, status_word) =
+            block_on(ServiceResponse::<()>::apdu_handle(&device, script)).unwrap();
+        assert_eq!(responses[1], format!("{}9000", SIMULATOR_SEID));
+        assert_eq!(status_word, "9000");
+        assert!(!device.is_secure());
+
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        DeviceManage::close_secure_channel(&device);
+        assert!(!device.is_secure());
+        assert_eq!(
+            block_on(device_manager::get_se_id(&device)).unwrap(),
+            SIMULATOR_SEID
+        );
+    }
+
+    #[test]
+    fn secure_channel_unbound_test() {
+        let _device = bind_test();
+        //the host knows no keys for a device it has not read the SEID of
+        let device = Device::new(Simulator::new());
+        let result = block_on(DeviceManage::open_secure_channel(&device));
+        assert_eq!(result.err().unwrap().to_string(), "imkey_device_not_bound");
+
+        //same SEID, so the keys of the other simulator, which this one is not bound to
+        block_on(device_manager::get_se_id(&device)).unwrap();
+        let result = block_on(DeviceManage::open_secure_channel(&device));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_conditions_not_satisfied"
+        );
+        assert!(!device.is_secure());
+
+        //a known SEID whose keys were never loaded or generated
+        let device = Device::new(Simulator::with_seid("19060000000200860001010000000098"));
+        let seid = block_on(device_manager::get_se_id(&device)).unwrap();
+        assert!(find_key_manager(&seid).is_none());
+        let result = device_key_manager(&device);
+        assert_eq!(result.err().unwrap().to_string(), "imkey_device_not_bound");
+        let result = block_on(DeviceManage::open_secure_channel(&device));
+        assert_eq!(result.err().unwrap().to_string(), "imkey_device_not_bound");
+        //looking the keys up does not create them
+        assert!(find_key_manager(&seid).is_none());
+    }
+
+    #[test]
+    fn multiple_devices_test() {
+        let _lock = lock_simulator();
+        let key_store = MemoryKeyStore::new();
+        let secret = test_secret();
+        let (_, device_a) = bind_with(&key_store, &secret);
+        let simulator_b = Simulator::with_seid("19060000000200860001010000000099");
+        let device_b = Device::new(simulator_b.clone());
+        let bind_result = block_on(DeviceManage::bind_check(&device_b, &key_store, &secret));
+        assert_eq!(bind_result.unwrap(), BindingState::Unbound);
+        block_on(DeviceManage::display_bind_code(&device_b)).unwrap();
+        let bind_code = simulator_b.bind_code().unwrap();
+        block_on(DeviceManage::bind_acquire(&device_b, &bind_code)).unwrap();
+
+        //each device has its own host key, neither binding replaced the other
+        let key_a = device_key_manager(&device_a)
+            .unwrap()
+            .lock()
+            .pri_key
+            .clone();
+        let key_b = device_key_manager(&device_b)
+            .unwrap()
+            .lock()
+            .pri_key
+            .clone();
+        assert_ne!(key_a, key_b);
+        for device in [&device_a, &device_b] {
+            let bind_result = block_on(DeviceManage::bind_check(device, &key_store, &secret));
+            assert_eq!(bind_result.unwrap(), BindingState::Bound);
+            block_on(DeviceManage::open_secure_channel(device)).unwrap();
+            assert!(device.is_secure());
+        }
+    }
+}
@@ -1,48 +1,508 @@
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
-use ikc_common::apdu::{Apdu, ApduCheck};
-use crate::Result;
+use crate::binding_session::BindingState;
 use crate::device_binding::DeviceManage;
-use futures::executor::block_on;
+use crate::error::{BindError, ImkeyError};
+use crate::key_manager::KeyFileSecret;
+use crate::key_store::KeyStore;
+use crate::tsm::{SeActivateRequest, SeSecureCheckRequest, TsmClient};
+use crate::Result;
+use ikc_common::apdu::{Apdu, Command, Exchange, Response};
+use ikc_common::constants::{
+    self, BCH_AID, BL_AID, BTC_AID, COSMOS_AID, EOS_AID, ETH_AID, FILECOIN_AID, IMK_AID,
+    KUSAMA_AID, LTC_AID, NERVOS_AID, POLKADOT_AID, TEZOS_AID, TRON_AID,
+};
+use ikc_common::error::ApduError;
+use ikc_common::status_word::StatusWord;
+use ikc_common::tlv::Tlv;
+use ikc_transport::device::Device;
+use regex::Regex;
+use serde::Serialize;
+
+//GET STATUS answers 6310 while more load files are left to read with P2 = 03
+const GET_STATUS_MORE_DATA: u16 = 0x6310;
+
+const APPLET_NAMES: &[(&str, &str)] = &[
+    (IMK_AID, "IMK"),
+    (BTC_AID, "BTC"),
+    (ETH_AID, "ETH"),
+    (EOS_AID, "EOS"),
+    (COSMOS_AID, "COSMOS"),
+    (FILECOIN_AID, "FILECOIN"),
+    (POLKADOT_AID, "POLKADOT"),
+    (KUSAMA_AID, "KUSAMA"),
+    (TRON_AID, "TRON"),
+    (NERVOS_AID, "NERVOS"),
+    (TEZOS_AID, "TEZOS"),
+    (BCH_AID, "BCH"),
+    (LTC_AID, "LTC"),
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppletInfo {
+    pub name: String,
+    pub aid: String,
+    pub version: String,
+}
+
+/**
+Everything shown about a device by the support dashboard. Battery and bluetooth fields are
+None on devices without them.
+*/
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub se_id: String,
+    pub sn: String,
+    pub cos_version: String,
+    pub se_cert: String,
+    pub ram_size: u32,
+    pub battery_level: Option<u8>,
+    pub ble_name: Option<String>,
+    pub applets: Vec<AppletInfo>,
+}
+
+/**
+state of the device for the TSM server, from the SE secure check
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    Latest,
+    Inactivated,
+    StopUsing,
+    Illegal,
+}
 
-pub async fn select_isd() -> Result<String> {
-    let res = send_apdu("00A4040000".to_string()).await?;
-    ApduCheck::check_response(res.as_str())?;
+impl DeviceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceStatus::Latest => constants::IMKEY_DEV_STATUS_LATEST,
+            DeviceStatus::Inactivated => constants::IMKEY_DEV_STATUS_INACTIVATED,
+            DeviceStatus::StopUsing => constants::IMKEY_DEV_STATUS_STOP_USING,
+            DeviceStatus::Illegal => constants::IMKEY_DEV_STATUS_ILLEGAL,
+        }
+    }
+}
+
+pub async fn select_isd(device: &Device) -> Result<Response> {
+    let res = device
+        .exchange(&Command::new(0x00, 0xA4, 0x04, 0x00, &[], Some(0x00)))
+        .await?;
+    res.check()?;
     Ok(res)
 }
 
-pub async fn get_se_id() -> Result<String> {
-    select_isd().await?;
-    let res = send_apdu("80CB800005DFFF028101".to_string()).await?;
-    ApduCheck::check_response(res.as_str())?;
-    Ok(String::from(&res[0..res.len() - 4]))
+/**
+select the ISD, or the bootloader of a device left in bootloader mode by an interrupted
+COS upgrade, true when the bootloader answered
+*/
+pub async fn select_isd_or_bootloader(device: &Device) -> Result<bool> {
+    let res = device
+        .exchange(&Command::new(0x00, 0xA4, 0x04, 0x00, &[], Some(0x00)))
+        .await?;
+    match res.status() {
+        StatusWord::Success => return Ok(false),
+        StatusWord::AppletNotExist => {}
+        status => return Err(status.into()),
+    }
+    let res = device
+        .exchange(&Command::new(
+            0x00,
+            0xA4,
+            0x04,
+            0x00,
+            &hex::decode(BL_AID)?,
+            Some(0x00),
+        ))
+        .await?;
+    res.check()?;
+    Ok(true)
 }
 
-pub async fn get_sn() -> Result<String> {
-    select_isd().await?;
-    let res = send_apdu("80CA004400".to_string()).await?;
-    ApduCheck::check_response(res.as_str())?;
-    let hex_decode = hex::decode(String::from(&res[0..res.len() - 4]));
-    match hex_decode {
-        Ok(sn) => Ok(String::from_utf8(sn).unwrap()),
-        Err(error) => Err(error.into()),
+/**
+GET DATA of an ISD device property (tag DFFF), the bootloader also tells the SEID
+*/
+async fn get_device_data(device: &Device, property: &[u8]) -> Result<Vec<u8>> {
+    select_isd_or_bootloader(device).await?;
+    let data = Tlv::new(&[0xDF, 0xFF], property).encode();
+    let res = device
+        .exchange(&Command::new(0x80, 0xCB, 0x80, 0x00, &data, None))
+        .await?;
+    res.into_data()
+}
+
+pub async fn get_se_id(device: &Device) -> Result<String> {
+    let seid = hex::encode_upper(get_device_data(device, &[0x81, 0x01]).await?);
+    device.set_seid(&seid);
+    Ok(seid)
+}
+
+pub async fn get_sn(device: &Device) -> Result<String> {
+    select_isd_or_bootloader(device).await?;
+    let res = device
+        .exchange(&Command::new(0x80, 0xCA, 0x00, 0x44, &[], Some(0x00)))
+        .await?;
+    Ok(String::from_utf8(res.into_data()?)?)
+}
+
+pub async fn get_cos_version(device: &Device) -> Result<String> {
+    Ok(format_version(
+        &get_device_data(device, &[0x80, 0x03]).await?,
+    ))
+}
+
+/**
+free RAM of the secure element in bytes
+*/
+pub async fn get_ram_size(device: &Device) -> Result<u32> {
+    let data = get_device_data(device, &[0x81, 0x46]).await?;
+    if data.is_empty() || data.len() > 4 {
+        return Err(ApduError::ImkeyInvalidResponse.into());
+    }
+    Ok(data.iter().fold(0, |size, byte| size << 8 | *byte as u32))
+}
+
+/**
+SE certificate (7F21) from the certificate store (BF21) of the ISD
+*/
+pub async fn get_cert(device: &Device) -> Result<String> {
+    select_isd(device).await?;
+    let key_reference = Tlv::constructed(&[0xA6], &[Tlv::new(&[0x83], &[0x15, 0x18])]);
+    let res = device
+        .exchange(&Command::new(
+            0x80,
+            0xCA,
+            0xBF,
+            0x21,
+            &key_reference.encode(),
+            Some(0x00),
+        ))
+        .await?;
+    let (store, _) = Tlv::decode(&res.into_data()?)?;
+    match store.find(&[0x7F, 0x21])? {
+        Some(cert) => Ok(hex::encode_upper(cert.encode())),
+        None => Err(ApduError::ImkeyInvalidResponse.into()),
+    }
+}
+
+/**
+battery level in percent
+*/
+pub async fn get_battery_level(device: &Device) -> Result<u8> {
+    let res = device
+        .exchange(&Command::new(0x00, 0xD6, 0xFE, 0xED, &[], Some(0x01)))
+        .await?;
+    let level = res.into_data()?;
+    level
+        .first()
+        .copied()
+        .ok_or_else(|| ApduError::ImkeyInvalidResponse.into())
+}
+
+pub async fn get_ble_name(device: &Device) -> Result<String> {
+    let res = device
+        .exchange(&Command::new(0xFF, 0xDB, 0x46, 0x54, &[], Some(0x00)))
+        .await?;
+    Ok(String::from_utf8(res.into_data()?)?)
+}
+
+/**
+rename the device as advertised over bluetooth, 1 to 12 letters, digits or dashes
+*/
+pub async fn set_ble_name(device: &Device, ble_name: &str) -> Result<()> {
+    let ble_name_regex = Regex::new(r"^[a-zA-Z0-9\-]{1,12}$").unwrap();
+    if !ble_name_regex.is_match(ble_name) {
+        return Err(BindError::ImkeySdkIllegalArgument.into());
     }
+    device
+        .exchange(&Apdu::set_ble_name(ble_name))
+        .await?
+        .check()
 }
 
-// pub fn get_cert() -> Result<String> {
-//     select_isd()?;
-//     let res = block_on(send_apdu("80CABF2106A6048302151800".to_string()))?;
-//     ApduCheck::check_response(&res)?;
-//     Ok(res.chars().take(res.len() - 4).collect())
-// }
+/**
+installed applets with their load file version, read with GP GET STATUS
+*/
+pub async fn get_applets(device: &Device) -> Result<Vec<AppletInfo>> {
+    select_isd(device).await?;
+    let mut applets = vec![];
+    let mut p2 = 0x02;
+    loop {
+        let res = device
+            .exchange(&Command::new(
+                0x80,
+                0xF2,
+                0x20,
+                p2,
+                &[0x4F, 0x00],
+                Some(0x00),
+            ))
+            .await?;
+        if res.sw != GET_STATUS_MORE_DATA {
+            res.check()?;
+        }
+        for load_file in Tlv::decode_all(&res.data)? {
+            let aid = match load_file.find(&[0x4F])? {
+                Some(aid) => hex::encode_upper(aid.value),
+                None => return Err(ApduError::ImkeyInvalidResponse.into()),
+            };
+            let version = load_file
+                .find(&[0xCE])?
+                .map(|version| format_version(&version.value))
+                .unwrap_or_default();
+            let name = APPLET_NAMES
+                .iter()
+                .find(|(applet_aid, _)| *applet_aid == aid)
+                .map_or(aid.clone(), |(_, name)| name.to_string());
+            applets.push(AppletInfo { name, aid, version });
+        }
+        if res.sw != GET_STATUS_MORE_DATA {
+            return Ok(applets);
+        }
+        p2 = 0x03;
+    }
+}
 
-pub async fn bind_check(file_path: &str) -> Result<String> {
-    DeviceManage::bind_check(&file_path.to_string()).await
+pub async fn get_device_info(device: &Device) -> Result<DeviceInfo> {
+    Ok(DeviceInfo {
+        se_id: get_se_id(device).await?,
+        sn: get_sn(device).await?,
+        cos_version: get_cos_version(device).await?,
+        se_cert: get_cert(device).await?,
+        ram_size: get_ram_size(device).await?,
+        battery_level: unsupported_as_none(get_battery_level(device).await)?,
+        ble_name: unsupported_as_none(get_ble_name(device).await)?,
+        applets: get_applets(device).await?,
+    })
 }
-pub async fn bind_display_code() -> Result<()> {
-    DeviceManage::display_bind_code().await
+
+/**
+run the SE secure check with the TSM server: a genuine device is either activated (latest)
+or waits for activate_device, the server may also have stopped or blacklisted it
+*/
+pub async fn check_device(device: &Device, tsm: &TsmClient) -> Result<DeviceStatus> {
+    let seid = get_se_id(device).await?;
+    let sn = get_sn(device).await?;
+    let device_cert = get_cert(device).await?;
+    let mut request = SeSecureCheckRequest::new(&seid, &sn, &device_cert);
+    let error = match tsm.run_script(device, &mut request).await {
+        Ok(()) => return Ok(DeviceStatus::Latest),
+        Err(error) => error,
+    };
+    match error.downcast_ref::<ImkeyError>() {
+        Some(ImkeyError::ImkeyTsmDeviceNotActivated) => Ok(DeviceStatus::Inactivated),
+        Some(ImkeyError::ImkeyTsmDeviceStopUsing) => Ok(DeviceStatus::StopUsing),
+        Some(ImkeyError::ImkeyTsmDeviceIllegal) => Ok(DeviceStatus::Illegal),
+        _ => Err(error),
+    }
 }
-pub async fn bind_acquire(bind_code: &str) -> Result<String> {
-    DeviceManage::bind_acquire(&bind_code.to_string()).await
+
+/**
+activate a new device with the TSM server
+*/
+pub async fn activate_device(device: &Device, tsm: &TsmClient) -> Result<()> {
+    let seid = get_se_id(device).await?;
+    let sn = get_sn(device).await?;
+    let device_cert = get_cert(device).await?;
+    let mut request = SeActivateRequest::new(&seid, &sn, &device_cert);
+    tsm.run_script(device, &mut request).await
 }
 
+/**
+None when the device does not know the command, like the bluetooth ones on USB only models
+*/
+fn unsupported_as_none<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(error) => match error.downcast_ref::<StatusWord>() {
+            Some(StatusWord::InsNotSupported) | Some(StatusWord::ClaNotSupported) => Ok(None),
+            _ => Err(error),
+        },
+    }
+}
+
+/**
+one number per byte: 01 07 02 is 1.7.2
+*/
+fn format_version(version: &[u8]) -> String {
+    version
+        .iter()
+        .map(|number| number.to_string())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+pub async fn bind_check(
+    device: &Device,
+    key_store: &dyn KeyStore,
+    secret: &KeyFileSecret,
+) -> Result<BindingState> {
+    DeviceManage::bind_check(device, key_store, secret).await
+}
+pub async fn bind_display_code(device: &Device) -> Result<()> {
+    DeviceManage::display_bind_code(device).await
+}
+pub async fn bind_acquire(device: &Device, bind_code: &str) -> Result<BindingState> {
+    DeviceManage::bind_acquire(device, &bind_code.to_string()).await
+}
+pub async fn open_secure_channel(device: &Device) -> Result<()> {
+    DeviceManage::open_secure_channel(device).await
+}
+pub fn close_secure_channel(device: &Device) {
+    DeviceManage::close_secure_channel(device)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::device_manager::{get_battery_level, get_se_id, get_sn, unsupported_as_none};
+    use futures::executor::block_on;
+    use ikc_transport::device::Device;
+    use ikc_transport::mock::MockTransport;
+
+    fn mock_device() -> Device {
+        Device::new(MockTransport::new(|apdu: &[u8]| {
+            match hex::encode_upper(apdu).as_str() {
+                "00A4040000" => Ok(hex::decode("9000")?),
+                "80CB800005DFFF028101" => Ok(hex::decode("190600000002008600010100000000149000")?),
+                "80CA004400" => Ok(hex::decode("696D4B657930313139313230303030319000")?),
+                _ => Ok(hex::decode("6D00")?),
+            }
+        }))
+    }
+
+    #[test]
+    fn get_se_id_test() {
+        let device = mock_device();
+        assert_eq!(
+            block_on(get_se_id(&device)).unwrap(),
+            "19060000000200860001010000000014"
+        );
+    }
+
+    #[test]
+    fn get_sn_test() {
+        let device = mock_device();
+        assert_eq!(block_on(get_sn(&device)).unwrap(), "imKey01191200001");
+    }
+
+    #[test]
+    fn unsupported_as_none_test() {
+        let device = mock_device();
+        let battery_level = unsupported_as_none(block_on(get_battery_level(&device)));
+        assert_eq!(battery_level.unwrap(), None);
+        let se_id = unsupported_as_none(block_on(get_se_id(&device)));
+        assert!(se_id.unwrap().is_some());
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn get_device_info_test() {
+        use crate::device_binding::lock_simulator;
+        use crate::device_manager::get_device_info;
+        use crate::se_cert::SeCert;
+        use ikc_simulator::simulator::{
+            Simulator, SIMULATOR_BLE_NAME, SIMULATOR_COS_VERSION, SIMULATOR_SEID, SIMULATOR_SN,
+        };
+
+        //trusts the simulator certificate authority
+        let _lock = lock_simulator();
+        let device = Device::new(Simulator::new());
+        let device_info = block_on(get_device_info(&device)).unwrap();
+        assert_eq!(device_info.se_id, SIMULATOR_SEID);
+        assert_eq!(device_info.sn, SIMULATOR_SN);
+        assert_eq!(device_info.cos_version, SIMULATOR_COS_VERSION);
+        assert_eq!(device_info.ram_size, 0x1800);
+        assert_eq!(device_info.battery_level, Some(80));
+        assert_eq!(device_info.ble_name.as_deref(), Some(SIMULATOR_BLE_NAME));
+        let cert = SeCert::parse(&hex::decode(&device_info.se_cert).unwrap()).unwrap();
+        cert.verify(&hex::decode(SIMULATOR_SEID).unwrap()).unwrap();
+
+        let applets: Vec<(&str, &str)> = device_info
+            .applets
+            .iter()
+            .map(|applet| (applet.name.as_str(), applet.version.as_str()))
+            .collect();
+        assert_eq!(applets, vec![("IMK", "1.0"), ("BTC", "1.5")]);
+
+        let json = serde_json::to_value(&device_info).unwrap();
+        assert_eq!(json["seId"], SIMULATOR_SEID);
+        assert_eq!(json["applets"][1]["aid"], "695F627463");
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn check_device_test() {
+        use crate::device_manager::{activate_device, check_device, DeviceStatus};
+        use crate::tsm::TsmClient;
+        use ikc_common::constants;
+        use ikc_simulator::simulator::Simulator;
+        use ikc_simulator::tsm::MockTsm;
+        use std::rc::Rc;
+
+        let server = Rc::new(MockTsm::new());
+        let tsm = TsmClient::with_endpoint("https://tsm.test/imkey", server.clone());
+        let device = Device::new(Simulator::new());
+
+        server.set_activated(false);
+        let status = block_on(check_device(&device, &tsm)).unwrap();
+        assert_eq!(status, DeviceStatus::Inactivated);
+        assert_eq!(status.as_str(), "inactivated");
+
+        block_on(activate_device(&device, &tsm)).unwrap();
+        let status = block_on(check_device(&device, &tsm)).unwrap();
+        assert_eq!(status.as_str(), constants::IMKEY_DEV_STATUS_LATEST);
+
+        server.set_return_code(
+            constants::TSM_ACTION_SE_SECURE_CHECK,
+            constants::TSM_RETURNCODE_DEVICE_STOP_USING,
+        );
+        let status = block_on(check_device(&device, &tsm)).unwrap();
+        assert_eq!(status, DeviceStatus::StopUsing);
+        server.set_return_code(
+            constants::TSM_ACTION_SE_SECURE_CHECK,
+            constants::TSM_RETURNCODE_SEID_ILLEGAL,
+        );
+        let status = block_on(check_device(&device, &tsm)).unwrap();
+        assert_eq!(status, DeviceStatus::Illegal);
+
+        //other failures are errors
+        server.set_return_code(
+            constants::TSM_ACTION_SE_SECURE_CHECK,
+            constants::TSM_RETURNCODE_DEVICE_CHECK_FAIL,
+        );
+        let result = block_on(check_device(&device, &tsm));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_device_authenticity_check_fail"
+        );
+        server.set_return_code(
+            constants::TSM_ACTION_SE_ACTIVATE,
+            constants::TSM_RETURNCODE_DEVICE_ACTIVE_FAIL,
+        );
+        let result = block_on(activate_device(&device, &tsm));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_device_active_fail"
+        );
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn set_ble_name_test() {
+        use crate::device_manager::{get_ble_name, set_ble_name};
+        use ikc_simulator::simulator::Simulator;
+
+        let device = Device::new(Simulator::new());
+        block_on(set_ble_name(&device, "imKey-01")).unwrap();
+        assert_eq!(block_on(get_ble_name(&device)).unwrap(), "imKey-01");
+
+        for ble_name in ["", "imKey 01", "imKeyProMax-01"] {
+            assert_eq!(
+                block_on(set_ble_name(&device, ble_name))
+                    .err()
+                    .unwrap()
+                    .to_string(),
+                "imkey_sdk_illegal_argument"
+            );
+        }
+    }
+}
@@ -48,6 +48,8 @@ pub enum ImkeyError {
     ImkeyTsmCosVersionUnsupportApplet,
     #[error("imkey_tsm_device_unsupport_applet")]
     ImkeyTsmDeviceUnsupportApplet,
+    #[error("imkey_tsm_too_many_steps")]
+    ImkeyTsmTooManySteps,
 }
 
 #[derive(Error, Debug, PartialOrd, PartialEq)]
@@ -62,4 +64,16 @@ pub enum BindError {
     ImkeySaveKeyFileFail,
     #[error("imkey_authcode_error")]
     ImkeyAuthcodeError,
+    #[error("imkey_device_not_bound")]
+    ImkeyDeviceNotBound,
+    #[error("imkey_keyfile_decrypt_fail")]
+    ImkeyKeyfileDecryptFail,
+    #[error("imkey_keyfile_secret_missing")]
+    ImkeyKeyfileSecretMissing,
+    #[error("imkey_bind_step_out_of_order")]
+    ImkeyBindStepOutOfOrder,
+    #[error("imkey_bind_code_attempts_exceeded")]
+    ImkeyBindCodeAttemptsExceeded,
+    #[error("imkey_bind_device_changed")]
+    ImkeyBindDeviceChanged,
 }
@@ -1,15 +1,70 @@
 use crate::error::BindError;
+use crate::key_store::KeyStore;
 use crate::Result;
 use base64::{decode, encode};
-use ikc_common::aes::cbc::{decrypt_pkcs7, encrypt_pkcs7};
+use hkdf::Hkdf;
+use ikc_common::aes::cbc::decrypt_pkcs7;
+use ikc_common::aes::gcm;
 use ikc_common::utility::{is_valid_hex, sha256_hash};
+use rand::RngCore;
 use secp256k1::rand::rngs::OsRng;
 use secp256k1::Secp256k1;
-use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind, Read, Write};
-use std::path::Path;
-use web_sys::console;
+use sha2::Sha256;
+
+//v2 key file: "IMK" 02, kdf (1), pbkdf2 iterations (4, password only), salt (16), nonce (12),
+//then the AES-256-GCM ciphertext of the keys, authenticated with all of the header
+const KEY_FILE_MAGIC: &[u8] = b"IMK";
+const KEY_FILE_VERSION: u8 = 0x02;
+const KDF_HOST_SECRET: u8 = 0x01;
+const KDF_PASSWORD: u8 = 0x02;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KDF_INFO: &[u8] = b"imkey key file";
+//private key, public key, se public key and session key
+const KEYS_LENGTH: usize = 178;
+
+/**
+Secret the key file is encrypted with, along with the SEID and SN of the device. It is never
+empty: the SEID and SN alone can be read by anyone able to read the key file.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyFileSecret {
+    /**
+    random secret kept by the host application apart from the key files
+    */
+    HostSecret(Vec<u8>),
+    Password(String),
+}
+
+impl KeyFileSecret {
+    pub fn check(&self) -> Result<()> {
+        let empty = match self {
+            KeyFileSecret::HostSecret(secret) => secret.is_empty(),
+            KeyFileSecret::Password(password) => password.is_empty(),
+        };
+        if empty {
+            return Err(BindError::ImkeyKeyfileSecretMissing.into());
+        }
+        Ok(())
+    }
+
+    fn kdf(&self) -> u8 {
+        match self {
+            KeyFileSecret::HostSecret(_) => KDF_HOST_SECRET,
+            KeyFileSecret::Password(_) => KDF_PASSWORD,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFileFormat {
+    /**
+    AES-CBC with the key and IV derived from the SEID and SN, before the v2 format
+    */
+    Legacy,
+    V2,
+}
 
 pub struct KeyManager {
     pub pri_key: Vec<u8>,
@@ -56,73 +111,125 @@ impl KeyManager {
     }
 
     /**
-    Organize and encrypt key file data
+    Organize and encrypt key file data, in the v2 format
     */
-    pub fn encrypt_data(&self) -> Result<String> {
+    pub fn encrypt_data(&self, secret: &KeyFileSecret) -> Result<String> {
+        secret.check()?;
         let mut data = vec![];
-        //
         data.extend(self.pri_key.iter());
         data.extend(self.pub_key.iter());
         data.extend(self.se_pub_key.iter());
         data.extend(self.session_key.iter());
 
-        //calc HASH
-        let hash = sha256_hash(data.as_slice());
-        data.extend(&hash[..4]);
+        let mut header = KEY_FILE_MAGIC.to_vec();
+        header.push(KEY_FILE_VERSION);
+        header.push(secret.kdf());
+        if let KeyFileSecret::Password(_) = secret {
+            header.extend(PBKDF2_ITERATIONS.to_be_bytes().iter());
+        }
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+        header.extend(salt.iter());
+        header.extend(nonce.iter());
 
-        //AES-CBC encryption
-        let ciphertext = encrypt_pkcs7(&data, &self.encry_key, &self.iv)?;
+        //AES-GCM encryption
+        let key = self.derive_key(secret, &salt);
+        let ciphertext = gcm::encrypt(&data, &key, &nonce, &header)?;
+        header.extend(ciphertext);
 
         //base64 coding
+        Ok(encode(&header))
+    }
+
+    /**
+    key file in the legacy format, for the migration tests
+    */
+    #[cfg(test)]
+    pub(crate) fn encrypt_legacy_data(&self) -> Result<String> {
+        let mut data = vec![];
+        data.extend(self.pri_key.iter());
+        data.extend(self.pub_key.iter());
+        data.extend(self.se_pub_key.iter());
+        data.extend(self.session_key.iter());
+        let hash = sha256_hash(data.as_slice());
+        data.extend(&hash[..4]);
+        let ciphertext = ikc_common::aes::cbc::encrypt_pkcs7(&data, &self.encry_key, &self.iv)?;
         Ok(encode(&ciphertext))
     }
 
     /**
-    Get key file data
+    key of the v2 format: HKDF over the host secret, or over PBKDF2 of the password, bound to the
+    device by the SEID and SN hash of gen_encrypt_key
     */
-    pub fn get_key_file_data(path: &str, seid: &str) -> Result<String> {
-        let mut return_data = String::new();
-        // !!! compatibility issue, the path of key file in android is different with ios before 2.0.0
-        let android_path = format!("{}/keys{}", path, seid[seid.len() - 8..].to_string());
-        let ios_path = format!("{}/keys{}", path, seid);
-        let path = if Path::new(android_path.as_str()).exists() {
-            android_path
-        } else {
-            ios_path
+    fn derive_key(&self, secret: &KeyFileSecret, salt: &[u8]) -> [u8; 32] {
+        let ikm = match secret {
+            KeyFileSecret::HostSecret(secret) => secret.clone(),
+            KeyFileSecret::Password(password) => {
+                let mut derived = vec![0u8; 32];
+                pbkdf2::pbkdf2::<hmac::Hmac<Sha256>>(
+                    password.as_bytes(),
+                    salt,
+                    PBKDF2_ITERATIONS,
+                    &mut derived,
+                );
+                derived
+            }
         };
-        let file = File::open(&path);
-        match file {
-            Ok(mut f) => {
-                f.read_to_string(&mut return_data)
-                    .expect("imkey_keyfile_io_error");
-                Ok(return_data)
+        let mut info = KDF_INFO.to_vec();
+        info.extend(self.encry_key.iter());
+        info.extend(self.iv.iter());
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &ikm)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+        key
+    }
+
+    pub fn key_file_format(data: &str) -> KeyFileFormat {
+        let is_v2 = !is_valid_hex(data)
+            && decode(data.as_bytes()).is_ok_and(|data| {
+                data.starts_with(KEY_FILE_MAGIC) && data.get(3) == Some(&KEY_FILE_VERSION)
+            });
+        if is_v2 {
+            KeyFileFormat::V2
+        } else {
+            KeyFileFormat::Legacy
+        }
+    }
+
+    /**
+    Get key file data, empty when the store has none for the device
+    */
+    pub async fn get_key_file_data(store: &dyn KeyStore, seid: &str) -> Result<String> {
+        // !!! compatibility issue, the key file in android is named after the end of the seid,
+        // in ios after the whole seid, before 2.0.0
+        for name in [key_file_name(seid)?, format!("keys{}", seid)] {
+            let data = store
+                .load(&name)
+                .await
+                .map_err(|_| BindError::ImkeyKeyfileIoError)?;
+            if let Some(data) = data {
+                return Ok(data);
             }
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => Ok(return_data),
-                _ => {
-                    // Err(BindError::ImkeyKeyfileIoError.into())
-                    Ok(return_data)
-                },
-            },
         }
+        Ok(String::new())
     }
 
     /**
-    Decrypt key file data
+    Decrypt key file data of either format. False when a legacy key file does not decrypt,
+    an error when a v2 key file does not: it is not for this secret, or it was tampered with.
     */
-    pub fn decrypt_keys(&mut self, ciphertext: &str) -> Result<bool> {
-        let ciphertext_bytes = match is_valid_hex(ciphertext) {
-            true => hex::decode(ciphertext).expect("invalid keys"),
-            false => decode(ciphertext.as_bytes()).expect("invalid keys"), //base64 decode
+    pub fn decrypt_keys(&mut self, ciphertext: &str, secret: &KeyFileSecret) -> Result<bool> {
+        let decrypted_data = match KeyManager::key_file_format(ciphertext) {
+            KeyFileFormat::V2 => self.decrypt_v2_keys(ciphertext, secret)?,
+            KeyFileFormat::Legacy => match self.decrypt_legacy_keys(ciphertext) {
+                Some(data) => data,
+                None => return Ok(false),
+            },
         };
 
-        //AES-CBC Decrypt
-        let plaintext = decrypt_pkcs7(&ciphertext_bytes, &self.encry_key, &self.iv);
-        if plaintext.is_err() {
-            return Ok(false);
-        }
-        let decrypted_data = plaintext?;
-
         //Parsing data
         //pri_key
         self.pri_key = decrypted_data[..32].to_vec();
@@ -135,19 +242,66 @@ impl KeyManager {
 
         //session key
         self.session_key = decrypted_data[162..178].to_vec();
+        Ok(true)
+    }
+
+    fn decrypt_v2_keys(&self, ciphertext: &str, secret: &KeyFileSecret) -> Result<Vec<u8>> {
+        let data = decode(ciphertext.as_bytes()).map_err(|_| BindError::ImkeyKeyfileDecryptFail)?;
+        let kdf = *data.get(4).ok_or(BindError::ImkeyKeyfileDecryptFail)?;
+        if kdf != secret.kdf() {
+            return Err(BindError::ImkeyKeyfileDecryptFail.into());
+        }
+        let mut offset = 5;
+        if kdf == KDF_PASSWORD {
+            //read before the tag is checked, any other count could make the derivation last
+            //forever
+            let iterations = data
+                .get(offset..offset + 4)
+                .ok_or(BindError::ImkeyKeyfileDecryptFail)?;
+            if iterations != PBKDF2_ITERATIONS.to_be_bytes() {
+                return Err(BindError::ImkeyKeyfileDecryptFail.into());
+            }
+            offset += 4;
+        }
+        let header_length = offset + SALT_LENGTH + NONCE_LENGTH;
+        if data.len() < header_length {
+            return Err(BindError::ImkeyKeyfileDecryptFail.into());
+        }
+        let (header, ciphertext) = data.split_at(header_length);
+        let salt = &header[offset..offset + SALT_LENGTH];
+        let nonce = &header[offset + SALT_LENGTH..];
+
+        let key = self.derive_key(secret, salt);
+        let plaintext = gcm::decrypt(ciphertext, &key, nonce, header)
+            .map_err(|_| BindError::ImkeyKeyfileDecryptFail)?;
+        if plaintext.len() != KEYS_LENGTH {
+            return Err(BindError::ImkeyKeyfileDecryptFail.into());
+        }
+        Ok(plaintext)
+    }
+
+    /**
+    keys of a legacy key file, None when it does not decrypt or its checksum is wrong
+    */
+    fn decrypt_legacy_keys(&mut self, ciphertext: &str) -> Option<Vec<u8>> {
+        let ciphertext_bytes = match is_valid_hex(ciphertext) {
+            true => hex::decode(ciphertext).ok()?,
+            false => decode(ciphertext.as_bytes()).ok()?, //base64 decode
+        };
 
-        //check sum
-        self.check_sum = decrypted_data[178..].to_vec();
+        //AES-CBC Decrypt
+        let decrypted_data = decrypt_pkcs7(&ciphertext_bytes, &self.encry_key, &self.iv).ok()?;
+        if decrypted_data.len() < KEYS_LENGTH + 4 {
+            return None;
+        }
 
         //check checksum
-        let data = &decrypted_data[..178];
-        let data_hash = sha256_hash(data);
-        for (index, val) in self.check_sum.iter().enumerate() {
-            if val != &data_hash[index] {
-                return Ok(false);
-            }
+        self.check_sum = decrypted_data[KEYS_LENGTH..].to_vec();
+        let data_hash = sha256_hash(&decrypted_data[..KEYS_LENGTH]);
+        if self.check_sum[..] != data_hash[..self.check_sum.len()] {
+            return None;
         }
-        Ok(true)
+        Some(decrypted_data)
     }
 
     /**
@@ -161,31 +315,57 @@ impl KeyManager {
         Ok(())
     }
     /**
-     Store key data
+     Store key data, replacing the key file named after the whole seid that ios used
     */
-    pub fn save_keys_to_local_file(keys: &String, path: &String, seid: &str) -> Result<()> {
-        if !Path::new(path).exists() {
-            fs::create_dir_all(path)?;
-        }
+    pub async fn save_key_file_data(store: &dyn KeyStore, keys: &str, seid: &str) -> Result<()> {
+        store
+            .save(&key_file_name(seid)?, keys)
+            .await
+            .map_err(|_| BindError::ImkeySaveKeyFileFail)?;
+        store
+            .remove(&format!("keys{}", seid))
+            .await
+            .map_err(|_| BindError::ImkeySaveKeyFileFail.into())
+    }
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(Path::new(
-                format!("{}/keys{}", path, seid[seid.len() - 8..].to_string()).as_str(),
-            ))
-            .expect("imkey_keyfile_opertion_error");
-        match file.write_all(keys.as_bytes()) {
-            Ok(val) => Ok(val),
-            Err(_e) => Err(BindError::ImkeySaveKeyFileFail.into()),
+    /**
+    delete the key file of the device, under either name
+    */
+    pub async fn remove_key_file_data(store: &dyn KeyStore, seid: &str) -> Result<()> {
+        for name in [key_file_name(seid)?, format!("keys{}", seid)] {
+            store
+                .remove(&name)
+                .await
+                .map_err(|_| BindError::ImkeyKeyfileIoError)?;
         }
+        Ok(())
+    }
+
+    /**
+    forget the keys of the binding, the encryption key of the key file stays
+    */
+    pub fn clear_keys(&mut self) {
+        self.pri_key.clear();
+        self.pub_key.clear();
+        self.se_pub_key.clear();
+        self.session_key.clear();
+        self.check_sum.clear();
     }
 }
 
+fn key_file_name(seid: &str) -> Result<String> {
+    if seid.len() < 8 || !seid.is_ascii() {
+        return Err(BindError::ImkeySdkIllegalArgument.into());
+    }
+    Ok(format!("keys{}", &seid[seid.len() - 8..]))
+}
+
 #[cfg(test)]
 mod test {
-    use crate::key_manager::KeyManager;
+    use crate::error::BindError;
+    use crate::key_manager::{KeyFileFormat, KeyFileSecret, KeyManager};
+    use crate::key_store::{KeyStore, MemoryKeyStore};
+    use futures::executor::block_on;
 
     #[test]
     fn gen_encrypt_key_test() {
@@ -207,4 +387,180 @@ mod test {
             "92AF372F64C10BAA942478560F91F346".to_string()
         );
     }
+
+    #[test]
+    fn short_seid_test() {
+        let store = MemoryKeyStore::new();
+        for seid in ["", "1234567", "éééé"] {
+            let result = block_on(KeyManager::get_key_file_data(&store, seid));
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                BindError::ImkeySdkIllegalArgument.to_string()
+            );
+            let result = block_on(KeyManager::save_key_file_data(&store, "keys", seid));
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                BindError::ImkeySdkIllegalArgument.to_string()
+            );
+            let result = block_on(KeyManager::remove_key_file_data(&store, seid));
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                BindError::ImkeySdkIllegalArgument.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn key_file_test() {
+        let seid = "19060000000200860001010000000014";
+        let store = MemoryKeyStore::new();
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(&store, seid)).unwrap(),
+            ""
+        );
+
+        //key file saved by ios before 2.0.0
+        block_on(store.save(&format!("keys{}", seid), "ios")).unwrap();
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(&store, seid)).unwrap(),
+            "ios"
+        );
+
+        block_on(KeyManager::save_key_file_data(&store, "keys", seid)).unwrap();
+        assert_eq!(
+            block_on(store.load("keys00000014")).unwrap(),
+            Some("keys".to_string())
+        );
+        assert_eq!(
+            block_on(store.load(&format!("keys{}", seid))).unwrap(),
+            None
+        );
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(&store, seid)).unwrap(),
+            "keys"
+        );
+
+        block_on(store.save(&format!("keys{}", seid), "ios")).unwrap();
+        block_on(KeyManager::remove_key_file_data(&store, seid)).unwrap();
+        assert_eq!(
+            block_on(KeyManager::get_key_file_data(&store, seid)).unwrap(),
+            ""
+        );
+    }
+
+    fn key_manager(sn: &str) -> KeyManager {
+        let mut key_manager_obj = KeyManager::new();
+        key_manager_obj.gen_encrypt_key("19060000000200860001010000000014", sn);
+        key_manager_obj.gen_local_keys().unwrap();
+        key_manager_obj.se_pub_key = key_manager_obj.pub_key.clone();
+        key_manager_obj.session_key = vec![0x11; 16];
+        key_manager_obj
+    }
+
+    fn check_decrypt(ciphertext: &str, secret: &KeyFileSecret, expected: &KeyManager) {
+        let mut key_manager_obj = key_manager("imKey01191200001");
+        assert!(key_manager_obj.decrypt_keys(ciphertext, secret).unwrap());
+        assert_eq!(key_manager_obj.pri_key, expected.pri_key);
+        assert_eq!(key_manager_obj.pub_key, expected.pub_key);
+        assert_eq!(key_manager_obj.se_pub_key, expected.se_pub_key);
+        assert_eq!(key_manager_obj.session_key, expected.session_key);
+    }
+
+    #[test]
+    fn v2_key_file_test() {
+        let key_manager_obj = key_manager("imKey01191200001");
+        let host_secret = KeyFileSecret::HostSecret(vec![0x5A; 32]);
+        let ciphertext = key_manager_obj.encrypt_data(&host_secret).unwrap();
+        assert_eq!(KeyManager::key_file_format(&ciphertext), KeyFileFormat::V2);
+        check_decrypt(&ciphertext, &host_secret, &key_manager_obj);
+        //a fresh salt and nonce every time
+        assert_ne!(
+            key_manager_obj.encrypt_data(&host_secret).unwrap(),
+            ciphertext
+        );
+
+        let password = KeyFileSecret::Password("correct horse".to_string());
+        let ciphertext = key_manager_obj.encrypt_data(&password).unwrap();
+        check_decrypt(&ciphertext, &password, &key_manager_obj);
+
+        let mut tampered = base64::decode(&ciphertext).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        //an iteration count other than the one written is rejected before any derivation
+        let mut iterations = base64::decode(&ciphertext).unwrap();
+        iterations[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        let wrong = [
+            (
+                ciphertext.clone(),
+                KeyFileSecret::Password("wrong".to_string()),
+            ),
+            (ciphertext.clone(), host_secret.clone()),
+            (base64::encode(&tampered), password.clone()),
+            (base64::encode(&tampered[..40]), password.clone()),
+            (base64::encode(&iterations), password.clone()),
+        ];
+        for (ciphertext, secret) in wrong.iter() {
+            let mut key_manager_obj = key_manager("imKey01191200001");
+            assert_eq!(
+                key_manager_obj
+                    .decrypt_keys(ciphertext, secret)
+                    .err()
+                    .unwrap()
+                    .to_string(),
+                "imkey_keyfile_decrypt_fail"
+            );
+        }
+
+        //the key file is bound to the device
+        let ciphertext = key_manager_obj.encrypt_data(&host_secret).unwrap();
+        let mut other_device = key_manager("imKey01191200002");
+        assert!(other_device
+            .decrypt_keys(&ciphertext, &host_secret)
+            .is_err());
+
+        for secret in [
+            KeyFileSecret::HostSecret(vec![]),
+            KeyFileSecret::Password(String::new()),
+        ] {
+            assert_eq!(
+                key_manager_obj
+                    .encrypt_data(&secret)
+                    .err()
+                    .unwrap()
+                    .to_string(),
+                "imkey_keyfile_secret_missing"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_key_file_test() {
+        let key_manager_obj = key_manager("imKey01191200001");
+        let ciphertext = key_manager_obj.encrypt_legacy_data().unwrap();
+        assert_eq!(
+            KeyManager::key_file_format(&ciphertext),
+            KeyFileFormat::Legacy
+        );
+        //the secret plays no part in the legacy format
+        let host_secret = KeyFileSecret::HostSecret(vec![0x5A; 32]);
+        check_decrypt(&ciphertext, &host_secret, &key_manager_obj);
+        let hex_ciphertext = hex::encode_upper(base64::decode(&ciphertext).unwrap());
+        check_decrypt(
+            &hex_ciphertext,
+            &KeyFileSecret::Password("any".to_string()),
+            &key_manager_obj,
+        );
+
+        //a key file of another device or a corrupted one is replaced, as before
+        let mut other_device = key_manager("imKey01191200002");
+        assert!(!other_device
+            .decrypt_keys(&ciphertext, &host_secret)
+            .unwrap());
+        assert!(!other_device
+            .decrypt_keys("not a key file", &host_secret)
+            .unwrap());
+        assert!(!other_device
+            .decrypt_keys(&ciphertext[..20], &host_secret)
+            .unwrap());
+    }
 }
@@ -0,0 +1,132 @@
+use crate::error::BindError;
+use crate::Result;
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/**
+Storage of the key files holding the binding keys of the host, one per device.
+The embedding application picks where they live: a directory, the browser storage or memory.
+*/
+#[async_trait(?Send)]
+pub trait KeyStore {
+    /**
+    content of the key file, None when there is none
+    */
+    async fn load(&self, name: &str) -> Result<Option<String>>;
+
+    async fn save(&self, name: &str, data: &str) -> Result<()>;
+
+    /**
+    delete the key file, nothing to do when there is none
+    */
+    async fn remove(&self, name: &str) -> Result<()>;
+}
+
+/**
+Key files kept in memory, lost with the store. Used by tests and by hosts that bind every session.
+*/
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    files: RefCell<HashMap<String, String>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        MemoryKeyStore::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl KeyStore for MemoryKeyStore {
+    async fn load(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.files.borrow().get(name).cloned())
+    }
+
+    async fn save(&self, name: &str, data: &str) -> Result<()> {
+        self.files
+            .borrow_mut()
+            .insert(name.to_string(), data.to_string());
+        Ok(())
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.files.borrow_mut().remove(name);
+        Ok(())
+    }
+}
+
+/**
+Key files in a directory of the filesystem, created on the first save.
+Not available in the browser, where std::fs fails.
+*/
+pub struct FileKeyStore {
+    dir: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(dir: &str) -> Self {
+        FileKeyStore {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl KeyStore for FileKeyStore {
+    async fn load(&self, name: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.dir.join(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(_) => Err(BindError::ImkeyKeyfileIoError.into()),
+        }
+    }
+
+    async fn save(&self, name: &str, data: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir).map_err(|_| BindError::ImkeyKeyfileIoError)?;
+        fs::write(self.dir.join(name), data).map_err(|_| BindError::ImkeyKeyfileIoError)?;
+        Ok(())
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.dir.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(BindError::ImkeyKeyfileIoError.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::key_store::{FileKeyStore, KeyStore, MemoryKeyStore};
+    use futures::executor::block_on;
+
+    fn check_store(store: &dyn KeyStore) {
+        assert_eq!(block_on(store.load("keys00000014")).unwrap(), None);
+        block_on(store.save("keys00000014", "first")).unwrap();
+        block_on(store.save("keys00000014", "second")).unwrap();
+        assert_eq!(
+            block_on(store.load("keys00000014")).unwrap(),
+            Some("second".to_string())
+        );
+        block_on(store.remove("keys00000014")).unwrap();
+        block_on(store.remove("keys00000014")).unwrap();
+        assert_eq!(block_on(store.load("keys00000014")).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_key_store_test() {
+        check_store(&MemoryKeyStore::new());
+    }
+
+    #[test]
+    fn file_key_store_test() {
+        let dir = std::env::temp_dir().join(format!("imkey-key-store-{}", std::process::id()));
+        let store = FileKeyStore::new(dir.to_str().unwrap());
+        check_store(&store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
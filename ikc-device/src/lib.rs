@@ -1,7 +1,14 @@
+pub mod app_manager;
+pub mod binding_session;
+pub mod connection_manager;
+pub mod cos_upgrade;
 pub mod device_binding;
 extern crate ikc_common;
 pub mod device_manager;
 pub mod key_manager;
+pub mod key_store;
+pub mod se_cert;
+pub mod tsm;
 #[macro_use]
 extern crate lazy_static;
 pub mod error;
@@ -9,12 +16,13 @@ extern crate anyhow;
 use core::result;
 pub type Result<T> = result::Result<T, anyhow::Error>;
 use crate::error::ImkeyError;
+use crate::tsm::TsmClient;
+use async_trait::async_trait;
+use ikc_common::apdu::{Command, Exchange};
 use ikc_common::constants;
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb;
+use ikc_transport::device::Device;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use futures::executor::block_on;
-
 
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -27,9 +35,17 @@ pub struct ServiceResponse<T> {
     pub return_data: T,
 }
 
-pub trait TsmService {
-    type ReturnData;
-    fn send_message(&mut self) -> Result<Self::ReturnData>;
+/**
+Request of a TSM action, posted as JSON to the action path of the server
+*/
+#[async_trait(?Send)]
+pub trait TsmService: Serialize {
+    type ReturnData: DeserializeOwned;
+    const ACTION: &'static str;
+
+    async fn send_message(&mut self, client: &TsmClient) -> Result<Self::ReturnData> {
+        client.post(Self::ACTION, self).await
+    }
 }
 
 impl<T> ServiceResponse<T> {
@@ -57,6 +73,9 @@ impl<T> ServiceResponse<T> {
             constants::TSM_RETURNCODE_APP_DOWNLOAD_FAIL => {
                 Err(ImkeyError::ImkeyTsmAppDownloadFail.into())
             }
+            constants::TSM_RETURNCODE_APP_UPDATE_FAIL => {
+                Err(ImkeyError::ImkeyTsmAppUpdateFail.into())
+            }
             constants::TSM_RETURNCODE_AUTH_CODE_HANDLE_FAIL => {
                 Err(ImkeyError::ImkeyTsmAuthCodeCiphertextStorageFail.into())
             }
@@ -98,19 +117,19 @@ impl<T> ServiceResponse<T> {
         }
     }
 
-    // #[cfg(target_arch = "wasm32")]
-    pub fn apdu_handle(apdu_list: Vec<String>) -> Result<(Vec<String>, String)> {
-        if apdu_list.is_empty() {
-            ()
-        }
+    pub async fn apdu_handle(
+        device: &Device,
+        apdu_list: Vec<String>,
+    ) -> Result<(Vec<String>, String)> {
         let mut apdu_res: Vec<String> = vec![];
         let mut status_word: String = String::new();
         for (index_val, apdu_val) in apdu_list.iter().enumerate() {
-            //sende apdu command
-            let res = block_on(webusb::send_apdu(apdu_val.to_string()))?;
-            apdu_res.push(res.clone());
+            //sende apdu command, through the secure channel which a SELECT of the ISD ends
+            let command = Command::decode(&hex::decode(apdu_val)?)?;
+            let res = device.exchange(&command).await?;
+            apdu_res.push(hex::encode_upper(res.encode()));
             if index_val == apdu_list.len() - 1 {
-                status_word = String::from(&res[res.len() - 4..]);
+                status_word = format!("{:04X}", res.sw);
             }
         }
         Ok((apdu_res, status_word))
@@ -0,0 +1,246 @@
+use crate::error::ImkeyError;
+use crate::Result;
+use ikc_common::constants::SECP256K1_ENGINE;
+use ikc_common::tlv::Tlv;
+use ikc_common::utility::sha256_hash;
+#[cfg(any(test, feature = "simulator"))]
+use parking_lot::RwLock;
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey};
+
+const TAG_CERTIFICATE: &[u8] = &[0x7F, 0x21];
+const TAG_SERIAL_NUMBER: &[u8] = &[0x93];
+const TAG_CA_IDENTIFIER: &[u8] = &[0x42];
+const TAG_SUBJECT_IDENTIFIER: &[u8] = &[0x5F, 0x20];
+const TAG_PUBLIC_KEY: &[u8] = &[0x7F, 0x49];
+const TAG_PUBLIC_KEY_POINT: &[u8] = &[0xB0];
+const TAG_SIGNATURE: &[u8] = &[0x5F, 0x37];
+
+/**
+imKey certificate authorities trusted to sign SE certificates: CA identifier (tag 42)
+and uncompressed secp256k1 public key. Release builds trust this list only, the production
+CA keys have to be listed here for bind_check to accept real devices.
+*/
+const SE_ROOT_KEYS: &[(&[u8], &str)] = &[];
+
+#[cfg(any(test, feature = "simulator"))]
+lazy_static! {
+    //certificate authorities trusted by tests, like the simulator one
+    static ref ADDED_ROOT_KEYS: RwLock<Vec<(Vec<u8>, Vec<u8>)>> = RwLock::new(vec![]);
+}
+
+/**
+trust another certificate authority in tests, the bundled imKey ones are always checked first.
+Not built into releases, trust roots cannot be added at run time there.
+*/
+#[cfg(any(test, feature = "simulator"))]
+pub(crate) fn add_root_key(ca_identifier: &[u8], public_key: &[u8]) {
+    let mut root_keys = ADDED_ROOT_KEYS.write();
+    if !root_keys
+        .iter()
+        .any(|(identifier, _)| identifier == ca_identifier)
+    {
+        root_keys.push((ca_identifier.to_vec(), public_key.to_vec()));
+    }
+}
+
+/**
+SE certificate in GlobalPlatform format (7F21), its signature covers the TLVs before 5F37
+*/
+pub struct SeCert {
+    pub serial_number: Vec<u8>,
+    pub ca_identifier: Vec<u8>,
+    pub subject_identifier: Vec<u8>,
+    pub public_key: Vec<u8>,
+    signed_data: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SeCert {
+    pub fn parse(data: &[u8]) -> Result<SeCert> {
+        let (cert, _) = decode(data)?;
+        if cert.tag != TAG_CERTIFICATE {
+            return Err(ImkeyError::ImkeySeCertInvalid.into());
+        }
+
+        let mut serial_number = None;
+        let mut ca_identifier = None;
+        let mut subject_identifier = None;
+        let mut public_key = None;
+        let mut remaining = cert.value.as_slice();
+        while !remaining.is_empty() {
+            let (object, rest) = decode(remaining)?;
+            match object.tag.as_slice() {
+                TAG_SERIAL_NUMBER => serial_number = Some(object.value),
+                TAG_CA_IDENTIFIER => ca_identifier = Some(object.value),
+                TAG_SUBJECT_IDENTIFIER => subject_identifier = Some(object.value),
+                TAG_PUBLIC_KEY => {
+                    public_key = object
+                        .find(TAG_PUBLIC_KEY_POINT)
+                        .map_err(|_| ImkeyError::ImkeySeCertInvalid)?
+                        .map(|point| point.value)
+                }
+                TAG_SIGNATURE => {
+                    //the signed data ends where the signature starts
+                    let signed_length = cert.value.len() - remaining.len();
+                    return Ok(SeCert {
+                        serial_number: required(serial_number)?,
+                        ca_identifier: required(ca_identifier)?,
+                        subject_identifier: required(subject_identifier)?,
+                        public_key: required(public_key)?,
+                        signed_data: cert.value[..signed_length].to_vec(),
+                        signature: object.value,
+                    });
+                }
+                _ => {}
+            }
+            remaining = rest;
+        }
+        Err(ImkeyError::ImkeySeCertInvalid.into())
+    }
+
+    /**
+    check that the certificate belongs to the device and is signed by a trusted CA key
+    */
+    pub fn verify(&self, seid: &[u8]) -> Result<()> {
+        let root_key = self.root_key()?.ok_or(ImkeyError::ImkeySeCertInvalid)?;
+        self.verify_with(seid, &root_key)
+    }
+
+    fn root_key(&self) -> Result<Option<Vec<u8>>> {
+        let root_key = match SE_ROOT_KEYS
+            .iter()
+            .find(|(ca_identifier, _)| *ca_identifier == self.ca_identifier.as_slice())
+        {
+            Some((_, root_key)) => Some(hex::decode(root_key)?),
+            None => None,
+        };
+        #[cfg(any(test, feature = "simulator"))]
+        let root_key = root_key.or_else(|| {
+            ADDED_ROOT_KEYS
+                .read()
+                .iter()
+                .find(|(ca_identifier, _)| *ca_identifier == self.ca_identifier)
+                .map(|(_, root_key)| root_key.clone())
+        });
+        Ok(root_key)
+    }
+
+    pub fn verify_with(&self, seid: &[u8], root_key: &[u8]) -> Result<()> {
+        if self.subject_identifier != seid {
+            return Err(ImkeyError::ImkeySeCertInvalid.into());
+        }
+        PublicKey::from_slice(&self.public_key).map_err(|_| ImkeyError::ImkeySeCertInvalid)?;
+
+        let root_key = PublicKey::from_slice(root_key)?;
+        let signature = match self.signature.len() {
+            64 => Signature::from_compact(&self.signature),
+            _ => Signature::from_der(&self.signature),
+        }
+        .map_err(|_| ImkeyError::ImkeySeCertInvalid)?;
+        let message = Message::from_slice(&sha256_hash(&self.signed_data))?;
+        SECP256K1_ENGINE
+            .verify_ecdsa(&message, &signature, &root_key)
+            .map_err(|_| ImkeyError::ImkeySeCertInvalid.into())
+    }
+}
+
+fn required(value: Option<Vec<u8>>) -> Result<Vec<u8>> {
+    value.ok_or_else(|| ImkeyError::ImkeySeCertInvalid.into())
+}
+
+fn decode(data: &[u8]) -> Result<(Tlv, &[u8])> {
+    Tlv::decode(data).map_err(|_| ImkeyError::ImkeySeCertInvalid.into())
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::se_cert::{add_root_key, SeCert};
+    use ikc_simulator::simulator::{
+        Simulator, SIMULATOR_CA_ID, SIMULATOR_CA_PUBLIC_KEY, SIMULATOR_SEID,
+    };
+
+    //secp256k1 generator point, any valid key gets the certificate
+    const HOST_KEY: &str = "0479BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798483ADA7726A3C4655DA4FBFC0E1108A8FD17B448A68554199C47D08FFB10D4B8";
+
+    //bind check response: bind status, certificate, status word
+    fn simulator_cert() -> Vec<u8> {
+        let bind_check = hex::decode(format!("8071000041{}00", HOST_KEY)).unwrap();
+        let simulator = Simulator::new();
+        simulator.process(&hex::decode("00A4040005695F696D6B00").unwrap());
+        let response = simulator.process(&bind_check);
+        response[1..response.len() - 2].to_vec()
+    }
+
+    #[test]
+    fn parse_test() {
+        let cert = SeCert::parse(&simulator_cert()).unwrap();
+        assert_eq!(hex::encode_upper(&cert.serial_number), SIMULATOR_SEID);
+        assert_eq!(cert.ca_identifier, SIMULATOR_CA_ID.as_bytes());
+        assert_eq!(hex::encode_upper(&cert.subject_identifier), SIMULATOR_SEID);
+        assert_eq!(cert.public_key.len(), 65);
+        assert_eq!(cert.public_key[0], 0x04);
+
+        let result = SeCert::parse(&simulator_cert()[..100]);
+        assert_eq!(result.err().unwrap().to_string(), "imkey_se_cert_invalid");
+        let result = SeCert::parse(&hex::decode("7F4903B00100").unwrap());
+        assert_eq!(result.err().unwrap().to_string(), "imkey_se_cert_invalid");
+    }
+
+    #[test]
+    fn verify_test() {
+        let seid = hex::decode(SIMULATOR_SEID).unwrap();
+        let ca_key = hex::decode(SIMULATOR_CA_PUBLIC_KEY).unwrap();
+        let cert = SeCert::parse(&simulator_cert()).unwrap();
+        cert.verify_with(&seid, &ca_key).unwrap();
+
+        //certificate of another device
+        let result = cert.verify_with(&[0x19, 0x06], &ca_key);
+        assert_eq!(result.err().unwrap().to_string(), "imkey_se_cert_invalid");
+
+        //replaced public key
+        let mut data = simulator_cert();
+        let index = hex::encode_upper(&data).find("B04104").unwrap() / 2 + 3;
+        data[index + 10] ^= 0x01;
+        let cert = SeCert::parse(&data).unwrap();
+        assert_eq!(
+            cert.verify_with(&seid, &ca_key).err().unwrap().to_string(),
+            "imkey_se_cert_invalid"
+        );
+
+        //signed by another key
+        let cert = SeCert::parse(&simulator_cert()).unwrap();
+        let other_key = hex::decode(HOST_KEY).unwrap();
+        assert_eq!(
+            cert.verify_with(&seid, &other_key)
+                .err()
+                .unwrap()
+                .to_string(),
+            "imkey_se_cert_invalid"
+        );
+    }
+
+    #[test]
+    fn root_key_test() {
+        let seid = hex::decode(SIMULATOR_SEID).unwrap();
+        //the simulator CA is not bundled
+        let mut data = simulator_cert();
+        let index = hex::encode(&data)
+            .find(&hex::encode(SIMULATOR_CA_ID))
+            .unwrap()
+            / 2;
+        data[index] = b'X';
+        let cert = SeCert::parse(&data).unwrap();
+        assert_eq!(
+            cert.verify(&seid).err().unwrap().to_string(),
+            "imkey_se_cert_invalid"
+        );
+
+        add_root_key(
+            SIMULATOR_CA_ID.as_bytes(),
+            &hex::decode(SIMULATOR_CA_PUBLIC_KEY).unwrap(),
+        );
+        let cert = SeCert::parse(&simulator_cert()).unwrap();
+        cert.verify(&seid).unwrap();
+    }
+}
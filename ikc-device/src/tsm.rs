@@ -0,0 +1,569 @@
+use crate::error::ImkeyError;
+use crate::{Result, ServiceResponse, TsmService};
+use async_trait::async_trait;
+use ikc_common::constants;
+use ikc_transport::device::Device;
+use ikc_transport::http::HttpClient;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+
+/**
+Client of the imKey TSM server: posts the JSON requests of the actions and runs the APDU
+scripts the server issues until it sends the end flag.
+Only the browser build has an HttpClient (ikc_webusb::fetch::FetchClient), native applications
+give their own.
+*/
+#[derive(Clone)]
+pub struct TsmClient {
+    endpoint: String,
+    http: Rc<dyn HttpClient>,
+}
+
+impl TsmClient {
+    pub fn new(http: Rc<dyn HttpClient>) -> TsmClient {
+        TsmClient::with_endpoint(constants::URL, http)
+    }
+
+    pub fn with_endpoint(endpoint: &str, http: Rc<dyn HttpClient>) -> TsmClient {
+        TsmClient {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /**
+    post the request to the action, return the data of a successful response
+    */
+    pub async fn post<Req, Res>(&self, action: &str, request: &Req) -> Result<Res>
+    where
+        Req: Serialize + ?Sized,
+        Res: DeserializeOwned,
+    {
+        let url = format!("{}{}", self.endpoint, action);
+        let response = self
+            .http
+            .post(&url, &serde_json::to_string(request)?)
+            .await?;
+        //failed responses may carry no data, check the return code first
+        let response: ServiceResponse<serde_json::Value> =
+            serde_json::from_str(&response).map_err(|_| ImkeyError::ImkeyTsmServerError)?;
+        response.service_res_check()?;
+        serde_json::from_value(response.return_data)
+            .map_err(|_| ImkeyError::ImkeyTsmServerError.into())
+    }
+
+    /**
+    send the request, then the results of each APDU script to the device and back to the server
+    until the server sends the end flag, within TSM_MAX_SCRIPT_STEPS steps
+    */
+    pub async fn run_script<R: ScriptRequest>(
+        &self,
+        device: &Device,
+        request: &mut R,
+    ) -> Result<()> {
+        for _ in 0..constants::TSM_MAX_SCRIPT_STEPS {
+            let response = request.send_message(self).await?;
+            if response.next_step_key == constants::TSM_END_FLAG {
+                return Ok(());
+            }
+            let apdu_list = response.apdu_list.unwrap_or_default();
+            let (card_ret_data_list, status_word) =
+                ServiceResponse::<ScriptResponse>::apdu_handle(device, apdu_list).await?;
+            let step = request.step_mut();
+            step.step_key = response.next_step_key;
+            step.status_word = Some(status_word);
+            step.card_ret_data_list = Some(card_ret_data_list);
+        }
+        Err(ImkeyError::ImkeyTsmTooManySteps.into())
+    }
+}
+
+/**
+Progress of an APDU script, sent back to the server with every request of the action
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptStep {
+    pub step_key: String,
+    pub status_word: Option<String>,
+    #[serde(rename = "commandID")]
+    pub command_id: String,
+    pub card_ret_data_list: Option<Vec<String>>,
+}
+
+impl ScriptStep {
+    //the first request of an action has no step key
+    fn new(action: &str) -> ScriptStep {
+        ScriptStep {
+            step_key: String::new(),
+            status_word: None,
+            command_id: action.to_string(),
+            card_ret_data_list: None,
+        }
+    }
+}
+
+/**
+next APDUs to send to the device, none once the step key is the end flag
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptResponse {
+    pub next_step_key: String,
+    pub apdu_list: Option<Vec<String>>,
+}
+
+/**
+Action run as an APDU script
+*/
+pub trait ScriptRequest: TsmService<ReturnData = ScriptResponse> {
+    fn step_mut(&mut self) -> &mut ScriptStep;
+}
+
+macro_rules! script_request {
+    ($request:ident, $action:expr, $($step:tt).+) => {
+        #[async_trait(?Send)]
+        impl TsmService for $request {
+            type ReturnData = ScriptResponse;
+            const ACTION: &'static str = $action;
+        }
+
+        impl ScriptRequest for $request {
+            fn step_mut(&mut self) -> &mut ScriptStep {
+                &mut self.$($step).+
+            }
+        }
+    };
+}
+
+macro_rules! request {
+    ($request:ident, $response:ty, $action:expr) => {
+        #[async_trait(?Send)]
+        impl TsmService for $request {
+            type ReturnData = $response;
+            const ACTION: &'static str = $action;
+        }
+    };
+}
+
+/**
+authenticity check of the SE, the server also tells whether the device is activated
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeSecureCheckRequest {
+    pub seid: String,
+    pub sn: String,
+    pub device_cert: String,
+    pub sdk_version: String,
+    #[serde(flatten)]
+    pub step: ScriptStep,
+}
+
+impl SeSecureCheckRequest {
+    pub fn new(seid: &str, sn: &str, device_cert: &str) -> SeSecureCheckRequest {
+        SeSecureCheckRequest {
+            seid: seid.to_string(),
+            sn: sn.to_string(),
+            device_cert: device_cert.to_string(),
+            sdk_version: constants::VERSION.to_string(),
+            step: ScriptStep::new(constants::TSM_ACTION_SE_SECURE_CHECK),
+        }
+    }
+}
+
+script_request!(
+    SeSecureCheckRequest,
+    constants::TSM_ACTION_SE_SECURE_CHECK,
+    step
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeActivateRequest {
+    pub seid: String,
+    pub sn: String,
+    pub device_cert: String,
+    #[serde(flatten)]
+    pub step: ScriptStep,
+}
+
+impl SeActivateRequest {
+    pub fn new(seid: &str, sn: &str, device_cert: &str) -> SeActivateRequest {
+        SeActivateRequest {
+            seid: seid.to_string(),
+            sn: sn.to_string(),
+            device_cert: device_cert.to_string(),
+            step: ScriptStep::new(constants::TSM_ACTION_SE_ACTIVATE),
+        }
+    }
+}
+
+script_request!(SeActivateRequest, constants::TSM_ACTION_SE_ACTIVATE, step);
+
+/**
+download, update or delete of the applet instance, the action tells which
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRequest {
+    pub seid: String,
+    pub instance_aid: String,
+    pub device_cert: String,
+    pub sdk_version: String,
+    #[serde(flatten)]
+    pub step: ScriptStep,
+}
+
+impl AppRequest {
+    fn new(action: &str, seid: &str, instance_aid: &str, device_cert: &str) -> AppRequest {
+        AppRequest {
+            seid: seid.to_string(),
+            instance_aid: instance_aid.to_string(),
+            device_cert: device_cert.to_string(),
+            sdk_version: constants::VERSION.to_string(),
+            step: ScriptStep::new(action),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct AppDownloadRequest(pub AppRequest);
+
+impl AppDownloadRequest {
+    pub fn new(seid: &str, instance_aid: &str, device_cert: &str) -> AppDownloadRequest {
+        AppDownloadRequest(AppRequest::new(
+            constants::TSM_ACTION_APP_DOWNLOAD,
+            seid,
+            instance_aid,
+            device_cert,
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct AppUpdateRequest(pub AppRequest);
+
+impl AppUpdateRequest {
+    pub fn new(seid: &str, instance_aid: &str, device_cert: &str) -> AppUpdateRequest {
+        AppUpdateRequest(AppRequest::new(
+            constants::TSM_ACTION_APP_UPDATE,
+            seid,
+            instance_aid,
+            device_cert,
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct AppDeleteRequest(pub AppRequest);
+
+impl AppDeleteRequest {
+    pub fn new(seid: &str, instance_aid: &str, device_cert: &str) -> AppDeleteRequest {
+        AppDeleteRequest(AppRequest::new(
+            constants::TSM_ACTION_APP_DELETE,
+            seid,
+            instance_aid,
+            device_cert,
+        ))
+    }
+}
+
+script_request!(
+    AppDownloadRequest,
+    constants::TSM_ACTION_APP_DOWNLOAD,
+    0.step
+);
+script_request!(AppUpdateRequest, constants::TSM_ACTION_APP_UPDATE, 0.step);
+script_request!(AppDeleteRequest, constants::TSM_ACTION_APP_DELETE, 0.step);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CosUpgradeRequest {
+    pub seid: String,
+    pub sn: String,
+    pub device_cert: String,
+    pub cos_version: String,
+    //the device already runs the bootloader, e.g. an upgrade resumed after a disconnection
+    pub is_bl_status: bool,
+    #[serde(flatten)]
+    pub step: ScriptStep,
+}
+
+impl CosUpgradeRequest {
+    pub fn new(
+        seid: &str,
+        sn: &str,
+        device_cert: &str,
+        cos_version: &str,
+        is_bl_status: bool,
+    ) -> CosUpgradeRequest {
+        CosUpgradeRequest {
+            seid: seid.to_string(),
+            sn: sn.to_string(),
+            device_cert: device_cert.to_string(),
+            cos_version: cos_version.to_string(),
+            is_bl_status,
+            step: ScriptStep::new(constants::TSM_ACTION_COS_UPGRADE),
+        }
+    }
+}
+
+script_request!(CosUpgradeRequest, constants::TSM_ACTION_COS_UPGRADE, step);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeQueryRequest {
+    pub seid: String,
+    pub sn: String,
+    pub sdk_version: String,
+}
+
+impl SeQueryRequest {
+    pub fn new(seid: &str, sn: &str) -> SeQueryRequest {
+        SeQueryRequest {
+            seid: seid.to_string(),
+            sn: sn.to_string(),
+            sdk_version: constants::VERSION.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AvailableApp {
+    pub app_name: String,
+    pub instance_aid: String,
+    //empty when the applet is not installed
+    #[serde(default)]
+    pub installed_version: String,
+    pub latest_version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeQueryResponse {
+    pub seid: String,
+    pub sn: String,
+    //inactivated or latest
+    pub status: String,
+    #[serde(default)]
+    pub available_app_list: Vec<AvailableApp>,
+}
+
+request!(
+    SeQueryRequest,
+    SeQueryResponse,
+    constants::TSM_ACTION_SE_QUERY
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CosCheckUpdateRequest {
+    pub seid: String,
+    pub cos_version: String,
+}
+
+impl CosCheckUpdateRequest {
+    pub fn new(seid: &str, cos_version: &str) -> CosCheckUpdateRequest {
+        CosCheckUpdateRequest {
+            seid: seid.to_string(),
+            cos_version: cos_version.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CosCheckUpdateResponse {
+    pub seid: String,
+    pub is_latest: bool,
+    pub latest_cos_version: String,
+    #[serde(default)]
+    pub update_type: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+request!(
+    CosCheckUpdateRequest,
+    CosCheckUpdateResponse,
+    constants::TSM_ACTION_COS_CHECK_UPDATE
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCertCheckRequest {
+    pub seid: String,
+    pub sn: String,
+    pub device_cert: String,
+}
+
+impl DeviceCertCheckRequest {
+    pub fn new(seid: &str, sn: &str, device_cert: &str) -> DeviceCertCheckRequest {
+        DeviceCertCheckRequest {
+            seid: seid.to_string(),
+            sn: sn.to_string(),
+            device_cert: device_cert.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCertCheckResponse {
+    pub seid: String,
+    pub verify_result: bool,
+}
+
+request!(
+    DeviceCertCheckRequest,
+    DeviceCertCheckResponse,
+    constants::TSM_ACTION_DEVICE_CERT_CHECK
+);
+
+/**
+binding code encrypted for the server, kept there to recover a lost binding
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthCodeStorageRequest {
+    pub seid: String,
+    pub auth_code: String,
+}
+
+impl AuthCodeStorageRequest {
+    pub fn new(seid: &str, auth_code: &str) -> AuthCodeStorageRequest {
+        AuthCodeStorageRequest {
+            seid: seid.to_string(),
+            auth_code: auth_code.to_string(),
+        }
+    }
+}
+
+request!(
+    AuthCodeStorageRequest,
+    IgnoredAny,
+    constants::TSM_ACTION_AUTHCODE_STORAGE
+);
+
+#[cfg(all(test, feature = "simulator"))]
+mod test {
+    use crate::device_manager;
+    use crate::tsm::{
+        AuthCodeStorageRequest, CosCheckUpdateRequest, SeQueryRequest, SeSecureCheckRequest,
+        TsmClient,
+    };
+    use crate::TsmService;
+    use futures::executor::block_on;
+    use ikc_common::constants;
+    use ikc_simulator::simulator::{
+        Simulator, SIMULATOR_COS_VERSION, SIMULATOR_SEID, SIMULATOR_SN,
+    };
+    use ikc_simulator::tsm::MockTsm;
+    use ikc_transport::device::Device;
+    use std::rc::Rc;
+
+    fn tsm() -> (Rc<MockTsm>, TsmClient) {
+        let server = Rc::new(MockTsm::new());
+        let client = TsmClient::with_endpoint("https://tsm.test/imkey/", server.clone());
+        (server, client)
+    }
+
+    #[test]
+    fn post_test() {
+        let (server, client) = tsm();
+        assert_eq!(client.endpoint(), "https://tsm.test/imkey");
+
+        let mut request = SeQueryRequest::new(SIMULATOR_SEID, SIMULATOR_SN);
+        let response = block_on(request.send_message(&client)).unwrap();
+        assert_eq!(response.seid, SIMULATOR_SEID);
+        assert_eq!(response.status, constants::IMKEY_DEV_STATUS_LATEST);
+        assert!(!response.available_app_list.is_empty());
+
+        let mut request = CosCheckUpdateRequest::new(SIMULATOR_SEID, SIMULATOR_COS_VERSION);
+        let response = block_on(request.send_message(&client)).unwrap();
+        assert!(response.is_latest);
+
+        let mut request = AuthCodeStorageRequest::new(SIMULATOR_SEID, "00AA");
+        block_on(request.send_message(&client)).unwrap();
+
+        let (action, body) = server.requests().last().cloned().unwrap();
+        assert_eq!(action, constants::TSM_ACTION_AUTHCODE_STORAGE);
+        assert_eq!(body["authCode"], "00AA");
+    }
+
+    #[test]
+    fn return_code_test() {
+        let (server, client) = tsm();
+        server.set_return_code(
+            constants::TSM_ACTION_SE_QUERY,
+            constants::TSM_RETURNCODE_DEVICE_STOP_USING,
+        );
+        let mut request = SeQueryRequest::new(SIMULATOR_SEID, SIMULATOR_SN);
+        let result = block_on(request.send_message(&client));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_device_stop_using"
+        );
+
+        //unknown action
+        let result: crate::Result<SeQueryRequest> =
+            block_on(client.post("/unknownAction", &SeQueryRequest::new("", "")));
+        assert_eq!(result.err().unwrap().to_string(), "imkey_tsm_server_error");
+    }
+
+    #[test]
+    fn endless_script_test() {
+        let (server, client) = tsm();
+        server.set_endless(constants::TSM_ACTION_SE_SECURE_CHECK);
+        let device = Device::new(Simulator::new());
+        let mut request = SeSecureCheckRequest::new(SIMULATOR_SEID, SIMULATOR_SN, "7F21");
+        let result = block_on(client.run_script(&device, &mut request));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_too_many_steps"
+        );
+        assert_eq!(server.requests().len(), constants::TSM_MAX_SCRIPT_STEPS);
+    }
+
+    #[test]
+    fn run_script_test() {
+        let (server, client) = tsm();
+        let device = Device::new(Simulator::new());
+        let mut request = SeSecureCheckRequest::new(SIMULATOR_SEID, SIMULATOR_SN, "7F21");
+        block_on(client.run_script(&device, &mut request)).unwrap();
+        assert_eq!(request.step.step_key, "01");
+        assert_eq!(request.step.status_word.as_deref(), Some("9000"));
+
+        //every step went to the server, the last one with the card results
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].1["stepKey"], "");
+        assert_eq!(requests[0].1["commandID"], "/seSecureCheck");
+        assert_eq!(
+            requests[1].1["cardRetDataList"][1],
+            format!("{}9000", SIMULATOR_SEID)
+        );
+
+        //the script checks the SEID read from the device
+        let mut request = SeSecureCheckRequest::new("1906", SIMULATOR_SN, "7F21");
+        let result = block_on(client.run_script(&device, &mut request));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_tsm_device_authenticity_check_fail"
+        );
+
+        //the device is left on the ISD
+        let seid = block_on(device_manager::get_se_id(&device)).unwrap();
+        assert_eq!(seid, SIMULATOR_SEID);
+    }
+}
@@ -0,0 +1,505 @@
+use crate::command::{Command, Reply};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::hashes::{sha256, sha256d, Hash};
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::{Network, PackedLockTime, Transaction, TxIn, TxOut};
+use ikc_common::constants::{
+    APDU_CONDITIONS_NOT_SATISFIED, APDU_RSP_APPLET_WRONG_DATA, APDU_RSP_FUNCTION_NOT_SUPPORTED,
+    APDU_RSP_INCORRECT_P1P2, APDU_RSP_SIGNATURE_VERIFY_FAILED, BTC_PSBT_TRX_PER_PAGE_NUMBER,
+    SECP256K1_ENGINE,
+};
+use secp256k1::ecdsa::Signature;
+use secp256k1::{KeyPair, Message, PublicKey, Scalar};
+use std::convert::TryInto;
+use std::io::Cursor;
+use std::str::FromStr;
+
+/**
+Transaction data confirmed by the user on the preview screen
+*/
+struct Preview {
+    version: i32,
+    input_count: usize,
+    lock_time: u32,
+    //empty for psbt, whose outputs are only shown page by page
+    outputs: Vec<TxOut>,
+    //single sha256 of the serialized outputs
+    output_hash: Vec<u8>,
+    display_number: u16,
+}
+
+/**
+BTC applet: derives keys from the wallet seed and signs legacy, segwit and taproot inputs
+of a previewed transaction
+*/
+pub struct BtcApplet {
+    master_key: ExtendedPrivKey,
+    buffer: Vec<u8>,
+    buffer_key: Option<(u8, u8)>,
+    preview: Option<Preview>,
+    prevouts: Vec<u8>,
+    sequences: Vec<u8>,
+    amounts: Vec<u8>,
+    script_pubkeys: Vec<u8>,
+    inputs: Vec<TxIn>,
+    //outputs sent along with the inputs when signing a psbt p2pkh input
+    input_outputs: Option<Vec<TxOut>>,
+}
+
+impl BtcApplet {
+    pub fn new(seed: &[u8]) -> BtcApplet {
+        BtcApplet {
+            master_key: ExtendedPrivKey::new_master(Network::Bitcoin, seed)
+                .expect("invalid wallet seed"),
+            buffer: vec![],
+            buffer_key: None,
+            preview: None,
+            prevouts: vec![],
+            sequences: vec![],
+            amounts: vec![],
+            script_pubkeys: vec![],
+            inputs: vec![],
+            input_outputs: None,
+        }
+    }
+
+    pub fn process(&mut self, command: &Command, host_key: Option<&PublicKey>) -> Reply {
+        match command.ins {
+            0x43 => self.get_xpub(command.data),
+            //register address, the device only displays it
+            0x36 | 0x37 => Ok(vec![]),
+            0x41 if command.p1 == 0x80 => self.prepare_input(command.data),
+            0x41 | 0x34 if command.p1 == 0x00 => match self.collect(command) {
+                Some(data) => self.tx_preview(&verify_preview(&data, host_key)?),
+                None => Ok(vec![]),
+            },
+            0x44 => self.tx_preview(&verify_preview(command.data, host_key)?),
+            0x31 | 0x34 => match self.collect(command) {
+                Some(data) => self.hash_prepare(command.p1, data),
+                None => Ok(vec![]),
+            },
+            0x4B => match self.collect(command) {
+                Some(data) => self.psbt_preview(&verify_preview(&data, host_key)?),
+                None => Ok(vec![]),
+            },
+            0x4C => self.psbt_page(command.p2, &verify_preview(command.data, host_key)?),
+            0x46 | 0x50 => {
+                if command.p1 == 0x00 {
+                    self.buffer_key = None;
+                }
+                let command = Command {
+                    p1: 0x00,
+                    ..*command
+                };
+                match self.collect(&command) {
+                    Some(data) => self.sign_prepare(command.ins == 0x50, &data),
+                    None => Ok(vec![]),
+                }
+            }
+            0x42 | 0x45 => self.legacy_sign(command),
+            0x32 => self.segwit_sign(command),
+            0x40 => self.taproot_sign(command),
+            _ => Err(APDU_RSP_FUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    /**
+    gather chained commands, return the whole data once the last one (p2 0x80) arrives
+    */
+    fn collect(&mut self, command: &Command) -> Option<Vec<u8>> {
+        let key = (command.ins, command.p1);
+        if self.buffer_key != Some(key) {
+            self.buffer.clear();
+            self.buffer_key = Some(key);
+        }
+        self.buffer.extend_from_slice(command.data);
+        if command.p2 & 0x80 == 0 {
+            return None;
+        }
+        self.buffer_key = None;
+        Some(std::mem::take(&mut self.buffer))
+    }
+
+    fn derive(&self, path: &[u8]) -> Result<ExtendedPrivKey, &'static str> {
+        let path = std::str::from_utf8(path)
+            .ok()
+            .and_then(|path| DerivationPath::from_str(path).ok())
+            .ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+        self.master_key
+            .derive_priv(&SECP256K1_ENGINE, &path)
+            .map_err(|_| APDU_RSP_APPLET_WRONG_DATA)
+    }
+
+    fn get_xpub(&self, path: &[u8]) -> Reply {
+        let key = self.derive(path)?;
+        let public_key = PublicKey::from_secret_key(&SECP256K1_ENGINE, &key.private_key);
+        let mut response = public_key.serialize_uncompressed().to_vec();
+        response.extend_from_slice(key.chain_code.as_bytes());
+        Ok(response)
+    }
+
+    fn tx_preview(&mut self, data: &[u8]) -> Reply {
+        let mut cursor = Cursor::new(data);
+        let version: i32 = decode(&mut cursor)?;
+        let input_count: u8 = decode(&mut cursor)?;
+        let outputs: Vec<TxOut> = decode(&mut cursor)?;
+        let lock_time: u32 = decode(&mut cursor)?;
+
+        let output_hash = outputs_hash(&outputs);
+        self.inputs = vec![TxIn::default(); input_count as usize];
+        self.input_outputs = None;
+        self.preview = Some(Preview {
+            version,
+            input_count: input_count as usize,
+            lock_time,
+            outputs,
+            output_hash,
+            display_number: 0,
+        });
+        Ok(vec![])
+    }
+
+    fn psbt_preview(&mut self, data: &[u8]) -> Reply {
+        //version(4) input number(1) lock time(4) hash type(4) amount(8) fee(8) output hash(32) display number(2)
+        if data.len() != 63 {
+            return Err(APDU_RSP_APPLET_WRONG_DATA);
+        }
+        let mut cursor = Cursor::new(data);
+        let version: i32 = decode(&mut cursor)?;
+        let input_count: u8 = decode(&mut cursor)?;
+        let lock_time: u32 = decode(&mut cursor)?;
+
+        self.inputs = vec![TxIn::default(); input_count as usize];
+        self.input_outputs = None;
+        self.preview = Some(Preview {
+            version,
+            input_count: input_count as usize,
+            lock_time,
+            outputs: vec![],
+            output_hash: data[29..61].to_vec(),
+            display_number: u16::from_be_bytes([data[61], data[62]]),
+        });
+        Ok(vec![])
+    }
+
+    /**
+    show one page of psbt outputs, answer the index of the next page if any is left
+    */
+    fn psbt_page(&self, p2: u8, data: &[u8]) -> Reply {
+        let preview = self.preview.as_ref().ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        //message signing has no output to show
+        if p2 == 0x80 {
+            return Ok(vec![]);
+        }
+        if data.len() < 4 {
+            return Err(APDU_RSP_APPLET_WRONG_DATA);
+        }
+        let next_index = u16::from_be_bytes([data[2], data[3]]) as usize + 1;
+        if next_index >= preview.display_number as usize {
+            return Ok(vec![]);
+        }
+        let next_page = (next_index / BTC_PSBT_TRX_PER_PAGE_NUMBER) as u16;
+        Ok(next_page.to_be_bytes().to_vec())
+    }
+
+    fn hash_prepare(&mut self, p1: u8, data: Vec<u8>) -> Reply {
+        match p1 {
+            0x40 => self.prevouts = data,
+            0x80 => self.sequences = data,
+            0x20 => self.amounts = data,
+            0x21 => self.script_pubkeys = data,
+            _ => return Err(APDU_RSP_INCORRECT_P1P2),
+        }
+        Ok(vec![])
+    }
+
+    /**
+    store one input of a legacy transaction: index(1) followed by the serialized input
+    */
+    fn prepare_input(&mut self, data: &[u8]) -> Reply {
+        if self.preview.is_none() {
+            return Err(APDU_CONDITIONS_NOT_SATISFIED);
+        }
+        let index = *data.first().ok_or(APDU_RSP_APPLET_WRONG_DATA)? as usize;
+        let input: TxIn = decode(&mut Cursor::new(&data[1..]))?;
+        *self
+            .inputs
+            .get_mut(index)
+            .ok_or(APDU_RSP_APPLET_WRONG_DATA)? = input;
+        Ok(vec![])
+    }
+
+    /**
+    store all inputs of a legacy transaction, psbt also sends the outputs after them
+    */
+    fn sign_prepare(&mut self, with_outputs: bool, data: &[u8]) -> Reply {
+        let input_count = self
+            .preview
+            .as_ref()
+            .ok_or(APDU_CONDITIONS_NOT_SATISFIED)?
+            .input_count;
+        let mut cursor = Cursor::new(data);
+        let mut inputs = vec![];
+        for _ in 0..input_count {
+            inputs.push(decode(&mut cursor)?);
+        }
+        self.input_outputs = if with_outputs {
+            Some(decode(&mut cursor)?)
+        } else {
+            None
+        };
+        self.inputs = inputs;
+        Ok(vec![])
+    }
+
+    fn legacy_sign(&mut self, command: &Command) -> Reply {
+        let preview = self.preview.as_ref().ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        let index = command.p1 as usize;
+        let script = &self
+            .inputs
+            .get(index)
+            .ok_or(APDU_RSP_INCORRECT_P1P2)?
+            .script_sig;
+        let transaction = Transaction {
+            version: preview.version,
+            lock_time: PackedLockTime(preview.lock_time),
+            input: self.inputs.clone(),
+            output: self
+                .input_outputs
+                .clone()
+                .unwrap_or_else(|| preview.outputs.clone()),
+        };
+        let sighash = transaction.signature_hash(index, script, command.p2 as u32);
+        self.ecdsa_sign(&sighash[..], command.data)
+    }
+
+    fn segwit_sign(&mut self, command: &Command) -> Reply {
+        let preview = self.preview.as_ref().ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        //length(1) txhash vout, script code, amount, sequence, path length(1) path
+        let data = command.data;
+        let input_end = *data.first().ok_or(APDU_RSP_APPLET_WRONG_DATA)? as usize + 1;
+        let path_len = *data.get(input_end).ok_or(APDU_RSP_APPLET_WRONG_DATA)? as usize;
+        let path = data
+            .get(input_end + 1..input_end + 1 + path_len)
+            .ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+
+        //bip143 signature message
+        let mut message = serialize(&preview.version);
+        message.extend_from_slice(&sha256d::Hash::hash(&self.prevouts)[..]);
+        message.extend_from_slice(&sha256d::Hash::hash(&self.sequences)[..]);
+        message.extend_from_slice(&data[1..input_end]);
+        message.extend_from_slice(&sha256::Hash::hash(&preview.output_hash)[..]);
+        message.extend(serialize(&preview.lock_time));
+        message.extend(serialize(&(command.p2 as u32)));
+
+        let response = self.ecdsa_sign(&sha256d::Hash::hash(&message)[..], path)?;
+        self.finish(command.p1);
+        Ok(response)
+    }
+
+    fn taproot_sign(&mut self, command: &Command) -> Reply {
+        let preview = self.preview.as_ref().ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        //epoch(1) hash type(1) lock time(4) spend type(1) input index(4)
+        let data = command.data;
+        if data.len() < 11 || data[0] != 0x00 || data[1] > 0x01 {
+            return Err(APDU_RSP_APPLET_WRONG_DATA);
+        }
+        let spend_type = data[6];
+        let mut cursor = 11;
+        //leaf hash, key version and code separator position of a script path spend
+        let extension = if spend_type & 0x02 != 0 {
+            let len = *data.get(cursor).ok_or(APDU_RSP_APPLET_WRONG_DATA)? as usize;
+            cursor += len + 1;
+            data.get(cursor - len..cursor)
+                .ok_or(APDU_RSP_APPLET_WRONG_DATA)?
+        } else {
+            &[]
+        };
+        let path = read_lv(data, &mut cursor)?;
+        let tweak = read_lv(data, &mut cursor)?;
+
+        //bip341 signature message
+        let mut message = data[..2].to_vec();
+        message.extend(serialize(&preview.version));
+        message.extend_from_slice(&data[2..6]);
+        message.extend_from_slice(&sha256::Hash::hash(&self.prevouts)[..]);
+        message.extend_from_slice(&sha256::Hash::hash(&self.amounts)[..]);
+        message.extend_from_slice(&sha256::Hash::hash(&self.script_pubkeys)[..]);
+        message.extend_from_slice(&sha256::Hash::hash(&self.sequences)[..]);
+        message.extend_from_slice(&preview.output_hash);
+        message.extend_from_slice(&data[6..11]);
+        message.extend_from_slice(extension);
+        let sighash = tagged_hash("TapSighash", &message);
+
+        let key = self.derive(path)?;
+        let mut key_pair = KeyPair::from_secret_key(&SECP256K1_ENGINE, &key.private_key);
+        //key path spends sign with the output key, script path spends with the internal key
+        if command.p2 == 0x00 {
+            let tweak: [u8; 32] = tweak.try_into().map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+            let tweak = Scalar::from_be_bytes(tweak).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+            key_pair = key_pair
+                .add_xonly_tweak(&SECP256K1_ENGINE, &tweak)
+                .map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+        }
+        let message = Message::from_slice(&sighash).unwrap();
+        let signature = SECP256K1_ENGINE.sign_schnorr_no_aux_rand(&message, &key_pair);
+
+        let mut response = vec![0x40];
+        response.extend_from_slice(signature.as_ref());
+        self.finish(command.p1);
+        Ok(response)
+    }
+
+    /**
+    sign a hash with the key at path, answer length(1) r s(64) recovery id(1)
+    */
+    fn ecdsa_sign(&self, hash: &[u8], path: &[u8]) -> Reply {
+        let key = self.derive(path)?;
+        let message = Message::from_slice(hash).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+        let (recovery_id, signature) = SECP256K1_ENGINE
+            .sign_ecdsa_recoverable(&message, &key.private_key)
+            .serialize_compact();
+        let mut response = vec![0x41];
+        response.extend_from_slice(&signature);
+        response.push(recovery_id.to_i32() as u8);
+        Ok(response)
+    }
+
+    /**
+    the last input is signed (p1 0x80), a new transaction needs a new preview
+    */
+    fn finish(&mut self, p1: u8) {
+        if p1 & 0x80 != 0 {
+            self.preview = None;
+            self.inputs.clear();
+            self.input_outputs = None;
+        }
+    }
+}
+
+/**
+check the preview data signed by the bound host key: 00 len signature 01 len data
+*/
+fn verify_preview(data: &[u8], host_key: Option<&PublicKey>) -> Reply {
+    let host_key = host_key.ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+    if data.len() < 2 || data[0] != 0x00 {
+        return Err(APDU_RSP_APPLET_WRONG_DATA);
+    }
+    let signature_end = 2 + data[1] as usize;
+    let signed_data = data
+        .get(signature_end..)
+        .filter(|signed_data| signed_data.len() >= 2 && signed_data[0] == 0x01)
+        .ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+
+    let signature = Signature::from_der(&data[2..signature_end])
+        .map_err(|_| APDU_RSP_SIGNATURE_VERIFY_FAILED)?;
+    let message = Message::from_slice(&sha256d::Hash::hash(signed_data)[..]).unwrap();
+    SECP256K1_ENGINE
+        .verify_ecdsa(&message, &signature, host_key)
+        .map_err(|_| APDU_RSP_SIGNATURE_VERIFY_FAILED)?;
+    Ok(signed_data[2..].to_vec())
+}
+
+fn decode<T: Decodable>(cursor: &mut Cursor<&[u8]>) -> Result<T, &'static str> {
+    T::consensus_decode(cursor).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)
+}
+
+fn read_lv<'a>(data: &'a [u8], cursor: &mut usize) -> Result<&'a [u8], &'static str> {
+    let len = *data.get(*cursor).ok_or(APDU_RSP_APPLET_WRONG_DATA)? as usize;
+    let value = data
+        .get(*cursor + 1..*cursor + 1 + len)
+        .ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+    *cursor += len + 1;
+    Ok(value)
+}
+
+fn outputs_hash(outputs: &[TxOut]) -> Vec<u8> {
+    let mut data = vec![];
+    for output in outputs {
+        data.extend(serialize(output));
+    }
+    sha256::Hash::hash(&data).to_vec()
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> Vec<u8> {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut message = tag_hash.to_vec();
+    message.extend_from_slice(&tag_hash[..]);
+    message.extend_from_slice(data);
+    sha256::Hash::hash(&message).to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::btc::BtcApplet;
+    use crate::command::Command;
+    use bitcoin::{Address, Network, PublicKey};
+    use ikc_common::constants::SECP256K1_ENGINE;
+    use ikc_common::utility::secp256k1_sign;
+    use secp256k1::SecretKey;
+
+    const SEED: &str = "ee3fce3ccf05a2b58c851e321077a63ee2113235112a16fc783dc16279ff818a549ff735ac4406c624235db2d37108e34c6cbe853cbe09eb9e2369e6dd1c5aaa";
+
+    fn apdu(ins: u8, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x80, ins, p1, p2, data.len() as u8];
+        apdu.extend(data);
+        apdu.push(0x00);
+        apdu
+    }
+
+    #[test]
+    fn get_xpub_test() {
+        let mut applet = BtcApplet::new(&hex::decode(SEED).unwrap());
+        let apdu = apdu(0x43, 0x00, 0x00, b"m/44'/0'/0'/0/0");
+        let response = applet
+            .process(&Command::parse(&apdu).unwrap(), None)
+            .unwrap();
+        assert_eq!(response.len(), 97);
+
+        let mut public_key = PublicKey::from_slice(&response[..65]).unwrap();
+        public_key.compressed = true;
+        assert_eq!(
+            Address::p2pkh(&public_key, Network::Bitcoin).to_string(),
+            "12z6UzsA3tjpaeuvA2Zr9jwx19Azz74D6g"
+        );
+    }
+
+    #[test]
+    fn tx_preview_verify_test() {
+        let mut applet = BtcApplet::new(&hex::decode(SEED).unwrap());
+        let host_private_key = [0x11u8; 32];
+        let host_key = secp256k1::PublicKey::from_secret_key(
+            &SECP256K1_ENGINE,
+            &SecretKey::from_slice(&host_private_key).unwrap(),
+        );
+
+        //version, input number, no output, lock time, hash type, fee, address version
+        let mut preview = hex::decode("01").unwrap();
+        preview.push(0x00);
+        preview.extend(hex::decode("0100000001000000000000000001000000000000271000").unwrap());
+        preview[1] = preview.len() as u8 - 2;
+        let mut data = secp256k1_sign(&host_private_key, &preview).unwrap();
+        data.insert(0, data.len() as u8);
+        data.insert(0, 0x00);
+        data.extend(preview.iter());
+
+        let command = apdu(0x41, 0x00, 0x80, &data);
+        let command = Command::parse(&command).unwrap();
+        assert_eq!(applet.process(&command, None), Err("6985"));
+        assert_eq!(applet.process(&command, Some(&host_key)), Ok(vec![]));
+
+        let other_key = secp256k1::PublicKey::from_secret_key(
+            &SECP256K1_ENGINE,
+            &SecretKey::from_slice(&[0x22u8; 32]).unwrap(),
+        );
+        assert_eq!(applet.process(&command, Some(&other_key)), Err("6942"));
+    }
+
+    #[test]
+    fn sign_without_preview_test() {
+        let mut applet = BtcApplet::new(&hex::decode(SEED).unwrap());
+        let apdu = apdu(0x45, 0x00, 0x01, b"m/44'/0'/0'/0/0");
+        let response = applet.process(&Command::parse(&apdu).unwrap(), None);
+        assert_eq!(response, Err("6985"));
+    }
+}
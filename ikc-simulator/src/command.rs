@@ -0,0 +1,87 @@
+use ikc_common::constants::APDU_RSP_SUCCESS;
+
+/**
+response data of a handled command, or the status word it failed with
+*/
+pub type Reply = std::result::Result<Vec<u8>, &'static str>;
+
+/**
+A command APDU as seen by the applets: header fields and the Lc bytes of data
+*/
+pub struct Command<'a> {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(apdu: &'a [u8]) -> Option<Command<'a>> {
+        if apdu.len() < 4 {
+            return None;
+        }
+        //a single byte after the header is either Lc=0 or Le,
+        //a zero byte followed by more data starts an extended length Lc
+        let data = match apdu.get(4) {
+            Some(&0) if apdu.len() > 7 => {
+                let lc = u16::from_be_bytes([apdu[5], apdu[6]]) as usize;
+                apdu.get(7..7 + lc)?
+            }
+            Some(&lc) if apdu.len() > 5 => apdu.get(5..5 + lc as usize)?,
+            _ => &[],
+        };
+        Some(Command {
+            cla: apdu[0],
+            ins: apdu[1],
+            p1: apdu[2],
+            p2: apdu[3],
+            data,
+        })
+    }
+}
+
+/**
+build the raw response: data followed by the status word
+*/
+pub fn to_response(reply: Reply) -> Vec<u8> {
+    let (mut response, status) = match reply {
+        Ok(data) => (data, APDU_RSP_SUCCESS),
+        Err(status) => (vec![], status),
+    };
+    response.extend(hex::decode(status).expect("invalid status word"));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::{to_response, Command};
+
+    #[test]
+    fn parse_test() {
+        let apdu = hex::decode("00A4040005695F62746300").unwrap();
+        let command = Command::parse(&apdu).unwrap();
+        assert_eq!(command.ins, 0xA4);
+        assert_eq!(command.data, hex::decode("695F627463").unwrap().as_slice());
+
+        let command = Command::parse(&[0x00, 0xA4, 0x04, 0x00, 0x00]).unwrap();
+        assert!(command.data.is_empty());
+
+        assert!(Command::parse(&[0x80, 0x43, 0x00, 0x00, 0x05, 0x6D]).is_none());
+        assert!(Command::parse(&[0x80, 0x43]).is_none());
+
+        let mut apdu = hex::decode("804C00000001F4").unwrap();
+        apdu.extend([0x11; 500]);
+        apdu.extend([0x00, 0x00]);
+        let command = Command::parse(&apdu).unwrap();
+        assert_eq!(command.ins, 0x4C);
+        assert_eq!(command.data, [0x11; 500].as_slice());
+        assert!(Command::parse(&apdu[..100]).is_none());
+    }
+
+    #[test]
+    fn to_response_test() {
+        assert_eq!(to_response(Ok(vec![0x01])), vec![0x01, 0x90, 0x00]);
+        assert_eq!(to_response(Err("6A80")), vec![0x6A, 0x80]);
+    }
+}
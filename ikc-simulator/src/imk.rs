@@ -0,0 +1,243 @@
+use crate::command::{Command, Reply};
+use crate::simulator::SIMULATOR_CA_ID;
+use bitcoin::hashes::{sha256, Hash};
+use ikc_common::aes::cbc::encrypt_pkcs7;
+use ikc_common::constants::{
+    APDU_CONDITIONS_NOT_SATISFIED, APDU_RSP_APPLET_WRONG_DATA, APDU_RSP_FUNCTION_NOT_SUPPORTED,
+    APDU_RSP_INCORRECT_P1P2, SECP256K1_ENGINE,
+};
+use ikc_common::tlv::Tlv;
+use ikc_common::utility::sha256_hash;
+use rand::Rng;
+use secp256k1::{ecdh, Message, PublicKey, SecretKey};
+use sha1::Sha1;
+
+//private key of the simulated SE, its public key is published in the SE certificate
+const SE_PRIVATE_KEY: &str = "a14162753ed001ab536e0b8b002fa73033a6276ba95cb7d077c465fa7eed23c8";
+//private key of the simulated certificate authority signing the SE certificate
+const CA_PRIVATE_KEY: &str = "5270d604c8322766ff558965c0ac8533a45b4618fc5ecc4ed062c2333549c602";
+//characters of a binding code, same set as the host side check
+const BIND_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const BIND_STATUS_UNBOUND: u8 = 0x00;
+const BIND_STATUS_BOUND_THIS: u8 = 0x55;
+const BIND_STATUS_BOUND_OTHER: u8 = 0xAA;
+const BIND_RESULT_SUCCESS: u8 = 0x5A;
+const BIND_RESULT_ERROR: u8 = 0xA5;
+
+/**
+IMK applet: binds one host key to the device after the user types the displayed code
+*/
+pub struct ImkApplet {
+    seid: Vec<u8>,
+    se_key: SecretKey,
+    bound_key: Option<PublicKey>,
+    bind_code: Option<String>,
+}
+
+impl ImkApplet {
+    pub fn new(seid: &[u8]) -> ImkApplet {
+        ImkApplet {
+            seid: seid.to_vec(),
+            se_key: SecretKey::from_slice(&hex::decode(SE_PRIVATE_KEY).unwrap()).unwrap(),
+            bound_key: None,
+            bind_code: None,
+        }
+    }
+
+    /**
+    host public key bound to this device, used to check signed preview data
+    */
+    pub fn bound_key(&self) -> Option<&PublicKey> {
+        self.bound_key.as_ref()
+    }
+
+    /**
+    ECDH session key shared with the bound host, the key of its secure channels
+    */
+    pub fn bound_session_key(&self) -> Option<Vec<u8>> {
+        self.bound_key.map(|host_key| self.session_key(&host_key))
+    }
+
+    pub fn bind(&mut self, host_key: PublicKey) {
+        self.bound_key = Some(host_key);
+        self.bind_code = None;
+    }
+
+    pub fn bind_code(&self) -> Option<String> {
+        self.bind_code.clone()
+    }
+
+    pub fn process(&mut self, command: &Command) -> Reply {
+        match command.ins {
+            0x71 => self.bind_check(command.data),
+            0x72 => self.generate_bind_code(),
+            0x73 if command.p1 != 0x80 => Err(APDU_RSP_INCORRECT_P1P2),
+            0x73 => self.identity_verify(command.data),
+            _ => Err(APDU_RSP_FUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    fn bind_check(&mut self, data: &[u8]) -> Reply {
+        let host_key = PublicKey::from_slice(data).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+        let status = match self.bound_key {
+            Some(bound_key) if bound_key == host_key => BIND_STATUS_BOUND_THIS,
+            Some(_) => BIND_STATUS_BOUND_OTHER,
+            None => BIND_STATUS_UNBOUND,
+        };
+        let mut response = vec![status];
+        response.extend(self.se_cert());
+        Ok(response)
+    }
+
+    fn generate_bind_code(&mut self) -> Reply {
+        let mut rng = rand::thread_rng();
+        let bind_code = (0..8)
+            .map(|_| BIND_CODE_CHARS[rng.gen_range(0..BIND_CODE_CHARS.len())] as char)
+            .collect();
+        self.bind_code = Some(bind_code);
+        Ok(vec![])
+    }
+
+    fn identity_verify(&mut self, data: &[u8]) -> Reply {
+        let bind_code = self
+            .bind_code
+            .clone()
+            .ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        if data.len() <= 65 {
+            return Err(APDU_RSP_APPLET_WRONG_DATA);
+        }
+        let host_key =
+            PublicKey::from_slice(&data[..65]).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+
+        //the host encrypts hash(bind code | host key | se key) with the ecdh session key
+        let se_pub_key = PublicKey::from_secret_key(&SECP256K1_ENGINE, &self.se_key);
+        let mut hash_data = bind_code.as_bytes().to_vec();
+        hash_data.extend(host_key.serialize_uncompressed().iter());
+        hash_data.extend(se_pub_key.serialize_uncompressed().iter());
+        let expected = encrypt_pkcs7(
+            &sha256_hash(&hash_data),
+            &self.session_key(&host_key),
+            &bind_code_iv(&bind_code),
+        )
+        .map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+
+        if expected != data[65..] {
+            return Ok(vec![BIND_RESULT_ERROR]);
+        }
+        self.bound_key = Some(host_key);
+        self.bind_code = None;
+        Ok(vec![BIND_RESULT_SUCCESS])
+    }
+
+    fn session_key(&self, host_key: &PublicKey) -> Vec<u8> {
+        let shared_secret = ecdh::shared_secret_point(host_key, &self.se_key);
+        Sha1::from(&shared_secret[..32]).digest().bytes()[..16].to_vec()
+    }
+
+    /**
+    SE certificate in GlobalPlatform format (7F21), signed by the simulator CA key
+    */
+    pub fn se_cert(&self) -> Vec<u8> {
+        let se_pub_key = PublicKey::from_secret_key(&SECP256K1_ENGINE, &self.se_key);
+        let public_key = Tlv::constructed(
+            &[0x7F, 0x49],
+            &[
+                Tlv::new(&[0xB0], &se_pub_key.serialize_uncompressed()),
+                Tlv::new(&[0xF0], &[0x00]),
+            ],
+        );
+        let mut body = vec![
+            Tlv::new(&[0x93], &self.seid),
+            Tlv::new(&[0x42], SIMULATOR_CA_ID.as_bytes()),
+            Tlv::new(&[0x5F, 0x20], &self.seid),
+            Tlv::new(&[0x95], &[0x00, 0x80]),
+            Tlv::new(&[0x5F, 0x25], &[0x20, 0x19, 0x06, 0x01]),
+            Tlv::new(&[0x5F, 0x24], &[0x20, 0x49, 0x06, 0x01]),
+            public_key,
+        ];
+        let signed_data: Vec<u8> = body.iter().flat_map(Tlv::encode).collect();
+
+        let ca_key = SecretKey::from_slice(&hex::decode(CA_PRIVATE_KEY).unwrap()).unwrap();
+        let message = Message::from_slice(&sha256::Hash::hash(&signed_data)[..]).unwrap();
+        let signature = SECP256K1_ENGINE.sign_ecdsa(&message, &ca_key);
+        body.push(Tlv::new(&[0x5F, 0x37], &signature.serialize_compact()));
+        Tlv::constructed(&[0x7F, 0x21], &body).encode()
+    }
+}
+
+fn bind_code_iv(bind_code: &str) -> Vec<u8> {
+    let salt = sha256_hash("bindingCode".as_bytes());
+    let bind_code_hash = sha256_hash(bind_code.as_bytes());
+    bind_code_hash
+        .iter()
+        .zip(salt.iter())
+        .take(16)
+        .map(|(code, salt)| code ^ salt)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::Command;
+    use crate::imk::{ImkApplet, CA_PRIVATE_KEY};
+    use crate::simulator::SIMULATOR_CA_PUBLIC_KEY;
+    use ikc_common::constants::SECP256K1_ENGINE;
+    use secp256k1::{PublicKey, SecretKey};
+
+    fn host_key() -> Vec<u8> {
+        let secret_key = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        PublicKey::from_secret_key(&SECP256K1_ENGINE, &secret_key)
+            .serialize_uncompressed()
+            .to_vec()
+    }
+
+    #[test]
+    fn ca_public_key_test() {
+        let ca_key = SecretKey::from_slice(&hex::decode(CA_PRIVATE_KEY).unwrap()).unwrap();
+        let public_key = PublicKey::from_secret_key(&SECP256K1_ENGINE, &ca_key);
+        assert_eq!(
+            hex::encode_upper(public_key.serialize_uncompressed()),
+            SIMULATOR_CA_PUBLIC_KEY
+        );
+    }
+
+    #[test]
+    fn bind_check_unbound_test() {
+        let mut applet = ImkApplet::new(&[0x19, 0x06]);
+        let host_key = host_key();
+        let response = applet
+            .process(&Command::parse(&apdu(0x71, 0x00, &host_key)).unwrap())
+            .unwrap();
+        assert_eq!(response[0], 0x00);
+        assert!(hex::encode_upper(&response).contains("7F4946B041"));
+    }
+
+    #[test]
+    fn identity_verify_without_code_test() {
+        let mut applet = ImkApplet::new(&[0x19, 0x06]);
+        let mut data = host_key();
+        data.extend([0u8; 48].iter());
+        let response = applet.process(&Command::parse(&apdu(0x73, 0x80, &data)).unwrap());
+        assert_eq!(response, Err("6985"));
+    }
+
+    #[test]
+    fn generate_bind_code_test() {
+        let mut applet = ImkApplet::new(&[0x19, 0x06]);
+        assert!(applet.bind_code().is_none());
+        applet
+            .process(&Command::parse(&apdu(0x72, 0x00, &[])).unwrap())
+            .unwrap();
+        let bind_code = applet.bind_code().unwrap();
+        assert_eq!(bind_code.len(), 8);
+        assert!(!bind_code.contains('I') && !bind_code.contains('O'));
+    }
+
+    fn apdu(ins: u8, p1: u8, data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x80, ins, p1, 0x00, data.len() as u8];
+        apdu.extend(data);
+        apdu.push(0x00);
+        apdu
+    }
+}
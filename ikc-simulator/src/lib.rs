@@ -0,0 +1,10 @@
+mod btc;
+mod command;
+mod imk;
+mod secure;
+pub mod simulator;
+pub mod tsm;
+
+extern crate anyhow;
+use core::result;
+pub type Result<T> = result::Result<T, anyhow::Error>;
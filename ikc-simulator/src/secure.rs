@@ -0,0 +1,129 @@
+use ikc_common::apdu::{Command, Response};
+use ikc_common::secure_channel::{SessionKeys, CHALLENGE_LENGTH, CLA_SECURE_MESSAGING, MAC_LENGTH};
+use rand::RngCore;
+
+/**
+Card side of the secure channel: checks and decrypts the protected commands, encrypts and MACs
+their responses, errors included. Only the 6982 answering a wrong MAC is sent in clear.
+*/
+pub struct CardChannel {
+    keys: SessionKeys,
+    counter: u128,
+    chaining: [u8; 16],
+}
+
+impl CardChannel {
+    /**
+    start a session for the host challenge, return it with the card challenge and cryptogram
+    */
+    pub fn open(session_key: &[u8], host_challenge: &[u8]) -> Option<(CardChannel, Vec<u8>)> {
+        if host_challenge.len() != CHALLENGE_LENGTH {
+            return None;
+        }
+        let mut card_challenge = [0u8; CHALLENGE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut card_challenge);
+        let keys = SessionKeys::derive(session_key, host_challenge, &card_challenge).ok()?;
+        let mut response = card_challenge.to_vec();
+        response.extend(keys.card_cryptogram().ok()?);
+        let channel = CardChannel {
+            keys,
+            counter: 0,
+            chaining: [0u8; 16],
+        };
+        Some((channel, response))
+    }
+
+    /**
+    plain command APDU of a protected one, None if its MAC is wrong
+    */
+    pub fn unwrap_command(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let command = Command::decode(apdu).ok()?;
+        if command.data.len() < MAC_LENGTH {
+            return None;
+        }
+        let (data, mac) = command.data.split_at(command.data.len() - MAC_LENGTH);
+        let encrypted = Command::new(
+            command.cla,
+            command.ins,
+            command.p1,
+            command.p2,
+            data,
+            command.le,
+        );
+        let chaining = self.keys.command_mac(&self.chaining, &encrypted).ok()?;
+        if chaining[..MAC_LENGTH] != *mac {
+            return None;
+        }
+        self.chaining = chaining;
+        self.counter += 1;
+        let data = self.keys.decrypt_command(self.counter, data).ok()?;
+        let plain = Command::new(
+            command.cla & !CLA_SECURE_MESSAGING,
+            command.ins,
+            command.p1,
+            command.p2,
+            &data,
+            command.le,
+        );
+        plain.encode().ok()
+    }
+
+    pub fn wrap_response(&self, response: Vec<u8>) -> Vec<u8> {
+        let response = Response::decode(&response).expect("response without status word");
+        let mut data = self
+            .keys
+            .encrypt_response(self.counter, &response.data)
+            .expect("invalid session key");
+        let mac = self
+            .keys
+            .response_mac(&self.chaining, &data, response.sw)
+            .expect("invalid session key");
+        data.extend(&mac[..MAC_LENGTH]);
+        Response {
+            data,
+            sw: response.sw,
+        }
+        .encode()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::secure::CardChannel;
+    use ikc_common::apdu::{Command, Response};
+    use ikc_common::secure_channel::SecureChannel;
+
+    #[test]
+    fn round_trip_test() {
+        let session_key = [0x42u8; 16];
+        let host_challenge = [0x01u8; 8];
+        let (mut card, response) = CardChannel::open(&session_key, &host_challenge).unwrap();
+        let mut host = SecureChannel::open(&session_key, &host_challenge, &response).unwrap();
+
+        for _ in 0..2 {
+            let command = Command::new(0x80, 0x71, 0x00, 0x00, &[0x01, 0x02], Some(0x00));
+            let wrapped = host.wrap_command(&command).unwrap().encode().unwrap();
+            let plain = card.unwrap_command(&wrapped).unwrap();
+            assert_eq!(plain, command.encode().unwrap());
+
+            let response = card.wrap_response(vec![0x03, 0x04, 0x90, 0x00]);
+            let response = host
+                .unwrap_response(Response::decode(&response).unwrap())
+                .unwrap();
+            assert_eq!(response.data, vec![0x03, 0x04]);
+        }
+
+        //errors are MACed as well
+        let response = card.wrap_response(vec![0x6A, 0x80]);
+        let response = host
+            .unwrap_response(Response::decode(&response).unwrap())
+            .unwrap();
+        assert_eq!((response.data, response.sw), (vec![], 0x6A80));
+
+        //replaying a command is rejected once the MAC chain moved on
+        let command = Command::new(0x80, 0x71, 0x00, 0x00, &[], Some(0x00));
+        let wrapped = host.wrap_command(&command).unwrap().encode().unwrap();
+        assert!(card.unwrap_command(&wrapped).is_some());
+        assert!(card.unwrap_command(&wrapped).is_none());
+    }
+}
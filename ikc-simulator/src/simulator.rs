@@ -0,0 +1,582 @@
+use crate::btc::BtcApplet;
+use crate::command::{to_response, Command, Reply};
+use crate::imk::ImkApplet;
+use crate::secure::CardChannel;
+use crate::Result;
+use async_trait::async_trait;
+use ikc_common::constants::{
+    APDU_CONDITIONS_NOT_SATISFIED, APDU_RSP_APPLET_NOT_EXIST, APDU_RSP_APPLET_WRONG_DATA,
+    APDU_RSP_CLA_NOT_SUPPORTED, APDU_RSP_FUNCTION_NOT_SUPPORTED, APDU_RSP_INCORRECT_P1P2,
+    APDU_RSP_SECURITY_STATUS_NOT_SATISFIED, APDU_RSP_SWITCH_BL_STATUS_SUCCESS,
+    APDU_RSP_WRONG_LENGTH, BL_AID, BTC_AID, IMK_AID,
+};
+use ikc_common::secure_channel::CLA_SECURE_MESSAGING;
+use ikc_common::tlv::Tlv;
+use ikc_transport::error::TransportError;
+use ikc_transport::transport::Transport;
+use secp256k1::PublicKey;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SIMULATOR_SEID: &str = "19060000000200860001010000000014";
+pub const SIMULATOR_SN: &str = "imKey01191200001";
+pub const SIMULATOR_COS_VERSION: &str = "1.7.2";
+pub const SIMULATOR_BLE_NAME: &str = "imKeyPro";
+//certificate authority of the simulated SE certificate, only trusted by tests
+pub const SIMULATOR_CA_ID: &str = "imKeySimulatorCA";
+pub const SIMULATOR_CA_PUBLIC_KEY: &str = "0453A72F99AB92FF1F07A5BE5F0074F44B02D7CEAC3C7A4FE08A1DD1BA8B4365ED8F054367ED6F1A24E9F003686A798CB09B5C44B87B53063F67FDB86FE1A00C0E";
+const COS_VERSION: [u8; 3] = [0x01, 0x07, 0x02];
+const RAM_SIZE: u32 = 0x1800;
+const BATTERY_LEVEL: u8 = 80;
+//load files installed on a new device with their version
+const LOAD_FILES: &[(&str, [u8; 2])] = &[(IMK_AID, [0x01, 0x00]), (BTC_AID, [0x01, 0x05])];
+//install parameter carrying the version of the applet
+const TAG_APPLET_VERSION: u8 = 0xC9;
+/**
+BIP39 seed of the test mnemonic
+"inject kidney empty canal shadow pact comfort wife crush horse wife sketch"
+*/
+pub const SIMULATOR_SEED: &str = "ee3fce3ccf05a2b58c851e321077a63ee2113235112a16fc783dc16279ff818a549ff735ac4406c624235db2d37108e34c6cbe853cbe09eb9e2369e6dd1c5aaa";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Applet {
+    Isd,
+    Imk,
+    Btc,
+    //applet installed through the TSM, the simulator knows none of its commands
+    Other,
+}
+
+impl Applet {
+    fn is_secure_channel(self) -> bool {
+        self == Applet::Imk || self == Applet::Btc
+    }
+}
+
+struct LoadFile {
+    aid: Vec<u8>,
+    version: [u8; 2],
+}
+
+struct SimulatorState {
+    seid: Vec<u8>,
+    selected: Applet,
+    imk: ImkApplet,
+    btc: BtcApplet,
+    secure: Option<CardChannel>,
+    ble_name: String,
+    cos_version: [u8; 3],
+    //running the bootloader during a COS upgrade
+    bootloader: bool,
+    //re-enumerating on USB after switching to or from the bootloader
+    disconnected: bool,
+    load_files: Vec<LoadFile>,
+    next_load_file: usize,
+    //load file between INSTALL [for load] and INSTALL [for install]
+    loading: Option<Vec<u8>>,
+}
+
+/**
+Software imKey answering the ISD, IMK and BTC applet APDUs from a deterministic wallet seed.
+Every user confirmation is accepted, clones are handles on the same device.
+*/
+#[derive(Clone)]
+pub struct Simulator {
+    state: Rc<RefCell<SimulatorState>>,
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        Simulator::from_seed(&hex::decode(SIMULATOR_SEED).unwrap())
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Simulator {
+        let seid = hex::decode(SIMULATOR_SEID).unwrap();
+        let state = SimulatorState {
+            selected: Applet::Isd,
+            imk: ImkApplet::new(&seid),
+            seid,
+            btc: BtcApplet::new(seed),
+            secure: None,
+            ble_name: SIMULATOR_BLE_NAME.to_string(),
+            cos_version: COS_VERSION,
+            bootloader: false,
+            disconnected: false,
+            load_files: LOAD_FILES
+                .iter()
+                .map(|(aid, version)| LoadFile {
+                    aid: hex::decode(aid).unwrap(),
+                    version: *version,
+                })
+                .collect(),
+            next_load_file: 0,
+            loading: None,
+        };
+        Simulator {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /**
+    another device: same wallet, but its own SEID and binding
+    */
+    pub fn with_seid(seid: &str) -> Simulator {
+        let simulator = Simulator::new();
+        {
+            let mut state = simulator.state.borrow_mut();
+            state.seid = hex::decode(seid).unwrap();
+            state.imk = ImkApplet::new(&state.seid);
+        }
+        simulator
+    }
+
+    /**
+    bind the host key without the binding code exchange, for tests starting from a bound device
+    */
+    pub fn bind(&self, host_key: &[u8]) -> Result<()> {
+        let host_key = PublicKey::from_slice(host_key)?;
+        self.state.borrow_mut().imk.bind(host_key);
+        Ok(())
+    }
+
+    /**
+    binding code currently shown on the device screen
+    */
+    pub fn bind_code(&self) -> Option<String> {
+        self.state.borrow().imk.bind_code()
+    }
+
+    /**
+    process one command APDU, return the response data followed by the status word
+    */
+    pub fn process(&self, apdu: &[u8]) -> Vec<u8> {
+        self.state.borrow_mut().process(apdu)
+    }
+
+    /**
+    plug the device back after it re-enumerated, nothing is selected
+    */
+    pub fn reconnect(&self) {
+        let mut state = self.state.borrow_mut();
+        state.disconnected = false;
+        state.selected = Applet::Isd;
+        state.secure = None;
+    }
+
+    pub fn is_bootloader(&self) -> bool {
+        self.state.borrow().bootloader
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new()
+    }
+}
+
+impl SimulatorState {
+    fn process(&mut self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.first() == Some(&(0x80 | CLA_SECURE_MESSAGING)) {
+            return self.process_secure(apdu);
+        }
+        let command = match Command::parse(apdu) {
+            Some(command) => command,
+            None => return to_response(Err(APDU_RSP_WRONG_LENGTH)),
+        };
+        if self.bootloader {
+            return to_response(self.process_bootloader(&command));
+        }
+        //GET STATUS ends its data with 6310 while load files are left
+        if self.selected == Applet::Isd && command.cla == 0x80 && command.ins == 0xF2 {
+            return self.get_status(command.p2);
+        }
+        to_response(self.dispatch(&command))
+    }
+
+    /**
+    a command failing the secure channel check closes the channel, which only protects the
+    commands of the IMK and BTC applets
+    */
+    fn process_secure(&mut self, apdu: &[u8]) -> Vec<u8> {
+        if !self.selected.is_secure_channel() {
+            return to_response(Err(APDU_RSP_CLA_NOT_SUPPORTED));
+        }
+        let plain = self
+            .secure
+            .as_mut()
+            .and_then(|channel| channel.unwrap_command(apdu));
+        let plain = match plain {
+            Some(plain) => plain,
+            None => {
+                self.secure = None;
+                return to_response(Err(APDU_RSP_SECURITY_STATUS_NOT_SATISFIED));
+            }
+        };
+        let response = self.process(&plain);
+        match self.secure.as_ref() {
+            Some(channel) => channel.wrap_response(response),
+            None => response,
+        }
+    }
+
+    fn dispatch(&mut self, command: &Command) -> Reply {
+        match (command.cla, command.ins) {
+            (0x00, 0xA4) => return self.select(command.data),
+            (0x00, 0xD6) => return Ok(vec![BATTERY_LEVEL]),
+            (0xFF, 0xDB) => return Ok(self.ble_name.as_bytes().to_vec()),
+            (0xFF, 0xDA) => {
+                self.ble_name = String::from_utf8_lossy(command.data).to_string();
+                return Ok(vec![]);
+            }
+            _ => {}
+        }
+        if command.cla != 0x80 {
+            return Err(APDU_RSP_CLA_NOT_SUPPORTED);
+        }
+        match self.selected {
+            Applet::Isd => match command.ins {
+                0xCB => self.get_device_data(command.data),
+                0xCA if command.p1 == 0xBF && command.p2 == 0x21 => {
+                    let cert = Tlv::decode(&self.imk.se_cert()).unwrap().0;
+                    Ok(Tlv::constructed(&[0xBF, 0x21], &[cert]).encode())
+                }
+                0xCA => Ok(SIMULATOR_SN.as_bytes().to_vec()),
+                0xE6 => self.install(command.p1, command.data),
+                0xE8 => self.load(),
+                0xE4 => self.delete(command.data),
+                //SET STATUS of the ISD, sent by the TSM to activate the device
+                0xF0 if command.p1 == 0x80 => Ok(vec![]),
+                0x54 => {
+                    self.bootloader = true;
+                    self.disconnected = true;
+                    Err(APDU_RSP_SWITCH_BL_STATUS_SUCCESS)
+                }
+                _ => Err(APDU_RSP_FUNCTION_NOT_SUPPORTED),
+            },
+            Applet::Imk if command.ins == 0x74 => self.open_secure_channel(command.data),
+            Applet::Imk => self.imk.process(command),
+            Applet::Btc => self.btc.process(command, self.imk.bound_key()),
+            Applet::Other => Err(APDU_RSP_FUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    /**
+    DF FF 02 followed by the property, DF FF is read as a two bytes tag
+    */
+    fn get_device_data(&self, data: &[u8]) -> Reply {
+        match data {
+            [0xDF, 0xFF, 0x02, 0x81, 0x01] => Ok(self.seid.clone()),
+            [0xDF, 0xFF, 0x02, 0x80, 0x03] => Ok(self.cos_version.to_vec()),
+            [0xDF, 0xFF, 0x02, 0x81, 0x46] => Ok(RAM_SIZE.to_be_bytes().to_vec()),
+            _ => Err(APDU_RSP_APPLET_WRONG_DATA),
+        }
+    }
+
+    /**
+    The bootloader answers its SELECT, the SEID and SN, takes the COS image blocks (80 F6)
+    and the new version (80 F8), then restarts the COS
+    */
+    fn process_bootloader(&mut self, command: &Command) -> Reply {
+        match (command.cla, command.ins) {
+            (0x00, 0xA4) if hex::encode_upper(command.data) == BL_AID => Ok(vec![]),
+            (0x00, 0xA4) => Err(APDU_RSP_APPLET_NOT_EXIST),
+            (0x80, 0xCB) if command.data.ends_with(&[0x81, 0x01]) => {
+                self.get_device_data(command.data)
+            }
+            (0x80, 0xCA) => Ok(SIMULATOR_SN.as_bytes().to_vec()),
+            (0x80, 0xF6) => Ok(vec![]),
+            (0x80, 0xF8) => {
+                self.cos_version = match command.data {
+                    [major, minor, patch] => [*major, *minor, *patch],
+                    _ => return Err(APDU_RSP_APPLET_WRONG_DATA),
+                };
+                self.bootloader = false;
+                self.disconnected = true;
+                Ok(vec![])
+            }
+            (0x80, _) => Err(APDU_RSP_FUNCTION_NOT_SUPPORTED),
+            _ => Err(APDU_RSP_CLA_NOT_SUPPORTED),
+        }
+    }
+
+    /**
+    GET STATUS of the load files, P2 = 02 starts over and 03 reads the next one
+    */
+    fn get_status(&mut self, p2: u8) -> Vec<u8> {
+        if p2 == 0x02 {
+            self.next_load_file = 0;
+        }
+        let load_file = match self.load_files.get(self.next_load_file) {
+            Some(load_file) => load_file,
+            None => return to_response(Err(APDU_RSP_APPLET_NOT_EXIST)),
+        };
+        self.next_load_file += 1;
+        let load_file = Tlv::constructed(
+            &[0xE3],
+            &[
+                Tlv::new(&[0x4F], &load_file.aid),
+                Tlv::new(&[0x9F, 0x70], &[0x01]),
+                Tlv::new(&[0xCE], &load_file.version),
+            ],
+        );
+        let mut response = load_file.encode();
+        match self.next_load_file < self.load_files.len() {
+            true => response.extend([0x63, 0x10].iter()),
+            false => response.extend([0x90, 0x00].iter()),
+        }
+        response
+    }
+
+    /**
+    INSTALL [for load] (P1 02) starts loading the load file, INSTALL [for install] (P1 0C)
+    makes it an applet, with the version in the install parameters
+    */
+    fn install(&mut self, p1: u8, data: &[u8]) -> Reply {
+        let fields = length_values(data).ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+        match p1 {
+            0x02 => {
+                let aid = fields.first().ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+                if self.load_file(aid).is_some() {
+                    return Err(APDU_CONDITIONS_NOT_SATISFIED);
+                }
+                self.loading = Some(aid.to_vec());
+                Ok(vec![0x00])
+            }
+            0x0C => {
+                let aid = fields.first().ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+                if self.loading.as_deref() != Some(*aid) {
+                    return Err(APDU_CONDITIONS_NOT_SATISFIED);
+                }
+                let parameters = fields.get(4).ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+                let version = match parameters {
+                    [TAG_APPLET_VERSION, 0x02, major, minor] => [*major, *minor],
+                    _ => return Err(APDU_RSP_APPLET_WRONG_DATA),
+                };
+                self.loading = None;
+                self.load_files.push(LoadFile {
+                    aid: aid.to_vec(),
+                    version,
+                });
+                Ok(vec![0x00])
+            }
+            _ => Err(APDU_RSP_INCORRECT_P1P2),
+        }
+    }
+
+    /**
+    LOAD blocks are accepted as they come, the simulator does not run the code
+    */
+    fn load(&self) -> Reply {
+        match self.loading {
+            Some(_) => Ok(vec![0x00]),
+            None => Err(APDU_CONDITIONS_NOT_SATISFIED),
+        }
+    }
+
+    /**
+    DELETE of the load file (4F) and its applet
+    */
+    fn delete(&mut self, data: &[u8]) -> Reply {
+        let (aid, _) = Tlv::decode(data).map_err(|_| APDU_RSP_APPLET_WRONG_DATA)?;
+        if aid.tag != [0x4F] || aid.value == hex::decode(IMK_AID).unwrap() {
+            return Err(APDU_RSP_APPLET_WRONG_DATA);
+        }
+        let index = self
+            .load_file(&aid.value)
+            .ok_or(APDU_RSP_APPLET_NOT_EXIST)?;
+        self.load_files.remove(index);
+        Ok(vec![0x00])
+    }
+
+    fn load_file(&self, aid: &[u8]) -> Option<usize> {
+        self.load_files
+            .iter()
+            .position(|load_file| load_file.aid == aid)
+    }
+
+    fn open_secure_channel(&mut self, host_challenge: &[u8]) -> Reply {
+        let session_key = self
+            .imk
+            .bound_session_key()
+            .ok_or(APDU_CONDITIONS_NOT_SATISFIED)?;
+        let (channel, response) =
+            CardChannel::open(&session_key, host_challenge).ok_or(APDU_RSP_APPLET_WRONG_DATA)?;
+        self.secure = Some(channel);
+        Ok(response)
+    }
+
+    fn select(&mut self, aid: &[u8]) -> Reply {
+        if !aid.is_empty() && self.load_file(aid).is_none() {
+            return Err(APDU_RSP_APPLET_NOT_EXIST);
+        }
+        self.selected = match hex::encode_upper(aid).as_str() {
+            "" => Applet::Isd,
+            IMK_AID => Applet::Imk,
+            BTC_AID => Applet::Btc,
+            _ => Applet::Other,
+        };
+        //the secure channel is opened with the IMK applet and ends when neither it nor a coin
+        //applet is selected
+        if !self.selected.is_secure_channel() {
+            self.secure = None;
+        }
+        Ok(vec![])
+    }
+}
+
+/**
+fields of GP command data, each one preceded by its length
+*/
+fn length_values(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut fields = vec![];
+    while let Some((length, rest)) = data.split_first() {
+        fields.push(rest.get(..*length as usize)?);
+        data = &rest[*length as usize..];
+    }
+    Some(fields)
+}
+
+#[async_trait(?Send)]
+impl Transport for Simulator {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        if !self.is_connected() {
+            return Err(TransportError::DeviceNotConnected.into());
+        }
+        Ok(self.process(apdu))
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.state.borrow().disconnected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::simulator::Simulator;
+    use futures::executor::block_on;
+    use ikc_transport::device::Device;
+
+    #[test]
+    fn select_test() {
+        let simulator = Simulator::new();
+        assert_eq!(
+            simulator.process(&hex::decode("00A4040000").unwrap()),
+            [0x90, 0x00]
+        );
+        let response = simulator.process(&hex::decode("00A4040005695F62746300").unwrap());
+        assert_eq!(response, [0x90, 0x00]);
+        let response = simulator.process(&hex::decode("00A4040005695F65746800").unwrap());
+        assert_eq!(response, [0x6A, 0x82]);
+    }
+
+    #[test]
+    fn get_se_id_and_sn_test() {
+        let device = Device::new(Simulator::new());
+        block_on(device.send_apdu(&hex::decode("00A4040000").unwrap())).unwrap();
+        assert_eq!(
+            hex::encode_upper(
+                block_on(device.send_apdu(&hex::decode("80CB800005DFFF028101").unwrap())).unwrap()
+            ),
+            "190600000002008600010100000000149000"
+        );
+        assert_eq!(
+            hex::encode_upper(
+                block_on(device.send_apdu(&hex::decode("80CA004400").unwrap())).unwrap()
+            ),
+            "696D4B657930313139313230303030319000"
+        );
+    }
+
+    #[test]
+    fn get_status_test() {
+        let simulator = Simulator::new();
+        let response = simulator.process(&hex::decode("80F22002024F0000").unwrap());
+        assert_eq!(
+            hex::encode_upper(response),
+            "E30F4F05695F696D6B9F700101CE0201006310"
+        );
+        let response = simulator.process(&hex::decode("80F22003024F0000").unwrap());
+        assert_eq!(
+            hex::encode_upper(response),
+            "E30F4F05695F6274639F700101CE0201059000"
+        );
+        let response = simulator.process(&hex::decode("80F22003024F0000").unwrap());
+        assert_eq!(response, [0x6A, 0x82]);
+    }
+
+    #[test]
+    fn install_test() {
+        let simulator = Simulator::new();
+        let process =
+            |apdu: &str| hex::encode_upper(simulator.process(&hex::decode(apdu).unwrap()));
+        assert_eq!(process("00A4040005695F65746800"), "6A82");
+
+        assert_eq!(process("80E602000B05695F6574680000000000"), "009000");
+        assert_eq!(process("80E8800003C4010000"), "009000");
+        assert_eq!(
+            process("80E60C001A05695F65746805695F65746805695F657468010004C902010600"),
+            "009000"
+        );
+        assert_eq!(process("00A4040005695F65746800"), "9000");
+        assert_eq!(process("80CA000000"), "6D00");
+
+        assert_eq!(process("00A4040000"), "9000");
+        assert_eq!(process("80E40080074F05695F65746800"), "009000");
+        assert_eq!(process("80E40080074F05695F65746800"), "6A82");
+        assert_eq!(process("00A4040005695F65746800"), "6A82");
+
+        //the IMK applet can not be deleted
+        assert_eq!(process("80E40080074F05695F696D6B00"), "6A80");
+        //LOAD without INSTALL [for load]
+        assert_eq!(process("80E8800003C4010000"), "6985");
+    }
+
+    #[test]
+    fn bootloader_test() {
+        let simulator = Simulator::new();
+        let device = Device::new(simulator.clone());
+        let send = |apdu: &str| {
+            block_on(device.send_apdu(&hex::decode(apdu).unwrap())).map(hex::encode_upper)
+        };
+        assert_eq!(send("8054000000").unwrap(), "905A");
+        assert!(simulator.is_bootloader());
+        assert_eq!(
+            send("00A4040000").err().unwrap().to_string(),
+            "imkey_device_not_connected"
+        );
+
+        simulator.reconnect();
+        assert_eq!(send("00A4040000").unwrap(), "6A82");
+        assert_eq!(send("00A404000BD0426F6F746C6F6164657200").unwrap(), "9000");
+        assert_eq!(
+            send("80CB800005DFFF028101").unwrap(),
+            "190600000002008600010100000000149000"
+        );
+        assert_eq!(send("80F6000002AABB").unwrap(), "9000");
+        assert_eq!(send("80F8000003010800").unwrap(), "9000");
+        assert!(!device.is_connected());
+
+        simulator.reconnect();
+        assert!(!simulator.is_bootloader());
+        assert_eq!(send("80CB800005DFFF028003").unwrap(), "0108009000");
+    }
+
+    #[test]
+    fn unsupported_command_test() {
+        let simulator = Simulator::new();
+        assert_eq!(simulator.process(&[0x00]), [0x67, 0x00]);
+        assert_eq!(
+            simulator.process(&[0x80, 0x99, 0x00, 0x00, 0x00]),
+            [0x6D, 0x00]
+        );
+        assert_eq!(
+            simulator.process(&[0x90, 0x43, 0x00, 0x00, 0x00]),
+            [0x6E, 0x00]
+        );
+        //secure messaging is only known by the IMK and BTC applets
+        assert_eq!(
+            simulator.process(&hex::decode("8450000008010203040506070800").unwrap()),
+            [0x6E, 0x00]
+        );
+    }
+}
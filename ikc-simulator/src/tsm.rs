@@ -0,0 +1,427 @@
+use crate::Result;
+use async_trait::async_trait;
+use ikc_common::constants;
+use ikc_transport::http::HttpClient;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const SELECT_ISD: &str = "00A4040000";
+const GET_SEID: &str = "80CB800005DFFF028101";
+//SET STATUS of the ISD to SECURED
+const SET_SECURED: &str = "80F080070F";
+const SWITCH_BOOTLOADER: &str = "8054000000";
+const GET_COS_VERSION: &str = "80CB800005DFFF028003";
+const SCRIPT_STEP: &str = "01";
+//steps of the COS upgrade: write the image in two parts, then check the version
+const WRITE_STEP: &str = "02";
+const VERIFY_STEP: &str = "03";
+const LAST_WRITE_STEP: &str = "04";
+//blocks of the COS image written by the bootloader
+const COS_BLOCKS: u8 = 4;
+//applets the server can install, with their latest version
+const APPLETS: &[(&str, [u8; 2])] = &[
+    (constants::BTC_AID, [0x01, 0x05]),
+    (constants::ETH_AID, [0x01, 0x06]),
+    (constants::COSMOS_AID, [0x01, 0x02]),
+];
+
+/**
+Local TSM server answering the actions the way the imKey server does, for the simulator device.
+Every request is recorded, return codes can be forced per action to test the failures.
+*/
+pub struct MockTsm {
+    state: RefCell<MockTsmState>,
+}
+
+struct MockTsmState {
+    requests: Vec<(String, Value)>,
+    return_codes: HashMap<String, String>,
+    activated: bool,
+    latest_cos_version: String,
+    //actions answered with one more step forever
+    endless: Vec<String>,
+}
+
+impl MockTsm {
+    pub fn new() -> MockTsm {
+        let state = MockTsmState {
+            requests: vec![],
+            return_codes: HashMap::new(),
+            activated: true,
+            latest_cos_version: crate::simulator::SIMULATOR_COS_VERSION.to_string(),
+            endless: vec![],
+        };
+        MockTsm {
+            state: RefCell::new(state),
+        }
+    }
+
+    /**
+    actions and JSON bodies received so far
+    */
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        self.state.borrow().requests.clone()
+    }
+
+    /**
+    answer every request of the action with the return code
+    */
+    pub fn set_return_code(&self, action: &str, return_code: &str) {
+        self.state
+            .borrow_mut()
+            .return_codes
+            .insert(action.to_string(), return_code.to_string());
+    }
+
+    /**
+    misbehave like a server never sending the end flag of the action
+    */
+    pub fn set_endless(&self, action: &str) {
+        self.state.borrow_mut().endless.push(action.to_string());
+    }
+
+    pub fn set_activated(&self, activated: bool) {
+        self.state.borrow_mut().activated = activated;
+    }
+
+    pub fn set_latest_cos_version(&self, version: &str) {
+        self.state.borrow_mut().latest_cos_version = version.to_string();
+    }
+
+    /**
+    response of the action to the request, as the JSON body sent by the server
+    */
+    pub fn handle(&self, action: &str, request: &Value) -> Value {
+        let mut state = self.state.borrow_mut();
+        state.requests.push((action.to_string(), request.clone()));
+        if let Some(return_code) = state.return_codes.get(action) {
+            return response(return_code, Value::Null);
+        }
+        if state.endless.iter().any(|endless| endless == action) {
+            let data = script(SCRIPT_STEP, vec![SELECT_ISD.to_string()]);
+            return response(constants::TSM_RETURN_CODE_SUCCESS, data);
+        }
+        let result = match action {
+            constants::TSM_ACTION_SE_SECURE_CHECK => state.se_secure_check(request),
+            constants::TSM_ACTION_SE_ACTIVATE => state.se_activate(request),
+            constants::TSM_ACTION_SE_QUERY => Ok(state.se_query(request)),
+            constants::TSM_ACTION_COS_CHECK_UPDATE => Ok(state.cos_check_update(request)),
+            constants::TSM_ACTION_COS_UPGRADE => state.cos_upgrade(request),
+            constants::TSM_ACTION_DEVICE_CERT_CHECK => Ok(json!({
+                "seid": request["seid"],
+                "verifyResult": true,
+            })),
+            constants::TSM_ACTION_AUTHCODE_STORAGE => Ok(Value::Null),
+            constants::TSM_ACTION_APP_DOWNLOAD => app_download(request),
+            constants::TSM_ACTION_APP_UPDATE => app_update(request),
+            constants::TSM_ACTION_APP_DELETE => app_delete(request),
+            _ => Err("SYS0001"),
+        };
+        match result {
+            Ok(data) => response(constants::TSM_RETURN_CODE_SUCCESS, data),
+            Err(return_code) => response(return_code, Value::Null),
+        }
+    }
+}
+
+impl Default for MockTsm {
+    fn default() -> Self {
+        MockTsm::new()
+    }
+}
+
+impl MockTsmState {
+    /**
+    read the SEID from the ISD, then check it against the one of the request
+    */
+    fn se_secure_check(&self, request: &Value) -> std::result::Result<Value, &'static str> {
+        if request["stepKey"] == "" {
+            return Ok(script(
+                SCRIPT_STEP,
+                vec![SELECT_ISD.to_string(), GET_SEID.to_string()],
+            ));
+        }
+        let seid = request["seid"].as_str().unwrap_or_default();
+        let expected = format!("{}{}", seid, constants::APDU_RSP_SUCCESS);
+        if request["statusWord"] != constants::APDU_RSP_SUCCESS
+            || request["cardRetDataList"][1] != expected.as_str()
+        {
+            return Err(constants::TSM_RETURNCODE_DEVICE_CHECK_FAIL);
+        }
+        if !self.activated {
+            return Err(constants::TSM_RETURNCODE_DEV_INACTIVATED);
+        }
+        Ok(script(constants::TSM_END_FLAG, vec![]))
+    }
+
+    /**
+    the server registers the device as activated once the SE is secured
+    */
+    fn se_activate(&mut self, request: &Value) -> std::result::Result<Value, &'static str> {
+        if request["stepKey"] == "" {
+            return Ok(script(
+                SCRIPT_STEP,
+                vec![SELECT_ISD.to_string(), SET_SECURED.to_string()],
+            ));
+        }
+        if !all_success(request) {
+            return Err(constants::TSM_RETURNCODE_DEVICE_ACTIVE_FAIL);
+        }
+        self.activated = true;
+        Ok(script(constants::TSM_END_FLAG, vec![]))
+    }
+
+    /**
+    switch to the bootloader, write the COS, then check the version of the restarted device.
+    A device already running the bootloader starts with the write.
+    */
+    fn cos_upgrade(&self, request: &Value) -> std::result::Result<Value, &'static str> {
+        let version: Vec<u8> = self
+            .latest_cos_version
+            .split('.')
+            .map(|number| number.parse().unwrap_or_default())
+            .collect();
+        let card_ret_data = |index: usize| request["cardRetDataList"][index].as_str();
+        match request["stepKey"].as_str().unwrap_or_default() {
+            "" if request["isBlStatus"] != true => Ok(script(
+                SCRIPT_STEP,
+                vec![SELECT_ISD.to_string(), SWITCH_BOOTLOADER.to_string()],
+            )),
+            "" => Ok(script(WRITE_STEP, write_script(false, &version))),
+            SCRIPT_STEP => match card_ret_data(1) {
+                Some(constants::APDU_RSP_SWITCH_BL_STATUS_SUCCESS) => {
+                    Ok(script(WRITE_STEP, write_script(false, &version)))
+                }
+                _ => Err(constants::TSM_RETURNCODE_SWITCH_BL_STATUS_FAIL),
+            },
+            WRITE_STEP if all_success(request) => {
+                Ok(script(LAST_WRITE_STEP, write_script(true, &version)))
+            }
+            LAST_WRITE_STEP if all_success(request) => Ok(script(
+                VERIFY_STEP,
+                vec![SELECT_ISD.to_string(), GET_COS_VERSION.to_string()],
+            )),
+            VERIFY_STEP => {
+                let expected = format!(
+                    "{}{}",
+                    hex::encode_upper(&version),
+                    constants::APDU_RSP_SUCCESS
+                );
+                match card_ret_data(1) == Some(expected.as_str()) {
+                    true => Ok(script(constants::TSM_END_FLAG, vec![])),
+                    false => Err(constants::TSM_RETURNCODE_COS_UPGRADE_FAIL),
+                }
+            }
+            _ => Err(constants::TSM_RETURNCODE_COS_UPGRADE_FAIL),
+        }
+    }
+
+    fn se_query(&self, request: &Value) -> Value {
+        let status = match self.activated {
+            true => constants::IMKEY_DEV_STATUS_LATEST,
+            false => constants::IMKEY_DEV_STATUS_INACTIVATED,
+        };
+        json!({
+            "seid": request["seid"],
+            "sn": request["sn"],
+            "status": status,
+            "availableAppList": [
+                {
+                    "appName": "BTC",
+                    "instanceAid": constants::BTC_AID,
+                    "installedVersion": "1.5",
+                    "latestVersion": "1.5",
+                },
+                {
+                    "appName": "ETH",
+                    "instanceAid": constants::ETH_AID,
+                    "latestVersion": "1.6",
+                },
+            ],
+        })
+    }
+
+    fn cos_check_update(&self, request: &Value) -> Value {
+        json!({
+            "seid": request["seid"],
+            "isLatest": request["cosVersion"] == self.latest_cos_version.as_str(),
+            "latestCosVersion": self.latest_cos_version,
+            "updateType": "",
+            "description": "",
+        })
+    }
+}
+
+/**
+load and install the latest version of the applet
+*/
+fn app_download(request: &Value) -> std::result::Result<Value, &'static str> {
+    if request["stepKey"] == "" {
+        let mut apdu_list = vec![SELECT_ISD.to_string()];
+        apdu_list.extend(install_script(request)?);
+        return Ok(script(SCRIPT_STEP, apdu_list));
+    }
+    match all_success(request) {
+        true => Ok(script(constants::TSM_END_FLAG, vec![])),
+        false => Err(constants::TSM_RETURNCODE_APP_DOWNLOAD_FAIL),
+    }
+}
+
+/**
+delete the installed version, then install the latest one
+*/
+fn app_update(request: &Value) -> std::result::Result<Value, &'static str> {
+    if request["stepKey"] == "" {
+        let mut apdu_list = vec![SELECT_ISD.to_string(), delete_command(request)];
+        apdu_list.extend(install_script(request)?);
+        return Ok(script(SCRIPT_STEP, apdu_list));
+    }
+    match all_success(request) {
+        true => Ok(script(constants::TSM_END_FLAG, vec![])),
+        false => Err(constants::TSM_RETURNCODE_APP_UPDATE_FAIL),
+    }
+}
+
+fn app_delete(request: &Value) -> std::result::Result<Value, &'static str> {
+    if request["stepKey"] == "" {
+        let apdu_list = vec![SELECT_ISD.to_string(), delete_command(request)];
+        return Ok(script(SCRIPT_STEP, apdu_list));
+    }
+    match all_success(request) {
+        true => Ok(script(constants::TSM_END_FLAG, vec![])),
+        false => Err(constants::TSM_RETURNCODE_APP_DELETE_FAIL),
+    }
+}
+
+/**
+INSTALL [for load], LOAD and INSTALL [for install] of the applet, its version in the C9
+install parameters
+*/
+fn install_script(request: &Value) -> std::result::Result<Vec<String>, &'static str> {
+    let aid = request["instanceAid"].as_str().unwrap_or_default();
+    let version = APPLETS
+        .iter()
+        .find(|(applet, _)| *applet == aid)
+        .map(|(_, version)| version)
+        .ok_or(constants::TSM_RETURNCODE_DEVICE_UNSUPPORT_APPLET)?;
+    let aid = length_value(&hex::decode(aid).unwrap());
+    let install_for_load = [aid.as_slice(), &[0x00, 0x00, 0x00, 0x00]].concat();
+    let parameters = length_value(&[0xC9, 0x02, version[0], version[1]]);
+    let install = [
+        aid.as_slice(),
+        &aid,
+        &aid,
+        &[0x01, 0x00],
+        &parameters,
+        &[0x00],
+    ]
+    .concat();
+    Ok(vec![
+        command("80E60200", &install_for_load),
+        command("80E88000", &[0xC4, 0x01, 0x00]),
+        command("80E60C00", &install),
+    ])
+}
+
+fn delete_command(request: &Value) -> String {
+    let aid = hex::decode(request["instanceAid"].as_str().unwrap_or_default()).unwrap_or_default();
+    command("80E40080", &[&[0x4F][..], &length_value(&aid)].concat())
+}
+
+fn length_value(value: &[u8]) -> Vec<u8> {
+    [&[value.len() as u8][..], value].concat()
+}
+
+fn command(header: &str, data: &[u8]) -> String {
+    format!("{}{:02X}{}", header, data.len(), hex::encode_upper(data))
+}
+
+//every command of the script succeeded
+fn all_success(request: &Value) -> bool {
+    request["cardRetDataList"]
+        .as_array()
+        .is_some_and(|responses| {
+            responses.iter().all(|response| {
+                response
+                    .as_str()
+                    .is_some_and(|response| response.ends_with(constants::APDU_RSP_SUCCESS))
+            })
+        })
+}
+
+/**
+select the bootloader and write one half of the image blocks, the last part ends with the
+version the COS restarts with
+*/
+fn write_script(last: bool, version: &[u8]) -> Vec<String> {
+    let mut apdu_list = vec![command(
+        "00A40400",
+        &hex::decode(constants::BL_AID).unwrap(),
+    )];
+    let blocks = match last {
+        false => 0..COS_BLOCKS / 2,
+        true => COS_BLOCKS / 2..COS_BLOCKS,
+    };
+    for block in blocks {
+        apdu_list.push(command(&format!("80F600{:02X}", block), &[0xA5; 0x10]));
+    }
+    if last {
+        apdu_list.push(command("80F80000", version));
+    }
+    apdu_list
+}
+
+fn response(return_code: &str, data: Value) -> Value {
+    json!({
+        "_ReturnCode": return_code,
+        "_ReturnMsg": "",
+        "_ReturnData": data,
+    })
+}
+
+fn script(next_step_key: &str, apdu_list: Vec<String>) -> Value {
+    json!({
+        "nextStepKey": next_step_key,
+        "apduList": apdu_list,
+    })
+}
+
+#[async_trait(?Send)]
+impl HttpClient for MockTsm {
+    /**
+    the action is the last segment of the url, whatever the endpoint
+    */
+    async fn post(&self, url: &str, body: &str) -> Result<String> {
+        let action = &url[url.rfind('/').unwrap_or(0)..];
+        let request: Value = serde_json::from_str(body)?;
+        Ok(self.handle(action, &request).to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tsm::MockTsm;
+    use ikc_common::constants;
+    use serde_json::json;
+
+    #[test]
+    fn handle_test() {
+        let tsm = MockTsm::new();
+        let response = tsm.handle(
+            constants::TSM_ACTION_SE_SECURE_CHECK,
+            &json!({"stepKey": ""}),
+        );
+        assert_eq!(response["_ReturnCode"], "000000");
+        assert_eq!(response["_ReturnData"]["nextStepKey"], "01");
+        assert_eq!(response["_ReturnData"]["apduList"][0], "00A4040000");
+
+        let response = tsm.handle("/unknown", &json!({}));
+        assert_eq!(response["_ReturnCode"], "SYS0001");
+
+        tsm.set_activated(false);
+        let response = tsm.handle(constants::TSM_ACTION_SE_QUERY, &json!({"seid": "1906"}));
+        assert_eq!(response["_ReturnData"]["status"], "inactivated");
+        assert_eq!(tsm.requests().len(), 3);
+    }
+}
@@ -0,0 +1,14 @@
+use crate::device::Device;
+use crate::Result;
+use async_trait::async_trait;
+
+/**
+Source of the imKey devices the host may talk to, e.g. the devices already paired with the browser.
+*/
+#[async_trait(?Send)]
+pub trait Connector {
+    /**
+    open every available device and return a handle on each of them
+    */
+    async fn open_devices(&self) -> Result<Vec<Device>>;
+}
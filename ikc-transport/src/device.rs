@@ -0,0 +1,247 @@
+use crate::error::TransportError;
+use crate::trace::TraceRecorder;
+use crate::transport::Transport;
+use crate::Result;
+use futures::lock::{Mutex, OwnedMutexGuard};
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Duration;
+
+/**
+Handle to a connected imKey, passed to every device and coin operation.
+*/
+pub struct Device {
+    transport: Box<dyn Transport>,
+    session: Arc<Mutex<()>>,
+    recorder: RefCell<Option<TraceRecorder>>,
+    secure_messaging: RefCell<Option<Box<dyn SecureMessaging>>>,
+    seid: RefCell<Option<String>>,
+}
+
+/**
+Protection of the command APDUs sent to a device, removed again from the responses,
+e.g. a secure channel opened with the binding session key.
+*/
+pub trait SecureMessaging {
+    fn wrap(&mut self, command: &[u8]) -> Result<Vec<u8>>;
+    fn unwrap(&mut self, response: &[u8]) -> Result<Vec<u8>>;
+
+    /**
+    false once the protection ended, e.g. when the protected applet is no longer selected
+    */
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+/**
+Exclusive use of a device for one logical operation, released when dropped.
+It does not borrow the device, an operation can keep it while it opens the device again.
+*/
+pub struct Session {
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Device {
+    pub fn new<T: Transport + 'static>(transport: T) -> Device {
+        Device {
+            transport: Box::new(transport),
+            session: Arc::new(Mutex::new(())),
+            recorder: RefCell::new(None),
+            secure_messaging: RefCell::new(None),
+            seid: RefCell::new(None),
+        }
+    }
+
+    /**
+    start recording every command/response pair into the given recorder, or stop with None
+    */
+    pub fn set_recorder(&self, recorder: Option<TraceRecorder>) {
+        *self.recorder.borrow_mut() = recorder;
+    }
+
+    /**
+    protect the following exchanges, or go back to plain APDUs with None
+    */
+    pub fn set_secure_messaging(&self, secure_messaging: Option<Box<dyn SecureMessaging>>) {
+        *self.secure_messaging.borrow_mut() = secure_messaging;
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure_messaging.borrow().is_some()
+    }
+
+    /**
+    SEID of the device once read from it. Host state kept per device, like the binding keys,
+    is found with it without exchanging with the device.
+    */
+    pub fn seid(&self) -> Option<String> {
+        self.seid.borrow().clone()
+    }
+
+    pub fn set_seid(&self, seid: &str) {
+        *self.seid.borrow_mut() = Some(seid.to_string());
+    }
+
+    /**
+    command APDU as it has to be sent, unchanged without secure messaging. Secure messaging
+    ended by the command is dropped, its response is read in clear
+    */
+    pub fn wrap_command(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let mut secure_messaging = self.secure_messaging.borrow_mut();
+        let wrapped = match secure_messaging.as_mut() {
+            Some(secure_messaging) => secure_messaging.wrap(apdu)?,
+            None => return Ok(apdu.to_vec()),
+        };
+        if secure_messaging.as_ref().is_some_and(|sm| !sm.is_open()) {
+            *secure_messaging = None;
+        }
+        Ok(wrapped)
+    }
+
+    /**
+    check and decrypt a whole response, a response failing the check ends secure messaging
+    */
+    pub fn unwrap_response(&self, response: &[u8]) -> Result<Vec<u8>> {
+        let mut secure_messaging = self.secure_messaging.borrow_mut();
+        let result = match secure_messaging.as_mut() {
+            Some(secure_messaging) => secure_messaging.unwrap(response),
+            None => return Ok(response.to_vec()),
+        };
+        if result.is_err() {
+            *secure_messaging = None;
+        }
+        result
+    }
+
+    /**
+    wait for the running operation to finish, then take the device for a whole operation
+    (select applet, prepare, sign) so that no other caller can interleave its APDUs
+    */
+    pub async fn session(&self) -> Session {
+        Session {
+            _guard: self.session.clone().lock_owned().await,
+        }
+    }
+
+    /**
+    take the device for a whole operation, fail with a device busy error if another one is running
+    */
+    pub fn try_session(&self) -> Result<Session> {
+        match self.session.try_lock_owned() {
+            Some(guard) => Ok(Session { _guard: guard }),
+            None => Err(TransportError::DeviceBusy.into()),
+        }
+    }
+
+    /**
+    send a raw APDU, return the raw response including the status word
+    */
+    pub async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let response = self.transport.send_apdu(apdu).await?;
+        self.record(apdu, &response);
+        Ok(response)
+    }
+
+    /**
+    send a raw APDU that waits for a user confirmation, fail with a timeout error
+    if the device did not answer in time
+    */
+    pub async fn send_apdu_with_timeout(&self, apdu: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        let response = self.transport.send_apdu_with_timeout(apdu, timeout).await?;
+        self.record(apdu, &response);
+        Ok(response)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_connected()
+    }
+
+    pub async fn cancel(&self) -> Result<()> {
+        self.transport.cancel().await
+    }
+
+    fn record(&self, command: &[u8], response: &[u8]) {
+        if let Some(recorder) = self.recorder.borrow().as_ref() {
+            recorder.record(command, response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::device::{Device, SecureMessaging};
+    use crate::mock::MockTransport;
+    use crate::Result;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    #[test]
+    fn send_apdu_test() {
+        let device = Device::new(MockTransport::new(|apdu: &[u8]| {
+            assert_eq!(apdu, [0x00, 0xA4, 0x04, 0x00, 0x00]);
+            Ok(vec![0x6f, 0x00, 0x90, 0x00])
+        }));
+        let response = block_on(device.send_apdu(&[0x00, 0xA4, 0x04, 0x00, 0x00]));
+        assert_eq!(response.unwrap(), vec![0x6f, 0x00, 0x90, 0x00]);
+    }
+
+    //ends after a command A4
+    struct Xor(bool);
+
+    impl SecureMessaging for Xor {
+        fn wrap(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+            self.0 = command != [0xA4];
+            Ok(command.iter().map(|byte| byte ^ 0xFF).collect())
+        }
+
+        fn unwrap(&mut self, response: &[u8]) -> Result<Vec<u8>> {
+            match response {
+                [0x90, 0x00] => Ok(response.to_vec()),
+                _ => Err(anyhow::anyhow!("wrong_response")),
+            }
+        }
+
+        fn is_open(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn secure_messaging_test() {
+        let device = Device::new(MockTransport::new(|_apdu: &[u8]| Ok(vec![0x90, 0x00])));
+        assert_eq!(device.wrap_command(&[0x00]).unwrap(), vec![0x00]);
+        device.set_secure_messaging(Some(Box::new(Xor(true))));
+        assert!(device.is_secure());
+        assert_eq!(device.wrap_command(&[0x00]).unwrap(), vec![0xFF]);
+        assert!(device.unwrap_response(&[0x90, 0x00]).is_ok());
+        assert!(device.is_secure());
+
+        assert!(device.unwrap_response(&[0x6A, 0x80]).is_err());
+        assert!(!device.is_secure());
+        assert_eq!(
+            device.unwrap_response(&[0x6A, 0x80]).unwrap(),
+            vec![0x6A, 0x80]
+        );
+
+        device.set_secure_messaging(Some(Box::new(Xor(true))));
+        assert_eq!(device.wrap_command(&[0xA4]).unwrap(), vec![0x5B]);
+        assert!(!device.is_secure());
+        assert_eq!(device.wrap_command(&[0x00]).unwrap(), vec![0x00]);
+    }
+
+    #[test]
+    fn session_test() {
+        let device = Device::new(MockTransport::new(|_apdu: &[u8]| Ok(vec![0x90, 0x00])));
+        let session = device.try_session().unwrap();
+        assert_eq!(
+            device.try_session().err().unwrap().to_string(),
+            "imkey_device_busy"
+        );
+        assert!(device.session().now_or_never().is_none());
+
+        drop(session);
+        assert!(device.session().now_or_never().is_some());
+        assert!(device.try_session().is_ok());
+    }
+}
@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialOrd, PartialEq)]
+pub enum TransportError {
+    #[error("imkey_device_not_connected")]
+    DeviceNotConnected,
+    #[error("imkey_apdu_format_error")]
+    ApduFormatError,
+    #[error("imkey_device_busy")]
+    DeviceBusy,
+    #[error("imkey_command_timeout")]
+    Timeout,
+    #[error("imkey_device_cancelled")]
+    DeviceCancelled,
+    #[error("imkey_hid_invalid_command")]
+    HidInvalidCommand,
+    #[error("imkey_hid_invalid_parameter")]
+    HidInvalidParameter,
+    #[error("imkey_hid_invalid_length")]
+    HidInvalidLength,
+    #[error("imkey_hid_invalid_sequence")]
+    HidInvalidSequence,
+    #[error("imkey_hid_other_error")]
+    HidOtherError,
+    #[error("imkey_hid_unknown_error")]
+    HidUnknownError,
+    #[error("imkey_usb_transfer_fail")]
+    UsbTransferFail,
+    #[error("imkey_usb_interface_not_found")]
+    UsbInterfaceNotFound,
+    #[error("imkey_apdu_too_long")]
+    ApduTooLong,
+    #[error("imkey_network_fail")]
+    NetworkFail,
+}
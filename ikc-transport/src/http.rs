@@ -0,0 +1,15 @@
+use crate::Result;
+use async_trait::async_trait;
+
+/**
+Client for the HTTP services the SDK talks to, the TSM server in particular.
+The browser build uses fetch. The SDK has no native client, native applications plug in
+their own, the TSM calls of the ikc bindings fail with imkey_network_fail outside the browser.
+*/
+#[async_trait(?Send)]
+pub trait HttpClient {
+    /**
+    post a JSON body to the url, return the body of the response
+    */
+    async fn post(&self, url: &str, body: &str) -> Result<String>;
+}
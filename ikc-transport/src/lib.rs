@@ -0,0 +1,12 @@
+pub mod connector;
+pub mod device;
+pub mod error;
+pub mod http;
+pub mod mock;
+pub mod timeout;
+pub mod trace;
+pub mod transport;
+
+extern crate anyhow;
+use core::result;
+pub type Result<T> = result::Result<T, anyhow::Error>;
@@ -0,0 +1,69 @@
+use crate::transport::Transport;
+use crate::Result;
+use async_trait::async_trait;
+use futures::future::poll_fn;
+use std::task::Poll;
+
+/**
+In-memory transport answering every APDU with the given handler, used by tests.
+*/
+pub struct MockTransport<F>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    handler: F,
+}
+
+impl<F> MockTransport<F>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    pub fn new(handler: F) -> Self {
+        MockTransport { handler }
+    }
+}
+
+#[async_trait(?Send)]
+impl<F> Transport for MockTransport<F>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        (self.handler)(apdu)
+    }
+}
+
+/**
+Transport handing every APDU to the inner one after yielding once to the executor, so that tests
+can interleave the exchanges of several futures the way the browser event loop does.
+*/
+pub struct YieldingTransport<T: Transport> {
+    inner: T,
+}
+
+impl<T: Transport> YieldingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        YieldingTransport { inner }
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: Transport> Transport for YieldingTransport<T> {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        self.inner.send_apdu(apdu).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+}
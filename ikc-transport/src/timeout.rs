@@ -0,0 +1,77 @@
+use crate::error::TransportError;
+use crate::Result;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use std::future::Future;
+use std::time::Duration;
+
+//time left to the device to acknowledge a cancel before the exchange is abandoned
+pub const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+/**
+Run an APDU exchange with a deadline. When the deadline passes the device is told to cancel,
+the pending read is given a short grace period to consume the cancel acknowledgement,
+and a timeout error is returned.
+*/
+pub async fn exchange_with_timeout<E, S, T, C>(
+    exchange: E,
+    timeout: Duration,
+    sleep: S,
+    cancel: C,
+) -> Result<Vec<u8>>
+where
+    E: Future<Output = Result<Vec<u8>>>,
+    S: Fn(Duration) -> T,
+    T: Future<Output = ()>,
+    C: Future<Output = Result<()>>,
+{
+    pin_mut!(exchange);
+    let timer = sleep(timeout);
+    pin_mut!(timer);
+    let exchange = match select(exchange, timer).await {
+        Either::Left((response, _)) => return response,
+        Either::Right((_, exchange)) => exchange,
+    };
+
+    cancel.await?;
+    let grace = sleep(CANCEL_GRACE);
+    pin_mut!(grace);
+    let _ = select(exchange, grace).await;
+    Err(TransportError::Timeout.into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::timeout::exchange_with_timeout;
+    use futures::executor::block_on;
+    use futures::future::{pending, ready};
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    #[test]
+    fn exchange_in_time_test() {
+        let response = block_on(exchange_with_timeout(
+            ready(Ok(vec![0x90, 0x00])),
+            Duration::from_secs(1),
+            |_| pending(),
+            async { panic!("cancel sent") },
+        ));
+        assert_eq!(response.unwrap(), vec![0x90, 0x00]);
+    }
+
+    #[test]
+    fn exchange_timeout_test() {
+        let cancelled = Cell::new(false);
+        let response = block_on(exchange_with_timeout(
+            pending(),
+            Duration::from_secs(1),
+            |_| ready(()),
+            async {
+                cancelled.set(true);
+                Ok(())
+            },
+        ));
+        assert!(cancelled.get());
+        assert_eq!(response.err().unwrap().to_string(), "imkey_command_timeout");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::transport::Transport;
+use crate::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/**
+one command APDU and the response the device gave, both upper case hex
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub command: String,
+    pub response: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Trace {
+    pub fn from_json(json: &str) -> Result<Trace> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/**
+Collects the exchanges of a device session, clones share the same trace.
+*/
+#[derive(Clone, Default)]
+pub struct TraceRecorder {
+    trace: Rc<RefCell<Trace>>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> TraceRecorder {
+        TraceRecorder::default()
+    }
+
+    pub fn record(&self, command: &[u8], response: &[u8]) {
+        self.trace.borrow_mut().exchanges.push(Exchange {
+            command: hex::encode_upper(command),
+            response: hex::encode_upper(response),
+        });
+    }
+
+    pub fn trace(&self) -> Trace {
+        self.trace.borrow().clone()
+    }
+}
+
+/**
+Transport answering with the responses of a recorded trace.
+Any command deviating from the recorded sequence panics, as does dropping it before
+the whole trace has been replayed.
+*/
+pub struct ReplayTransport {
+    trace: Trace,
+    position: Cell<usize>,
+}
+
+impl ReplayTransport {
+    pub fn new(trace: Trace) -> ReplayTransport {
+        ReplayTransport {
+            trace,
+            position: Cell::new(0),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<ReplayTransport> {
+        Ok(ReplayTransport::new(Trace::from_json(json)?))
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for ReplayTransport {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let position = self.position.get();
+        let command = hex::encode_upper(apdu);
+        let exchange = match self.trace.exchanges.get(position) {
+            Some(exchange) => exchange,
+            None => panic!(
+                "replay: command #{} {} sent after the end of the trace",
+                position, command
+            ),
+        };
+        if !exchange.command.eq_ignore_ascii_case(&command) {
+            panic!(
+                "replay: command #{} deviates from the trace\nexpected {}\n     got {}",
+                position, exchange.command, command
+            );
+        }
+        self.position.set(position + 1);
+        Ok(hex::decode(&exchange.response)?)
+    }
+}
+
+impl Drop for ReplayTransport {
+    fn drop(&mut self) {
+        let position = self.position.get();
+        if position < self.trace.exchanges.len() && !std::thread::panicking() {
+            panic!(
+                "replay: only {} of the {} recorded commands were sent",
+                position,
+                self.trace.exchanges.len()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::device::Device;
+    use crate::mock::MockTransport;
+    use crate::trace::{ReplayTransport, Trace, TraceRecorder};
+    use futures::executor::block_on;
+
+    const TRACE: &str = r#"{
+  "exchanges": [
+    {
+      "command": "00A4040000",
+      "response": "9000"
+    },
+    {
+      "command": "80CA004400",
+      "response": "696D4B657930313139313230303030319000"
+    }
+  ]
+}"#;
+
+    #[test]
+    fn record_test() {
+        let device = Device::new(MockTransport::new(|apdu: &[u8]| match apdu {
+            [0x80, 0xCA, ..] => Ok(hex::decode("696D4B657930313139313230303030319000")?),
+            _ => Ok(vec![0x90, 0x00]),
+        }));
+        let recorder = TraceRecorder::new();
+        block_on(device.send_apdu(&hex::decode("00A4040001").unwrap())).unwrap();
+        device.set_recorder(Some(recorder.clone()));
+        block_on(device.send_apdu(&hex::decode("00a4040000").unwrap())).unwrap();
+        block_on(device.send_apdu(&hex::decode("80CA004400").unwrap())).unwrap();
+        device.set_recorder(None);
+        block_on(device.send_apdu(&hex::decode("00A4040001").unwrap())).unwrap();
+
+        assert_eq!(recorder.trace().to_json().unwrap(), TRACE);
+    }
+
+    #[test]
+    fn replay_test() {
+        let device = Device::new(ReplayTransport::from_json(TRACE).unwrap());
+        assert_eq!(
+            hex::encode_upper(
+                block_on(device.send_apdu(&hex::decode("00A4040000").unwrap())).unwrap()
+            ),
+            "9000"
+        );
+        assert_eq!(
+            hex::encode_upper(
+                block_on(device.send_apdu(&hex::decode("80ca004400").unwrap())).unwrap()
+            ),
+            "696D4B657930313139313230303030319000"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "replay: command #1 deviates from the trace")]
+    fn replay_deviation_test() {
+        let device = Device::new(ReplayTransport::from_json(TRACE).unwrap());
+        block_on(device.send_apdu(&hex::decode("00A4040000").unwrap())).unwrap();
+        let _ = block_on(device.send_apdu(&hex::decode("80CB800005DFFF028101").unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "replay: only 1 of the 2 recorded commands were sent")]
+    fn replay_unfinished_test() {
+        let device = Device::new(ReplayTransport::new(Trace::from_json(TRACE).unwrap()));
+        block_on(device.send_apdu(&hex::decode("00A4040000").unwrap())).unwrap();
+    }
+}
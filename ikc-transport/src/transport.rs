@@ -0,0 +1,37 @@
+use crate::Result;
+use async_trait::async_trait;
+use std::time::Duration;
+
+/**
+A channel able to carry one APDU to the device and bring back its response.
+Implemented by the WebUSB backend, native backends and in-memory mocks.
+*/
+#[async_trait(?Send)]
+pub trait Transport {
+    /**
+    send a raw command APDU, return the raw response (data followed by the status word)
+    */
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>>;
+
+    /**
+    send a command APDU waiting at most the given duration for the response,
+    transports answering immediately have no need to override it
+    */
+    async fn send_apdu_with_timeout(&self, apdu: &[u8], _timeout: Duration) -> Result<Vec<u8>> {
+        self.send_apdu(apdu).await
+    }
+
+    /**
+    false once the device has been unplugged or closed
+    */
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /**
+    abort the command the device is processing, e.g. one waiting for a user confirmation
+    */
+    async fn cancel(&self) -> Result<()> {
+        Ok(())
+    }
+}
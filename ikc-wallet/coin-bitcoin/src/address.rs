@@ -5,14 +5,12 @@ use bitcoin::schnorr::UntweakedPublicKey;
 use bitcoin::util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
 use bitcoin::{Address, PublicKey};
 use bitcoin_hashes::{hash160, Hash};
-use ikc_common::apdu::{ApduCheck, BtcApdu, CoinCommonApdu};
+use ikc_common::apdu::{BtcApdu, CoinCommonApdu, Exchange};
 use ikc_common::constants;
 use ikc_common::error::CommonError;
 use ikc_common::path::check_path_validity;
 use ikc_common::utility::hex_to_bytes;
-// use ikc_transport::message::send_apdu;
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
+use ikc_transport::device::Device;
 use secp256k1::{PublicKey as Secp256k1PublicKey, Secp256k1};
 use std::str::FromStr;
 
@@ -22,22 +20,20 @@ impl BtcAddress {
     /**
     get btc xpub by path
     */
-    pub async fn get_xpub(network: Network, path: &str) -> Result<String> {
+    pub async fn get_xpub(device: &Device, network: Network, path: &str) -> Result<String> {
         check_path_validity(path)?;
 
-        let xpub_data = get_xpub_data(path, true).await?;
-        let xpub_data = &xpub_data[..194].to_string();
+        let xpub_data = get_xpub_data(device, path, true).await?;
 
-        let pub_key = &xpub_data[..130];
-        let chain_code = &xpub_data[130..];
+        let pub_key = xpub_data.field(0, 65)?;
+        let chain_code = xpub_data.field(65, 32)?;
 
-        let parent_xpub = get_xpub_data(Self::get_parent_path(path)?, true).await?;
-        let parent_xpub = &parent_xpub[..130].to_string();
-        let parent_pub_key_obj = Secp256k1PublicKey::from_str(parent_xpub)?;
+        let parent_xpub = get_xpub_data(device, Self::get_parent_path(path)?, true).await?;
+        let parent_pub_key_obj = Secp256k1PublicKey::from_slice(parent_xpub.field(0, 65)?)?;
 
-        let pub_key_obj = Secp256k1PublicKey::from_str(pub_key)?;
+        let pub_key_obj = Secp256k1PublicKey::from_slice(pub_key)?;
 
-        let chain_code_obj = ChainCode::from(hex::decode(chain_code).unwrap().as_slice());
+        let chain_code_obj = ChainCode::from(chain_code);
         let parent_ext_pub_key = ExtendedPubKey {
             network,
             depth: 0u8,
@@ -49,7 +45,7 @@ impl BtcAddress {
         let fingerprint_obj = parent_ext_pub_key.fingerprint();
 
         //build extend public key obj
-        let chain_code_obj = ChainCode::from(hex::decode(chain_code).unwrap().as_slice());
+        let chain_code_obj = ChainCode::from(chain_code);
         let chain_number_vec: Vec<ChildNumber> = DerivationPath::from_str(path)?.into();
         let extend_public_key = ExtendedPubKey {
             network,
@@ -65,15 +61,15 @@ impl BtcAddress {
     /**
     get btc address by path
     */
-    pub async fn p2pkh(network: Network, path: &str) -> Result<String> {
+    pub async fn p2pkh(device: &Device, network: Network, path: &str) -> Result<String> {
         //path check
         check_path_validity(path)?;
 
         //get xpub
-        let xpub_data = get_xpub_data(path, true).await?;
-        let pub_key = &xpub_data[..130];
+        let xpub_data = get_xpub_data(device, path, true).await?;
+        let pub_key = xpub_data.field(0, 65)?;
 
-        let mut pub_key_obj = PublicKey::from_str(pub_key)?;
+        let mut pub_key_obj = PublicKey::from_slice(pub_key)?;
         pub_key_obj.compressed = true;
 
         Ok(Address::p2pkh(&pub_key_obj, network).to_string())
@@ -82,52 +78,51 @@ impl BtcAddress {
     /**
     get segwit address by path
     */
-    pub async fn p2shwpkh(network: Network, path: &str) -> Result<String> {
+    pub async fn p2shwpkh(device: &Device, network: Network, path: &str) -> Result<String> {
         //path check
         check_path_validity(path)?;
 
         //get xpub
-        let xpub_data = get_xpub_data(path, true).await?;
-        let pub_key = &xpub_data[..130];
+        let xpub_data = get_xpub_data(device, path, true).await?;
+        let pub_key = xpub_data.field(0, 65)?;
 
-        let mut pub_key_obj = PublicKey::from_str(pub_key)?;
+        let mut pub_key_obj = PublicKey::from_slice(pub_key)?;
         pub_key_obj.compressed = true;
 
         Ok(Address::p2shwpkh(&pub_key_obj, network)?.to_string())
     }
 
-    pub async fn p2wpkh(network: Network, path: &str) -> Result<String> {
+    pub async fn p2wpkh(device: &Device, network: Network, path: &str) -> Result<String> {
         check_path_validity(path)?;
 
-        let xpub_data = get_xpub_data(path, true).await?;
-        let pub_key = &xpub_data[..130];
-        let mut pub_key_obj = PublicKey::from_str(pub_key)?;
+        let xpub_data = get_xpub_data(device, path, true).await?;
+        let pub_key = xpub_data.field(0, 65)?;
+        let mut pub_key_obj = PublicKey::from_slice(pub_key)?;
         pub_key_obj.compressed = true;
 
         Ok(Address::p2wpkh(&pub_key_obj, network)?.to_string())
     }
 
-    pub async fn p2tr(network: Network, path: &str) -> Result<String> {
+    pub async fn p2tr(device: &Device, network: Network, path: &str) -> Result<String> {
         check_path_validity(path)?;
 
-        let xpub_data = get_xpub_data(path, true).await?;
-        let pub_key = &xpub_data[..130];
-        let untweak_pub_key =
-            UntweakedPublicKey::from(secp256k1::PublicKey::from_slice(&hex_to_bytes(&pub_key)?)?);
+        let xpub_data = get_xpub_data(device, path, true).await?;
+        let pub_key = xpub_data.field(0, 65)?;
+        let untweak_pub_key = UntweakedPublicKey::from(secp256k1::PublicKey::from_slice(pub_key)?);
 
         let secp256k1 = Secp256k1::new();
         Ok(Address::p2tr(&secp256k1, untweak_pub_key, None, network).to_string())
     }
 
-    pub async fn get_pub_key(path: &str) -> Result<String> {
+    pub async fn get_pub_key(device: &Device, path: &str) -> Result<String> {
         //path check
         check_path_validity(path)?;
 
         //get xpub
-        let xpub_data = get_xpub_data(path, true).await?;
-        let pub_key = &xpub_data[..130];
+        let xpub_data = get_xpub_data(device, path, true).await?;
+        let pub_key = xpub_data.field(0, 65)?;
 
-        Ok(pub_key.to_string())
+        Ok(hex::encode_upper(pub_key))
     }
 
     /**
@@ -146,18 +141,25 @@ impl BtcAddress {
         Ok(&path[..end_flg])
     }
 
-    pub async fn display_address(network: Network, path: &str, seg_wit: &str) -> Result<String> {
+    pub async fn display_address(
+        device: &Device,
+        network: Network,
+        path: &str,
+        seg_wit: &str,
+    ) -> Result<String> {
         check_path_validity(path)?;
 
         let address = match seg_wit {
-            constants::BTC_SEG_WIT_TYPE_P2WPKH => Self::p2shwpkh(network, path).await?,
-            constants::BTC_SEG_WIT_TYPE_VERSION_0 => Self::p2wpkh(network, path).await?,
-            constants::BTC_SEG_WIT_TYPE_VERSION_1 => Self::p2tr(network, path).await?,
-            _ => Self::p2pkh(network, path).await?,
+            constants::BTC_SEG_WIT_TYPE_P2WPKH => Self::p2shwpkh(device, network, path).await?,
+            constants::BTC_SEG_WIT_TYPE_VERSION_0 => Self::p2wpkh(device, network, path).await?,
+            constants::BTC_SEG_WIT_TYPE_VERSION_1 => Self::p2tr(device, network, path).await?,
+            _ => Self::p2pkh(device, network, path).await?,
         };
 
-        let apdu_res = send_apdu(BtcApdu::register_address(&address.as_bytes())).await?;
-        ApduCheck::check_response(apdu_res.as_str())?;
+        let apdu_res = device
+            .exchange(&BtcApdu::register_address(&address.as_bytes())?)
+            .await?;
+        apdu_res.check()?;
         Ok(address)
     }
 
@@ -189,15 +191,38 @@ impl BtcAddress {
 mod test {
     use crate::address::BtcAddress;
     use bitcoin::Network;
-    use ikc_device::device_binding::bind_test;
+    use futures::executor::block_on;
+    use ikc_device::device_binding::{bind_test, DeviceManage};
+    use ikc_transport::trace::TraceRecorder;
+
+    #[test]
+    fn secure_channel_test() {
+        let device = bind_test();
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        let recorder = TraceRecorder::new();
+        device.set_recorder(Some(recorder.clone()));
+        let path: &str = "m/44'/0'/0'/0/0";
+        let btc_address = block_on(BtcAddress::p2pkh(&device, Network::Bitcoin, path)).unwrap();
+        assert_eq!("12z6UzsA3tjpaeuvA2Zr9jwx19Azz74D6g", btc_address);
+
+        //the public key is read through the channel, encrypted and MACed
+        assert!(device.is_secure());
+        let trace = recorder.trace();
+        let get_xpub = trace
+            .exchanges
+            .iter()
+            .find(|exchange| exchange.command.starts_with("8443"))
+            .unwrap();
+        assert!(get_xpub.response.ends_with("9000"));
+    }
 
     #[test]
     fn get_xpub_test() {
-        bind_test();
+        let device = bind_test();
 
         let version: Network = Network::Bitcoin;
         let path: &str = "m/44'/0'/0'/0/0";
-        let get_xpub_result = BtcAddress::get_xpub(version, path);
+        let get_xpub_result = block_on(BtcAddress::get_xpub(&device, version, path));
         assert!(get_xpub_result.is_ok());
         let xpub = get_xpub_result.ok().unwrap();
         assert_eq!("xpub6FuzpGNBc46EfvmcvECyqXjrzGcKErQgpQcpvhw1tiC5yXvi1jUkzudMpdg5AaguiFstdVR5ASDbSceBswKRy6cAhpTgozmgxMUayPDrLLX", xpub);
@@ -205,31 +230,31 @@ mod test {
 
     #[test]
     fn get_xpub_path_error_test() {
-        bind_test();
+        let device = bind_test();
 
         let version: Network = Network::Bitcoin;
         let path: &str = "m/44'";
-        let get_xpub_result = BtcAddress::get_xpub(version, path);
+        let get_xpub_result = block_on(BtcAddress::get_xpub(&device, version, path));
         assert!(get_xpub_result.is_err());
     }
 
     #[test]
     fn get_xpub_path_is_null_test() {
-        bind_test();
+        let device = bind_test();
 
         let version: Network = Network::Bitcoin;
         let path: &str = "";
-        let get_xpub_result = BtcAddress::get_xpub(version, path);
+        let get_xpub_result = block_on(BtcAddress::get_xpub(&device, version, path));
         assert!(get_xpub_result.is_err());
     }
 
     #[test]
     fn p2pkh_test() {
-        bind_test();
+        let device = bind_test();
 
         let version: Network = Network::Bitcoin;
         let path: &str = "m/44'/0'/0'/0/0";
-        let get_btc_address_result = BtcAddress::p2pkh(version, path);
+        let get_btc_address_result = block_on(BtcAddress::p2pkh(&device, version, path));
 
         assert!(get_btc_address_result.is_ok());
         let btc_address = get_btc_address_result.ok().unwrap();
@@ -238,11 +263,11 @@ mod test {
 
     #[test]
     fn p2shwpkh_address_test() {
-        bind_test();
+        let device = bind_test();
 
         let version: Network = Network::Bitcoin;
         let path: &str = "m/49'/0'/0'/0/22";
-        let segwit_address_result = BtcAddress::p2shwpkh(version, path);
+        let segwit_address_result = block_on(BtcAddress::p2shwpkh(&device, version, path));
 
         assert!(segwit_address_result.is_ok());
         let segwit_address = segwit_address_result.ok().unwrap();
@@ -250,11 +275,11 @@ mod test {
     }
     #[test]
     fn p2wpkh_address_test() {
-        bind_test();
+        let device = bind_test();
 
         let network: Network = Network::Bitcoin;
         let path: &str = "m/49'/0'/0'/0/22";
-        let segwit_address_result = BtcAddress::p2wpkh(network, path);
+        let segwit_address_result = block_on(BtcAddress::p2wpkh(&device, network, path));
 
         assert!(segwit_address_result.is_ok());
         let segwit_address = segwit_address_result.ok().unwrap();
@@ -263,11 +288,11 @@ mod test {
 
     #[test]
     fn p2tr_address_test() {
-        bind_test();
+        let device = bind_test();
 
         let network: Network = Network::Bitcoin;
         let path: &str = "m/49'/0'/0'/0/22";
-        let segwit_address_result = BtcAddress::p2tr(network, path);
+        let segwit_address_result = block_on(BtcAddress::p2tr(&device, network, path));
 
         assert!(segwit_address_result.is_ok());
         let segwit_address = segwit_address_result.ok().unwrap();
@@ -299,10 +324,10 @@ mod test {
 
     #[test]
     fn display_address_test() {
-        bind_test();
+        let device = bind_test();
         let version: Network = Network::Bitcoin;
         let path: &str = "m/44'/0'/0'/0/0";
-        let result = BtcAddress::display_address(version, path, "NONE");
+        let result = block_on(BtcAddress::display_address(&device, version, path, "NONE"));
 
         assert!(result.is_ok());
         let btc_address = result.ok().unwrap();
@@ -311,10 +336,12 @@ mod test {
 
     #[test]
     fn display_segwit_address_test() {
-        bind_test();
+        let device = bind_test();
         let network: Network = Network::Bitcoin;
         let path: &str = "m/49'/0'/0'/0/22";
-        let result = BtcAddress::display_address(network, path, "P2WPKH");
+        let result = block_on(BtcAddress::display_address(
+            &device, network, path, "P2WPKH",
+        ));
 
         assert!(result.is_ok());
         let segwit_address = result.ok().unwrap();
@@ -323,10 +350,15 @@ mod test {
 
     #[test]
     fn display_native_segwit_address_test() {
-        bind_test();
+        let device = bind_test();
         let network: Network = Network::Bitcoin;
         let path: &str = "m/84'/0'/0'";
-        let result = BtcAddress::display_address(network, path, "VERSION_0");
+        let result = block_on(BtcAddress::display_address(
+            &device,
+            network,
+            path,
+            "VERSION_0",
+        ));
 
         assert!(result.is_ok());
         let segwit_address = result.ok().unwrap();
@@ -335,10 +367,15 @@ mod test {
 
     #[test]
     fn display_taproot_address_test() {
-        bind_test();
+        let device = bind_test();
         let network: Network = Network::Bitcoin;
         let path: &str = "m/86'/0'/0'";
-        let result = BtcAddress::display_address(network, path, "VERSION_1");
+        let result = block_on(BtcAddress::display_address(
+            &device,
+            network,
+            path,
+            "VERSION_1",
+        ));
 
         assert!(result.is_ok());
         let segwit_address = result.ok().unwrap();
@@ -4,26 +4,23 @@ use bitcoin::schnorr::UntweakedPublicKey;
 use bitcoin::util::base58;
 use bitcoin::util::bip32::{ChainCode, ChildNumber, ExtendedPubKey};
 use bitcoin::{Address, AddressType, Network, PublicKey};
-use ikc_common::apdu::{ApduCheck, BtcApdu, CoinCommonApdu};
+use ikc_common::apdu::{BtcApdu, CoinCommonApdu, Exchange, Response};
 use ikc_common::error::CoinError;
 use ikc_common::utility::{hex_to_bytes, sha256_hash};
-// use ikc_transport::message::send_apdu;
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
+use ikc_transport::device::Device;
 use secp256k1::{ecdsa::Signature, Message, PublicKey as Secp256k1PublicKey, Secp256k1};
-use std::str::FromStr;
 
 /**
 get utxo public key
 */
-pub async fn get_utxo_pub_key(utxos: &Vec<Utxo>) -> Result<Vec<String>> {
+pub async fn get_utxo_pub_key(device: &Device, utxos: &Vec<Utxo>) -> Result<Vec<String>> {
     let mut utxo_pub_key_vec: Vec<String> = vec![];
     for utxo in utxos {
-        let xpub_data = get_xpub_data(&utxo.derive_path, false).await?;
+        let xpub_data = get_xpub_data(device, &utxo.derive_path, false).await?;
         //parsing xpub data
-        let derive_pub_key = &xpub_data[..130];
+        let derive_pub_key = xpub_data.field(0, 65)?;
 
-        let mut public_key = PublicKey::from_str(derive_pub_key)?;
+        let mut public_key = PublicKey::from_slice(derive_pub_key)?;
         public_key.compressed = true;
 
         utxo_pub_key_vec.push(public_key.to_string());
@@ -32,23 +29,25 @@ pub async fn get_utxo_pub_key(utxos: &Vec<Utxo>) -> Result<Vec<String>> {
 }
 
 /**
-get xpub
+get xpub, the response data starts with the uncompressed public key (65) and the chain code (32)
 */
-pub async fn get_xpub_data(path: &str, verify_flag: bool) -> Result<String> {
-    let select_response = send_apdu(BtcApdu::select_applet()).await?;
-    ApduCheck::check_response(&select_response)?;
-    let xpub_data = send_apdu(BtcApdu::get_xpub(path, verify_flag)).await?;
-    ApduCheck::check_response(&xpub_data)?;
+pub async fn get_xpub_data(device: &Device, path: &str, verify_flag: bool) -> Result<Response> {
+    let select_response = device.exchange(&BtcApdu::select_applet()).await?;
+    select_response.check()?;
+    let xpub_data = device
+        .exchange(&BtcApdu::get_xpub(path, verify_flag)?)
+        .await?;
+    xpub_data.check()?;
     Ok(xpub_data)
 }
 
 /**
 select btc applet
  */
-pub async fn select_btc_applet() -> Result<()> {
+pub async fn select_btc_applet(device: &Device) -> Result<()> {
     // let select_response = send_apdu(BtcApdu::select_applet())?;
-    let select_response = send_apdu(BtcApdu::select_applet()).await?;
-    ApduCheck::check_response(&select_response)?;
+    let select_response = device.exchange(&BtcApdu::select_applet()).await?;
+    select_response.check()?;
     Ok(())
 }
 
@@ -11,6 +11,7 @@ use bitcoin_hashes::hex::ToHex;
 use hex::FromHex;
 use ikc_common::error::CoinError;
 use ikc_common::utility::{network_convert, sha256_hash, utf8_or_hex_to_bytes};
+use ikc_transport::device::Device;
 use std::str::FromStr;
 
 pub struct MessageSinger {
@@ -20,23 +21,34 @@ pub struct MessageSinger {
     pub seg_wit: String,
 }
 impl MessageSinger {
-    pub async fn sign_message(&self, input: BtcMessageInput) -> Result<BtcMessageOutput> {
+    pub async fn sign_message(
+        &self,
+        device: &Device,
+        input: BtcMessageInput,
+    ) -> Result<BtcMessageOutput> {
         let data = utf8_or_hex_to_bytes(&input.message)?;
 
         let path = format!("{}/0/0", self.derivation_path);
 
-        let pub_key = BtcAddress::get_pub_key(&path).await?;
+        let pub_key = BtcAddress::get_pub_key(device, &path).await?;
 
         let network = network_convert(&self.network);
         let address = BtcAddress::from_public_key(&pub_key, network, &self.seg_wit)?;
         let script_pubkey = Address::from_str(&address)?.script_pubkey();
         let tx_id = get_spend_tx_id(&data, script_pubkey.clone())?;
 
-        select_btc_applet().await?;
+        select_btc_applet(device).await?;
 
         let mut psbt = create_to_sign_empty(tx_id, script_pubkey)?;
-        let mut psbt_signer =
-            PsbtSigner::new(&mut psbt, &self.derivation_path, true, network, true).await?;
+        let mut psbt_signer = PsbtSigner::new(
+            device,
+            &mut psbt,
+            &self.derivation_path,
+            true,
+            network,
+            true,
+        )
+        .await?;
 
         psbt_signer.prevouts()?;
 
@@ -154,16 +166,16 @@ mod tests {
     use crate::btcapi::BtcMessageInput;
     use crate::message::MessageSinger;
     use bitcoin::{Address, Network};
-    use ikc_common::SignParam;
+    use futures::executor::block_on;
     use ikc_device::device_binding::bind_test;
     use std::str::FromStr;
 
     #[test]
     fn test_to_spend_tx_id() {
-        bind_test();
+        let device = bind_test();
 
         let derivation_path = "m/44'/0'/0'/0/0";
-        let pub_key = BtcAddress::get_pub_key(derivation_path).unwrap();
+        let pub_key = block_on(BtcAddress::get_pub_key(&device, derivation_path)).unwrap();
         let network = Network::Bitcoin;
         let seg_wit = "VERSION_0";
         let address = BtcAddress::from_public_key(&pub_key, Network::Testnet, seg_wit).unwrap();
@@ -180,7 +192,7 @@ mod tests {
 
     #[test]
     fn test_bip32_p2sh_p2wpkh() {
-        bind_test();
+        let device = bind_test();
 
         let singer = MessageSinger {
             derivation_path: "m/49'/0'/0'".to_string(),
@@ -192,13 +204,13 @@ mod tests {
             message: "hello world".to_string(),
         };
 
-        let output = singer.sign_message(input).unwrap();
+        let output = block_on(singer.sign_message(&device, input)).unwrap();
         assert_eq!(output.signature, "02473044022000ae3c9439681a4ba05e74d0805210f71c31f92130bcec28934d29beaf5f4f890220327cbf8a189eee4cb35a2599f6fd97b0774bec2e4191d74b3460f746732f8a03012103036695c5f3de2e2792b170f59679d4db88a8516728012eaa42a22ce6f8bf593b");
     }
 
     #[test]
     fn test_bip32_p2pkh() {
-        bind_test();
+        let device = bind_test();
 
         let singer = MessageSinger {
             derivation_path: "m/44'/0'/0'".to_string(),
@@ -209,13 +221,13 @@ mod tests {
         let input = BtcMessageInput {
             message: "hello world".to_string(),
         };
-        let output = singer.sign_message(input).unwrap();
+        let output = block_on(singer.sign_message(&device, input)).unwrap();
         assert_eq!(output.signature, "02483045022100dbbdfedfb1902ca12c6cba14d4892a98f77c434daaa4f97fd35e618374c908f602206527ff2b1ce550c16c836c2ce3508bfae543fa6c11759d2f4966cc0d3552c4430121026b5b6a9d041bc5187e0b34f9e496436c7bff261c6c1b5f3c06b433c61394b868");
     }
 
     #[test]
     fn test_bip322_p2wpkh() {
-        bind_test();
+        let device = bind_test();
 
         let singer = MessageSinger {
             derivation_path: "m/44'/0'/0'".to_string(),
@@ -226,13 +238,13 @@ mod tests {
         let input = BtcMessageInput {
             message: "hello world".to_string(),
         };
-        let output = singer.sign_message(input).unwrap();
+        let output = block_on(singer.sign_message(&device, input)).unwrap();
         assert_eq!(output.signature, "024830450221009f003820d1db93bf78be08dafdd05b7dde7c31a73c9be36b705a15329bd3d0e502203eb6f1a34466995e4b9c281bf4a093a1f55a21b2ef961438c9ae284efab27dda0121026b5b6a9d041bc5187e0b34f9e496436c7bff261c6c1b5f3c06b433c61394b868");
     }
 
     #[test]
     fn test_bip322_p2tr() {
-        bind_test();
+        let device = bind_test();
 
         let singer = MessageSinger {
             derivation_path: "m/86'/0'/0'".to_string(),
@@ -244,7 +256,7 @@ mod tests {
             message: "Sign this message to log in to https://www.subber.xyz // 200323342"
                 .to_string(),
         };
-        let output = singer.sign_message(input).unwrap();
+        let output = block_on(singer.sign_message(&device, input)).unwrap();
         // assert_eq!(output.signature, "0140a868e67a50f6dff3e25f6b015f595d89de54e330a6e1dfb4925269577730803e10a43562b25979a704f1d6c856e623681f292ce0ddf2281f42c033db013b4326");
     }
 }
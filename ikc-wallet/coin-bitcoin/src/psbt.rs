@@ -16,26 +16,26 @@ use bitcoin::{
 use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::{hash160, Hash};
 use hex::FromHex;
-use ikc_common::apdu::{ApduCheck, BtcApdu};
+use ikc_common::apdu::{BtcApdu, Exchange};
 use ikc_common::coin_info::coin_info_from_param;
 use ikc_common::constants;
 use ikc_common::constants::TIMEOUT_LONG;
 use ikc_common::error::CoinError;
 use ikc_common::path::{check_path_validity, get_account_path};
 use ikc_common::utility::{bigint_to_byte_vec, hex_to_bytes, secp256k1_sign, sha256_hash};
-use ikc_device::device_binding::KEY_MANAGER;
-// use ikc_transport::message::{send_apdu, send_apdu_timeout};
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
+use ikc_device::device_binding::device_key_manager;
+use ikc_transport::device::Device;
 use secp256k1::{
     ecdsa::Signature, schnorr::Signature as SchnorrSignature, PublicKey as Secp256k1PublicKey,
 };
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::str::FromStr;
+use std::time::Duration;
 use std::usize;
 
 pub struct PsbtSigner<'a> {
+    device: &'a Device,
     psbt: &'a mut Psbt,
     derivation_path: String,
     auto_finalize: bool,
@@ -47,6 +47,7 @@ pub struct PsbtSigner<'a> {
 
 impl<'a> PsbtSigner<'a> {
     pub async fn new(
+        device: &'a Device,
         psbt: &'a mut Psbt,
         derivation_path: &str,
         auto_finalize: bool,
@@ -54,6 +55,7 @@ impl<'a> PsbtSigner<'a> {
         is_sign_message: bool,
     ) -> Result<Self> {
         let mut psbt_signer = PsbtSigner {
+            device,
             psbt,
             derivation_path: derivation_path.to_string(),
             prevouts: vec![],
@@ -99,7 +101,8 @@ impl<'a> PsbtSigner<'a> {
                         TapLeafHash::from_script(script, leaf_version.clone()).into(),
                         0xFFFFFFFF,
                     )),
-                ).await?;
+                )
+                .await?;
 
                 if self.auto_finalize {
                     self.finalize_p2tr(idx);
@@ -173,9 +176,9 @@ impl<'a> PsbtSigner<'a> {
                 self.get_path(idx, false)?
             };
 
-            let xpub_data = get_xpub_data(&path, false).await?;
-            let derive_pub_key = &xpub_data[..130];
-            let public_key = Secp256k1PublicKey::from_str(derive_pub_key)?;
+            let xpub_data = get_xpub_data(self.device, &path, false).await?;
+            let derive_pub_key = xpub_data.field(0, 65)?;
+            let public_key = Secp256k1PublicKey::from_slice(derive_pub_key)?;
             pub_key_vec.push(public_key.to_string())
         }
 
@@ -201,18 +204,16 @@ impl<'a> PsbtSigner<'a> {
         let btc_perpare_apdu_list = BtcApdu::btc_single_utxo_sign_prepare(0x50, &input_data_vec);
         for apdu in btc_perpare_apdu_list {
             // ApduCheck::check_response(&send_apdu(apdu)?)?;
-            ApduCheck::check_response(&send_apdu(apdu).await?)?;
+            self.device.exchange(&apdu).await?.check()?;
         }
         let path = self.get_path(idx, false)?;
         let btc_sign_apdu =
             BtcApdu::btc_single_utxo_sign(idx as u8, EcdsaSighashType::All.to_u32() as u8, &path);
 
         // let btc_sign_apdu_return = send_apdu(btc_sign_apdu)?;
-        let btc_sign_apdu_return = send_apdu(btc_sign_apdu).await?;
-        ApduCheck::check_response(&btc_sign_apdu_return)?;
-        let btc_sign_apdu_return =
-            &btc_sign_apdu_return[..btc_sign_apdu_return.len() - 4].to_string();
-        let sign_result_str = btc_sign_apdu_return[2..btc_sign_apdu_return.len() - 2].to_string();
+        let btc_sign_apdu_return = self.device.exchange(&btc_sign_apdu).await?;
+        btc_sign_apdu_return.check()?;
+        let sign_result_str = hex::encode_upper(btc_sign_apdu_return.field(1, 64)?);
 
         let mut signature_obj = Signature::from_compact(&hex::decode(&sign_result_str)?)?;
         signature_obj.normalize_s();
@@ -268,12 +269,11 @@ impl<'a> PsbtSigner<'a> {
             BtcApdu::btc_segwit_sign(false, 0x01, data)
         };
         // let sign_apdu_return_data = send_apdu(sign_apdu)?;
-        let sign_apdu_return_data = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_apdu_return_data)?;
+        let sign_apdu_return_data = self.device.exchange(&sign_apdu?).await?;
+        sign_apdu_return_data.check()?;
 
         //build signature obj
-        let sign_result_vec =
-            Vec::from_hex(&sign_apdu_return_data[2..sign_apdu_return_data.len() - 6]).unwrap();
+        let sign_result_vec = sign_apdu_return_data.field(1, 64)?.to_vec();
         let mut signature_obj = Signature::from_compact(sign_result_vec.as_slice())?;
         signature_obj.normalize_s();
         //generator der sign data
@@ -330,11 +330,10 @@ impl<'a> PsbtSigner<'a> {
             BtcApdu::btc_segwit_sign(false, 0x01, data)
         };
         // let sign_apdu_return_data = send_apdu(sign_apdu)?;
-        let sign_apdu_return_data = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_apdu_return_data)?;
+        let sign_apdu_return_data = self.device.exchange(&sign_apdu?).await?;
+        sign_apdu_return_data.check()?;
         //build signature obj
-        let sign_result_vec =
-            Vec::from_hex(&sign_apdu_return_data[2..sign_apdu_return_data.len() - 6]).unwrap();
+        let sign_result_vec = sign_apdu_return_data.field(1, 64)?.to_vec();
         let mut signature_obj = Signature::from_compact(sign_result_vec.as_slice())?;
         signature_obj.normalize_s();
         let pub_key = PublicKey::from_str(pub_key)?;
@@ -379,10 +378,10 @@ impl<'a> PsbtSigner<'a> {
             BtcApdu::btc_taproot_sign(false, data)
         };
         // let sign_result = send_apdu(sign_apdu)?;
-        let sign_result = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_result)?;
+        let sign_result = self.device.exchange(&sign_apdu?).await?;
+        sign_result.check()?;
 
-        let sign_bytes = hex_to_bytes(&sign_result[2..(sign_result.len() - 4)])?;
+        let sign_bytes = sign_result.field(1, 64)?.to_vec();
         let sig = SchnorrSignature::from_slice(&sign_bytes)?;
         self.psbt.inputs[idx].tap_key_sig = Some(SchnorrSig {
             hash_ty: SchnorrSighashType::Default,
@@ -444,10 +443,10 @@ impl<'a> PsbtSigner<'a> {
         };
 
         // let sign_result = send_apdu(sign_apdu)?;
-        let sign_result = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_result)?;
+        let sign_result = self.device.exchange(&sign_apdu?).await?;
+        sign_result.check()?;
 
-        let sign_bytes = hex_to_bytes(&sign_result[2..(sign_result.len() - 4)])?;
+        let sign_bytes = sign_result.field(1, 64)?.to_vec();
         let sig = SchnorrSignature::from_slice(&sign_bytes)?;
         self.psbt.inputs[idx].tap_key_sig = Some(SchnorrSig {
             hash_ty: SchnorrSighashType::Default,
@@ -476,7 +475,7 @@ impl<'a> PsbtSigner<'a> {
         calc_hash_apdu.extend(BtcApdu::btc_prepare(0x31, 0x21, &script_pubkeys_vec));
         for apdu in calc_hash_apdu {
             // ApduCheck::check_response(&send_apdu(apdu)?)?;
-            ApduCheck::check_response(&send_apdu(apdu).await?)?;
+            self.device.exchange(&apdu).await?.check()?;
         }
         Ok(())
     }
@@ -511,15 +510,15 @@ impl<'a> PsbtSigner<'a> {
         preview_data.insert(0, 0x01);
 
         //use local private key sign data
-        let key_manager_obj = KEY_MANAGER.lock();
-        let mut output_pareper_data = secp256k1_sign(&key_manager_obj.pri_key, &preview_data)?;
+        let pri_key = device_key_manager(self.device)?.lock().pri_key.clone();
+        let mut output_pareper_data = secp256k1_sign(&pri_key, &preview_data)?;
         output_pareper_data.insert(0, output_pareper_data.len() as u8);
         output_pareper_data.insert(0, 0x00);
         output_pareper_data.extend(preview_data.iter());
         let btc_prepare_apdu_vec = BtcApdu::btc_prepare(0x4B, 0x00, &output_pareper_data);
         for temp_str in btc_prepare_apdu_vec {
             // ApduCheck::check_response(&send_apdu(temp_str)?)?;
-            ApduCheck::check_response(&send_apdu(temp_str).await?)?;
+            self.device.exchange(&temp_str).await?.check()?;
         }
 
         let mut page_number = 0;
@@ -533,7 +532,7 @@ impl<'a> PsbtSigner<'a> {
             outputs_data.insert(0, outputs_data.len() as u8);
             outputs_data.insert(0, 0x01);
             //use local private key sign data
-            let mut output_pareper_data = secp256k1_sign(&key_manager_obj.pri_key, &outputs_data)?;
+            let mut output_pareper_data = secp256k1_sign(&pri_key, &outputs_data)?;
             output_pareper_data.insert(0, output_pareper_data.len() as u8);
             output_pareper_data.insert(0, 0x00);
             output_pareper_data.extend(outputs_data.iter());
@@ -542,12 +541,15 @@ impl<'a> PsbtSigner<'a> {
             } else {
                 BtcApdu::btc_psbt_preview(&output_pareper_data, 0x00)
             };
-            // let response = &send_apdu_timeout(sign_confirm, TIMEOUT_LONG)?;
-            let response = &send_apdu(sign_confirm).await?;
-            ApduCheck::check_response(response)?;
-            if response.len() > 4 {
-                let page_index = &response[..response.len() - 4];
-                page_number = u16::from_str_radix(page_index, 16)? as usize;
+            let response = self
+                .device
+                .exchange_with_timeout(&sign_confirm?, Duration::from_secs(TIMEOUT_LONG as u64))
+                .await?;
+            response.check()?;
+            //the device asks for the next page while there is one
+            if !response.data.is_empty() {
+                let page_index = response.field(0, 2)?;
+                page_number = u16::from_be_bytes([page_index[0], page_index[1]]) as usize;
             } else {
                 break;
             }
@@ -654,7 +656,7 @@ impl<'a> PsbtSigner<'a> {
             let address = if tx_out_script.is_p2pkh() {
                 let change_path =
                     Self::get_change_index(self.network, constants::BTC_SEG_WIT_TYPE_LEGACY)?;
-                let pub_key = BtcAddress::get_pub_key(&change_path).await?;
+                let pub_key = BtcAddress::get_pub_key(self.device, &change_path).await?;
                 BtcAddress::from_public_key(
                     &pub_key,
                     self.network,
@@ -663,7 +665,7 @@ impl<'a> PsbtSigner<'a> {
             } else if tx_out_script.is_v0_p2wpkh() {
                 let change_path =
                     Self::get_change_index(self.network, constants::BTC_SEG_WIT_TYPE_VERSION_0)?;
-                let pub_key = BtcAddress::get_pub_key(&change_path).await?;
+                let pub_key = BtcAddress::get_pub_key(self.device, &change_path).await?;
                 BtcAddress::from_public_key(
                     &pub_key,
                     self.network,
@@ -672,7 +674,7 @@ impl<'a> PsbtSigner<'a> {
             } else if tx_out_script.is_p2sh() {
                 let change_path =
                     Self::get_change_index(self.network, constants::BTC_SEG_WIT_TYPE_P2WPKH)?;
-                let pub_key = BtcAddress::get_pub_key(&change_path).await?;
+                let pub_key = BtcAddress::get_pub_key(self.device, &change_path).await?;
                 BtcAddress::from_public_key(
                     &pub_key,
                     self.network,
@@ -681,7 +683,7 @@ impl<'a> PsbtSigner<'a> {
             } else if tx_out_script.is_v1_p2tr() {
                 let change_path =
                     Self::get_change_index(self.network, constants::BTC_SEG_WIT_TYPE_VERSION_1)?;
-                let pub_key = BtcAddress::get_pub_key(&change_path).await?;
+                let pub_key = BtcAddress::get_pub_key(self.device, &change_path).await?;
                 BtcAddress::from_public_key(
                     &pub_key,
                     self.network,
@@ -772,23 +774,26 @@ impl<'a> PsbtSigner<'a> {
 }
 
 pub async fn sign_psbt(
+    device: &Device,
     derivation_path: &str,
     psbt_input: PsbtInput,
     network: Network,
 ) -> Result<PsbtOutput> {
     check_path_validity(derivation_path)?;
 
-    select_btc_applet().await?;
+    select_btc_applet(device).await?;
 
     let mut reader = Cursor::new(Vec::<u8>::from_hex(psbt_input.psbt)?);
     let mut psbt = Psbt::consensus_decode(&mut reader)?;
     let mut signer = PsbtSigner::new(
+        device,
         &mut psbt,
         derivation_path,
         psbt_input.auto_finalize,
         network,
         false,
-    ).await?;
+    )
+    .await?;
 
     signer.prevouts()?;
 
@@ -813,6 +818,7 @@ pub async fn sign_psbt(
 
 #[cfg(test)]
 mod test {
+    use crate::address::BtcAddress;
     use crate::btcapi::PsbtInput;
     use crate::common::select_btc_applet;
     use crate::psbt::PsbtSigner;
@@ -823,8 +829,10 @@ mod test {
     use bitcoin::util::bip32::DerivationPath;
     use bitcoin::{schnorr, Address, Network, Transaction, TxOut};
     use bitcoin_hashes::hex::ToHex;
+    use futures::executor::block_on;
     use hex::FromHex;
-    use ikc_device::device_binding::bind_test;
+    use ikc_device::device_binding::{bind_test, record_test, replay_test};
+    use ikc_transport::device::Device;
     use secp256k1::schnorr::Signature;
     use secp256k1::{Message, XOnlyPublicKey};
     use std::io::Cursor;
@@ -832,14 +840,20 @@ mod test {
 
     #[test]
     fn test_sign_psbt_no_script() {
-        bind_test();
+        let device = bind_test();
 
         let psbt_input = PsbtInput {
             psbt: "70736274ff0100db0200000001fa4c8d58b9b6c56ed0b03f78115246c99eb70f99b837d7b4162911d1016cda340200000000fdffffff0350c30000000000002251202114eda66db694d87ff15ddd5d3c4e77306b6e6dd5720cbd90cd96e81016c2b30000000000000000496a47626274340066f873ad53d80688c7739d0d268acd956366275004fdceab9e9fc30034a4229ec20acf33c17e5a6c92cced9f1d530cccab7aa3e53400456202f02fac95e9c481fa00d47b1700000000002251208f4ca6a7384f50a1fe00cba593d5a834b480c65692a76ae6202e1ce46cb1c233d80f03000001012be3bf1d00000000002251208f4ca6a7384f50a1fe00cba593d5a834b480c65692a76ae6202e1ce46cb1c23301172066f873ad53d80688c7739d0d268acd956366275004fdceab9e9fc30034a4229e00000000".to_string(),
             auto_finalize: true,
         };
 
-        let psbt_output = super::sign_psbt("m/86'/1'/0'", psbt_input, Network::Bitcoin).unwrap();
+        let psbt_output = block_on(super::sign_psbt(
+            &device,
+            "m/86'/1'/0'",
+            psbt_input,
+            Network::Bitcoin,
+        ))
+        .unwrap();
         let mut reader = Cursor::new(Vec::<u8>::from_hex(&psbt_output.psbt).unwrap());
         let psbt = Psbt::consensus_decode(&mut reader).unwrap();
         let tx = psbt.extract_tx();
@@ -863,14 +877,20 @@ mod test {
 
     #[test]
     fn test_sign_psbt_script() {
-        bind_test();
+        let device = bind_test();
 
         let psbt_input = PsbtInput {
             psbt: "70736274ff01005e02000000012bd2f6479f3eeaffe95c03b5fdd76a873d346459114dec99c59192a0cb6409e90000000000ffffffff01409c000000000000225120677cc88dc36a75707b370e27efff3e454d446ad55004dac1685c1725ee1a89ea000000000001012b50c3000000000000225120a9a3350206de400f09a73379ec1bcfa161fc11ac095e5f3d7354126f0ec8e87f6215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0d2956573f010fa1a3c135279c5eb465ec2250205dcdfe2122637677f639b1021356c963cd9c458508d6afb09f3fa2f9b48faec88e75698339a4bbb11d3fc9b0efd570120aff94eb65a2fe773a57c5bd54e62d8436a5467573565214028422b41bd43e29bad200aee0509b16db71c999238a4827db945526859b13c95487ab46725357c9a9f25ac20113c3a32a9d320b72190a04a020a0db3976ef36972673258e9a38a364f3dc3b0ba2017921cf156ccb4e73d428f996ed11b245313e37e27c978ac4d2cc21eca4672e4ba203bb93dfc8b61887d771f3630e9a63e97cbafcfcc78556a474df83a31a0ef899cba2040afaf47c4ffa56de86410d8e47baa2bb6f04b604f4ea24323737ddc3fe092dfba2079a71ffd71c503ef2e2f91bccfc8fcda7946f4653cef0d9f3dde20795ef3b9f0ba20d21faf78c6751a0d38e6bd8028b907ff07e9a869a43fc837d6b3f8dff6119a36ba20f5199efae3f28bb82476163a7e458c7ad445d9bffb0682d10d3bdb2cb41f8e8eba20fa9d882d45f4060bdb8042183828cd87544f1ea997380e586cab77d5fd698737ba569cc001172050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac00000".to_string(),
             auto_finalize: true,
         };
 
-        let psbt_output = super::sign_psbt("m/86'/1'/0'", psbt_input, Network::Bitcoin).unwrap();
+        let psbt_output = block_on(super::sign_psbt(
+            &device,
+            "m/86'/1'/0'",
+            psbt_input,
+            Network::Bitcoin,
+        ))
+        .unwrap();
         let mut reader = Cursor::new(Vec::<u8>::from_hex(&psbt_output.psbt).unwrap());
         let psbt = Psbt::consensus_decode(&mut reader).unwrap();
         let tx = psbt.extract_tx();
@@ -898,7 +918,7 @@ mod test {
 
     #[test]
     fn test_sign_psbt_multipayment() {
-        bind_test();
+        let device = bind_test();
 
         let raw_tx = "02000000054adc61444e5a4dd7021e52dc6f5adadd9a3286d346f5d9f023ebcde2af80a0ae0000000000ffffffff4adc61444e5a4dd7021e52dc6f5adadd9a3286d346f5d9f023ebcde2af80a0ae0100000000ffffffff12cc8049bf85b5e18cb2be8aa7aefc3afb8df4ec5c1f766750014cc95ca2dc130000000000ffffffff729e6570928cc65200f1d53def65a7934d2e9b543059d90598ed1d166af422010100000000ffffffffa126724475cd2f3252352b3543c8455c7999a8283883bd7a712a7d66609d92d80100000000ffffffff02409c00000000000022512036079c540758a51a86eeaf9e17668d4d8543d8b1b7e56fe2da0982c390c5655ef8fa0700000000002251209303a116174dd21ea473766659568ac24eb6b828c3ee998982d2ba070ea0615500000000";
         let mut tx = Transaction::deserialize(&Vec::from_hex(&raw_tx).unwrap()).unwrap();
@@ -998,21 +1018,29 @@ mod test {
                 .script_pubkey(),
         });
 
-        select_btc_applet().unwrap();
+        block_on(select_btc_applet(&device)).unwrap();
 
-        let mut signer = PsbtSigner::new(&mut psbt, "", true, Network::Testnet, false).unwrap();
+        let mut signer = block_on(PsbtSigner::new(
+            &device,
+            &mut psbt,
+            "",
+            true,
+            Network::Testnet,
+            false,
+        ))
+        .unwrap();
 
         signer.prevouts().unwrap();
 
-        let pub_keys = signer.get_pub_key().unwrap();
+        let pub_keys = block_on(signer.get_pub_key()).unwrap();
 
-        signer.calc_tx_hash().unwrap();
+        block_on(signer.calc_tx_hash()).unwrap();
 
         signer.get_preview_info().unwrap();
 
-        signer.tx_preview(Network::Bitcoin).unwrap();
+        block_on(signer.tx_preview(Network::Bitcoin)).unwrap();
 
-        signer.sign(&pub_keys).unwrap();
+        block_on(signer.sign(&pub_keys)).unwrap();
 
         let tx = psbt.extract_tx();
 
@@ -1056,4 +1084,52 @@ mod test {
 
         assert_eq!(tx.input[4].script_sig.to_hex(), "483045022100ca32abc7b180c84cf76907e4e1e0c3f4c0d6e64de23b0708647ac6fee1c04c5b02206e7412a712424eb9406f18e00a42e0dffbfb5901932d1ef97843d9273865550e0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4e");
     }
+
+    //session replayed by test_sign_psbt_replay: get the taproot address, then sign a psbt
+    fn trace_session(device: &Device) -> (String, String) {
+        let address = block_on(BtcAddress::p2tr(
+            device,
+            Network::Bitcoin,
+            "m/86'/0'/0'/0/0",
+        ))
+        .unwrap();
+        let psbt_input = PsbtInput {
+            psbt: "70736274ff0100db0200000001fa4c8d58b9b6c56ed0b03f78115246c99eb70f99b837d7b4162911d1016cda340200000000fdffffff0350c30000000000002251202114eda66db694d87ff15ddd5d3c4e77306b6e6dd5720cbd90cd96e81016c2b30000000000000000496a47626274340066f873ad53d80688c7739d0d268acd956366275004fdceab9e9fc30034a4229ec20acf33c17e5a6c92cced9f1d530cccab7aa3e53400456202f02fac95e9c481fa00d47b1700000000002251208f4ca6a7384f50a1fe00cba593d5a834b480c65692a76ae6202e1ce46cb1c233d80f03000001012be3bf1d00000000002251208f4ca6a7384f50a1fe00cba593d5a834b480c65692a76ae6202e1ce46cb1c23301172066f873ad53d80688c7739d0d268acd956366275004fdceab9e9fc30034a4229e00000000".to_string(),
+            auto_finalize: true,
+        };
+        let psbt_output = block_on(super::sign_psbt(
+            device,
+            "m/86'/1'/0'",
+            psbt_input,
+            Network::Bitcoin,
+        ))
+        .unwrap();
+        (address, psbt_output.psbt)
+    }
+
+    //regenerates test-data/sign_psbt_trace.json, run with --ignored after changing the APDUs sent
+    #[test]
+    #[ignore]
+    fn record_sign_psbt_trace() {
+        let (device, recorder) = record_test();
+        trace_session(&device);
+        std::fs::write(
+            "test-data/sign_psbt_trace.json",
+            recorder.trace().to_json().unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sign_psbt_replay() {
+        let device = replay_test(include_str!("../test-data/sign_psbt_trace.json"));
+        let (address, psbt) = trace_session(&device);
+        assert_eq!(
+            address,
+            "bc1pqvrla5hul9cqdtz60lwwn35zdcx363pyxua0trqnz3wx8hvjxzdsdevceu"
+        );
+        let mut reader = Cursor::new(Vec::<u8>::from_hex(&psbt).unwrap());
+        let tx = Psbt::consensus_decode(&mut reader).unwrap().extract_tx();
+        assert_eq!(tx.input[0].witness.to_vec()[0].to_hex(), "101248d8690849419c2016295107c45c85a6574f1927e406a7edb134b635903fb261dd95638ed58a373239d591756e8fadabcf145d855b8a76abb897f1bd16af");
+    }
 }
@@ -14,19 +14,18 @@ use bitcoin::{
 use bitcoin_hashes::hash160;
 use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::Hash;
-use ikc_common::apdu::{ApduCheck, BtcApdu};
+use ikc_common::apdu::{BtcApdu, Exchange};
 use ikc_common::constants::{MAX_OPRETURN_SIZE, MAX_UTXO_NUMBER, MIN_NONDUST_OUTPUT, TIMEOUT_LONG};
 use ikc_common::error::CoinError;
 use ikc_common::path::{check_path_validity, get_account_path};
 use ikc_common::utility::{bigint_to_byte_vec, hex_to_bytes, secp256k1_sign};
-use ikc_device::device_binding::KEY_MANAGER;
-// use ikc_transport::message::{send_apdu, send_apdu_timeout};
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
+use ikc_device::device_binding::device_key_manager;
+use ikc_transport::device::Device;
 use secp256k1::ecdsa::Signature;
 use secp256k1::PublicKey;
 use std::borrow::Borrow;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Utxo {
@@ -49,6 +48,7 @@ pub struct BtcTransaction {
 impl BtcTransaction {
     pub async fn sign_Transaction(
         &self,
+        device: &Device,
         network: Network,
         path: &str,
         change_idx: Option<u32>,
@@ -69,9 +69,11 @@ impl BtcTransaction {
         }
 
         //utxo address verify
-        let utxo_pub_key_vec = get_utxo_pub_key(&self.unspents).await?;
+        let utxo_pub_key_vec = get_utxo_pub_key(device, &self.unspents).await?;
 
-        let output = self.tx_output(change_idx, &path, network, seg_wit, extra_data).await?;
+        let output = self
+            .tx_output(device, change_idx, &path, network, seg_wit, extra_data)
+            .await?;
 
         let mut tx_to_sign = Transaction {
             version: 1i32,
@@ -80,26 +82,36 @@ impl BtcTransaction {
             output,
         };
 
-        self.calc_tx_hash(&mut tx_to_sign).await?;
+        self.calc_tx_hash(device, &mut tx_to_sign).await?;
 
-        self.tx_preview(&tx_to_sign, network).await?;
+        self.tx_preview(device, &tx_to_sign, network).await?;
 
         let input_with_sigs: Vec<TxIn> = vec![];
         for (idx, utxo) in self.unspents.iter().enumerate() {
             let script = Script::from_str(&utxo.script_pubkey)?;
             if script.is_p2pkh() {
-                self.sign_p2pkh_input(idx, &utxo_pub_key_vec[idx], &mut tx_to_sign).await?;
+                self.sign_p2pkh_input(device, idx, &utxo_pub_key_vec[idx], &mut tx_to_sign)
+                    .await?;
             } else if script.is_p2sh() {
-                self.sign_p2sh_nested_p2wpkh_input(idx, &utxo_pub_key_vec[idx], &mut tx_to_sign).await?;
+                self.sign_p2sh_nested_p2wpkh_input(
+                    device,
+                    idx,
+                    &utxo_pub_key_vec[idx],
+                    &mut tx_to_sign,
+                )
+                .await?;
             } else if script.is_v0_p2wpkh() {
-                self.sign_p2wpkh_input(idx, &utxo_pub_key_vec[idx], &mut tx_to_sign).await?;
+                self.sign_p2wpkh_input(device, idx, &utxo_pub_key_vec[idx], &mut tx_to_sign)
+                    .await?;
             } else if script.is_v1_p2tr() {
                 self.sign_p2tr_input(
+                    device,
                     idx,
                     &utxo_pub_key_vec[idx],
                     &mut tx_to_sign,
                     SchnorrSighashType::Default,
-                ).await?;
+                )
+                .await?;
             } else {
                 return Err(CoinError::InvalidUtxo.into());
             };
@@ -116,6 +128,7 @@ impl BtcTransaction {
 
     async fn sign_p2pkh_input(
         &self,
+        device: &Device,
         idx: usize,
         pub_key: &str,
         transaction: &mut Transaction,
@@ -141,7 +154,7 @@ impl BtcTransaction {
         let btc_perpare_apdu_list = BtcApdu::btc_single_utxo_sign_prepare(0x46, &input_data_vec);
         for apdu in btc_perpare_apdu_list {
             // ApduCheck::check_response(&send_apdu(apdu)?)?;
-            ApduCheck::check_response(&send_apdu(apdu).await?)?;
+            device.exchange(&apdu).await?.check()?;
         }
 
         let btc_sign_apdu = BtcApdu::btc_single_utxo_sign(
@@ -151,11 +164,9 @@ impl BtcTransaction {
         );
 
         // let btc_sign_apdu_return = send_apdu(btc_sign_apdu)?;
-        let btc_sign_apdu_return = send_apdu(btc_sign_apdu).await?;
-        ApduCheck::check_response(&btc_sign_apdu_return)?;
-        let btc_sign_apdu_return =
-            &btc_sign_apdu_return[..btc_sign_apdu_return.len() - 4].to_string();
-        let sign_result_str = btc_sign_apdu_return[2..btc_sign_apdu_return.len() - 2].to_string();
+        let btc_sign_apdu_return = device.exchange(&btc_sign_apdu).await?;
+        btc_sign_apdu_return.check()?;
+        let sign_result_str = hex::encode_upper(btc_sign_apdu_return.field(1, 64)?);
 
         let mut signature_obj = Signature::from_compact(&hex::decode(&sign_result_str)?)?;
         signature_obj.normalize_s();
@@ -177,6 +188,7 @@ impl BtcTransaction {
 
     async fn sign_p2sh_nested_p2wpkh_input(
         &self,
+        device: &Device,
         idx: usize,
         pub_key: &str,
         transaction: &mut Transaction,
@@ -225,12 +237,11 @@ impl BtcTransaction {
             BtcApdu::btc_segwit_sign(false, 0x01, data)
         };
         // let sign_apdu_return_data = send_apdu(sign_apdu)?;
-        let sign_apdu_return_data = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_apdu_return_data)?;
+        let sign_apdu_return_data = device.exchange(&sign_apdu?).await?;
+        sign_apdu_return_data.check()?;
 
         //build signature obj
-        let sign_result_vec =
-            Vec::from_hex(&sign_apdu_return_data[2..sign_apdu_return_data.len() - 6]).unwrap();
+        let sign_result_vec = sign_apdu_return_data.field(1, 64)?.to_vec();
         let mut signature_obj = Signature::from_compact(sign_result_vec.as_slice())?;
         signature_obj.normalize_s();
         //generator der sign data
@@ -254,6 +265,7 @@ impl BtcTransaction {
 
     async fn sign_p2wpkh_input(
         &self,
+        device: &Device,
         idx: usize,
         pub_key: &str,
         transaction: &mut Transaction,
@@ -303,11 +315,10 @@ impl BtcTransaction {
             BtcApdu::btc_segwit_sign(false, 0x01, data)
         };
         // let sign_apdu_return_data = send_apdu(sign_apdu)?;
-        let sign_apdu_return_data = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_apdu_return_data)?;
+        let sign_apdu_return_data = device.exchange(&sign_apdu?).await?;
+        sign_apdu_return_data.check()?;
         //build signature obj
-        let sign_result_vec =
-            Vec::from_hex(&sign_apdu_return_data[2..sign_apdu_return_data.len() - 6]).unwrap();
+        let sign_result_vec = sign_apdu_return_data.field(1, 64)?.to_vec();
         let mut signature_obj = Signature::from_compact(sign_result_vec.as_slice())?;
         signature_obj.normalize_s();
         //generator der sign data
@@ -322,6 +333,7 @@ impl BtcTransaction {
 
     async fn sign_p2tr_input(
         &self,
+        device: &Device,
         idx: usize,
         pub_key: &str,
         transaction: &mut Transaction,
@@ -361,10 +373,10 @@ impl BtcTransaction {
         } else {
             BtcApdu::btc_taproot_sign(false, data)
         };
-        let sign_result = send_apdu(sign_apdu).await?;
-        ApduCheck::check_response(&sign_result)?;
+        let sign_result = device.exchange(&sign_apdu?).await?;
+        sign_result.check()?;
 
-        let sign_bytes = hex_to_bytes(&sign_result[2..(sign_result.len() - 4)])?;
+        let sign_bytes = sign_result.field(1, 64)?.to_vec();
         let witness = Witness::from_vec(vec![sign_bytes]);
         transaction.input.push(TxIn {
             previous_output: OutPoint {
@@ -426,6 +438,7 @@ impl BtcTransaction {
 
     pub async fn tx_output(
         &self,
+        device: &Device,
         change_idx: Option<u32>,
         change_path: &str,
         network: Network,
@@ -444,7 +457,7 @@ impl BtcTransaction {
                     "/1/",
                     change_address_index
                 );
-                let pub_key = BtcAddress::get_pub_key(&change_path).await?;
+                let pub_key = BtcAddress::get_pub_key(device, &change_path).await?;
                 let change_address = BtcAddress::from_public_key(&pub_key, network, seg_wit)?;
                 let change_address = Address::from_str(&change_address)?;
                 change_address.script_pubkey()
@@ -468,7 +481,7 @@ impl BtcTransaction {
         Ok(outputs)
     }
 
-    pub async fn calc_tx_hash(&self, transaction: &mut Transaction) -> Result<()> {
+    pub async fn calc_tx_hash(&self, device: &Device, transaction: &mut Transaction) -> Result<()> {
         let mut txhash_vout_vec = vec![];
         let mut sequence_vec = vec![];
         let mut amount_vec = vec![];
@@ -501,13 +514,18 @@ impl BtcTransaction {
             calc_hash_apdu.extend(BtcApdu::btc_prepare(0x31, 0x21, &script_pubkeys_vec));
             for apdu in calc_hash_apdu {
                 // ApduCheck::check_response(&send_apdu(apdu)?)?;
-                ApduCheck::check_response(&send_apdu(apdu).await?)?;
+                device.exchange(&apdu).await?.check()?;
             }
         }
         Ok(())
     }
 
-    pub async fn tx_preview(&self, transaction: &Transaction, network: Network) -> Result<()> {
+    pub async fn tx_preview(
+        &self,
+        device: &Device,
+        transaction: &Transaction,
+        network: Network,
+    ) -> Result<()> {
         let mut output_serialize_data = serialize(&transaction);
 
         output_serialize_data.remove(5);
@@ -537,17 +555,18 @@ impl BtcTransaction {
         output_serialize_data.insert(0, 0x01);
 
         //use local private key sign data
-        let key_manager_obj = KEY_MANAGER.lock();
-        let mut output_pareper_data =
-            secp256k1_sign(&key_manager_obj.pri_key, &output_serialize_data)?;
+        let pri_key = device_key_manager(device)?.lock().pri_key.clone();
+        let mut output_pareper_data = secp256k1_sign(&pri_key, &output_serialize_data)?;
         output_pareper_data.insert(0, output_pareper_data.len() as u8);
         output_pareper_data.insert(0, 0x00);
         output_pareper_data.extend(output_serialize_data.iter());
 
         let btc_prepare_apdu_vec = BtcApdu::btc_prepare(0x41, 0x00, &output_pareper_data);
         for temp_str in btc_prepare_apdu_vec {
-            // ApduCheck::check_response(&send_apdu_timeout(temp_str, TIMEOUT_LONG)?)?;
-            ApduCheck::check_response(&send_apdu(temp_str).await?)?;
+            device
+                .exchange_with_timeout(&temp_str, Duration::from_secs(TIMEOUT_LONG as u64))
+                .await?
+                .check()?;
         }
 
         Ok(())
@@ -556,21 +575,77 @@ impl BtcTransaction {
 
 #[cfg(test)]
 mod tests {
+    use crate::common::TxSignResult;
     use crate::transaction::{BtcTransaction, Utxo};
+    use crate::Result;
     use bitcoin::psbt::serialize::Deserialize;
     use bitcoin::{Address, Network, Transaction};
     use bitcoin_hashes::hex::ToHex;
+    use futures::executor::block_on;
+    use futures::future::join;
     use hex::FromHex;
     use ikc_common::utility::hex_to_bytes;
-    use ikc_device::device_binding::bind_test;
+    use ikc_device::device_binding::{bind_test, device_key_manager, DeviceManage};
+    use ikc_simulator::simulator::{Simulator, SIMULATOR_SEID};
+    use ikc_transport::device::Device;
+    use ikc_transport::mock::YieldingTransport;
+    use ikc_transport::trace::TraceRecorder;
     use secp256k1::schnorr::Signature;
     use secp256k1::{Message, Secp256k1, XOnlyPublicKey};
     use std::str::FromStr;
 
     #[test]
     fn test_sign_p2pkh() {
-        bind_test();
+        sign_p2pkh(&bind_test());
+    }
+
+    #[test]
+    fn test_sign_p2pkh_interleaved() {
+        let bound = bind_test();
+        let host_pub_key = device_key_manager(&bound).unwrap().lock().pub_key.clone();
+        //two devices bound to the host, answering only after the other signing had its turn
+        let devices: Vec<Device> = (0..2)
+            .map(|_| {
+                let simulator = Simulator::new();
+                simulator.bind(&host_pub_key).unwrap();
+                let device = Device::new(YieldingTransport::new(simulator));
+                device.set_seid(SIMULATOR_SEID);
+                device
+            })
+            .collect();
+        //a binding key lock held across an exchange would block the other signing forever
+        let (result_a, result_b) =
+            block_on(join(p2pkh_signing(&devices[0]), p2pkh_signing(&devices[1])));
+        check_p2pkh(&result_a.unwrap());
+        check_p2pkh(&result_b.unwrap());
+    }
+
+    #[test]
+    fn test_sign_p2pkh_secure_channel() {
+        let device = bind_test();
+        block_on(DeviceManage::open_secure_channel(&device)).unwrap();
+        let recorder = TraceRecorder::new();
+        device.set_recorder(Some(recorder.clone()));
+        sign_p2pkh(&device);
+
+        //the preparation and signing commands of the BTC applet went through the channel
+        assert!(device.is_secure());
+        let trace = recorder.trace();
+        let commands: Vec<&str> = trace
+            .exchanges
+            .iter()
+            .map(|exchange| exchange.command.as_str())
+            .collect();
+        assert!(commands.iter().any(|command| command.starts_with("8441")));
+        assert!(commands.iter().any(|command| command.starts_with("8445")));
+        assert!(commands.iter().all(|command| !command.starts_with("80")));
+    }
 
+    fn sign_p2pkh(device: &Device) {
+        check_p2pkh(&block_on(p2pkh_signing(device)).unwrap());
+    }
+
+    async fn p2pkh_signing(device: &Device) -> Result<TxSignResult> {
         let utxos = vec![
             Utxo {
                 txhash: "983adf9d813a2b8057454cc6f36c6081948af849966f9b9a33e5b653b02f227a"
@@ -620,30 +695,36 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
-            Network::Testnet,
-            &"m/44'/1'/0'".to_string(),
-            Some(53),
-            Some("0200000080a10bc28928f4c17a287318125115c3f098ed20a8237d1e8e4125bc25d1be99752adad0a7b9ceca853768aebb6965eca126a62965f698a0c1bc43d83db632ad7f717276057e6012afa99385"),
-            "DEFAULT",
-        );
+        transaction
+            .sign_Transaction(
+                device,
+                Network::Testnet,
+                "m/44'/1'/0'",
+                Some(53),
+                Some("0200000080a10bc28928f4c17a287318125115c3f098ed20a8237d1e8e4125bc25d1be99752adad0a7b9ceca853768aebb6965eca126a62965f698a0c1bc43d83db632ad7f717276057e6012afa99385"),
+                "DEFAULT",
+            )
+            .await
+    }
+
+    fn check_p2pkh(sign_result: &TxSignResult) {
         assert_eq!(
             "01000000047a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000006b483045022100c7755417be55e4fa04896ce424609ef1b12e82a8adb94fbb6c99c62f521f2eb5022075d21cc4fc534024d993c30536fe1a51a4fdf07defe14209d5696acf354edec301210312a0cb31ff52c480c049da26d0aaa600f47e9deee53d02fc2b0e9acf3c20fbdfffffffff31b5a9794dcaf82af1738745afe1ecf402ea4a93e71ae75c7d3d8bf7c78aef45010000006a47304402202e3ed884e978eab56b14860b0ce56c230ace84d1ba4bf233df21ec934b57afa00220346a624c16cee70c8fea6818e008c2151946eadd04a62b47b4cc73d8a55ae7ab0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffa92c40dfd195a188d87110557fb7f46dbbfb68c4bb8718f33dc31d61927ec614000000006a473044022011561ce180af03beb2f4a0b4a086bf2d6afc84efd7329104e229c960c54e7c4f0220626bafb1bd38fcf8c2152c94f1563fce89b46f0616ce3533801390bd4663bf960121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffb99a3e8884b14f330d2a444a4bc2a03af16804fb99b5e37ee892ed5db8b67f11010000006a4730440220420bed1fc49d9ac045c1a16c7acb40676cfb144a7dc82b69a63977ddc44ca99e0220276bec561f39e8c5de8a3a35be4fa80ca5889939537768cca7c26345149aa0690121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff0320d9ae2f000000001976a91455bdc1b42e3bed851959846ddf600e96125423e088acd0070000000000001976a91412967cdd9ceb72bbdbb7e5db85e2dbc6d6c3ab1a88ac0000000000000000536a4c500200000080a10bc28928f4c17a287318125115c3f098ed20a8237d1e8e4125bc25d1be99752adad0a7b9ceca853768aebb6965eca126a62965f698a0c1bc43d83db632ad7f717276057e6012afa9938500000000",
-            sign_result.as_ref().unwrap().signature
+            sign_result.signature
         );
         assert_eq!(
             "aa9b55f0bdc2330b31af00ea89aa2f54e508dc708a5aacb4ce76e7431245f29b",
-            sign_result.as_ref().unwrap().tx_hash
+            sign_result.tx_hash
         );
         assert_eq!(
             "aa9b55f0bdc2330b31af00ea89aa2f54e508dc708a5aacb4ce76e7431245f29b",
-            sign_result.as_ref().unwrap().wtx_id
+            sign_result.wtx_id
         );
     }
 
     #[test]
     fn test_sign_p2wpkh() {
-        bind_test();
+        let device = bind_test();
 
         let extra_data = Vec::from_hex("1234").unwrap();
         let utxos = vec![
@@ -675,13 +756,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             Some("1234"),
             "P2WPKH",
-        );
+        ));
         assert_eq!(
             "dc021850ca46b2fdc3f278020ac4e27ee18d9753dd07cbd97b84a2a0a2af3940",
             sign_result.as_ref().unwrap().tx_hash
@@ -695,7 +777,7 @@ mod tests {
 
     #[test]
     fn test_native_segwit_bech32_to_bech32_no_change() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "d7c2e585d5eaa185808addb3ef703f2a8fe09288b4f40b757a812d6d63b7c9c4".to_string(),
@@ -713,13 +795,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "02000000000101c4c9b7636d2d817a750bf4b48892e08f2a3f70efb3dd8a8085a1ead585e5c2d70100000000ffffffff02c057010000000000160014654fbb08267f3d50d715a8f1abb55979b160dd5bd007000000000000160014622347653655d57ee8e8f25983f646bcdf9c503202473044022055b4bbbad7e85e9b359a69e8f68801066e9368dbeb3ed777c418f83f175d1ef802206f2a70af6443083f58df7882028f0c94505d1c06167202db21eb2d98d250289a0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -736,7 +819,7 @@ mod tests {
 
     #[test]
     fn test_native_segwit_bech32_to_bech32_has_change() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "7c99f906e291d453b2c039939598eefd182dafb20d53bd0eebc2a1aa635ff60f".to_string(),
@@ -754,13 +837,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "020000000001010ff65f63aaa1c2eb0ebd530db2af2d18fdee98959339c0b253d491e206f9997c0000000000ffffffff0250c3000000000000160014654fbb08267f3d50d715a8f1abb55979b160dd5b606d000000000000160014622347653655d57ee8e8f25983f646bcdf9c50320248304502210099fc03a90559def6c8b8a9d6283f419189445200ae0218d5f9c53ea745d3c0ef0220590069313bac5f52f003dc7626148af6c85c479a93c0dd21c2a82c73f1576ed90121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -777,7 +861,7 @@ mod tests {
 
     #[test]
     fn test_native_segwit_bech32_to_p2pkh() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "64381306678c6a868e8778adee1ee9d1746e5e8dd3535fcbaa1a25baab49f015".to_string(),
@@ -795,13 +879,14 @@ mod tests {
             unspents: utxos,
             fee: 8000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "0200000000010115f049abba251aaacb5f53d38d5e6e74d1e91eeead78878e866a8c67061338640100000000ffffffff0230750000000000001976a914383fb81cb0a3fc724b5e08cf8bbd404336d711f688ac30f2000000000000160014622347653655d57ee8e8f25983f646bcdf9c503202483045022100bc0e5f620554681ccd336cd9e12a244abd40d374a3a7668671a73edfb561a7900220534617da8eb8636f2db8bdb6191323bb766d534235d97ad08935a05ffb8b81010121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -818,7 +903,7 @@ mod tests {
 
     #[test]
     fn test_native_segwit_bech32_to_p2shp2wpkh() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "fcc622970fd80c14b111ee7950bcc309469b575194072209598176123fd06598".to_string(),
@@ -836,13 +921,14 @@ mod tests {
             unspents: utxos,
             fee: 7000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "020000000001019865d03f127681590922079451579b4609c3bc5079ee11b1140cd80f9722c6fc0000000000ffffffff02307500000000000017a9142d2b1ef5ee4cf6c3ebc8cf66a602783798f7875987c832000000000000160014622347653655d57ee8e8f25983f646bcdf9c503202483045022100f2d33b3a6f592f6f9ec9f2e560aaa2323e59cbc9e42cf9161b690ce26ef8371702203b2bebece7c8cfb9c24baf56bef8eecb9ec0be322889ac8053da1722a97c45160121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -859,7 +945,7 @@ mod tests {
 
     #[test]
     fn test_legacy_p2pkh_to_bech32() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "eb3ea0d4b360a304849b90baf49197eb449ca746febd60f8f29cd279c966a3ea".to_string(),
@@ -876,13 +962,14 @@ mod tests {
             unspents: utxos,
             fee: 5000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/44'/1'/0'".to_string(),
             Some(0),
             None,
             "DEFAULT",
-        );
+        ));
         assert_eq!(
             "0100000001eaa366c979d29cf2f860bdfe46a79c44eb9791f4ba909b8404a360b3d4a03eeb000000006b483045022100e8209a6692b87d0e743509e314894affefdb1f02ae0a210184c3d4c2c75394a70220144af4619d8b16dd3a7cb6f4a10552e766a7e9e16786c796cd9a162d8c0041880121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff01a861000000000000160014654fbb08267f3d50d715a8f1abb55979b160dd5b00000000",
             sign_result.as_ref().unwrap().signature
@@ -895,7 +982,7 @@ mod tests {
 
     #[test]
     fn test_segwit_p2sh_p2wpkh_to_bech32() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "e5add8950cb37b1d80ff18cb2ba775e185e1843b845e18b532dc4b5d8ffec7a9".to_string(),
@@ -913,13 +1000,14 @@ mod tests {
             unspents: utxos,
             fee: 4000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             Some("1234"),
             "P2WPKH",
-        );
+        ));
         assert_eq!(
             "02000000000101a9c7fe8f5d4bdc32b5185e843b84e185e175a72bcb18ff801d7bb30c95d8ade50000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff029065000000000000160014654fbb08267f3d50d715a8f1abb55979b160dd5b0000000000000000046a02123402483045022100aca51e4f49ea1222a2a0ee92b4f76ab3cc4f81ee34fdabc51dfd5115fb4f472f022024c2c860b01e5314139c6a9442679e3a10ca5003f37eb727aa9b1af322a0ba8c0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -936,7 +1024,7 @@ mod tests {
 
     #[test]
     fn test_native_segwit_bech32_to_bech32_multiutxo() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -967,13 +1055,14 @@ mod tests {
             unspents: utxos,
             fee: 5000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'".to_string(),
             Some(0),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "02000000000102cc6132e93c42b43f98db2c2aa1a0004b5a6246848f776d5ac5c1d34af95919400000000000ffffffffa9c7fe8f5d4bdc32b5185e843b84e185e175a72bcb18ff801d7bb30c95d8ade50100000000ffffffff021879000000000000160014654fbb08267f3d50d715a8f1abb55979b160dd5bb80b000000000000160014622347653655d57ee8e8f25983f646bcdf9c50320248304502210098aea910af0731b676ec0b09f5e9b78be165808e7cda7f56fff535aab3ace1f5022062546d6894f0e6a0ae24e659fe37fb11c407739970a8aeb05b79c7bf8e012f4b0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc02483045022100bd8dc6ec13fb55900441ab8449675995bc9b046709c1bd1831b7bbc3066e2f8e02205f9dd402d1133ab92cbe46abcda11b332280955525fa4ff94832ecdf83803d89012103d83187d984c44ec073d4661d93fa306b613c0c91a1661d919dd43814da1a5f8900000000",
             sign_result.as_ref().unwrap().signature
@@ -990,7 +1079,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_p2shp2wpkh_utxo_nochange() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "32f734241b2dee423ee736ddfd26ea341d56a0ded67f4e1c658d0119977c1b3a".to_string(),
@@ -1008,8 +1097,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result =
-            transaction.sign_Transaction(Network::Testnet, "m/49'/1'/0'", Some(0), None, "P2WPKH");
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
+            Network::Testnet,
+            "m/49'/1'/0'",
+            Some(0),
+            None,
+            "P2WPKH",
+        ));
 
         assert_eq!(
             "020000000001013a1b7c9719018d651c4e7fd6dea0561d34ea26fddd36e73e42ee2d1b2434f7320000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff01905f01000000000017a9148bbb53570df9656926ea0ef029cd2ee84dbc7d0f870247304402202931423820466e0554d99eb93d6c9b6a1b7270c21e1ed7279f98152247103ab602201df7809aa81b66bace7131a260fb1de661c9da9d6ddbb82ceac3c6bbb043122f0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
@@ -1027,7 +1122,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_single_legacy_and_segwit_utxo_has_change() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1058,13 +1153,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'/0/0".to_string(),
             Some(53),
             None,
             "P2WPKH",
-        );
+        ));
         assert_eq!(
             "020000000001023c8225a97ec8d51d25ecebcf44f9ee6c222043e191e91d2c076f4628865e6d35010000006b483045022100e3f1bffc773f0bd984f4d0cb727b4beb5c9833a701e2af3b26479a93eb764bc6022017b3269ade37bb70f84ed9576ac9bc96f262ac249b781bd5592069aceb01f4e80121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff0eaebea0d14ecf8d818c1251d3f7e62cf1ef0dbb7f01418b7cfd612559a33cb60100000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff01f40517000000000017a9148bbb53570df9656926ea0ef029cd2ee84dbc7d0f87000247304402206b159cc6edc019125ea87b4df39a566520e092371ddb030071f150476a1bbd8d022074c43c41557ab6be848d48ccc611225b3a36ea3b4163f0cfc970fc945dfa7acf0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc00000000",
             sign_result.as_ref().unwrap().signature
@@ -1082,7 +1178,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_single_bech32_utxo_haschange() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "41eb7058313847d1f1b0cfee964a436d55eab5ca29fdbb42dbb5107a85afdda7".to_string(),
@@ -1100,13 +1196,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/84'/1'/0'".to_string(),
             Some(53),
             None,
             "VERSION_0",
-        );
+        ));
         assert_eq!(
             "02000000000101a7ddaf857a10b5db42bbfd29cab5ea556d434a96eecfb0f1d14738315870eb410100000000ffffffff0230750000000000001600140efbea077aa9cdb69569176ef5172de8c13a997360ea0000000000001600147805a6361d2532deac1b62c93288aa159308dcc002483045022100ae80f750fc99a9db1a017fd7021b102524edb7b708611aab83c4fe068c4a47110220743dd9c574956c736d38d3b072bd105b1b4e283ca9a0df2e95c7a6a4373cfe30012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c000000000",
             sign_result.as_ref().unwrap().signature
@@ -1124,7 +1221,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_multi_bech32_utxo_haschange() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1155,13 +1252,14 @@ mod tests {
             unspents: utxos,
             fee: 20000,
         };
-        let sign_result = transaction_req_data.sign_Transaction(
+        let sign_result = block_on(transaction_req_data.sign_Transaction(
+            &device,
             Network::Testnet,
             &"m/84'/1'/0'".to_string(),
             Some(53),
             None,
             "VERSION_0",
-        );
+        ));
 
         assert_eq!(
             "020000000001020d043bea7b3d40519c90ce63daae617d826b7552fcb2a805c2081589aa82f4800100000000ffffffff7faf4ab52498c34c75481012514851f595844901eda82958e8646a886c96b3140100000000ffffffff02b0ad0100000000001600140efbea077aa9cdb69569176ef5172de8c13a997370110100000000001600147805a6361d2532deac1b62c93288aa159308dcc002483045022100d0c50b5d3641db7417108217a2d686ae6d34f93a69b5856bf3a3bd33531e30ae02206d661be346d456ad9dad0a458169802b0b66df6d6fd7a22eb1586855dd891fe4012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c002483045022100be8664eb39f8f6cf5948e43c4a1cdd8cd5aedb6a0e6084709b322fc41a2380be02206831c1776daaad80d75440ac3b773970499c6e17821f434bb271aab0ee84e239012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c000000000",
@@ -1180,7 +1278,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_p2shp2wpkh_and_bech32_utxo_haschange() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1211,13 +1309,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/84'/1'/0'",
             Some(53),
             None,
             "VERSION_0",
-        );
+        ));
 
         assert_eq!(
             "02000000000102d7577189012a74ad94f3f5d18ec7f1b2d35fcaf3d9ce5a83fb036d1cfe37790a0000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffffdd4ec3a05e81576ab1ef90fe6efbf3fafbf090b4121368e7a1c6344b62ccfb940000000000ffffffff02905f0100000000001600140efbea077aa9cdb69569176ef5172de8c13a9973a0860100000000001600147805a6361d2532deac1b62c93288aa159308dcc002483045022100e44a802d1a9f70e4087541808b39f4ba4b455f6371b471fa0cc122e2e8a163500220423a35e6c79cbe6287cde4b771b37966d6627defac5d0ed57b53e6c9ffa57c1f0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc02473044022018b55722a8c933fcb75309aedb4269d55d2e32549b431822f09019013785b8aa02205980d4f9233bae825cad9cf37b59aeac8fded55c450c59ce02f2bc4bb62352a3012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c000000000",
@@ -1236,7 +1335,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_transaction() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1287,13 +1386,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/84'/1'/0'",
             Some(53),
             None,
             "VERSION_0",
-        );
+        ));
 
         assert_eq!(
             "02000000000104601e02999f6427896801174bf5a89a95ed4029f045757f8d03ad4dcd825eb0b7010000006a47304402205363ea34883d551c35c2338e1809566e424489167e69a404120c6684827443bf02200fbdcb4ff821c5aa28c1633e36406d3597f729b61dd631175d52964397138a7f0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffa011a8b84393834b2010a7287d1733b2379811258770b9e92a54728f4b1b67360000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff3a565653f6d376f0be94d9f09232d7dbf54ae2232f9f09c950c2e1ae5b9459640100000000ffffffffacff40c69ca5d9894882840a4e75980d9fb5085d0de289e49de93ff9168f1d6d0100000000ffffffff02f0ba0400000000001600140efbea077aa9cdb69569176ef5172de8c13a997350c30000000000001600147805a6361d2532deac1b62c93288aa159308dcc00002473044022073a93e5bc5f739d9f54198f2d4da1dfc8f79f23a62a8fada6f5edd54f6a1f358022028b3c86a2683cfed9128b2bea71de30e2e3e29e48996a383ec030403c1b716360121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc02483045022100b4608108057f49a58ef4a9e49107232e140cb6729a69b0ac48c0bfeb237bf75e02206b6336c759ef83cb20b073545ca4227b280ebcb2aab932928a967e74bc6e4d42012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c00247304402205155631ba66e009c677cc7e4f67183922eaff389719e604d1ff72fe7fbd1b27d0220523baa8575da69b150da6ecf56814bc34820ed1950ec59943b36c0d5451b3ffe01210383f26c44bf1607224237a93e8735ff69a23655878ddb22c46fcdd850417097a400000000",
@@ -1311,7 +1411,7 @@ mod tests {
 
     #[test]
     fn test_sign_mixed_single_legacy_utxotransaction() {
-        bind_test();
+        let device = bind_test();
 
         let mut utxos = vec![Utxo {
             txhash: "1cd9bfa2cabf071aca138e38e7ba281fa0aa26dd554d3518a2f3f74d33e9d3f5".to_string(),
@@ -1329,8 +1429,14 @@ mod tests {
             unspents: utxos,
             fee: 10000,
         };
-        let sign_result =
-            transaction.sign_Transaction(Network::Testnet, "m/49'/1'/0'", Some(53), None, "P2WPKH");
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
+            Network::Testnet,
+            "m/49'/1'/0'",
+            Some(53),
+            None,
+            "P2WPKH",
+        ));
 
         assert_eq!(
             "0100000001f5d3e9334df7f3a218354d55dd26aaa01f28bae7388e13ca1a07bfcaa2bfd91c000000006b48304502210091a1232f0c63dd72dcbf07092b92fe360ebb76425c57cb0281e12addfd92940d022055b500ccd12861bad5d48785a369157fca3a633df09e5cb800053e6e3b3d691c0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff02307500000000000017a9148bbb53570df9656926ea0ef029cd2ee84dbc7d0f8760ea00000000000017a914a906137d79fc84a9685de5e6185bf397c2249bcd8700000000",
@@ -1348,7 +1454,7 @@ mod tests {
 
     #[test]
     fn test_sign_with_hd_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1399,13 +1505,14 @@ mod tests {
             unspents: utxos.clone(),
             fee: 12000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/44'/1'/0'/0/0",
             Some(53),
             None,
             "NONE",
-        );
+        ));
 
         assert_eq!(
             "01000000047a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000006b483045022100c610f77f71cc8afcfbd46df8e3d564fb8fb0f2c041bdf0869512c461901a8ad802206b92460cccbcb2a525877db1b4b7530d9b85e135ce88424d1f5f345dc65b881401210312a0cb31ff52c480c049da26d0aaa600f47e9deee53d02fc2b0e9acf3c20fbdfffffffff31b5a9794dcaf82af1738745afe1ecf402ea4a93e71ae75c7d3d8bf7c78aef45010000006b483045022100dce4a4c3d79bf9392832f68da3cd2daf85ac7fa851402ecc9aaac69b8761941d02201e1fd6601812ea9e39c6df0030cb754d4c578ff48bc9db6072ba5207a4ebc2b60121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffa92c40dfd195a188d87110557fb7f46dbbfb68c4bb8718f33dc31d61927ec614000000006b483045022100e1802d80d72f5f3be624df3ab668692777188a9255c58067840e4b73a5a61a99022025b23942deb21f5d1959aae85421299ecc9efefb250dbacb46a4130abd538d730121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffb99a3e8884b14f330d2a444a4bc2a03af16804fb99b5e37ee892ed5db8b67f11010000006a47304402207b82a62ed0d35c9878e6a7946d04679c8a17a8dd0a856b5cc14928fe1e9b554a0220411dd1a61f8ac2a8d7564de84e2c8a2c2583986bd71ac316ade480b8d0b4fffd0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff0120d9ae2f000000001976a91455bdc1b42e3bed851959846ddf600e96125423e088ac00000000",
@@ -1415,7 +1522,7 @@ mod tests {
 
     #[test]
     fn test_sign_p2shwpkh_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1446,13 +1553,14 @@ mod tests {
             unspents: utxos.clone(),
             fee: 12000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/49'/1'/0'/0/0",
             Some(0),
             None,
             "P2WPKH",
-        );
+        ));
 
         assert_eq!(
             "020000000001027f717276057e6012afa99385c18cc692397a666560520577679bf38c08b5cec20000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff74cdd54bc48333e1d2f108460284d137c39b6c417d9ff55a572a9550d428d69a00000000171600149d66aa6399de69d5c5ae19f9098047760251a854ffffffff01c05701000000000017a914b710f6e5049eaf0404c2f02f091dd5bb79fa135e870247304402205fd9dea5df0db5cc7b1d4b969f63b4526fb00fd5563ab91012cb511744a53d570220784abfe099a2b063b1cfc1f145fef2ffcb100b0891514fa164d357f0ef7ca6bb0121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc02483045022100b0246c12428dbf863fcc9060ab6fc46dc2135adaa6cf8117de49f9acecaccf6c022059377d05c9cab24b7dec14242ea3206cc1f464d5ff9904dca515fc71766507cd012103a241c8d13dd5c92475652c43bf56580fbf9f1e8bc0aa0132ddc8443c03062bb900000000",
@@ -1465,13 +1573,14 @@ mod tests {
             unspents: utxos.clone(),
             fee: 10000,
         };
-        let sign_result = transaction_req_data.sign_Transaction(
+        let sign_result = block_on(transaction_req_data.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/49'/1'/0'/0/0",
             Some(0),
             None,
             "P2WPKH",
-        );
+        ));
 
         assert_eq!(
             "020000000001027f717276057e6012afa99385c18cc692397a666560520577679bf38c08b5cec20000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff74cdd54bc48333e1d2f108460284d137c39b6c417d9ff55a572a9550d428d69a00000000171600149d66aa6399de69d5c5ae19f9098047760251a854ffffffff02803801000000000017a914b710f6e5049eaf0404c2f02f091dd5bb79fa135e87102700000000000017a914755fba51b5c443b9f16b1f86665dec10dd7a25c58702483045022100f0c66cd322e50f992ad34448fb3bf823066e5ffaa8e840a901058a863a4d950c02206cdafb1ad1ef4d938122b106069d8b435387e4d55711f50a46a8d91d9f674c550121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc02483045022100cfe92e4ad4fbfc13be20afc6f37429e26426257d015b409d28c260544e581b2c022028412816d1fef11093b474c2c662a25a4062f4e37d06ce66207863de98814a07012103a241c8d13dd5c92475652c43bf56580fbf9f1e8bc0aa0132ddc8443c03062bb900000000",
@@ -1481,7 +1590,7 @@ mod tests {
 
     #[test]
     fn test_sign_segwit_with_op_return_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1512,13 +1621,14 @@ mod tests {
             unspents: utxos.clone(),
             fee: 12000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/49'/1'/0'/0/0",
             Some(0),
             Some("1234"),
             "P2WPKH",
-        );
+        ));
 
         assert_eq!(
             "020000000001027f717276057e6012afa99385c18cc692397a666560520577679bf38c08b5cec20000000017160014654fbb08267f3d50d715a8f1abb55979b160dd5bffffffff74cdd54bc48333e1d2f108460284d137c39b6c417d9ff55a572a9550d428d69a00000000171600149d66aa6399de69d5c5ae19f9098047760251a854ffffffff02c05701000000000017a914b710f6e5049eaf0404c2f02f091dd5bb79fa135e870000000000000000046a021234024730440220527c098c320c54ac56445b757a76833f7a47229fa0fe2b179f55bd718822695e022060f48b05c46f8335266eade0fe503448ff4222efc7e84ef86a25abffbe02ead20121031aee5e20399d68cf0035d1a21564868f22bc448ab205292b4279136b15ecaebc024730440220551ab42b94841b43e6118a6adb39a561f138ae9ee8d2c00ffa3886839afe66d2022046686666245b94b7272d7cbd17a6f20639532ddefafe0572ecc28dcb246d33f8012103a241c8d13dd5c92475652c43bf56580fbf9f1e8bc0aa0132ddc8443c03062bb900000000",
@@ -1528,7 +1638,7 @@ mod tests {
 
     #[test]
     fn test_sign_with_taproot_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "2bdcfa88d5f48954e98018da33aaf11a4951b4167ba8121bc787880890dee5f0".to_string(),
@@ -1551,13 +1661,14 @@ mod tests {
             unspents: utxos.clone(),
             fee: 1000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/86'/1'/0'/0/0",
             Some(53),
             None,
             "VERSION_1",
-        );
+        ));
         assert_eq!(
             "0fb223cd2cd90830827ab235b752de841153d69a75649d8f92ffa2198d645852",
             sign_result.as_ref().unwrap().tx_hash
@@ -1566,7 +1677,7 @@ mod tests {
 
     #[test]
     fn test_sign_with_p2wpkh_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![Utxo {
             txhash: "cebc5c2b4f5533428ad0cca94e9bfefa6410a270ed1d7116e2ee8592494c66bd".to_string(),
@@ -1585,13 +1696,14 @@ mod tests {
             unspents: utxos,
             fee: 20000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/86'/1'/0'/0/0",
             Some(53),
             None,
             "VERSION_1",
-        );
+        ));
         assert_eq!(
             "02000000000101bd664c499285eee216711ded70a21064fafe9b4ea9ccd08a4233554f2b5cbcce0100000000ffffffff0250c30000000000002251208f4ca6a7384f50a1fe00cba593d5a834b480c65692a76ae6202e1ce46cb1c23330750000000000002251209303a116174dd21ea473766659568ac24eb6b828c3ee998982d2ba070ea0615502483045022100bed2bc8b4bf2beb4dacda077b47f96b4070af659ca241c343eccfe3ebc4a6f600220379c51f6456adff08a7605496a88653689af9e44f5d324e2ad2e1eae330b434f012102e24f625a31c9a8bae42239f2bf945a306c01a450a03fd123316db0e837a660c000000000",
             sign_result.as_ref().unwrap().signature
@@ -1604,7 +1716,7 @@ mod tests {
 
     #[test]
     fn test_sign_with_multi_payment_on_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1676,13 +1788,14 @@ mod tests {
             unspents: utxos,
             fee: 40000,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/86'/1'/0'/0/0",
             Some(53),
             None,
             "VERSION_1",
-        );
+        ));
         let tx_sign_result = &sign_result.unwrap();
         let tx =
             Transaction::deserialize(&hex_to_bytes(&tx_sign_result.signature).unwrap()).unwrap();
@@ -1738,7 +1851,7 @@ mod tests {
 
     #[test]
     fn test_sign_with_hd_testnet() {
-        bind_test();
+        let device = bind_test();
 
         let utxos = vec![
             Utxo {
@@ -1789,13 +1902,14 @@ mod tests {
             unspents: utxos,
             fee: 502130,
         };
-        let sign_result = transaction.sign_Transaction(
+        let sign_result = block_on(transaction.sign_Transaction(
+            &device,
             Network::Testnet,
             "m/44'/1'/0'/0/0",
             Some(53),
             None,
             "NONE",
-        );
+        ));
         assert_eq!(
             "01000000047a222fb053b6e5339a9b6f9649f88a9481606cf3c64c4557802b3a819ddf3a98000000006b483045022100c4f39ce7f2448ab8e7154a7b7ce82edd034e3f33e1f917ca43e4aff822ba804c02206dd146d1772a45bb5e51abb081d066114e78bcb504671f61c5a301a647a494ac01210312a0cb31ff52c480c049da26d0aaa600f47e9deee53d02fc2b0e9acf3c20fbdfffffffff31b5a9794dcaf82af1738745afe1ecf402ea4a93e71ae75c7d3d8bf7c78aef45010000006b483045022100d235afda9a56aaa4cbe05df712202e6b1a45aab7a0c83540d3053133f15acc5602201b0e144bec3a02a5c556596040b0be81b0202c19b163bb537b8d965afd61403a0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffa92c40dfd195a188d87110557fb7f46dbbfb68c4bb8718f33dc31d61927ec614000000006b483045022100dd8f1e20116f96a3400f55e0c637a0ad21ae47ff92d83ffb0c3d324c684a54be0220064b0a6d316154ef07a69bd82de3a052e43c3c6bb0e55e4de4de939b093e1a3a0121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffffb99a3e8884b14f330d2a444a4bc2a03af16804fb99b5e37ee892ed5db8b67f11010000006a473044022048d8cb0f1480174b3b9186cc6fe410db765f1f9d3ce036b0d4dee0eb19aa3641022073de4bb2b00a0533e9c8f3e074c655e0695c8b223233ddecf3c99a84351d50a60121033d710ab45bb54ac99618ad23b3c1da661631aa25f23bfe9d22b41876f1d46e4effffffff028017b42c000000001976a91455bdc1b42e3bed851959846ddf600e96125423e088ac0e47f302000000001976a91412967cdd9ceb72bbdbb7e5db85e2dbc6d6c3ab1a88ac00000000",
             sign_result.as_ref().unwrap().signature
@@ -11,20 +11,20 @@ use bitcoin::{
 use bitcoin_hashes::hash160;
 use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::Hash;
-use ikc_common::apdu::{ApduCheck, BtcApdu};
+use ikc_common::apdu::{BtcApdu, Command, Exchange};
 use ikc_common::constants::{EACH_ROUND_NUMBER, MAX_UTXO_NUMBER, MIN_NONDUST_OUTPUT, TIMEOUT_LONG};
 use ikc_common::error::CoinError;
 use ikc_common::path::check_path_validity;
 use ikc_common::utility::{bigint_to_byte_vec, hex_to_bytes, secp256k1_sign};
-use ikc_device::device_binding::KEY_MANAGER;
-// use ikc_transport::message::{send_apdu, send_apdu_timeout};
-#[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::send_apdu;
+use ikc_device::device_binding::device_key_manager;
+use ikc_transport::device::Device;
 use secp256k1::ecdsa::Signature;
+use std::time::Duration;
 
 impl BtcTransaction {
     pub async fn sign_omni_transaction(
         &self,
+        device: &Device,
         network: Network,
         path: &str,
         property_id: i32,
@@ -41,12 +41,12 @@ impl BtcTransaction {
         }
 
         //check change amount
-        if self.amount - self.fee < MIN_NONDUST_OUTPUT {
+        if self.amount.saturating_sub(self.fee) < MIN_NONDUST_OUTPUT {
             return Err(CoinError::ImkeyAmountLessThanMinimum.into());
         }
 
         //utxo address verify
-        let utxo_pub_key_vec = get_utxo_pub_key(&self.unspents).await?;
+        let utxo_pub_key_vec = get_utxo_pub_key(device, &self.unspents).await?;
 
         //add change output
         let mut txouts: Vec<TxOut> = Vec::new();
@@ -105,17 +105,21 @@ impl BtcTransaction {
         output_serialize_data.insert(0, 0x01);
 
         //use local private key sign data
-        let key_manager_obj = KEY_MANAGER.lock();
-        let mut output_pareper_data =
-            secp256k1_sign(&key_manager_obj.pri_key, &output_serialize_data)?;
+        let pri_key = device_key_manager(device)?.lock().pri_key.clone();
+        let mut output_pareper_data = secp256k1_sign(&pri_key, &output_serialize_data)?;
         output_pareper_data.insert(0, output_pareper_data.len() as u8);
         output_pareper_data.insert(0, 0x00);
         output_pareper_data.extend(output_serialize_data.iter());
 
         //send output prepare command
-        let omni_prepare_apdu_str = BtcApdu::omni_prepare_data(0x00, output_pareper_data);
-        // ApduCheck::check_response(&send_apdu_timeout(omni_prepare_apdu_str, TIMEOUT_LONG)?)?;
-        ApduCheck::check_response(&send_apdu(omni_prepare_apdu_str).await?)?;
+        let omni_prepare_apdu_str = BtcApdu::omni_prepare_data(0x00, output_pareper_data)?;
+        device
+            .exchange_with_timeout(
+                &omni_prepare_apdu_str,
+                Duration::from_secs(TIMEOUT_LONG as u64),
+            )
+            .await?
+            .check()?;
         let mut lock_script_ver: Vec<Script> = vec![];
         let count = (self.unspents.len() - 1) / EACH_ROUND_NUMBER + 1;
         for i in 0..count {
@@ -137,10 +141,10 @@ impl BtcTransaction {
                         Script::from(Vec::from_hex(temp_utxo.script_pubkey.as_str())?);
                 }
                 input_data_vec.extend_from_slice(serialize(&temp_serialize_txin).as_slice());
-                let btc_perpare_apdu = BtcApdu::btc_perpare_input(0x80, &input_data_vec);
+                let btc_perpare_apdu = BtcApdu::btc_perpare_input(0x80, &input_data_vec)?;
                 //send perpare apdu
                 // ApduCheck::check_response(&send_apdu(btc_perpare_apdu)?)?;
-                ApduCheck::check_response(&send_apdu(btc_perpare_apdu).await?)?;
+                device.exchange(&btc_perpare_apdu).await?.check()?;
             }
             for y in i * EACH_ROUND_NUMBER..(i + 1) * EACH_ROUND_NUMBER {
                 if y >= utxo_pub_key_vec.len() {
@@ -153,10 +157,9 @@ impl BtcTransaction {
                 );
                 //send sign apdu
                 // let btc_sign_apdu_return = send_apdu(btc_sign_apdu)?;
-                let btc_sign_apdu_return = send_apdu(btc_sign_apdu).await?;
-                ApduCheck::check_response(&btc_sign_apdu_return)?;
-                let sign_result_str =
-                    btc_sign_apdu_return[2..btc_sign_apdu_return.len() - 6].to_string();
+                let btc_sign_apdu_return = device.exchange(&btc_sign_apdu).await?;
+                btc_sign_apdu_return.check()?;
+                let sign_result_str = hex::encode_upper(btc_sign_apdu_return.field(1, 64)?);
 
                 lock_script_ver.push(self.build_unlock_script(
                     sign_result_str.as_str(),
@@ -189,6 +192,7 @@ impl BtcTransaction {
 
     pub async fn sign_omni_segwit_transaction(
         &self,
+        device: &Device,
         network: Network,
         path: &str,
         property_id: i32,
@@ -203,14 +207,16 @@ impl BtcTransaction {
         if &self.unspents.len() > &MAX_UTXO_NUMBER {
             return Err(CoinError::ImkeyExceededMaxUtxoNumber.into());
         }
-        let change_amount = self.get_total_amount() - self.fee - MIN_NONDUST_OUTPUT;
+        let change_amount = self
+            .get_total_amount()
+            .saturating_sub(self.fee + MIN_NONDUST_OUTPUT);
         //check change amount
         if change_amount < MIN_NONDUST_OUTPUT {
             return Err(CoinError::ImkeyAmountLessThanMinimum.into());
         }
 
         //utxo address verify
-        let utxo_pub_key_vec = get_utxo_pub_key(&self.unspents).await?;
+        let utxo_pub_key_vec = get_utxo_pub_key(device, &self.unspents).await?;
 
         //5.add change output
         let mut txouts: Vec<TxOut> = vec![];
@@ -262,23 +268,24 @@ impl BtcTransaction {
         output_serialize_data.insert(0, output_serialize_data.len() as u8);
         output_serialize_data.insert(0, 0x01);
         //use local private key sign data
-        let key_manager_obj = KEY_MANAGER.lock();
-        let mut output_pareper_data =
-            secp256k1_sign(&key_manager_obj.pri_key, &output_serialize_data)?;
+        let pri_key = device_key_manager(device)?.lock().pri_key.clone();
+        let mut output_pareper_data = secp256k1_sign(&pri_key, &output_serialize_data)?;
         output_pareper_data.insert(0, output_pareper_data.len() as u8);
         output_pareper_data.insert(0, 0x00);
         output_pareper_data.extend(output_serialize_data.iter());
 
         let btc_prepare_apdu_vec = BtcApdu::btc_prepare(0x34, 0x00, &output_pareper_data);
         for temp_str in btc_prepare_apdu_vec {
-            // ApduCheck::check_response(&send_apdu_timeout(temp_str, TIMEOUT_LONG)?)?;
-            ApduCheck::check_response(&send_apdu(temp_str).await?)?;
+            device
+                .exchange_with_timeout(&temp_str, Duration::from_secs(TIMEOUT_LONG as u64))
+                .await?
+                .check()?;
         }
 
         let mut txinputs: Vec<TxIn> = vec![];
         let mut txhash_vout_vec = vec![];
         let mut sequence_vec: Vec<u8> = vec![];
-        let mut sign_apdu_vec: Vec<String> = vec![];
+        let mut sign_apdu_vec: Vec<Command> = vec![];
         for (index, unspent) in self.unspents.iter().enumerate() {
             let txin = TxIn {
                 previous_output: OutPoint {
@@ -327,9 +334,9 @@ impl BtcTransaction {
 
             data.extend(address_data.iter());
             if index == self.unspents.len() - 1 {
-                sign_apdu_vec.push(BtcApdu::btc_segwit_sign(true, 0x01, data));
+                sign_apdu_vec.push(BtcApdu::btc_segwit_sign(true, 0x01, data)?);
             } else {
-                sign_apdu_vec.push(BtcApdu::btc_segwit_sign(false, 0x01, data));
+                sign_apdu_vec.push(BtcApdu::btc_segwit_sign(false, 0x01, data)?);
             }
 
             txinputs.push(txin.clone());
@@ -340,7 +347,7 @@ impl BtcTransaction {
         let mut sequence_prepare_apdu_vec = BtcApdu::btc_prepare(0x34, 0x80, &sequence_vec);
         txhash_vout_prepare_apdu_vec.append(&mut sequence_prepare_apdu_vec);
         for prepare_apdu in txhash_vout_prepare_apdu_vec {
-            ApduCheck::check_response(&send_apdu(prepare_apdu).await?)?;
+            device.exchange(&prepare_apdu).await?.check()?;
         }
 
         //send sign apdu
@@ -348,11 +355,10 @@ impl BtcTransaction {
         for (index, segwit_sign_apdu) in sign_apdu_vec.iter().enumerate() {
             //send sign apdu
             // let sign_apdu_return_data = send_apdu(segwit_sign_apdu.clone())?;
-            let sign_apdu_return_data = send_apdu(segwit_sign_apdu.clone()).await?;
-            ApduCheck::check_response(&sign_apdu_return_data)?;
+            let sign_apdu_return_data = device.exchange(segwit_sign_apdu).await?;
+            sign_apdu_return_data.check()?;
             //build signature obj
-            let sign_result_vec =
-                Vec::from_hex(&sign_apdu_return_data[2..sign_apdu_return_data.len() - 6]).unwrap();
+            let sign_result_vec = sign_apdu_return_data.field(1, 64)?.to_vec();
             let mut temp_signature_obj = Signature::from_compact(sign_result_vec.as_slice())?;
             temp_signature_obj.normalize_s();
             //generator der sign data
@@ -428,12 +434,13 @@ mod tests {
     use bitcoin::{Address, Network};
     use std::str::FromStr;
 
+    use futures::executor::block_on;
     use ikc_device::device_binding::bind_test;
 
     #[test]
     fn test_sign_transaction() {
         //binding device
-        bind_test();
+        let device = bind_test();
 
         let utxo = Utxo {
             txhash: "0dd195c815c5086c5995f43a0c67d28344ae5fa130739a5e03ef40fea54f2031".to_string(),
@@ -453,11 +460,12 @@ mod tests {
             unspents: utxos,
             fee: 4000,
         };
-        let sign_result = transaction_req_data.sign_omni_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_transaction(
+            &device,
             Network::Testnet,
             &"m/44'/1'/0'".to_string(),
             31,
-        );
+        ));
         assert_eq!(
             "36a25fa2005b5d4922d18f6f819bf068dca479d4103904ce225a9438a2c1f5a0",
             sign_result.as_ref().unwrap().tx_hash
@@ -471,7 +479,7 @@ mod tests {
     #[test]
     fn test_sign_segwit_transaction() {
         //binding device
-        bind_test();
+        let device = bind_test();
 
         let utxo = Utxo {
             txhash: "9baf6fd0e560f9f199f4879c23cb73b9c4affb54a1cfdbacb85687efa89f4c78".to_string(),
@@ -491,11 +499,12 @@ mod tests {
             unspents: utxos,
             fee: 4000,
         };
-        let sign_result = transaction_req_data.sign_omni_segwit_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_segwit_transaction(
+            &device,
             Network::Testnet,
             &"m/49'/1'/0'/".to_string(),
             31,
-        );
+        ));
 
         assert_eq!(
             "e664888c4a67cfed29786e5ada0c24cb25b91cafca4ae699fb7b90e7071e88bc",
@@ -510,7 +519,7 @@ mod tests {
     #[test]
     fn test_segwit_transaction_8utxo() {
         //binding device
-        bind_test();
+        let device = bind_test();
 
         let utxo = Utxo {
             txhash: "983adf9d813a2b8057454cc6f36c6081948af849966f9b9a33e5b653b02f227a".to_string(),
@@ -600,11 +609,12 @@ mod tests {
             unspents: utxos,
             fee: 502130,
         };
-        let sign_result = transaction_req_data.sign_omni_segwit_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_segwit_transaction(
+            &device,
             Network::Bitcoin,
             &"m/49'/0'/0'/".to_string(),
             31,
-        );
+        ));
         assert_eq!(
             "79ec1ab9008e3ce2809419d7b25c58de0f03a782e81f15d0e92042e16f141434",
             sign_result.as_ref().unwrap().tx_hash
@@ -618,7 +628,7 @@ mod tests {
     #[test]
     fn test_sign_segwit_transaction_mainnet() {
         //binding device
-        bind_test();
+        let device = bind_test();
 
         let utxo = Utxo {
             txhash: "983adf9d813a2b8057454cc6f36c6081948af849966f9b9a33e5b653b02f227a".to_string(),
@@ -637,11 +647,12 @@ mod tests {
             unspents: utxos,
             fee: 502130,
         };
-        let sign_result = transaction_req_data.sign_omni_segwit_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_segwit_transaction(
+            &device,
             Network::Bitcoin,
             &"m/49'/0'/0'/".to_string(),
             31,
-        );
+        ));
         assert_eq!(
             "0f3365929829d1d519751ed65bc0751cae6fe4480bc7b2098efa8c634e8b11b5",
             sign_result.as_ref().unwrap().tx_hash
@@ -654,6 +665,8 @@ mod tests {
 
     #[test]
     fn exceeded_max_utxo_number_test() {
+        let device = bind_test();
+
         let utxo = Utxo {
             txhash: "983adf9d813a2b8057454cc6f36c6081948af849966f9b9a33e5b653b02f227a".to_string(),
             vout: 0,
@@ -673,11 +686,12 @@ mod tests {
             unspents: utxos,
             fee: 502130,
         };
-        let sign_result = transaction_req_data.sign_omni_segwit_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_segwit_transaction(
+            &device,
             Network::Bitcoin,
             &"m/49'/0'/0'".to_string(),
             31,
-        );
+        ));
         assert_eq!(
             format!("{}", sign_result.err().unwrap()),
             "imkey_exceeded_max_utxo_number"
@@ -703,11 +717,12 @@ mod tests {
             unspents: utxos,
             fee: 4000,
         };
-        let sign_result = transaction_req_data.sign_omni_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_transaction(
+            &device,
             Network::Testnet,
             &"m/44'/1'/0'".to_string(),
             31,
-        );
+        ));
 
         assert_eq!(
             format!("{}", sign_result.err().unwrap()),
@@ -717,6 +732,8 @@ mod tests {
 
     #[test]
     fn amount_less_than_minimum_test() {
+        let device = bind_test();
+
         let utxo = Utxo {
             txhash: "983adf9d813a2b8057454cc6f36c6081948af849966f9b9a33e5b653b02f227a".to_string(),
             vout: 0,
@@ -735,11 +752,12 @@ mod tests {
             unspents: utxos,
             fee: 900,
         };
-        let sign_result = transaction_req_data.sign_omni_segwit_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_segwit_transaction(
+            &device,
             Network::Bitcoin,
             &"m/49'/0'/0'/".to_string(),
             31,
-        );
+        ));
         assert_eq!(
             format!("{}", sign_result.err().unwrap()),
             "imkey_amount_less_than_minimum"
@@ -763,11 +781,12 @@ mod tests {
             unspents: utxos,
             fee: 900,
         };
-        let sign_result = transaction_req_data.sign_omni_transaction(
+        let sign_result = block_on(transaction_req_data.sign_omni_transaction(
+            &device,
             Network::Testnet,
             &"m/44'/1'/0'".to_string(),
             31,
-        );
+        ));
 
         assert_eq!(
             format!("{}", sign_result.err().unwrap()),
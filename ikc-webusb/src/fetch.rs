@@ -0,0 +1,50 @@
+use crate::Result;
+use async_trait::async_trait;
+use ikc_transport::error::TransportError;
+use ikc_transport::http::HttpClient;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{window, Request, RequestInit, RequestMode, Response};
+
+/**
+HTTP client of the browser build, posting through the fetch API of the page.
+*/
+#[derive(Default)]
+pub struct FetchClient;
+
+impl FetchClient {
+    pub fn new() -> Self {
+        FetchClient
+    }
+}
+
+#[async_trait(?Send)]
+impl HttpClient for FetchClient {
+    async fn post(&self, url: &str, body: &str) -> Result<String> {
+        let init = RequestInit::new();
+        init.set_method("POST");
+        init.set_mode(RequestMode::Cors);
+        init.set_body(&JsValue::from_str(body));
+        let request =
+            Request::new_with_str_and_init(url, &init).map_err(|_| TransportError::NetworkFail)?;
+        request
+            .headers()
+            .set("Content-Type", "application/json")
+            .map_err(|_| TransportError::NetworkFail)?;
+
+        let window = window().expect("window should be available");
+        let response: Response = JsFuture::from(window.fetch_with_request(&request))
+            .await
+            .map_err(|_| TransportError::NetworkFail)?
+            .unchecked_into();
+        if !response.ok() {
+            return Err(TransportError::NetworkFail.into());
+        }
+        let text = response.text().map_err(|_| TransportError::NetworkFail)?;
+        let text = JsFuture::from(text)
+            .await
+            .map_err(|_| TransportError::NetworkFail)?;
+        text.as_string()
+            .ok_or_else(|| TransportError::NetworkFail.into())
+    }
+}
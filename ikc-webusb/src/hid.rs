@@ -0,0 +1,459 @@
+use crate::Result;
+use bytes::{BufMut, BytesMut};
+use ikc_transport::error::TransportError;
+use serde::{Deserialize, Serialize};
+
+pub const IMKEY_VENDOR_ID: u16 = 0x096e;
+pub const IMKEY_PRODUCT_ID: u16 = 0x0891;
+const DEFAULT_CONFIGURATION: u8 = 1;
+
+pub const PACKET_SIZE: usize = 64;
+//channel id (4) + command type or sequence (1)
+const HEADER_SIZE: usize = 5;
+
+pub const COMMAND_TYPE_MESSAGE: u8 = 0x43 | 0x80;
+pub const COMMAND_TYPE_CANCEL: u8 = 0x51 | 0x80;
+pub const COMMAND_TYPE_ERROR: u8 = 0x7F | 0x80;
+pub const COMMAND_TYPE_KEEPALIVE: u8 = 0x7B | 0x80;
+
+pub const ERR_INVALID_CMD: u8 = 0x01;
+pub const ERR_INVALID_PAR: u8 = 0x02;
+pub const ERR_INVALID_LEN: u8 = 0x03;
+pub const ERR_INVALID_SEQ: u8 = 0x04;
+
+pub const ERR_DEVICE_BUSY: u8 = 0x06;
+pub const ERR_DEVICE_CANCEL: u8 = 0xFE;
+pub const ERR_OTHER: u8 = 0x07;
+
+//continuation sequence numbers wrap before reaching the command types
+const SEQUENCE_MODULO: usize = 0x80;
+
+fn as_u16_be(value: usize) -> BytesMut {
+    let mut b = BytesMut::with_capacity(2);
+    b.put_u16(value as u16);
+    b
+}
+
+/**
+split an APDU into 64 bytes HID reports: an initialization block carrying the total length,
+followed by continuation blocks numbered from 0, wrapping after 0x7F
+*/
+pub fn make_blocks(apdu: &[u8]) -> Result<Vec<BytesMut>> {
+    //the total length is carried on two bytes
+    if apdu.len() > u16::MAX as usize {
+        return Err(TransportError::ApduTooLong.into());
+    }
+    let mut data = BytesMut::with_capacity(2 + apdu.len()); //指令长度（2） + 指令原值
+    data.put(as_u16_be(apdu.len()));
+    data.put(apdu);
+    let block_size = PACKET_SIZE - HEADER_SIZE;
+    let nb_blocks = data.len().div_ceil(block_size);
+    let mut blocks: Vec<BytesMut> = Vec::with_capacity(nb_blocks);
+    let mut data_index = 0;
+
+    for i in 0..nb_blocks {
+        let mut head = BytesMut::with_capacity(HEADER_SIZE);
+
+        if i == 0 {
+            head.put_slice(&[0x00, 0x00, 0x00, 0x00]);
+            head.put_u8(COMMAND_TYPE_MESSAGE);
+        } else {
+            head.put_slice(&[0x00, 0x00, 0x00, 0x00]);
+            head.put_u8(((i - 1) % SEQUENCE_MODULO) as u8);
+        }
+        let chunk = &data[data_index..std::cmp::min(data.len(), data_index + block_size)];
+        data_index += block_size;
+
+        let mut block = BytesMut::with_capacity(PACKET_SIZE);
+        block.put(head);
+        block.put(chunk);
+        block.resize(PACKET_SIZE, 0);
+        blocks.push(block);
+
+        if data_index >= data.len() {
+            break;
+        }
+    }
+
+    Ok(blocks)
+}
+
+/**
+the single block aborting the command the device is currently processing,
+only sent by Transport::cancel and never built from APDU bytes
+*/
+pub fn make_cancel_block() -> BytesMut {
+    let mut block = BytesMut::with_capacity(PACKET_SIZE);
+    block.put_slice(&[0x00, 0x00, 0x00, 0x00]);
+    block.put_u8(COMMAND_TYPE_CANCEL);
+    block.resize(PACKET_SIZE, 0);
+    block
+}
+
+/**
+Reassembles the HID reports of one response.
+Keepalive reports sent while the user confirms on the device are skipped,
+error reports are turned into typed transport errors.
+*/
+#[derive(Default)]
+pub struct ResponseAcc {
+    data: Vec<u8>,
+    data_length: usize,
+    sequence: usize,
+    started: bool,
+}
+
+impl ResponseAcc {
+    pub fn new() -> Self {
+        ResponseAcc::default()
+    }
+
+    /**
+    feed one report, return the response once all of its data has been received
+    */
+    pub fn reduce_response(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>> {
+        if chunk.len() <= HEADER_SIZE {
+            return Err(TransportError::HidInvalidLength.into());
+        }
+
+        match chunk[4] {
+            COMMAND_TYPE_KEEPALIVE => return Ok(None),
+            COMMAND_TYPE_ERROR => {
+                return match chunk.get(7) {
+                    Some(&ERR_INVALID_CMD) => Err(TransportError::HidInvalidCommand.into()),
+                    Some(&ERR_INVALID_PAR) => Err(TransportError::HidInvalidParameter.into()),
+                    Some(&ERR_INVALID_LEN) => Err(TransportError::HidInvalidLength.into()),
+                    Some(&ERR_INVALID_SEQ) => Err(TransportError::HidInvalidSequence.into()),
+                    Some(&ERR_DEVICE_BUSY) => Err(TransportError::DeviceBusy.into()),
+                    Some(&ERR_DEVICE_CANCEL) => Err(TransportError::DeviceCancelled.into()),
+                    Some(&ERR_OTHER) => Err(TransportError::HidOtherError.into()),
+                    _ => Err(TransportError::HidUnknownError.into()),
+                };
+            }
+            COMMAND_TYPE_MESSAGE => {
+                if self.started || chunk.len() < 7 {
+                    return Err(TransportError::HidInvalidSequence.into());
+                }
+                self.started = true;
+                self.data_length = ((chunk[5] as usize) << 8) | (chunk[6] as usize);
+                self.data.extend_from_slice(&chunk[7..]);
+            }
+            sequence => {
+                if !self.started || sequence as usize != self.sequence % SEQUENCE_MODULO {
+                    return Err(TransportError::HidInvalidSequence.into());
+                }
+                self.sequence += 1;
+                self.data.extend_from_slice(&chunk[HEADER_SIZE..]);
+            }
+        }
+
+        if self.data.len() < self.data_length {
+            return Ok(None);
+        }
+        self.data.truncate(self.data_length);
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+}
+
+/**
+Which devices are offered to the user and which interface of theirs is claimed.
+New hardware revisions, or devices in bootloader mode (BL_AID) enumerating with another
+product id, are opened by adding their product id.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConnectOptions {
+    pub product_ids: Vec<u16>,
+    //any interface with an interrupt endpoint pair when not set
+    pub interface_class: Option<u8>,
+    pub configuration: u8,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            product_ids: vec![IMKEY_PRODUCT_ID],
+            interface_class: None,
+            configuration: DEFAULT_CONFIGURATION,
+        }
+    }
+}
+
+impl ConnectOptions {
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        vendor_id == IMKEY_VENDOR_ID && self.product_ids.contains(&product_id)
+    }
+
+    /**
+    the request filters of the WebUSB and WebHID device choosers
+    */
+    pub fn filters(&self) -> Vec<serde_json::Value> {
+        self.product_ids
+            .iter()
+            .map(|product_id| {
+                let mut filter = serde_json::json!({
+                    "vendorId": IMKEY_VENDOR_ID,
+                    "productId": product_id,
+                });
+                if let Some(class_code) = self.interface_class {
+                    filter["classCode"] = class_code.into();
+                }
+                filter
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointDescriptor {
+    pub number: u8,
+    pub direction_in: bool,
+    pub interrupt: bool,
+}
+
+/**
+one alternate setting of a USB interface, as reported by the platform
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+/**
+the interface and interrupt endpoint numbers the HID reports are exchanged on
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointPair {
+    pub interface: u8,
+    pub alternate: u8,
+    pub endpoint_in: u8,
+    pub endpoint_out: u8,
+}
+
+/**
+find the first interface, of the requested class if any, having an interrupt IN and OUT endpoint
+*/
+pub fn find_endpoints(
+    interfaces: &[InterfaceDescriptor],
+    interface_class: Option<u8>,
+) -> Result<EndpointPair> {
+    let find = |interface: &InterfaceDescriptor, direction_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.interrupt && endpoint.direction_in == direction_in)
+            .map(|endpoint| endpoint.number)
+    };
+    interfaces
+        .iter()
+        .filter(|interface| interface_class.is_none_or(|class| interface.class == class))
+        .find_map(|interface| {
+            Some(EndpointPair {
+                interface: interface.number,
+                alternate: interface.alternate,
+                endpoint_in: find(interface, true)?,
+                endpoint_out: find(interface, false)?,
+            })
+        })
+        .ok_or_else(|| TransportError::UsbInterfaceNotFound.into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hid::{
+        find_endpoints, make_blocks, make_cancel_block, ConnectOptions, EndpointDescriptor,
+        EndpointPair, InterfaceDescriptor, ResponseAcc, PACKET_SIZE,
+    };
+
+    fn report(hex: &str) -> Vec<u8> {
+        let mut report = hex::decode(hex).unwrap();
+        report.resize(PACKET_SIZE, 0);
+        report
+    }
+
+    #[test]
+    fn make_blocks_test() {
+        let blocks = make_blocks(&hex::decode("00A4040005695F62746300").unwrap()).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            hex::encode_upper(&blocks[0][..18]),
+            "00000000C3000B00A4040005695F62746300"
+        );
+
+        let blocks = make_blocks(&[0x11; 100]).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0][4], 0xC3);
+        assert_eq!(&blocks[0][5..7], &[0x00, 0x64]);
+        assert_eq!(blocks[1][4], 0x00);
+        assert!(blocks.iter().all(|block| block.len() == PACKET_SIZE));
+
+        assert_eq!(hex::encode_upper(&make_cancel_block()[..5]), "00000000D1");
+        assert_eq!(make_cancel_block().len(), PACKET_SIZE);
+
+        //an APDU is always sent as a message, even with the bytes of an empty length
+        let blocks = make_blocks(&[0x00, 0x00]).unwrap();
+        assert_eq!(hex::encode_upper(&blocks[0][..9]), "00000000C300020000");
+
+        assert!(make_blocks(&vec![0x11; u16::MAX as usize + 1]).is_err());
+    }
+
+    #[test]
+    fn extended_length_round_trip_test() {
+        let apdu: Vec<u8> = (0..u16::MAX as usize).map(|i| i as u8).collect();
+        let blocks = make_blocks(&apdu).unwrap();
+        assert!(blocks.len() > 0x80);
+        assert_eq!(blocks[0x80][4], 0x7F);
+        assert_eq!(blocks[0x81][4], 0x00);
+        assert!(blocks[1..].iter().all(|block| block[4] < 0x80));
+
+        let mut acc = ResponseAcc::new();
+        let mut response = None;
+        for block in blocks.iter() {
+            assert!(response.is_none());
+            response = acc.reduce_response(block).unwrap();
+        }
+        assert_eq!(response, Some(apdu));
+    }
+
+    #[test]
+    fn reduce_single_report_test() {
+        let mut acc = ResponseAcc::new();
+        let response = acc.reduce_response(&report("00000000C300029000")).unwrap();
+        assert_eq!(response, Some(vec![0x90, 0x00]));
+    }
+
+    #[test]
+    fn reduce_multi_report_test() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut first = hex::decode("00000000C30064").unwrap();
+        first.extend(&data[..57]);
+        let mut second = hex::decode("0000000000").unwrap();
+        second.extend(&data[57..]);
+
+        let mut acc = ResponseAcc::new();
+        assert_eq!(acc.reduce_response(&first).unwrap(), None);
+        assert_eq!(
+            acc.reduce_response(&report(&hex::encode(second))).unwrap(),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn reduce_keepalive_test() {
+        let mut acc = ResponseAcc::new();
+        assert_eq!(
+            acc.reduce_response(&report("00000000FB0001")).unwrap(),
+            None
+        );
+        let response = acc.reduce_response(&report("00000000C300026940")).unwrap();
+        assert_eq!(response, Some(vec![0x69, 0x40]));
+    }
+
+    #[test]
+    fn reduce_error_report_test() {
+        let errors = [
+            ("00000000FF000106", "imkey_device_busy"),
+            ("00000000FF000104", "imkey_hid_invalid_sequence"),
+            ("00000000FF0001FE", "imkey_device_cancelled"),
+            ("00000000FF000101", "imkey_hid_invalid_command"),
+            ("00000000FF000107", "imkey_hid_other_error"),
+            ("00000000FF000155", "imkey_hid_unknown_error"),
+        ];
+        for (frame, error) in errors.iter() {
+            let mut acc = ResponseAcc::new();
+            let result = acc.reduce_response(&report(frame));
+            assert_eq!(result.err().unwrap().to_string(), *error);
+        }
+    }
+
+    #[test]
+    fn reduce_out_of_sequence_test() {
+        let mut acc = ResponseAcc::new();
+        let result = acc.reduce_response(&report("0000000000"));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_hid_invalid_sequence"
+        );
+
+        let mut acc = ResponseAcc::new();
+        acc.reduce_response(&report("00000000C30080")).unwrap();
+        let result = acc.reduce_response(&report("0000000001"));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "imkey_hid_invalid_sequence"
+        );
+    }
+
+    fn endpoint(number: u8, direction_in: bool, interrupt: bool) -> EndpointDescriptor {
+        EndpointDescriptor {
+            number,
+            direction_in,
+            interrupt,
+        }
+    }
+
+    #[test]
+    fn find_endpoints_test() {
+        let interfaces = vec![
+            InterfaceDescriptor {
+                number: 0,
+                alternate: 0,
+                class: 0x0B,
+                endpoints: vec![endpoint(1, true, false), endpoint(2, false, false)],
+            },
+            InterfaceDescriptor {
+                number: 1,
+                alternate: 0,
+                class: 0x03,
+                endpoints: vec![endpoint(5, true, true), endpoint(4, false, true)],
+            },
+            InterfaceDescriptor {
+                number: 2,
+                alternate: 1,
+                class: 0xFF,
+                endpoints: vec![endpoint(7, false, true), endpoint(6, true, true)],
+            },
+        ];
+
+        let pair = find_endpoints(&interfaces, None).unwrap();
+        assert_eq!(
+            pair,
+            EndpointPair {
+                interface: 1,
+                alternate: 0,
+                endpoint_in: 5,
+                endpoint_out: 4,
+            }
+        );
+        let pair = find_endpoints(&interfaces, Some(0xFF)).unwrap();
+        assert_eq!((pair.interface, pair.alternate), (2, 1));
+        assert_eq!((pair.endpoint_in, pair.endpoint_out), (6, 7));
+        assert_eq!(
+            find_endpoints(&interfaces, Some(0x0B))
+                .err()
+                .unwrap()
+                .to_string(),
+            "imkey_usb_interface_not_found"
+        );
+    }
+
+    #[test]
+    fn connect_options_test() {
+        let options: ConnectOptions =
+            serde_json::from_str(r#"{"productIds": [2193, 2194], "interfaceClass": 255}"#).unwrap();
+        assert_eq!(options.configuration, 1);
+        assert!(options.matches(0x096e, 0x0892));
+        assert!(!options.matches(0x096f, 0x0891));
+        assert_eq!(
+            serde_json::to_string(&options.filters()).unwrap(),
+            r#"[{"classCode":255,"productId":2193,"vendorId":2414},{"classCode":255,"productId":2194,"vendorId":2414}]"#
+        );
+        assert_eq!(
+            ConnectOptions::default().filters(),
+            vec![serde_json::json!({"vendorId": 0x096e, "productId": 0x0891})]
+        );
+    }
+}
@@ -1,11 +1,16 @@
 extern crate web_sys;
-use wasm_bindgen::prelude::*;
-// #[cfg(target_arch = "wasm32")]
+#[cfg(target_arch = "wasm32")]
+pub mod fetch;
+pub mod hid;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod native;
+#[cfg(target_arch = "wasm32")]
+pub mod webhid;
+#[cfg(target_arch = "wasm32")]
 pub mod webusb;
 use core::result;
 pub type Result<T> = result::Result<T, anyhow::Error>;
 
-
 // #[cfg(target_arch = "wasm32")]
 // #[wasm_bindgen]
 // pub async fn connect() {
@@ -17,4 +22,3 @@ pub type Result<T> = result::Result<T, anyhow::Error>;
 // pub async fn send_apdu(apdu: &str) ->String {
 //     webusb::send_apdu(apdu.to_string()).await.unwrap()
 // }
-
@@ -0,0 +1,393 @@
+use crate::hid::{make_blocks, make_cancel_block, ResponseAcc};
+use crate::Result;
+use async_trait::async_trait;
+use ikc_transport::error::TransportError;
+use ikc_transport::timeout::CANCEL_GRACE;
+use ikc_transport::transport::Transport;
+use std::time::{Duration, Instant};
+
+//longest blocking read, the deadline of a command is checked between two reads
+const READ_INTERVAL: Duration = Duration::from_millis(500);
+
+/**
+The interrupt endpoint pair of an opened imKey, exchanging 64 bytes HID reports.
+*/
+pub trait Endpoints {
+    fn write(&self, block: &[u8]) -> Result<()>;
+
+    /**
+    read one report, None when nothing arrived within the timeout
+    */
+    fn read(&self, timeout: Duration) -> Result<Option<Vec<u8>>>;
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+/**
+Transport framing APDUs over a native endpoint pair, the blocking counterpart of WebUsbTransport
+for servers and command line tools.
+*/
+pub struct NativeTransport<E: Endpoints> {
+    endpoints: E,
+}
+
+impl<E: Endpoints> NativeTransport<E> {
+    pub fn new(endpoints: E) -> Self {
+        NativeTransport { endpoints }
+    }
+
+    fn exchange(&self, apdu: &[u8], timeout: Option<Duration>) -> Result<Vec<u8>> {
+        for block in make_blocks(apdu)?.iter() {
+            self.endpoints.write(block)?;
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut response_acc = ResponseAcc::new();
+        loop {
+            let wait = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => READ_INTERVAL,
+            };
+            if wait.is_zero() {
+                self.endpoints.write(&make_cancel_block())?;
+                self.drain(response_acc);
+                return Err(TransportError::Timeout.into());
+            }
+            if let Some(block) = self.endpoints.read(wait.min(READ_INTERVAL))? {
+                if let Some(response) = response_acc.reduce_response(&block)? {
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    //consume the answer to a cancelled command so that it is not taken for the next response
+    fn drain(&self, mut response_acc: ResponseAcc) {
+        let deadline = Instant::now() + CANCEL_GRACE;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            match self.endpoints.read(wait) {
+                Ok(Some(block)) => match response_acc.reduce_response(&block) {
+                    Ok(None) => continue,
+                    _ => return,
+                },
+                _ => return,
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: Endpoints> Transport for NativeTransport<E> {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        self.exchange(apdu, None)
+    }
+
+    async fn send_apdu_with_timeout(&self, apdu: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        self.exchange(apdu, Some(timeout))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.endpoints.is_connected()
+    }
+
+    async fn cancel(&self) -> Result<()> {
+        self.endpoints.write(&make_cancel_block())
+    }
+}
+
+#[cfg(feature = "native")]
+pub use self::usb::{connect, RusbConnector, RusbEndpoints};
+
+#[cfg(feature = "native")]
+mod usb {
+    use super::{Endpoints, NativeTransport};
+    use crate::hid::{
+        find_endpoints, ConnectOptions, EndpointDescriptor, EndpointPair, InterfaceDescriptor,
+        PACKET_SIZE,
+    };
+    use crate::Result;
+    use async_trait::async_trait;
+    use ikc_transport::connector::Connector;
+    use ikc_transport::device::Device;
+    use ikc_transport::error::TransportError;
+    use rusb::{DeviceHandle, Direction, GlobalContext, TransferType};
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    //direction bit of the IN endpoint addresses
+    const ENDPOINT_IN_ADDRESS: u8 = 0x80;
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /**
+    Endpoints of an imKey opened through libusb, the HID kernel driver is detached while claimed.
+    */
+    pub struct RusbEndpoints {
+        handle: DeviceHandle<GlobalContext>,
+        endpoints: EndpointPair,
+        connected: Cell<bool>,
+    }
+
+    fn interface_descriptors(
+        device: &rusb::Device<GlobalContext>,
+        configuration: u8,
+    ) -> Result<Vec<InterfaceDescriptor>> {
+        let config_descriptor = device
+            .config_descriptor(configuration.saturating_sub(1))
+            .map_err(|_| TransportError::UsbInterfaceNotFound)?;
+        let mut descriptors = vec![];
+        for interface in config_descriptor.interfaces() {
+            for alternate in interface.descriptors() {
+                descriptors.push(InterfaceDescriptor {
+                    number: alternate.interface_number(),
+                    alternate: alternate.setting_number(),
+                    class: alternate.class_code(),
+                    endpoints: alternate
+                        .endpoint_descriptors()
+                        .map(|endpoint| EndpointDescriptor {
+                            number: endpoint.number(),
+                            direction_in: endpoint.direction() == Direction::In,
+                            interrupt: endpoint.transfer_type() == TransferType::Interrupt,
+                        })
+                        .collect(),
+                });
+            }
+        }
+        Ok(descriptors)
+    }
+
+    impl RusbEndpoints {
+        pub fn open(
+            device: &rusb::Device<GlobalContext>,
+            options: &ConnectOptions,
+        ) -> Result<Self> {
+            let descriptors = interface_descriptors(device, options.configuration)?;
+            let endpoints = find_endpoints(&descriptors, options.interface_class)?;
+            let handle = device
+                .open()
+                .map_err(|_| TransportError::DeviceNotConnected)?;
+            //not supported on every platform, claiming fails afterwards if it was needed
+            let _ = handle.set_auto_detach_kernel_driver(true);
+            handle
+                .claim_interface(endpoints.interface)
+                .map_err(|_| TransportError::DeviceNotConnected)?;
+            if endpoints.alternate != 0 {
+                handle
+                    .set_alternate_setting(endpoints.interface, endpoints.alternate)
+                    .map_err(|_| TransportError::DeviceNotConnected)?;
+            }
+            Ok(RusbEndpoints {
+                handle,
+                endpoints,
+                connected: Cell::new(true),
+            })
+        }
+
+        fn transfer_error(&self, error: rusb::Error) -> anyhow::Error {
+            match error {
+                rusb::Error::NoDevice => {
+                    self.connected.set(false);
+                    TransportError::DeviceNotConnected.into()
+                }
+                _ => TransportError::UsbTransferFail.into(),
+            }
+        }
+    }
+
+    impl Endpoints for RusbEndpoints {
+        fn write(&self, block: &[u8]) -> Result<()> {
+            self.handle
+                .write_interrupt(self.endpoints.endpoint_out, block, WRITE_TIMEOUT)
+                .map_err(|error| self.transfer_error(error))?;
+            Ok(())
+        }
+
+        fn read(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+            let mut block = vec![0u8; PACKET_SIZE];
+            let address = ENDPOINT_IN_ADDRESS | self.endpoints.endpoint_in;
+            match self.handle.read_interrupt(address, &mut block, timeout) {
+                Ok(size) => {
+                    block.truncate(size);
+                    Ok(Some(block))
+                }
+                Err(rusb::Error::Timeout) => Ok(None),
+                Err(error) => Err(self.transfer_error(error)),
+            }
+        }
+
+        fn is_connected(&self) -> bool {
+            self.connected.get()
+        }
+    }
+
+    fn imkey_devices(options: &ConnectOptions) -> Result<Vec<rusb::Device<GlobalContext>>> {
+        let devices = rusb::devices().map_err(|_| TransportError::DeviceNotConnected)?;
+        Ok(devices
+            .iter()
+            .filter(|device| match device.device_descriptor() {
+                Ok(descriptor) => options.matches(descriptor.vendor_id(), descriptor.product_id()),
+                Err(_) => false,
+            })
+            .collect())
+    }
+
+    /**
+    open the first imKey plugged in
+    */
+    pub fn connect(options: &ConnectOptions) -> Result<Device> {
+        for device in imkey_devices(options)? {
+            if let Ok(endpoints) = RusbEndpoints::open(&device, options) {
+                return Ok(Device::new(NativeTransport::new(endpoints)));
+            }
+        }
+        Err(TransportError::DeviceNotConnected.into())
+    }
+
+    /**
+    Opens every imKey plugged in, a device claimed by another process is skipped.
+    */
+    #[derive(Default)]
+    pub struct RusbConnector {
+        options: ConnectOptions,
+    }
+
+    impl RusbConnector {
+        pub fn new(options: ConnectOptions) -> Self {
+            RusbConnector { options }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Connector for RusbConnector {
+        async fn open_devices(&self) -> Result<Vec<Device>> {
+            Ok(imkey_devices(&self.options)?
+                .iter()
+                .filter_map(|device| RusbEndpoints::open(device, &self.options).ok())
+                .map(|endpoints| Device::new(NativeTransport::new(endpoints)))
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hid::{make_blocks, ResponseAcc, COMMAND_TYPE_CANCEL, PACKET_SIZE};
+    use crate::native::{Endpoints, NativeTransport};
+    use crate::Result;
+    use futures::executor::block_on;
+    use ikc_device::device_manager::get_sn;
+    use ikc_simulator::simulator::Simulator;
+    use ikc_transport::device::Device;
+    use ikc_transport::transport::Transport;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    /**
+    virtual device side of the endpoint pair, reassembles the commands written by the host
+    and queues the reports of the responses
+    */
+    struct VirtualEndpoints<F: Fn(&[u8]) -> Option<Vec<u8>>> {
+        process: F,
+        command: RefCell<ResponseAcc>,
+        reports: RefCell<VecDeque<Vec<u8>>>,
+        cancelled: RefCell<usize>,
+    }
+
+    impl<F: Fn(&[u8]) -> Option<Vec<u8>>> VirtualEndpoints<F> {
+        //process returns None for a command still waiting for the user
+        fn new(process: F) -> Self {
+            VirtualEndpoints {
+                process,
+                command: RefCell::new(ResponseAcc::new()),
+                reports: RefCell::new(VecDeque::new()),
+                cancelled: RefCell::new(0),
+            }
+        }
+
+        fn push_report(&self, header: &str) {
+            let mut report = hex::decode(header).unwrap();
+            report.resize(PACKET_SIZE, 0);
+            self.reports.borrow_mut().push_back(report);
+        }
+    }
+
+    impl<F: Fn(&[u8]) -> Option<Vec<u8>>> Endpoints for VirtualEndpoints<F> {
+        fn write(&self, block: &[u8]) -> Result<()> {
+            assert_eq!(block.len(), PACKET_SIZE);
+            if block[4] == COMMAND_TYPE_CANCEL {
+                *self.cancelled.borrow_mut() += 1;
+                self.push_report("00000000FF0001FE");
+                return Ok(());
+            }
+            let command = self.command.borrow_mut().reduce_response(block)?;
+            if let Some(command) = command {
+                *self.command.borrow_mut() = ResponseAcc::new();
+                self.push_report("00000000FB0001");
+                if let Some(response) = (self.process)(&command) {
+                    for report in make_blocks(&response).unwrap() {
+                        self.reports.borrow_mut().push_back(report.to_vec());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn read(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+            match self.reports.borrow_mut().pop_front() {
+                Some(report) => Ok(Some(report)),
+                None => {
+                    std::thread::sleep(timeout.min(Duration::from_millis(10)));
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exchange_test() {
+        let transport = NativeTransport::new(VirtualEndpoints::new(|command: &[u8]| {
+            let mut response = command.to_vec();
+            response.extend_from_slice(&[0x90, 0x00]);
+            Some(response)
+        }));
+        let device = Device::new(transport);
+        let command = vec![0x11; 200];
+        let response = block_on(device.send_apdu(&command)).unwrap();
+        assert_eq!(response, [command, vec![0x90, 0x00]].concat());
+    }
+
+    #[test]
+    fn simulator_test() {
+        let simulator = Simulator::new();
+        let device = Device::new(NativeTransport::new(VirtualEndpoints::new(
+            move |command: &[u8]| Some(simulator.process(command)),
+        )));
+        let sn = block_on(get_sn(&device)).unwrap();
+        assert_eq!(sn.len(), 16);
+    }
+
+    #[test]
+    fn timeout_test() {
+        let transport =
+            NativeTransport::new(VirtualEndpoints::new(|command: &[u8]| match command[1] {
+                0x43 => None,
+                _ => Some(vec![0x90, 0x00]),
+            }));
+        let result = block_on(
+            transport.send_apdu_with_timeout(&[0x80, 0x43, 0x00, 0x00], Duration::from_millis(50)),
+        );
+        assert_eq!(result.err().unwrap().to_string(), "imkey_command_timeout");
+        assert_eq!(*transport.endpoints.cancelled.borrow(), 1);
+
+        //the cancel acknowledgement has been consumed
+        assert!(transport.endpoints.reports.borrow().is_empty());
+        let response = block_on(transport.send_apdu(&[0x00, 0xA4, 0x04, 0x00, 0x00])).unwrap();
+        assert_eq!(response, vec![0x90, 0x00]);
+    }
+}
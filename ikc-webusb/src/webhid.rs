@@ -0,0 +1,262 @@
+use crate::hid::{make_blocks, make_cancel_block, ConnectOptions, ResponseAcc, IMKEY_VENDOR_ID};
+use crate::webusb::sleep;
+use crate::Result;
+use async_trait::async_trait;
+use ikc_transport::connector::Connector;
+use ikc_transport::device::Device;
+use ikc_transport::error::TransportError;
+use ikc_transport::timeout::exchange_with_timeout;
+use ikc_transport::transport::Transport;
+use js_sys::{Array, Function, Promise, Uint8Array};
+use serde_wasm_bindgen::to_value;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{console, window};
+use web_sys::{Hid, HidConnectionEvent, HidDevice, HidDeviceRequestOptions, HidInputReportEvent};
+
+//the imKey reports carry no report id
+const REPORT_ID: u8 = 0;
+
+fn hid() -> Hid {
+    window()
+        .expect("window should be available")
+        .navigator()
+        .hid()
+}
+
+//any product of the vendor, the connector filters the product ids
+fn is_imkey(device: &HidDevice) -> bool {
+    device.vendor_id() == IMKEY_VENDOR_ID
+}
+
+/**
+whether the browser exposes navigator.hid
+*/
+pub fn is_supported() -> bool {
+    let navigator = window().expect("window should be available").navigator();
+    js_sys::Reflect::has(&navigator, &JsValue::from_str("hid")).unwrap_or(false)
+}
+
+/**
+ask the user to pair an imKey with the page through WebHID and open it
+*/
+pub async fn connect(options: &ConnectOptions) -> Result<Device> {
+    let filters = to_value(&options.filters()).expect("valid filters");
+    let request_options = HidDeviceRequestOptions::new(&filters);
+    let devices: Array = match JsFuture::from(hid().request_device(&request_options)).await {
+        Ok(devices) => devices.unchecked_into(),
+        Err(err) => {
+            console::log_1(&format!("Error requesting device: {:?}", err).into());
+            return Err(TransportError::DeviceNotConnected.into());
+        }
+    };
+    //empty when the user closed the chooser
+    let device: HidDevice = devices
+        .iter()
+        .next()
+        .ok_or(TransportError::DeviceNotConnected)?
+        .unchecked_into();
+    open(&device).await?;
+    Ok(Device::new(WebHidTransport::new(device)))
+}
+
+async fn open(device: &HidDevice) -> Result<()> {
+    if !device.opened() {
+        JsFuture::from(device.open())
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?;
+    }
+    Ok(())
+}
+
+/**
+Opens the imKey devices the user already paired with the page through WebHID.
+*/
+#[derive(Default)]
+pub struct WebHidConnector {
+    options: ConnectOptions,
+}
+
+impl WebHidConnector {
+    pub fn new(options: ConnectOptions) -> Self {
+        WebHidConnector { options }
+    }
+}
+
+#[async_trait(?Send)]
+impl Connector for WebHidConnector {
+    async fn open_devices(&self) -> Result<Vec<Device>> {
+        let paired: Array = JsFuture::from(hid().get_devices())
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?
+            .unchecked_into();
+        let mut devices = vec![];
+        for device in paired.iter() {
+            let device: HidDevice = device.unchecked_into();
+            if !self
+                .options
+                .matches(device.vendor_id(), device.product_id())
+            {
+                continue;
+            }
+            match open(&device).await {
+                Ok(()) => devices.push(Device::new(WebHidTransport::new(device))),
+                Err(err) => console::log_1(&format!("Error opening device: {:?}", err).into()),
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/**
+register the callbacks run when a paired imKey is plugged in or unplugged
+*/
+pub fn watch_connections<C, D>(on_connect: C, on_disconnect: D)
+where
+    C: Fn() + 'static,
+    D: Fn() + 'static,
+{
+    let hid = hid();
+    let on_connect =
+        Closure::<dyn Fn(HidConnectionEvent)>::new(move |event: HidConnectionEvent| {
+            if is_imkey(&event.device()) {
+                on_connect();
+            }
+        });
+    let on_disconnect =
+        Closure::<dyn Fn(HidConnectionEvent)>::new(move |event: HidConnectionEvent| {
+            if is_imkey(&event.device()) {
+                on_disconnect();
+            }
+        });
+    hid.set_onconnect(Some(on_connect.as_ref().unchecked_ref()));
+    hid.set_ondisconnect(Some(on_disconnect.as_ref().unchecked_ref()));
+    //the callbacks live as long as the page
+    on_connect.forget();
+    on_disconnect.forget();
+}
+
+/**
+input reports received and not read yet, with the resolver of the read waiting for the next one
+*/
+#[derive(Default)]
+struct InputReports {
+    queue: VecDeque<Vec<u8>>,
+    waiting: Option<Function>,
+}
+
+pub struct WebHidTransport {
+    device: HidDevice,
+    reports: Rc<RefCell<InputReports>>,
+    _on_input_report: Closure<dyn Fn(HidInputReportEvent)>,
+}
+
+impl WebHidTransport {
+    pub fn new(device: HidDevice) -> Self {
+        let reports = Rc::new(RefCell::new(InputReports::default()));
+        let receiver = reports.clone();
+        let on_input_report =
+            Closure::<dyn Fn(HidInputReportEvent)>::new(move |event: HidInputReportEvent| {
+                let data = event.data();
+                let report = Uint8Array::new_with_byte_offset_and_length(
+                    &data.buffer(),
+                    data.byte_offset() as u32,
+                    data.byte_length() as u32,
+                );
+                let mut reports = receiver.borrow_mut();
+                reports.queue.push_back(report.to_vec());
+                if let Some(resolve) = reports.waiting.take() {
+                    let _ = resolve.call0(&JsValue::NULL);
+                }
+            });
+        device.set_oninputreport(Some(on_input_report.as_ref().unchecked_ref()));
+        WebHidTransport {
+            device,
+            reports,
+            _on_input_report: on_input_report,
+        }
+    }
+
+    async fn write_report(&self, block: &[u8]) -> Result<()> {
+        let mut report = block.to_vec();
+        JsFuture::from(
+            self.device
+                .send_report_with_u8_array(REPORT_ID, &mut report),
+        )
+        .await
+        .map_err(|_| TransportError::UsbTransferFail)?;
+        Ok(())
+    }
+
+    async fn read_report(&self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(report) = self.reports.borrow_mut().queue.pop_front() {
+                return Ok(report);
+            }
+            let reports = self.reports.clone();
+            let next_report = Promise::new(&mut |resolve, _reject| {
+                reports.borrow_mut().waiting = Some(resolve);
+            });
+            JsFuture::from(next_report)
+                .await
+                .map_err(|_| TransportError::UsbTransferFail)?;
+        }
+    }
+
+    async fn send_and_receive(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        for block in make_blocks(apdu)?.iter() {
+            self.write_report(block).await?;
+        }
+
+        let mut response_acc = ResponseAcc::new();
+        loop {
+            let report = self.read_report().await?;
+            if let Some(response) = response_acc.reduce_response(&report)? {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+impl Drop for WebHidTransport {
+    fn drop(&mut self) {
+        self.device.set_oninputreport(None);
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for WebHidTransport {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        console::log_1(&format!("-->{:?}", hex::encode_upper(apdu)).into());
+        let response_data = self.send_and_receive(apdu).await?;
+        console::log_1(&format!("<--{:?}", hex::encode_upper(&response_data)).into());
+
+        Ok(response_data)
+    }
+
+    async fn send_apdu_with_timeout(&self, apdu: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        console::log_1(&format!("-->{:?}", hex::encode_upper(apdu)).into());
+        let response_data = exchange_with_timeout(
+            self.send_and_receive(apdu),
+            timeout,
+            sleep,
+            self.write_report(&make_cancel_block()),
+        )
+        .await?;
+        console::log_1(&format!("<--{:?}", hex::encode_upper(&response_data)).into());
+
+        Ok(response_data)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.device.opened()
+    }
+
+    async fn cancel(&self) -> Result<()> {
+        self.write_report(&make_cancel_block()).await
+    }
+}
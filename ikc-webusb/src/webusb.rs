@@ -1,303 +1,319 @@
 extern crate wasm_bindgen;
 extern crate web_sys;
+use crate::hid::{
+    find_endpoints, make_blocks, make_cancel_block, ConnectOptions, EndpointDescriptor,
+    EndpointPair, InterfaceDescriptor, ResponseAcc, IMKEY_VENDOR_ID, PACKET_SIZE,
+};
+use crate::Result;
+use async_trait::async_trait;
+use ikc_transport::connector::Connector;
+use ikc_transport::device::Device;
+use ikc_transport::error::TransportError;
+use ikc_transport::timeout::exchange_with_timeout;
+use ikc_transport::transport::Transport;
+use js_sys::{Array, DataView, Promise, Uint8Array};
+use serde_wasm_bindgen::to_value;
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
-use web_sys::window;
-use web_sys::{Navigator, Usb, UsbDevice, UsbDeviceRequestOptions, UsbConfiguration, UsbInterface, UsbInTransferResult};
-use bytes::{BufMut, BytesMut};
-use web_sys::console;
-use js_sys::{Uint8Array, DataView, Promise, ArrayBuffer, Array}; 
 use wasm_bindgen_futures::JsFuture;
-use parking_lot::Mutex;
-use serde_wasm_bindgen::to_value;
-use lazy_static::lazy_static;
-use crate::Result;
-
-const COMMAND_TYPE_MESSAGE: u8 = 0x43 | 0x80;
-const COMMAND_TYPE_CANCEL: u8 = 0x51 | 0x80;
-const COMMAND_TYPE_ERROR: u8 = 0x7F | 0x80;
-const COMMAND_TYPE_KEEPALIVE: u8 = 0x7B | 0x80;
-
-const ERR_INVALID_CMD: u8 = 0x01;
-const ERR_INVALID_PAR: u8 = 0x02;
-const ERR_INVALID_LEN: u8 = 0x03;
-const ERR_INVALID_SEQ: u8 = 0x04;
-
-const ERR_DEVICE_BUSY: u8 = 0x06;
-const ERR_DEVICE_CANCEL: u8 = 0xFE;
-const ERR_OTHER: u8 = 0x07;
-
-
+use web_sys::console;
+use web_sys::window;
+use web_sys::{
+    Usb, UsbAlternateInterface, UsbConnectionEvent, UsbDevice, UsbDeviceRequestOptions,
+    UsbDirection, UsbEndpoint, UsbEndpointType, UsbInTransferResult, UsbInterface,
+};
+
+fn usb() -> Usb {
+    window()
+        .expect("window should be available")
+        .navigator()
+        .usb()
+}
 
-lazy_static! {
-    pub static ref WEB_USB_DEVICE: Mutex<Option<UsbDeviceBox>> = Mutex::new(None);
+//any product of the vendor, the connector filters the product ids
+fn is_imkey(device: &UsbDevice) -> bool {
+    device.vendor_id() == IMKEY_VENDOR_ID
 }
-#[derive(Debug)]
-pub struct UsbDeviceBox(UsbDevice);
-unsafe impl Send for UsbDeviceBox {}
 
-#[cfg(target_arch = "wasm32")]
-// #[wasm_bindgen]
-pub async fn connect() -> Result<()> {
+/**
+whether the browser exposes navigator.usb
+*/
+pub fn is_supported() -> bool {
     let navigator = window().expect("window should be available").navigator();
-    let usb: Usb = navigator.usb();
+    js_sys::Reflect::has(&navigator, &JsValue::from_str("usb")).unwrap_or(false)
+}
 
-    // 手动构造过滤器对象
-    let filters = vec![serde_json::json!({
-        "vendorId": 0x096e,
-        "productId": 0x0891,
-    })];
+/**
+ask the user to pair an imKey with the page and open it
+*/
+pub async fn connect(options: &ConnectOptions) -> Result<Device> {
     // 创建 UsbDeviceRequestOptions
-    let options = UsbDeviceRequestOptions::new(&to_value(&vec![filters]).unwrap());
+    let options_filters = to_value(&options.filters()).expect("valid filters");
+    let request_options = UsbDeviceRequestOptions::new(&options_filters);
     // 请求设备
-    let promise = usb.request_device(&options);
-    // 处理请求设备的结果
-    let device = wasm_bindgen_futures::JsFuture::from(promise).await;
-    // 请求设备
-    match device {
-        Ok(device) => {
-            // 继续处理设备
-            let device: UsbDevice = device.unchecked_into();
-            web_sys::console::log_1(&format!("Device Name: {:?}", device.product_name()).into());
-            let open_promise = device.open();
-            JsFuture::from(open_promise).await.unwrap();
-
-            let configurations: Array = device.configurations();
-            let configuration: UsbConfiguration = configurations.get(0).unchecked_into();
-             // 获取接口数组
-             let interfaces: Array = configuration.interfaces();
-             // 获取第一个接口
-             let interface: UsbInterface = interfaces.get(0).unchecked_into();
-             let interface_number = interface.interface_number(); // 获取接口编号
-             // 选择配置，通常为默认配置 1
-            let select_configuration_promise = device.select_configuration(1);
-            JsFuture::from(select_configuration_promise).await.unwrap();
-             // 声明接口 
-            let claim_promise = device.claim_interface(interface_number);
-            wasm_bindgen_futures::JsFuture::from(claim_promise).await.unwrap();
-
-            let mut hid_device_obj = WEB_USB_DEVICE.lock();
-            *hid_device_obj = Some(UsbDeviceBox(device));
-            Ok(())
-        },
+    let device: UsbDevice = match JsFuture::from(usb().request_device(&request_options)).await {
+        Ok(device) => device.unchecked_into(),
         Err(err) => {
             console::log_1(&format!("Error requesting device: {:?}", err).into());
-            Ok(())
+            return Err(TransportError::DeviceNotConnected.into());
         }
-    }
+    };
+    web_sys::console::log_1(&format!("Device Name: {:?}", device.product_name()).into());
+    let endpoints = open(&device, options).await?;
+    Ok(Device::new(WebUsbTransport::new(device, endpoints)))
+}
 
+fn interface_descriptors(interface: &UsbInterface) -> Vec<InterfaceDescriptor> {
+    interface
+        .alternates()
+        .iter()
+        .map(|alternate| {
+            let alternate: UsbAlternateInterface = alternate.unchecked_into();
+            let endpoints = alternate
+                .endpoints()
+                .iter()
+                .map(|endpoint| {
+                    let endpoint: UsbEndpoint = endpoint.unchecked_into();
+                    EndpointDescriptor {
+                        number: endpoint.endpoint_number(),
+                        direction_in: endpoint.direction() == UsbDirection::In,
+                        interrupt: endpoint.type_() == UsbEndpointType::Interrupt,
+                    }
+                })
+                .collect();
+            InterfaceDescriptor {
+                number: interface.interface_number(),
+                alternate: alternate.alternate_setting(),
+                class: alternate.interface_class(),
+                endpoints,
+            }
+        })
+        .collect()
 }
 
-#[cfg(target_arch = "wasm32")]
-// #[wasm_bindgen]
-pub async fn send_apdu(apdu: String) -> Result<String> {
-    console::log_1(&format!("-->{:?}", apdu).into());
-    // 访问存储的 UsbDevice
-    let hid_device_obj = WEB_USB_DEVICE.lock();
-    let mut response_data = "".to_string();
-    if let Some(device) = &*hid_device_obj {
-        // 这里可以使用 device 进行操作
-        // console::log_1(&format!("Using device: {:?}", device.0.product_name()).into());
-        response_data = send_and_receive(&device.0, hex::decode(apdu).unwrap().as_slice()).await;
-    } else {
-        console::log_1(&"No device found".into());
+/**
+open the device and claim the interface carrying the HID reports, nothing is done when it is
+already claimed
+*/
+async fn open(device: &UsbDevice, options: &ConnectOptions) -> Result<EndpointPair> {
+    if !device.opened() {
+        JsFuture::from(device.open())
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?;
     }
-    console::log_1(&format!("<--{:?}", response_data.clone()).into());
 
-    Ok(response_data.to_uppercase())
+    let selected = device
+        .configuration()
+        .map(|configuration| configuration.configuration_value());
+    if selected != Some(options.configuration) {
+        JsFuture::from(device.select_configuration(options.configuration))
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?;
+    }
+    let configuration = device
+        .configuration()
+        .ok_or(TransportError::DeviceNotConnected)?;
+    let interfaces: Vec<UsbInterface> = configuration
+        .interfaces()
+        .iter()
+        .map(|interface| interface.unchecked_into())
+        .collect();
+    let descriptors: Vec<InterfaceDescriptor> =
+        interfaces.iter().flat_map(interface_descriptors).collect();
+    let endpoints = find_endpoints(&descriptors, options.interface_class)?;
+
+    let interface = interfaces
+        .iter()
+        .find(|interface| interface.interface_number() == endpoints.interface)
+        .ok_or(TransportError::UsbInterfaceNotFound)?;
+    if !interface.claimed() {
+        // 声明接口
+        JsFuture::from(device.claim_interface(endpoints.interface))
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?;
+    }
+    if interface.alternate().alternate_setting() != endpoints.alternate {
+        JsFuture::from(device.select_alternate_interface(endpoints.interface, endpoints.alternate))
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?;
+    }
+    Ok(endpoints)
 }
 
-
-fn as_u16_be(value: usize) -> BytesMut {
-    let mut b = BytesMut::with_capacity(2);
-    b.put_u16(value as u16);
-    b
+/**
+Opens the imKey devices the user already paired with the page, without a permission prompt.
+*/
+#[derive(Default)]
+pub struct WebUsbConnector {
+    options: ConnectOptions,
 }
 
-fn make_blocks(apdu: &[u8]) -> Vec<BytesMut> {
-    let mut data = BytesMut::with_capacity(2 + apdu.len());//指令长度（2） + 指令原值
-    data.put(as_u16_be(apdu.len()));
-    data.put(apdu);
-    let packet_size = 64;
-    let block_size = packet_size - 5;
-    let nb_blocks = (data.len() + block_size - 1) / block_size;
-    let mut blocks: Vec<BytesMut> = Vec::with_capacity(nb_blocks);
-    let mut data_index = 0;
-
-    for i in 0..nb_blocks {
-        let mut head = BytesMut::with_capacity(5);
+impl WebUsbConnector {
+    pub fn new(options: ConnectOptions) -> Self {
+        WebUsbConnector { options }
+    }
+}
 
-        if i == 0 {
-            if apdu.len() == 2 && apdu == b"\x00\x00" {
-                head.put_slice(&[0x00, 0x00, 0x00, 0x00]);
-                head.put_u8(COMMAND_TYPE_CANCEL);
-                head.resize(64, 0);
-                blocks.push(head);
-                return blocks;
+#[async_trait(?Send)]
+impl Connector for WebUsbConnector {
+    async fn open_devices(&self) -> Result<Vec<Device>> {
+        let paired: Array = JsFuture::from(usb().get_devices())
+            .await
+            .map_err(|_| TransportError::DeviceNotConnected)?
+            .unchecked_into();
+        let mut devices = vec![];
+        for device in paired.iter() {
+            let device: UsbDevice = device.unchecked_into();
+            if !self
+                .options
+                .matches(device.vendor_id(), device.product_id())
+            {
+                continue;
+            }
+            match open(&device, &self.options).await {
+                Ok(endpoints) => devices.push(Device::new(WebUsbTransport::new(device, endpoints))),
+                Err(err) => console::log_1(&format!("Error opening device: {:?}", err).into()),
             }
-            head.put_slice(&[0x00, 0x00, 0x00, 0x00]);
-            head.put_u8(COMMAND_TYPE_MESSAGE);
-            let chunk = &data[data_index..std::cmp::min(data.len(), data_index + block_size)];
-            data_index += block_size;
-
-            let mut block = BytesMut::with_capacity(64);
-            block.put(head);
-            block.put(chunk);
-            block.resize(64, 0);
-            blocks.push(block);
-        } else {
-            head.put_slice(&[0x00, 0x00, 0x00, 0x00]);
-            head.put_u8((i - 1) as u8);
-            let chunk = &data[data_index..std::cmp::min(data.len(), data_index + block_size)];
-            data_index += block_size;
-
-            let mut block = BytesMut::with_capacity(64);
-            block.put(head);
-            block.put(chunk);
-            block.resize(64, 0);
-            blocks.push(block);
-        }
-
-        if data_index >= data.len() {
-            break;
         }
+        Ok(devices)
     }
-
-    blocks
 }
 
+/**
+register the callbacks run when a paired imKey is plugged in or unplugged
+*/
+pub fn watch_connections<C, D>(on_connect: C, on_disconnect: D)
+where
+    C: Fn() + 'static,
+    D: Fn() + 'static,
+{
+    let usb = usb();
+    let on_connect =
+        Closure::<dyn Fn(UsbConnectionEvent)>::new(move |event: UsbConnectionEvent| {
+            if is_imkey(&event.device()) {
+                on_connect();
+            }
+        });
+    let on_disconnect =
+        Closure::<dyn Fn(UsbConnectionEvent)>::new(move |event: UsbConnectionEvent| {
+            if is_imkey(&event.device()) {
+                on_disconnect();
+            }
+        });
+    usb.set_onconnect(Some(on_connect.as_ref().unchecked_ref()));
+    usb.set_ondisconnect(Some(on_disconnect.as_ref().unchecked_ref()));
+    //the callbacks live as long as the page
+    on_connect.forget();
+    on_disconnect.forget();
+}
 
-#[derive(Default)]
-struct ResponseAcc {
-    data: Vec<u8>,
-    data_length: usize,
-    sequence: usize,
+pub struct WebUsbTransport {
+    device: UsbDevice,
+    endpoints: EndpointPair,
 }
 
-impl ResponseAcc {
-    fn new() -> Self {
-        ResponseAcc {
-            data: Vec::new(),
-            data_length: 0,
-            sequence: 0,
-        }
+impl WebUsbTransport {
+    pub fn new(device: UsbDevice, endpoints: EndpointPair) -> Self {
+        WebUsbTransport { device, endpoints }
     }
+}
 
-    fn reduce_response(&mut self, chunk: &[u8]) {
-        // if chunk[4] == COMMAND_TYPE_KEEPALIVE {
-        //     return Ok(());
-        // }
-    
-        // if chunk[4] == COMMAND_TYPE_ERROR {
-        //     match chunk[7] {
-        //         ERR_INVALID_CMD => return Err("Invalid command".into()),
-        //         ERR_INVALID_PAR => return Err("Invalid parameter".into()),
-        //         ERR_INVALID_LEN => return Err("Invalid length".into()),
-        //         ERR_INVALID_SEQ => return Err("Invalid sequence".into()),
-        //         ERR_DEVICE_BUSY => return Err("Device busy".into()),
-        //         ERR_DEVICE_CANCEL => return Err("Device cancelled".into()),
-        //         ERR_OTHER => {
-        //             println!("apdu1 <= {}", hex::encode_upper(chunk));
-        //             return Ok(());
-        //         },
-        //         _ => {}
-        //     }
-        // }
-    
-        if chunk[4] == COMMAND_TYPE_MESSAGE {
-            if self.data_length == 0 {
-                self.data_length = ((chunk[5] as usize) << 8) | (chunk[6] as usize);
-            }
-            self.sequence += 1;
-        }
-    
-        let chunk_data = if self.data.is_empty() { &chunk[7..] } else { &chunk[5..] };
-        self.data.extend_from_slice(chunk_data);
-        
-        if self.data.len() > self.data_length {
-            self.data.truncate(self.data_length);
-        }
-        
+#[async_trait(?Send)]
+impl Transport for WebUsbTransport {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>> {
+        console::log_1(&format!("-->{:?}", hex::encode_upper(apdu)).into());
+        let response_data = send_and_receive(&self.device, &self.endpoints, apdu).await?;
+        console::log_1(&format!("<--{:?}", hex::encode_upper(&response_data)).into());
+
+        Ok(response_data)
     }
-    
-    fn get_reduced_result(&self) -> Option<Vec<u8>> {
-        if self.data.len() == self.data_length {
-            Some(self.data.clone())
-        } else {
-            None
-        }
+
+    async fn send_apdu_with_timeout(&self, apdu: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        console::log_1(&format!("-->{:?}", hex::encode_upper(apdu)).into());
+        let response_data = exchange_with_timeout(
+            send_and_receive(&self.device, &self.endpoints, apdu),
+            timeout,
+            sleep,
+            write_block(&self.device, &self.endpoints, &make_cancel_block()),
+        )
+        .await?;
+        console::log_1(&format!("<--{:?}", hex::encode_upper(&response_data)).into());
+
+        Ok(response_data)
     }
-}
 
+    fn is_connected(&self) -> bool {
+        self.device.opened()
+    }
 
-pub async fn send_and_receive(device: &UsbDevice, apdu: &[u8]) -> String{
-    //send
-    let send_list = make_blocks(apdu);
-    for val in send_list.iter() {
-        // console::log_1(&format!("write chunk--> {:?}", hex::encode(val.clone())).into());
-        let uint8_array = Uint8Array::new_with_length(val.len() as u32);
-        uint8_array.copy_from(val.as_ref());
-        device.transfer_out_with_buffer_source(4u8, &uint8_array);
+    async fn cancel(&self) -> Result<()> {
+        write_block(&self.device, &self.endpoints, &make_cancel_block()).await
     }
+}
 
-    //revice
-    let mut response_acc = ResponseAcc::new();
-    let packet_size = 64;
-    let mut buffer: Vec<u8> = vec![];
-    while true {
-        let transfer_promise: Promise = device.transfer_in(5u8, packet_size);
+pub(crate) async fn sleep(duration: Duration) {
+    let promise = Promise::new(&mut |resolve, _reject| {
+        window()
+            .expect("window should be available")
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                &resolve,
+                duration.as_millis() as i32,
+            )
+            .expect("set_timeout should be available");
+    });
+    let _ = JsFuture::from(promise).await;
+}
 
-        // 使用 JsFuture::from 将 Promise 转换为 Future，并等待其完成
-        let transfer_result: JsValue = JsFuture::from(transfer_promise).await.unwrap(); 
-        // // 将结果转换为 USBInTransferResult
-        let transfer_result: UsbInTransferResult = transfer_result.dyn_into::<UsbInTransferResult>().unwrap();
-        // 获取数据和状态
-        let data_view: DataView = transfer_result.data().unwrap().dyn_into::<DataView>().unwrap();
-        // 将 DataView 转换为 JsValue 
-        let array_buffer: ArrayBuffer = data_view.buffer(); 
+async fn write_block(device: &UsbDevice, endpoints: &EndpointPair, block: &[u8]) -> Result<()> {
+    let uint8_array = Uint8Array::new_with_length(block.len() as u32);
+    uint8_array.copy_from(block);
+    let transfer_promise =
+        device.transfer_out_with_buffer_source(endpoints.endpoint_out, &uint8_array);
+    JsFuture::from(transfer_promise)
+        .await
+        .map_err(|_| TransportError::UsbTransferFail)?;
+    Ok(())
+}
 
-        let byte_array: Uint8Array = Uint8Array::new(&array_buffer);
-        buffer = byte_array.to_vec();
-        if buffer[4] == 0xff {
-            continue;
-        }
-        // response_acc.data_length = u16::from_le_bytes([buffer[5], buffer[6]]) as usize;
-        let data_length: [u8; 2] = [buffer[5], buffer[6]];
-        response_acc.data_length = u16::from_be_bytes(data_length) as usize;
-        response_acc.data = buffer.as_slice()[7..].to_vec();
-        let first_chunk_data_length = std::cmp::min(response_acc.data_length, response_acc.data.len());
-        response_acc.data = buffer.as_slice()[7..(7 + first_chunk_data_length)].to_vec();
-        break;
-    }
+async fn read_block(device: &UsbDevice, endpoints: &EndpointPair) -> Result<Vec<u8>> {
+    let transfer_promise: Promise = device.transfer_in(endpoints.endpoint_in, PACKET_SIZE as u32);
+    // 使用 JsFuture::from 将 Promise 转换为 Future，并等待其完成
+    let transfer_result: UsbInTransferResult = JsFuture::from(transfer_promise)
+        .await
+        .and_then(|result| result.dyn_into::<UsbInTransferResult>())
+        .map_err(|_| TransportError::UsbTransferFail)?;
+    // 获取数据
+    let data_view: DataView = transfer_result
+        .data()
+        .ok_or(TransportError::UsbTransferFail)?;
+    let byte_array = Uint8Array::new_with_byte_offset_and_length(
+        &data_view.buffer(),
+        data_view.byte_offset() as u32,
+        data_view.byte_length() as u32,
+    );
+    Ok(byte_array.to_vec())
+}
 
-    // let status = transfer_result.status();
-    while true {
-        let result = response_acc.get_reduced_result();
-        if result.is_some(){
-            // console::log_1(&format!("read end--> {:?}", hex::encode(&response_acc.data)).into());
-            break;
-        }
+pub async fn send_and_receive(
+    device: &UsbDevice,
+    endpoints: &EndpointPair,
+    apdu: &[u8],
+) -> Result<Vec<u8>> {
+    //send
+    for block in make_blocks(apdu)?.iter() {
+        write_block(device, endpoints, block).await?;
+    }
 
-        let transfer_promise: Promise = device.transfer_in(5u8, packet_size);
-        // 使用 JsFuture::from 将 Promise 转换为 Future，并等待其完成
-        let transfer_result: JsValue = JsFuture::from(transfer_promise).await.unwrap(); 
-        // // 将结果转换为 USBInTransferResult
-        let transfer_result: UsbInTransferResult = transfer_result.dyn_into::<UsbInTransferResult>().unwrap();
-        // 获取数据和状态
-        let data_view: DataView = transfer_result.data().unwrap().dyn_into::<DataView>().unwrap();
-        // 将 DataView 转换为 JsValue 
-        let array_buffer: ArrayBuffer = data_view.buffer(); 
-        let byte_array: Uint8Array = Uint8Array::new(&array_buffer);
-        buffer = byte_array.to_vec();
-        let a = buffer[5..].to_vec();
-        response_acc.data.extend(buffer[5..].iter());
-        if response_acc.data.len() > response_acc.data_length {
-            response_acc.data = response_acc.data[..response_acc.data_length].to_vec();
+    //receive, keepalive reports are skipped until the whole response arrived
+    let mut response_acc = ResponseAcc::new();
+    loop {
+        let block = read_block(device, endpoints).await?;
+        if let Some(response) = response_acc.reduce_response(&block)? {
+            return Ok(response);
         }
     }
-
-    hex::encode(response_acc.data)
 }
 
-pub fn test1(){
+pub fn test1() {
     println!("function test");
-}
\ No newline at end of file
+}
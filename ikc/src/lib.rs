@@ -1,46 +1,578 @@
-use std::path;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[cfg(target_arch = "wasm32")]
+mod storage;
 
 use wasm_bindgen::prelude::*;
 
 #[cfg(target_arch = "wasm32")]
-use ikc_webusb::webusb::{connect, send_apdu};
-use ikc_device::device_manager;
+use crate::storage::LocalStorageKeyStore;
 use coin_bitcoin::address::BtcAddress;
+use ikc_common::constants;
 use ikc_common::utility::network_convert;
+use ikc_device::app_manager::AppManager;
+use ikc_device::binding_session::{BindingSession, BindingState};
+use ikc_device::connection_manager::{ConnectionEvent, ConnectionManager};
+use ikc_device::cos_upgrade::{CosUpgrade, UpgradeProgress};
+use ikc_device::device_manager;
+use ikc_device::error::BindError;
+use ikc_device::key_manager::KeyFileSecret;
+use ikc_device::key_store::{KeyStore, MemoryKeyStore};
+use ikc_device::tsm::TsmClient;
+use ikc_transport::device::Device;
+use ikc_transport::error::TransportError;
+use ikc_transport::trace::TraceRecorder;
+#[cfg(target_arch = "wasm32")]
+use ikc_webusb::fetch::FetchClient;
+#[cfg(target_arch = "wasm32")]
+use ikc_webusb::hid::ConnectOptions;
+#[cfg(target_arch = "wasm32")]
+use ikc_webusb::webhid::{self, WebHidConnector};
+#[cfg(target_arch = "wasm32")]
+use ikc_webusb::webusb::{self, WebUsbConnector};
+
+thread_local! {
+    static CONNECTION_MANAGER: RefCell<Option<Rc<ConnectionManager>>> = RefCell::new(None);
+    static TRACE_RECORDER: RefCell<Option<TraceRecorder>> = RefCell::new(None);
+    static TSM_ENDPOINT: RefCell<String> = RefCell::new(constants::URL.to_string());
+    static KEY_STORE: RefCell<Option<Rc<dyn KeyStore>>> = RefCell::new(None);
+    static KEY_FILE_SECRET: RefCell<Option<KeyFileSecret>> = RefCell::new(None);
+    static BINDING_SESSION: RefCell<Option<Rc<BindingSession>>> = RefCell::new(None);
+}
+
+fn to_js_error(error: anyhow::Error) -> JsValue {
+    JsValue::from_str(&error.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn connection_manager() -> Result<Rc<ConnectionManager>, JsValue> {
+    Ok(CONNECTION_MANAGER.with(|cell| {
+        cell.borrow_mut()
+            .get_or_insert_with(|| {
+                let manager = Rc::new(if webusb::is_supported() {
+                    ConnectionManager::new(WebUsbConnector::default())
+                } else {
+                    ConnectionManager::new(WebHidConnector::default())
+                });
+                if webusb::is_supported() {
+                    let (on_connect, on_disconnect) = connection_callbacks(&manager);
+                    webusb::watch_connections(on_connect, on_disconnect);
+                }
+                if webhid::is_supported() {
+                    let (on_connect, on_disconnect) = connection_callbacks(&manager);
+                    webhid::watch_connections(on_connect, on_disconnect);
+                }
+                manager
+            })
+            .clone()
+    }))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn connection_callbacks(manager: &Rc<ConnectionManager>) -> (impl Fn(), impl Fn()) {
+    let on_connect = manager.clone();
+    let on_disconnect = manager.clone();
+    (
+        move || {
+            let manager = on_connect.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = manager.refresh().await;
+            });
+        },
+        move || on_disconnect.check_connections(),
+    )
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connection_manager() -> Result<Rc<ConnectionManager>, JsValue> {
+    CONNECTION_MANAGER
+        .with(|cell| cell.borrow().clone())
+        .ok_or_else(|| JsValue::from_str("imkey_device_not_connected"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn tsm_client() -> Result<TsmClient, JsValue> {
+    let endpoint = TSM_ENDPOINT.with(|cell| cell.borrow().clone());
+    Ok(TsmClient::with_endpoint(
+        &endpoint,
+        Rc::new(FetchClient::new()),
+    ))
+}
+
+//no HTTP client outside the browser, the TSM calls fail with imkey_network_fail
+#[cfg(not(target_arch = "wasm32"))]
+fn tsm_client() -> Result<TsmClient, JsValue> {
+    Err(to_js_error(TransportError::NetworkFail.into()))
+}
+
+//the localStorage of the page unless the application picked another store
+#[cfg(target_arch = "wasm32")]
+fn default_key_store() -> Rc<dyn KeyStore> {
+    Rc::new(LocalStorageKeyStore::new(KEY_STORE_PREFIX))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_key_store() -> Rc<dyn KeyStore> {
+    Rc::new(MemoryKeyStore::new())
+}
+
+fn key_store() -> Rc<dyn KeyStore> {
+    KEY_STORE.with(|cell| {
+        cell.borrow_mut()
+            .get_or_insert_with(default_key_store)
+            .clone()
+    })
+}
+
+async fn current_device() -> Result<Rc<Device>, JsValue> {
+    connection_manager()?.current().await.map_err(to_js_error)
+}
+
+/**
+pair an imKey through "webusb" (the default) or "webhid", the latter for systems where the
+HID driver keeps the interface from being claimed through WebUSB.
+options: {productIds, interfaceClass, configuration}, all optional
+*/
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub async fn connect_imkey(transport: Option<String>, options: JsValue) -> Result<(), JsValue> {
+    let options: ConnectOptions = if options.is_undefined() || options.is_null() {
+        ConnectOptions::default()
+    } else {
+        serde_wasm_bindgen::from_value(options)
+            .map_err(|_| JsValue::from_str("imkey_invalid_connect_options"))?
+    };
+    let manager = connection_manager()?;
+    manager.check_not_upgrading().map_err(to_js_error)?;
+    let device = match transport.as_deref().unwrap_or("webusb") {
+        "webusb" if webusb::is_supported() => {
+            let device = webusb::connect(&options).await.map_err(to_js_error)?;
+            manager.set_connector(WebUsbConnector::new(options));
+            device
+        }
+        "webhid" if webhid::is_supported() => {
+            let device = webhid::connect(&options).await.map_err(to_js_error)?;
+            manager.set_connector(WebHidConnector::new(options));
+            device
+        }
+        _ => return Err(JsValue::from_str("imkey_transport_not_supported")),
+    };
+    let sn = device_manager::get_sn(&device).await.map_err(to_js_error)?;
+    //the device is opened again by the connection manager
+    drop(device);
+    manager.select(&sn).await.map_err(to_js_error)?;
+    Ok(())
+}
+
+/**
+serial numbers of the paired imKey devices currently plugged in
+*/
+#[wasm_bindgen]
+pub async fn get_devices() -> Result<Vec<String>, JsValue> {
+    connection_manager()?.refresh().await.map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn select_device(sn: String) -> Result<(), JsValue> {
+    connection_manager()?
+        .select(&sn)
+        .await
+        .map_err(to_js_error)?;
+    Ok(())
+}
+
+/**
+callback(event, sn) is called with "connect" or "disconnect" when a device comes or goes
+*/
+#[wasm_bindgen]
+pub fn on_device_event(callback: js_sys::Function) -> Result<(), JsValue> {
+    connection_manager()?.add_listener(move |event| {
+        let (name, sn) = match event {
+            ConnectionEvent::Connected(sn) => ("connect", sn),
+            ConnectionEvent::Disconnected(sn) => ("disconnect", sn),
+        };
+        let _ = callback.call2(&JsValue::NULL, &name.into(), &sn.into());
+    });
+    Ok(())
+}
 
 #[wasm_bindgen]
-pub async fn connect_imkey() {
-    connect().await;
+pub async fn send_command(apdu: &str) -> Result<String, JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let apdu =
+        hex::decode(apdu).map_err(|_| to_js_error(TransportError::ApduFormatError.into()))?;
+    let response = device.send_apdu(&apdu).await.map_err(to_js_error)?;
+    Ok(hex::encode_upper(response))
 }
 
 #[wasm_bindgen]
-pub async fn send_command(apdu: &str) ->String {
-    send_apdu(apdu.to_string()).await.unwrap()
+pub async fn cancel_command() -> Result<(), JsValue> {
+    //sent while the session of the operation to cancel is held
+    current_device().await?.cancel().await.map_err(to_js_error)
 }
 
+/**
+record the APDUs exchanged with the selected device until stop_trace is called
+*/
 #[wasm_bindgen]
-pub async fn bind_check(file_path: String) -> String{
-    device_manager::bind_check(&file_path).await.unwrap()
+pub async fn start_trace() -> Result<(), JsValue> {
+    let device = current_device().await?;
+    let recorder = TraceRecorder::new();
+    device.set_recorder(Some(recorder.clone()));
+    TRACE_RECORDER.with(|cell| *cell.borrow_mut() = Some(recorder));
+    Ok(())
 }
 
+/**
+stop recording and return the JSON trace, to be replayed with ReplayTransport in tests
+*/
 #[wasm_bindgen]
-pub async fn bind_acquire(file_path: String) -> String{
-    device_manager::bind_acquire(&file_path).await.unwrap()
+pub async fn stop_trace() -> Result<String, JsValue> {
+    let recorder = TRACE_RECORDER
+        .with(|cell| cell.borrow_mut().take())
+        .ok_or_else(|| JsValue::from_str("imkey_trace_not_started"))?;
+    if let Ok(device) = current_device().await {
+        device.set_recorder(None);
+    }
+    recorder.trace().to_json().map_err(to_js_error)
 }
 
+#[cfg(target_arch = "wasm32")]
+const KEY_STORE_PREFIX: &str = "imkey";
+
+/**
+keep the binding keys in the localStorage of the page, under names starting with the prefix
+("imkey" by default). This is the default store.
+*/
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn use_browser_key_store(prefix: Option<String>) {
+    let prefix = prefix.unwrap_or_else(|| KEY_STORE_PREFIX.to_string());
+    KEY_STORE.with(|cell| *cell.borrow_mut() = Some(Rc::new(LocalStorageKeyStore::new(&prefix))));
+}
+
+/**
+keep the binding keys in memory only, the device has to be bound again after a page reload
+*/
+#[wasm_bindgen]
+pub fn use_memory_key_store() {
+    KEY_STORE.with(|cell| *cell.borrow_mut() = Some(Rc::new(MemoryKeyStore::new())));
+}
+
+/**
+encrypt the key files with a random secret the application keeps apart from them, for instance
+a non extractable key of its own storage. bind_check needs this secret or a password.
+*/
 #[wasm_bindgen]
-pub async fn bind_display_code(){
-    device_manager::bind_display_code().await.expect("display_bind_code_error");
+pub fn set_host_secret(secret: Vec<u8>) {
+    KEY_FILE_SECRET.with(|cell| *cell.borrow_mut() = Some(KeyFileSecret::HostSecret(secret)));
 }
 
+/**
+encrypt the key files with a password of the user, asked for before bind_check
+*/
 #[wasm_bindgen]
-pub async fn get_address(seg_wit: String, network: String, path: String) -> String{
+pub fn set_key_file_password(password: String) {
+    KEY_FILE_SECRET.with(|cell| *cell.borrow_mut() = Some(KeyFileSecret::Password(password)));
+}
+
+/**
+state of the binding of this host to the device. Failed: the typed code was wrong,
+bind_remaining_attempts tells how many codes can still be tried.
+*/
+#[wasm_bindgen(js_name = BindingState)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsBindingState {
+    Unbound,
+    BoundOther,
+    AwaitingCode,
+    Bound,
+    Failed,
+}
+
+impl From<BindingState> for JsBindingState {
+    fn from(state: BindingState) -> Self {
+        match state {
+            BindingState::Unbound => JsBindingState::Unbound,
+            BindingState::BoundOther => JsBindingState::BoundOther,
+            BindingState::AwaitingCode => JsBindingState::AwaitingCode,
+            BindingState::Bound => JsBindingState::Bound,
+            BindingState::Failed { .. } => JsBindingState::Failed,
+        }
+    }
+}
+
+//the session of the last bind_check, the other binding steps need one and fail with
+//imkey_bind_device_changed when another device is selected since
+fn binding_session() -> Result<Rc<BindingSession>, JsValue> {
+    BINDING_SESSION
+        .with(|cell| cell.borrow().clone())
+        .ok_or_else(|| to_js_error(BindError::ImkeyBindStepOutOfOrder.into()))
+}
+
+/**
+first step of the binding, starting a new binding session.
+Key files written before the v2 format are read and rewritten in it.
+Fails with imkey_keyfile_secret_missing until set_host_secret or set_key_file_password was
+called, with imkey_keyfile_decrypt_fail when the key file is for another secret.
+*/
+#[wasm_bindgen]
+pub async fn bind_check() -> Result<JsBindingState, JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let secret = KEY_FILE_SECRET
+        .with(|cell| cell.borrow().clone())
+        .ok_or_else(|| to_js_error(BindError::ImkeyKeyfileSecretMissing.into()))?;
+    let binding = BindingSession::check(&device, key_store(), secret)
+        .await
+        .map_err(to_js_error)?;
+    let state = binding.state();
+    BINDING_SESSION.with(|cell| *cell.borrow_mut() = Some(Rc::new(binding)));
+    Ok(state.into())
+}
+
+/**
+show a new binding code on the device, unless it is already bound to this host
+*/
+#[wasm_bindgen]
+pub async fn bind_display_code() -> Result<JsBindingState, JsValue> {
+    let binding = binding_session()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let state = binding.display_code(&device).await.map_err(to_js_error)?;
+    Ok(state.into())
+}
+
+/**
+bind with the code the user typed: Bound, or Failed when the code is wrong
+*/
+#[wasm_bindgen]
+pub async fn bind_acquire(bind_code: String) -> Result<JsBindingState, JsValue> {
+    let binding = binding_session()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let state = binding
+        .acquire(&device, &bind_code)
+        .await
+        .map_err(to_js_error)?;
+    Ok(state.into())
+}
+
+#[wasm_bindgen]
+pub fn bind_state() -> Result<JsBindingState, JsValue> {
+    Ok(binding_session()?.state().into())
+}
+
+/**
+codes that can still be typed before bind_display_code has to show a new one
+*/
+#[wasm_bindgen]
+pub fn bind_remaining_attempts() -> Result<u8, JsValue> {
+    Ok(binding_session()?.remaining_attempts())
+}
+
+/**
+forget the binding keys of this host and its key file, the device stays bound to them until
+another binding: bind_check then tells BoundOther
+*/
+#[wasm_bindgen]
+pub async fn unbind() -> Result<JsBindingState, JsValue> {
+    let binding = binding_session()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let state = binding.unbind(&device).await.map_err(to_js_error)?;
+    Ok(state.into())
+}
+
+/**
+bind again with new keys: unbind then show a new binding code
+*/
+#[wasm_bindgen]
+pub async fn rebind() -> Result<JsBindingState, JsValue> {
+    let binding = binding_session()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let state = binding.rebind(&device).await.map_err(to_js_error)?;
+    Ok(state.into())
+}
+
+#[wasm_bindgen]
+pub async fn get_device_info() -> Result<JsValue, JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let device_info = device_manager::get_device_info(&device)
+        .await
+        .map_err(to_js_error)?;
+    serde_wasm_bindgen::to_value(&device_info).map_err(|error| error.into())
+}
+
+#[wasm_bindgen]
+pub async fn get_ble_name() -> Result<String, JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    device_manager::get_ble_name(&device)
+        .await
+        .map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn set_ble_name(ble_name: String) -> Result<(), JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    device_manager::set_ble_name(&device, &ble_name)
+        .await
+        .map_err(to_js_error)
+}
+
+/**
+encrypt and MAC the commands of the IMK and coin applets (addresses, signing) with the binding
+session key, until close_secure_channel or a call selecting another applet (device info, TSM)
+*/
+#[wasm_bindgen]
+pub async fn open_secure_channel() -> Result<(), JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    device_manager::open_secure_channel(&device)
+        .await
+        .map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn close_secure_channel() -> Result<(), JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    device_manager::close_secure_channel(&device);
+    Ok(())
+}
+
+/**
+base url of the TSM server, the imKey production server by default. The TSM calls
+(check_device, activate_device, cos_check_update, cos_upgrade and the applet calls) are posted
+with the fetch API of the page, they are only available in the browser build.
+*/
+#[wasm_bindgen]
+pub fn set_tsm_endpoint(endpoint: String) {
+    TSM_ENDPOINT.with(|cell| *cell.borrow_mut() = endpoint);
+}
+
+/**
+SE secure check with the TSM server: "latest", "inactivated" (call activate_device),
+"stop_using" or "illegal"
+*/
+#[wasm_bindgen]
+pub async fn check_device() -> Result<String, JsValue> {
+    let tsm = tsm_client()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let status = device_manager::check_device(&device, &tsm)
+        .await
+        .map_err(to_js_error)?;
+    Ok(status.as_str().to_string())
+}
+
+#[wasm_bindgen]
+pub async fn activate_device() -> Result<(), JsValue> {
+    let tsm = tsm_client()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    device_manager::activate_device(&device, &tsm)
+        .await
+        .map_err(to_js_error)
+}
+
+/**
+{seid, isLatest, latestCosVersion, updateType, description} of the COS of the device
+*/
+#[wasm_bindgen]
+pub async fn cos_check_update() -> Result<JsValue, JsValue> {
+    let tsm = tsm_client()?;
+    let manager = connection_manager()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    let update = CosUpgrade::new(&tsm, &*manager, |_: &UpgradeProgress| {})
+        .check_update(&device)
+        .await
+        .map_err(to_js_error)?;
+    serde_wasm_bindgen::to_value(&update).map_err(|error| error.into())
+}
+
+/**
+upgrade the COS to the latest version, on_progress is called with {stage, percent}.
+Call it again to finish an upgrade interrupted by a lost connection.
+The other calls fail with imkey_device_busy until the upgrade ends.
+*/
+#[wasm_bindgen]
+pub async fn cos_upgrade(on_progress: Option<js_sys::Function>) -> Result<(), JsValue> {
+    let tsm = tsm_client()?;
+    let manager = connection_manager()?;
+    let device = current_device().await?;
+    let _upgrading = manager.start_upgrade().map_err(to_js_error)?;
+    let progress = |progress: &UpgradeProgress| {
+        if let (Some(on_progress), Ok(progress)) =
+            (&on_progress, serde_wasm_bindgen::to_value(progress))
+        {
+            let _ = on_progress.call1(&JsValue::NULL, &progress);
+        }
+    };
+    let upgrade = CosUpgrade::new(&tsm, &*manager, progress);
+    //holds the session of the device, taken again on the new handle when it re-enumerates
+    upgrade.upgrade(device).await.map_err(to_js_error)?;
+    Ok(())
+}
+
+/**
+install the applet (AID in hex, e.g. 695F657468 for ETH) through the TSM server
+*/
+#[wasm_bindgen]
+pub async fn download_applet(aid: String) -> Result<(), JsValue> {
+    let tsm = tsm_client()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    AppManager::new(&device, &tsm)
+        .download(&aid)
+        .await
+        .map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn update_applet(aid: String) -> Result<(), JsValue> {
+    let tsm = tsm_client()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    AppManager::new(&device, &tsm)
+        .update(&aid)
+        .await
+        .map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn delete_applet(aid: String) -> Result<(), JsValue> {
+    let tsm = tsm_client()?;
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
+    AppManager::new(&device, &tsm)
+        .delete(&aid)
+        .await
+        .map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub async fn get_address(
+    seg_wit: String,
+    network: String,
+    path: String,
+) -> Result<String, JsValue> {
+    let device = current_device().await?;
+    let _session = device.try_session().map_err(to_js_error)?;
     let network = network_convert(&network);
+    let path = format!("{}/0/0", path);
     let main_address = match seg_wit.as_str() {
-        "P2WPKH" => BtcAddress::p2shwpkh(network, format!("{}/0/0", path).as_str()).await.unwrap(),
-        "VERSION_0" => BtcAddress::p2wpkh(network, format!("{}/0/0", path).as_str()).await.unwrap(),
-        "VERSION_1" => BtcAddress::p2tr(network, format!("{}/0/0", path).as_str()).await.unwrap(),
-        _ => BtcAddress::p2pkh(network, format!("{}/0/0", path).as_str()).await.unwrap(),
+        "P2WPKH" => BtcAddress::p2shwpkh(&device, network, &path).await,
+        "VERSION_0" => BtcAddress::p2wpkh(&device, network, &path).await,
+        "VERSION_1" => BtcAddress::p2tr(&device, network, &path).await,
+        _ => BtcAddress::p2pkh(&device, network, &path).await,
     };
-    main_address
-}
\ No newline at end of file
+    main_address.map_err(to_js_error)
+}
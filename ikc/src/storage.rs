@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use ikc_device::error::BindError;
+use ikc_device::key_store::KeyStore;
+use ikc_device::Result;
+use web_sys::{window, Storage};
+
+/**
+Key store of the browser build, keeping the key files in the localStorage of the page origin
+so that bindings survive page reloads. Names are prefixed to keep clear of the page's own keys.
+*/
+pub struct LocalStorageKeyStore {
+    prefix: String,
+}
+
+impl LocalStorageKeyStore {
+    pub fn new(prefix: &str) -> Self {
+        LocalStorageKeyStore {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+}
+
+fn storage() -> Result<Storage> {
+    window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .ok_or_else(|| BindError::ImkeyKeyfileIoError.into())
+}
+
+#[async_trait(?Send)]
+impl KeyStore for LocalStorageKeyStore {
+    async fn load(&self, name: &str) -> Result<Option<String>> {
+        storage()?
+            .get_item(&self.key(name))
+            .map_err(|_| BindError::ImkeyKeyfileIoError.into())
+    }
+
+    async fn save(&self, name: &str, data: &str) -> Result<()> {
+        storage()?
+            .set_item(&self.key(name), data)
+            .map_err(|_| BindError::ImkeyKeyfileIoError.into())
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        storage()?
+            .remove_item(&self.key(name))
+            .map_err(|_| BindError::ImkeyKeyfileIoError.into())
+    }
+}